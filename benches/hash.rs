@@ -0,0 +1,31 @@
+#![feature(test)]
+
+extern crate test;
+
+use private_share::hash::HashAlgorithm;
+use test::Bencher;
+
+const DATA_SIZE: usize = 16 * 1024 * 1024; // 16MiB
+
+fn bench_hash(b: &mut Bencher, hash_algorithm: HashAlgorithm) {
+    let data = vec![0x5a; DATA_SIZE];
+
+    b.bytes = DATA_SIZE as _;
+    b.iter(|| {
+        let mut hasher = hash_algorithm.hasher();
+        hasher.update(&data);
+
+        hasher.finalize()
+    });
+}
+
+#[bench]
+fn bench_sha256(b: &mut Bencher) {
+    bench_hash(b, HashAlgorithm::Sha256);
+}
+
+#[cfg(feature = "blake3")]
+#[bench]
+fn bench_blake3(b: &mut Bencher) {
+    bench_hash(b, HashAlgorithm::Blake3);
+}
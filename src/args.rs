@@ -14,11 +14,19 @@ pub enum Mode {
         secret_key_path: String,
         public_key_path: String,
     },
+    /// generate the example config into the config dir
+    GenConfig {
+        config_dir: String,
+    },
+    /// call the http api of a running node
+    Client(ClientArgs),
 }
 
 #[derive(Debug, Args)]
 pub struct RunArgs {
-    #[arg(short, long)]
+    /// the config dir, `-` reads the config from stdin and the http url fetches the config, the
+    /// config of them is read-only, so adding or removing peers at runtime fails
+    #[arg(short, long, alias = "config")]
     pub config_dir: String,
 
     #[arg(short, long)]
@@ -26,4 +34,75 @@ pub struct RunArgs {
 
     #[arg(long)]
     pub enable_relay_service: bool,
+
+    /// write the pid into the file, refuse to start when a live process holds it
+    #[arg(long)]
+    pub pidfile: Option<String>,
+
+    /// detach from the terminal and run in background
+    #[arg(long)]
+    pub daemonize: bool,
+}
+
+#[derive(Debug, Args)]
+pub struct ClientArgs {
+    /// the http api url of the node, including the api prefix
+    #[arg(short, long, default_value = "http://127.0.0.1:19998/api")]
+    pub server_url: String,
+
+    #[command(subcommand)]
+    pub command: ClientCommand,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum ClientCommand {
+    /// list the local and peer files
+    List {
+        /// only list the local files
+        #[arg(long)]
+        local: bool,
+        /// mark the local files whose index file is missing or broken
+        #[arg(long)]
+        check_health: bool,
+    },
+    /// add the file on the node host into the store
+    Add {
+        file_path: String,
+        /// the known hash of the file, the node trusts it without hashing when `verify_on_add`
+        /// is disabled
+        #[arg(long)]
+        hash: Option<String>,
+    },
+    /// list the connected peers
+    Peers,
+    AddPeers {
+        peers: Vec<String>,
+    },
+    RemovePeers {
+        peers: Vec<String>,
+    },
+    /// check whether the peer id or the multiaddr is reachable, the peer isn't added
+    Ping {
+        peer: String,
+    },
+    /// show the node stats, including the syncing files
+    Status,
+    /// shut down the node gracefully, it requires the api token
+    Shutdown,
+    /// show the downloaded ranges of the file
+    Availability {
+        filename: String,
+    },
+    /// show the peers advertising the file
+    FilePeers {
+        filename: String,
+    },
+    /// sync the remote file now instead of waiting for the next sync
+    Sync {
+        filename: String,
+    },
+    /// show the entry numbers of the node internal states, it helps to find out the leaks
+    Diagnostics,
+    /// show the downloading files and the files waiting for a sync attempt
+    Syncs,
 }
@@ -0,0 +1,418 @@
+use std::env;
+use std::time::{Duration, SystemTime};
+
+use anyhow::anyhow;
+use byte_unit::Byte;
+use http::header::{AUTHORIZATION, CONTENT_TYPE};
+use http::{Method, Request};
+use hyper::client::HttpConnector;
+use hyper::{Body, Client};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::args::{ClientArgs, ClientCommand};
+use crate::manipulate::http::encode_path_segment;
+use crate::manipulate::http::response::{
+    AddFileRequest, AddPeersRequest, DiagnosticsResponse, FileAvailabilityResponse,
+    FilePeersResponse, ListPeersResponse, ListResponse, PeerAddrStatus, PingPeerRequest,
+    PingPeerResponse, RemovePeersRequest, StatsResponse, SyncFileResponse, SyncState,
+    SyncsResponse,
+};
+
+/// the env of the api token, it is sent as the bearer token when it is set
+const API_TOKEN_ENV: &str = "PRIVATE_SHARE_API_TOKEN";
+
+/// the http client of the node api
+struct ApiClient {
+    client: Client<HttpConnector>,
+    server_url: String,
+    token: Option<String>,
+}
+
+impl ApiClient {
+    fn new(server_url: &str) -> Self {
+        Self {
+            client: Client::new(),
+            server_url: server_url.trim_end_matches('/').to_string(),
+            token: env::var(API_TOKEN_ENV)
+                .ok()
+                .filter(|token| !token.is_empty()),
+        }
+    }
+
+    async fn get<T: DeserializeOwned>(&self, path: &str) -> anyhow::Result<T> {
+        let body = self.request(Method::GET, path, Body::empty()).await?;
+
+        Ok(serde_json::from_slice(&body)?)
+    }
+
+    async fn post<B: Serialize>(&self, path: &str, body: &B) -> anyhow::Result<()> {
+        self.request(Method::POST, path, serde_json::to_vec(body)?.into())
+            .await?;
+
+        Ok(())
+    }
+
+    async fn request(&self, method: Method, path: &str, body: Body) -> anyhow::Result<Vec<u8>> {
+        let mut request = Request::builder()
+            .method(method)
+            .uri(format!("{}{path}", self.server_url))
+            .header(CONTENT_TYPE, "application/json");
+        if let Some(token) = &self.token {
+            request = request.header(AUTHORIZATION, format!("Bearer {token}"));
+        }
+
+        let response = self.client.request(request.body(body)?).await?;
+        let status = response.status();
+        let body = hyper::body::to_bytes(response.into_body()).await?;
+
+        if !status.is_success() {
+            return Err(anyhow!(
+                "request {path} failed, status {status}: {}",
+                String::from_utf8_lossy(&body)
+            ));
+        }
+
+        Ok(body.to_vec())
+    }
+}
+
+/// run the client command and print the result
+pub async fn run(args: ClientArgs) -> anyhow::Result<()> {
+    let client = ApiClient::new(&args.server_url);
+
+    match args.command {
+        ClientCommand::List {
+            local,
+            check_health,
+        } => {
+            let response = client
+                .get::<ListResponse>(&format!(
+                    "/list_files?include_peer={}&check_health={check_health}",
+                    !local
+                ))
+                .await?;
+
+            for file in response.files {
+                let state = match (file.downloaded, file.healthy) {
+                    (_, Some(false)) => "broken",
+                    (true, _) => "local",
+                    (false, _) => "remote",
+                };
+
+                println!("{}\t{}\t{state}\t{}", file.filename, file.size, file.hash);
+            }
+        }
+
+        ClientCommand::Add { file_path, hash } => {
+            client
+                .post("/add_file", &AddFileRequest { file_path, hash })
+                .await?;
+
+            println!("file is added");
+        }
+
+        ClientCommand::Peers => {
+            let response = client.get::<ListPeersResponse>("/list_peers").await?;
+
+            for peer in response.peers {
+                println!(
+                    "{}\t{}\tuptime {}\tconnections {}\t{}",
+                    peer.peer,
+                    peer.agent_version.as_deref().unwrap_or("unidentified"),
+                    humantime::format_duration(Duration::from_secs(peer.uptime_secs)),
+                    peer.connections,
+                    peer.connected_addrs.join(", ")
+                );
+            }
+
+            for peer in response.connecting {
+                println!("{}\tconnecting\t{}", peer.peer, peer.addr);
+            }
+
+            for peer in response.configured {
+                let status = match peer.status {
+                    PeerAddrStatus::Connected => "connected",
+                    PeerAddrStatus::Connecting => "connecting",
+                    PeerAddrStatus::Unreachable => "unreachable",
+                };
+
+                println!("{}\tconfigured\t{status}", peer.addr);
+            }
+
+            for peer in response.discovered {
+                println!("{}\tdiscovered\t{}", peer.peer, peer.addrs.join(", "));
+            }
+        }
+
+        ClientCommand::AddPeers { peers } => {
+            client
+                .post("/add_peers", &AddPeersRequest { peers })
+                .await?;
+
+            println!("peers are added");
+        }
+
+        ClientCommand::RemovePeers { peers } => {
+            client
+                .post("/remove_peers", &RemovePeersRequest { peers })
+                .await?;
+
+            println!("peers are removed");
+        }
+
+        ClientCommand::Ping { peer } => {
+            let body = client
+                .request(
+                    Method::POST,
+                    "/ping_peer",
+                    serde_json::to_vec(&PingPeerRequest { peer })?.into(),
+                )
+                .await?;
+            let response = serde_json::from_slice::<PingPeerResponse>(&body)?;
+
+            match (response.rtt_ms, response.error) {
+                (Some(rtt_ms), _) => {
+                    println!("{} is reachable, rtt {rtt_ms:.2}ms", response.peer_id)
+                }
+                (None, error) => println!(
+                    "{} is unreachable: {}",
+                    response.peer_id,
+                    error.unwrap_or_default()
+                ),
+            }
+        }
+
+        ClientCommand::Shutdown => {
+            client.post("/shutdown", &()).await?;
+
+            println!("node is shutting down");
+        }
+
+        ClientCommand::Status => {
+            let stats = client.get::<StatsResponse>("/stats").await?;
+
+            println!("peers: {}", stats.peers);
+            println!("local files: {}", stats.local_files);
+            println!("remote files: {}", stats.remote_files);
+            println!("shared: {}", format_bytes(stats.shared_bytes));
+            println!("syncing files: {}", stats.syncing_files);
+            println!("inbound: {}", format_bytes(stats.inbound));
+            println!("outbound: {}", format_bytes(stats.outbound));
+            println!(
+                "next refresh store: {}",
+                humantime::format_duration(Duration::from_secs(stats.next_refresh_store))
+            );
+            println!(
+                "next sync file: {}",
+                humantime::format_duration(Duration::from_secs(stats.next_sync_file))
+            );
+            println!(
+                "connection errors: dial {}, timeout {}, handshake {}, key mismatch {}, pnet {}, \
+                 other {}",
+                stats.connection_errors.dial,
+                stats.connection_errors.timeout,
+                stats.connection_errors.handshake,
+                stats.connection_errors.key_mismatch,
+                stats.connection_errors.pnet,
+                stats.connection_errors.other
+            );
+            println!(
+                "gossip: file topic mesh {} subscribed {}, discover topic mesh {} subscribed {}, \
+                 all mesh {}",
+                stats.gossip.file_topic.mesh_peers,
+                stats.gossip.file_topic.subscribed_peers,
+                stats.gossip.discover_topic.mesh_peers,
+                stats.gossip.discover_topic.subscribed_peers,
+                stats.gossip.all_mesh_peers
+            );
+            println!(
+                "sync: synced files {}, failed files {}, downloaded {}, served {}",
+                stats.sync_counters.synced_files,
+                stats.sync_counters.failed_files,
+                format_bytes(stats.sync_counters.downloaded_bytes),
+                format_bytes(stats.sync_counters.served_bytes)
+            );
+            if let Some(relay) = &stats.relay {
+                println!(
+                    "relay: reservations {}, circuits {}",
+                    relay.reservations, relay.circuits
+                );
+            }
+            for (dir, disk_space) in [
+                ("index dir", stats.disk_space.index_dir),
+                ("store dir", stats.disk_space.store_dir),
+                ("temp dir", stats.disk_space.temp_dir),
+            ] {
+                match disk_space {
+                    None => println!("{dir} space: unknown"),
+                    Some(disk_space) => println!(
+                        "{dir} space: {} free of {}",
+                        format_bytes(disk_space.free),
+                        format_bytes(disk_space.total)
+                    ),
+                }
+            }
+
+            if let Some(last_scrub_time) = stats.last_scrub_time {
+                println!(
+                    "last scrub: {}",
+                    humantime::format_rfc3339_seconds(
+                        SystemTime::UNIX_EPOCH + Duration::from_secs(last_scrub_time)
+                    )
+                );
+                println!(
+                    "corrupted files: {}",
+                    stats.scrub_corrupted_files.join(", ")
+                );
+                println!("scrub errors: {}", stats.scrub_errors.len());
+            }
+        }
+
+        ClientCommand::Availability { filename } => {
+            let availability = client
+                .get::<FileAvailabilityResponse>(&format!(
+                    "/availability/{}",
+                    encode_path_segment(&filename)
+                ))
+                .await?;
+
+            println!(
+                "{}\t{}/{}\tcomplete: {}",
+                availability.filename,
+                format_bytes(availability.available_size),
+                format_bytes(availability.size),
+                availability.complete
+            );
+            if let Some(bytes_per_sec) = availability.bytes_per_sec {
+                let eta = availability
+                    .eta_secs
+                    .map(|eta_secs| {
+                        humantime::format_duration(Duration::from_secs(eta_secs)).to_string()
+                    })
+                    .unwrap_or_else(|| "unknown".to_string());
+
+                println!("rate: {}/s\teta: {eta}", format_bytes(bytes_per_sec));
+            }
+            for (start, end) in availability.ranges {
+                println!("{start}-{end}");
+            }
+        }
+
+        ClientCommand::FilePeers { filename } => {
+            let response = client
+                .get::<FilePeersResponse>(&format!(
+                    "/file_peers/{}",
+                    encode_path_segment(&filename)
+                ))
+                .await?;
+
+            for peer in response.peers {
+                let state = if peer.connected {
+                    "connected"
+                } else {
+                    "advertised"
+                };
+
+                println!("{}\t{state}\t{}", peer.peer, peer.hash);
+            }
+        }
+
+        ClientCommand::Sync { filename } => {
+            let body = client
+                .request(
+                    Method::POST,
+                    &format!("/sync_file/{}", encode_path_segment(&filename)),
+                    Body::empty(),
+                )
+                .await?;
+            let response = serde_json::from_slice::<SyncFileResponse>(&body)?;
+
+            if response.already_syncing {
+                println!(
+                    "{} is already syncing, hash {}",
+                    response.filename, response.hash
+                );
+            } else {
+                println!(
+                    "{} starts syncing, hash {}",
+                    response.filename, response.hash
+                );
+            }
+        }
+
+        ClientCommand::Diagnostics => {
+            let diagnostics = client.get::<DiagnosticsResponse>("/diagnostics").await?;
+
+            println!(
+                "peer stores: {}, disconnected {}, files {}",
+                diagnostics.peer_stores,
+                diagnostics.disconnected_peer_stores,
+                diagnostics.peer_store_files
+            );
+            println!("file get requests: {}", diagnostics.file_get_requests);
+            println!("connected peers: {}", diagnostics.connected_peers);
+            println!(
+                "connecting peers: {}, pending addrs {}",
+                diagnostics.peer_addr_connecting, diagnostics.pending_peer_addrs
+            );
+            println!("discover peers: {}", diagnostics.discover_peers);
+            println!(
+                "syncing files: {}, failures {}",
+                diagnostics.syncing_files, diagnostics.sync_failures
+            );
+            println!("adding files: {}", diagnostics.adding_files);
+            println!(
+                "file cache: {}/{}, mapped {}",
+                diagnostics.cached_files,
+                diagnostics.max_cached_files,
+                format_bytes(diagnostics.cached_mapped_bytes)
+            );
+        }
+
+        ClientCommand::Syncs => {
+            let syncs = client.get::<SyncsResponse>("/syncs").await?;
+
+            for file in syncs.files {
+                let state = match file.state {
+                    SyncState::Active => "active",
+                    SyncState::Retrying => "retrying",
+                    SyncState::Queued => "queued",
+                    SyncState::Failed => "failed",
+                };
+                let rate = file
+                    .bytes_per_sec
+                    .map(|rate| format!("{}/s", format_bytes(rate)))
+                    .unwrap_or_else(|| "-".to_string());
+
+                println!(
+                    "{state}\t{}/{}\t{rate}\t{} peers\t{}",
+                    format_bytes(file.synced),
+                    format_bytes(file.size),
+                    file.peers.len(),
+                    file.filenames.join(", ")
+                );
+                if let Some(last_error) = file.last_error {
+                    println!("\tfailed {} times: {last_error}", file.failed_attempts);
+                }
+                if let Some(retry_in) = file.retry_in {
+                    println!(
+                        "\tretry in {}",
+                        humantime::format_duration(Duration::from_secs(retry_in))
+                    );
+                }
+                for source in file.sources {
+                    println!("\tfrom {}: {}", source.peer_id, format_bytes(source.bytes));
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn format_bytes(size: u64) -> String {
+    Byte::from_bytes(size)
+        .get_appropriate_unit(true)
+        .to_string()
+}
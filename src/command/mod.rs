@@ -1,13 +1,19 @@
 use std::collections::HashSet;
-use std::fmt::{Debug, Formatter};
+use std::error::Error;
+use std::fmt::{self, Debug, Display, Formatter};
 use std::io;
+use std::io::ErrorKind;
+use std::ops::Range;
 use std::path::PathBuf;
+use std::time::{Duration, SystemTime};
 
 use bytes::Bytes;
 pub use file::FileGetter;
 use futures_channel::oneshot::Sender;
 use futures_util::Stream;
 use libp2p::{Multiaddr, PeerId};
+use tokio::sync::broadcast;
+use tokio_util::sync::CancellationToken;
 
 mod file;
 
@@ -18,23 +24,31 @@ where
 {
     AddFile {
         file_path: PathBuf,
+        /// the hash known by the caller, it is verified unless the node trusts it
+        hash: Option<String>,
         result_sender: Sender<io::Result<()>>,
     },
 
     ListFiles {
         include_peer: bool,
+        /// check the index files of the local files, the broken files are listed as unhealthy
+        check_health: bool,
         result_sender: Sender<io::Result<Vec<ListFileDetail>>>,
     },
 
     UploadFile {
         filename: String,
         hash: Option<String>,
+        /// the stream is a chunk of the resumable upload, the hash is required
+        range: Option<UploadRange>,
         file_stream: FileStream,
+        /// canceled when the upload request is dropped, the early ended stream isn't stored
+        cancel: CancellationToken,
         result_sender: Sender<io::Result<()>>,
     },
 
     ListPeers {
-        result_sender: Sender<Vec<(PeerId, HashSet<Multiaddr>)>>,
+        result_sender: Sender<ListPeersDetail>,
     },
 
     GetBandwidth {
@@ -51,10 +65,108 @@ where
         result_sender: Sender<io::Result<()>>,
     },
 
+    /// remove the store files which are listed or match the pattern
+    RemoveFiles {
+        filenames: Vec<String>,
+        pattern: Option<String>,
+        result_sender: Sender<io::Result<RemoveFilesDetail>>,
+    },
+
     GetFile {
         filename: String,
         file_getter: FileGetter,
-        result_sender: Sender<io::Result<Option<FileGetter::FileContent>>>,
+        result_sender: Sender<io::Result<Option<GetFileDetail<FileGetter::FileContent>>>>,
+    },
+
+    /// get the index file by hash, unlike [`Command::GetFile`], the store dir is never changed
+    GetIndexFile {
+        hash: String,
+        file_getter: FileGetter,
+        result_sender: Sender<io::Result<Option<GetFileDetail<FileGetter::FileContent>>>>,
+    },
+
+    GetLocalPeerId {
+        result_sender: Sender<PeerId>,
+    },
+
+    GetFileAvailability {
+        filename: String,
+        result_sender: Sender<io::Result<Option<FileAvailability>>>,
+    },
+
+    /// fetch the range of the remote file from the peers out of the sync order, the fetched
+    /// chunks are kept for the later sync
+    FetchRange {
+        filename: String,
+        range: Range<u64>,
+        result_sender: Sender<io::Result<()>>,
+    },
+
+    GetStats {
+        result_sender: Sender<io::Result<NodeStats>>,
+    },
+
+    /// get the peers advertising the file
+    GetFilePeers {
+        filename: String,
+        result_sender: Sender<Vec<FilePeerDetail>>,
+    },
+
+    /// rehash the index file of the store file, `None` means the store file doesn't exist
+    VerifyFile {
+        filename: String,
+        result_sender: Sender<io::Result<Option<VerifyFileDetail>>>,
+    },
+
+    /// collect the files which the next sync would download, without downloading them
+    GetSyncPlan {
+        result_sender: Sender<io::Result<Vec<SyncPlanFile>>>,
+    },
+
+    /// list the syncing files and the files waiting for a sync attempt
+    ListSyncs {
+        result_sender: Sender<io::Result<Vec<SyncEntry>>>,
+    },
+
+    /// subscribe the changes of the files advertised by the peers
+    SubscribeFileEvents {
+        result_sender: Sender<broadcast::Receiver<FileEvent>>,
+    },
+
+    /// list the store files with the local only details
+    ListMyFiles {
+        result_sender: Sender<io::Result<Vec<MyFileDetail>>>,
+    },
+
+    /// start syncing the remote file now, out of the sync order and the max sync files, the max
+    /// auto sync file size doesn't apply
+    SyncFile {
+        filename: String,
+        result_sender: Sender<io::Result<SyncFileDetail>>,
+    },
+
+    /// get the sizes of the node internal states, it helps to find out the leaks
+    GetInternalDiagnostics {
+        result_sender: Sender<InternalDiagnostics>,
+    },
+
+    /// sign the list of the local files, the manifest is the encoded file message
+    ExportManifest {
+        result_sender: Sender<io::Result<Bytes>>,
+    },
+
+    /// verify the manifest of the other node and accept its files like its file message, the
+    /// files are synced at once
+    ImportManifest {
+        manifest: Bytes,
+        result_sender: Sender<io::Result<ImportManifestDetail>>,
+    },
+
+    /// dial the peer if it isn't connected and wait for its ping, the peer isn't added to the
+    /// config, the connection dialed for the ping is closed after it
+    PingPeer {
+        target: PingTarget,
+        result_sender: Sender<io::Result<PingPeerDetail>>,
     },
 }
 
@@ -65,26 +177,44 @@ where
 {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         let mut debug_struct = match self {
-            Command::AddFile { file_path, .. } => {
+            Command::AddFile {
+                file_path, hash, ..
+            } => {
                 let mut debug_struct = f.debug_struct("Command::AddFile");
 
-                debug_struct.field("file_path", file_path);
+                debug_struct
+                    .field("file_path", file_path)
+                    .field("hash", hash);
 
                 debug_struct
             }
 
-            Command::ListFiles { include_peer, .. } => {
+            Command::ListFiles {
+                include_peer,
+                check_health,
+                ..
+            } => {
                 let mut debug_struct = f.debug_struct("Command::ListFiles");
 
-                debug_struct.field("include_peer", include_peer);
+                debug_struct
+                    .field("include_peer", include_peer)
+                    .field("check_health", check_health);
 
                 debug_struct
             }
 
-            Command::UploadFile { filename, hash, .. } => {
+            Command::UploadFile {
+                filename,
+                hash,
+                range,
+                ..
+            } => {
                 let mut debug_struct = f.debug_struct("Command::UploadFile");
 
-                debug_struct.field("filename", filename).field("hash", hash);
+                debug_struct
+                    .field("filename", filename)
+                    .field("hash", hash)
+                    .field("range", range);
 
                 debug_struct
             }
@@ -109,6 +239,18 @@ where
                 debug_struct
             }
 
+            Command::RemoveFiles {
+                filenames, pattern, ..
+            } => {
+                let mut debug_struct = f.debug_struct("Command::RemoveFiles");
+
+                debug_struct
+                    .field("filenames", filenames)
+                    .field("pattern", pattern);
+
+                debug_struct
+            }
+
             Command::GetFile { filename, .. } => {
                 let mut debug_struct = f.debug_struct("Command::GetFile");
 
@@ -116,12 +258,484 @@ where
 
                 debug_struct
             }
+
+            Command::GetIndexFile { hash, .. } => {
+                let mut debug_struct = f.debug_struct("Command::GetIndexFile");
+
+                debug_struct.field("hash", hash);
+
+                debug_struct
+            }
+
+            Command::GetLocalPeerId { .. } => f.debug_struct("Command::GetLocalPeerId"),
+
+            Command::GetFileAvailability { filename, .. } => {
+                let mut debug_struct = f.debug_struct("Command::GetFileAvailability");
+
+                debug_struct.field("filename", filename);
+
+                debug_struct
+            }
+
+            Command::FetchRange {
+                filename, range, ..
+            } => {
+                let mut debug_struct = f.debug_struct("Command::FetchRange");
+
+                debug_struct
+                    .field("filename", filename)
+                    .field("range", range);
+
+                debug_struct
+            }
+
+            Command::GetStats { .. } => f.debug_struct("Command::GetStats"),
+
+            Command::GetFilePeers { filename, .. } => {
+                let mut debug_struct = f.debug_struct("Command::GetFilePeers");
+
+                debug_struct.field("filename", filename);
+
+                debug_struct
+            }
+
+            Command::VerifyFile { filename, .. } => {
+                let mut debug_struct = f.debug_struct("Command::VerifyFile");
+
+                debug_struct.field("filename", filename);
+
+                debug_struct
+            }
+
+            Command::GetSyncPlan { .. } => f.debug_struct("Command::GetSyncPlan"),
+
+            Command::ListSyncs { .. } => f.debug_struct("Command::ListSyncs"),
+
+            Command::SubscribeFileEvents { .. } => f.debug_struct("Command::SubscribeFileEvents"),
+
+            Command::ListMyFiles { .. } => f.debug_struct("Command::ListMyFiles"),
+
+            Command::SyncFile { filename, .. } => {
+                let mut debug_struct = f.debug_struct("Command::SyncFile");
+
+                debug_struct.field("filename", filename);
+
+                debug_struct
+            }
+
+            Command::GetInternalDiagnostics { .. } => {
+                f.debug_struct("Command::GetInternalDiagnostics")
+            }
+
+            Command::ExportManifest { .. } => f.debug_struct("Command::ExportManifest"),
+
+            Command::ImportManifest { manifest, .. } => {
+                let mut debug_struct = f.debug_struct("Command::ImportManifest");
+
+                debug_struct.field("manifest", &manifest.len());
+
+                debug_struct
+            }
+
+            Command::PingPeer { target, .. } => {
+                let mut debug_struct = f.debug_struct("Command::PingPeer");
+
+                debug_struct.field("target", target);
+
+                debug_struct
+            }
         };
 
         debug_struct.finish()
     }
 }
 
+/// the byte range of a resumable upload chunk, the chunk is appended to the partial upload file
+/// whose length must be `start`
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct UploadRange {
+    pub start: u64,
+    /// exclusive
+    pub end: u64,
+    /// the size of the whole file
+    pub total: u64,
+}
+
+/// the downloaded byte ranges of a file, a stored file is fully available
+#[derive(Debug)]
+pub struct FileAvailability {
+    pub hash: String,
+    pub size: u64,
+    pub ranges: Vec<Range<u64>>,
+    /// the file holding the available data, the temp index file when the file is syncing
+    pub path: PathBuf,
+    /// the recent download rate in bytes per second, only the syncing file has it
+    pub bytes_per_sec: Option<u64>,
+}
+
+/// the peer which advertises the file in its gossip store message
+#[derive(Debug)]
+pub struct FilePeerDetail {
+    pub peer: PeerId,
+    /// the peers may advertise the different contents with the same filename
+    pub hash: String,
+    /// the peer is connected now, otherwise only its last advertisement is known
+    pub connected: bool,
+}
+
+/// the store file of the node
+#[derive(Debug)]
+pub struct MyFileDetail {
+    pub filename: String,
+    pub hash: String,
+    pub size: u64,
+    /// the index file which the store file links to
+    pub index_path: PathBuf,
+    /// the file matches the exclude patterns or doesn't match the share only patterns, it is not
+    /// announced to the peers
+    pub excluded: bool,
+    pub verify_status: VerifyStatus,
+}
+
+/// the integrity of the index file found by the scrub
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum VerifyStatus {
+    /// no scrub has finished since the index file is written
+    Unverified,
+    Verified,
+    Corrupted,
+}
+
+/// the file which the next sync would download
+#[derive(Debug)]
+pub struct SyncPlanFile {
+    pub hash: String,
+    /// the filenames of the file advertised by the peers
+    pub filenames: Vec<String>,
+    pub size: u64,
+    /// the peers which would serve the file
+    pub peers: Vec<PeerId>,
+    /// the file is syncing now, only its missing chunks would be downloaded
+    pub syncing: bool,
+    /// the number of the failed sync attempts since the last success
+    pub failed_attempts: u32,
+    pub last_error: Option<String>,
+}
+
+/// the change of the files advertised by the peers
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum FileEvent {
+    /// the new file message of the peer doesn't list the file anymore
+    PeerFileRemoved {
+        peer: PeerId,
+        filename: String,
+        hash: String,
+    },
+}
+
+/// the sync state of a file, the states are ordered by how soon the file is downloaded
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd)]
+pub enum SyncState {
+    /// the file is downloading now
+    Active,
+    /// the last attempt failed, the file is synced again after the retry backoff
+    Retrying,
+    /// the file waits for a free sync slot or the next sync tick
+    Queued,
+    /// the last attempt failed and no retry is scheduled, the file waits for the next sync tick
+    Failed,
+}
+
+/// the file in the download manager view
+#[derive(Debug)]
+pub struct SyncEntry {
+    pub hash: String,
+    /// the filenames of the file advertised by the peers
+    pub filenames: Vec<String>,
+    pub size: u64,
+    /// the downloaded bytes which are kept for the next attempt
+    pub synced: u64,
+    /// the recent download rate, only the active file has it
+    pub bytes_per_sec: Option<u64>,
+    /// the peers which advertise the file
+    pub peers: Vec<PeerId>,
+    pub state: SyncState,
+    /// the number of the failed sync attempts since the last success
+    pub failed_attempts: u32,
+    pub last_error: Option<String>,
+    /// the time until the retry of the retrying file
+    pub retry_in: Option<Duration>,
+    /// the bytes downloaded from each peer, kept for the next attempt like `synced`
+    pub sources: Vec<DownloadSource>,
+}
+
+/// the bytes of a syncing file downloaded from a peer
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct DownloadSource {
+    pub peer: PeerId,
+    pub bytes: u64,
+}
+
+#[derive(Debug)]
+pub struct SyncFileDetail {
+    pub hash: String,
+    /// the file was already syncing, no new sync is started
+    pub already_syncing: bool,
+}
+
+#[derive(Debug)]
+pub struct ImportManifestDetail {
+    /// the node which exported the manifest
+    pub peer: PeerId,
+    pub files: usize,
+    /// the manifest is older than the accepted file message of the node, it is ignored
+    pub outdated: bool,
+}
+
+/// the peer to ping, the peer id is dialed by its discovered addrs
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum PingTarget {
+    Peer(PeerId),
+    /// the addr must contain the peer id
+    Addr(Multiaddr),
+}
+
+#[derive(Debug)]
+pub struct PingPeerDetail {
+    pub peer: PeerId,
+    /// the peer was connected before the ping
+    pub connected: bool,
+    /// the round trip time of the ping, or why the peer is unreachable
+    pub result: Result<Duration, String>,
+}
+
+/// the entry numbers of the node internal states, the entries of a state are expected to be
+/// removed when the peer disconnects or the request finishes
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+pub struct InternalDiagnostics {
+    pub peer_stores: usize,
+    /// the peer stores whose peers aren't connected now
+    pub disconnected_peer_stores: usize,
+    /// the files of all peer stores
+    pub peer_store_files: usize,
+    /// the file requests which are waiting for the peer responses
+    pub file_get_requests: usize,
+    pub connected_peers: usize,
+    /// the peers which are being dialed
+    pub peer_addr_connecting: usize,
+    /// the peer addrs which are waiting to be dialed
+    pub pending_peer_addrs: usize,
+    pub discover_peers: usize,
+    pub syncing_files: usize,
+    pub sync_failures: usize,
+    /// the store files which are being added
+    pub adding_files: usize,
+    /// the opened index files of the file cache
+    pub cached_files: usize,
+    pub max_cached_files: usize,
+    /// the total size of the memory mapped files of the file cache
+    pub cached_mapped_bytes: u64,
+}
+
+#[derive(Debug)]
+pub struct VerifyFileDetail {
+    /// the hash in the symlink target
+    pub expected_hash: String,
+    /// the hash of the index file content
+    pub computed_hash: String,
+}
+
+/// the snapshot of the node, it is gathered in one command so the numbers are consistent
+#[derive(Debug)]
+pub struct NodeStats {
+    pub peers: usize,
+    pub local_files: usize,
+    /// the peer files which are not in the store dir
+    pub remote_files: usize,
+    /// the total size of the local files, the files with the same hash are counted once
+    pub shared_bytes: u64,
+    pub syncing_files: usize,
+    pub inbound: u64,
+    pub outbound: u64,
+    /// when the last index files scrub finished
+    pub last_scrub_time: Option<SystemTime>,
+    /// the hashes of the index files which are found corrupted by the last scrub
+    pub scrub_corrupted_files: Vec<String>,
+    pub scrub_errors: Vec<String>,
+    pub connection_errors: ConnectionErrorStats,
+    /// the space of the filesystems backing the dirs, they may be on different mounts, `None`
+    /// means the query failed
+    pub index_dir_space: Option<DiskSpace>,
+    pub store_dir_space: Option<DiskSpace>,
+    pub temp_dir_space: Option<DiskSpace>,
+    /// the gossip peers of the file share topic
+    pub file_topic: GossipTopicStats,
+    /// the gossip peers of the discover topic
+    pub discover_topic: GossipTopicStats,
+    /// the peers meshed on any topic
+    pub all_mesh_peers: usize,
+    /// the time until the next refresh store tick publishes the local files
+    pub next_refresh_store: Duration,
+    /// the time until the next sync file tick picks up the missing peer files
+    pub next_sync_file: Duration,
+    /// `None` when the relay service is disabled
+    pub relay: Option<RelayServiceStats>,
+    pub sync_counters: SyncCounterStats,
+}
+
+/// the load of the relay service, the closed circuits are not reported by the relay behaviour
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+pub struct RelayServiceStats {
+    /// the connected peers which listen through this node
+    pub reservations: usize,
+    /// the circuits relayed since the node started
+    pub circuits: u64,
+}
+
+/// the gossip peers of a topic, the explicit peers receive the messages without joining the mesh,
+/// so a connected peer may be subscribed but not meshed
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+pub struct GossipTopicStats {
+    pub mesh_peers: usize,
+    /// the peers which subscribe the topic
+    pub subscribed_peers: usize,
+}
+
+/// the space of a filesystem in bytes, the free space is available to the unprivileged users
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct DiskSpace {
+    pub free: u64,
+    pub total: u64,
+}
+
+/// the number of the failed connections by the category since the node started
+#[derive(Debug, Copy, Clone, Default)]
+pub struct ConnectionErrorStats {
+    pub dial: u64,
+    pub timeout: u64,
+    /// the protocol negotiation or the noise handshake failures
+    pub handshake: u64,
+    /// the handshakes garbled by the different pre-shared keys
+    pub key_mismatch: u64,
+    /// the pnet handshake failures, the failed connections are counted by the other categories
+    /// too
+    pub pnet: u64,
+    pub other: u64,
+}
+
+/// the cumulative sync and serving counters since the node started
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+pub struct SyncCounterStats {
+    pub synced_files: u64,
+    /// the failed sync rounds, a retried file is counted once per failure
+    pub failed_files: u64,
+    /// the chunk bytes downloaded from the peers, including the on demand fetched ranges
+    pub downloaded_bytes: u64,
+    /// the file bytes served to the peers
+    pub served_bytes: u64,
+}
+
+#[derive(Debug)]
+pub struct ListPeerDetail {
+    pub peer: PeerId,
+    pub connected_addrs: HashSet<Multiaddr>,
+    /// the number of the connections with the peer
+    pub connections: usize,
+    /// when the first connection of the peer is established
+    pub connected_since: SystemTime,
+    /// `None` when the peer isn't identified yet
+    pub identity: Option<PeerIdentity>,
+}
+
+/// the software info which the peer reports by identify
+#[derive(Debug, Clone)]
+pub struct PeerIdentity {
+    pub protocol_version: String,
+    pub agent_version: String,
+    /// the protocols supported by the peer
+    pub protocols: Vec<String>,
+}
+
+#[derive(Debug)]
+pub struct RemoveFilesDetail {
+    /// the remove result of each file
+    pub files: Vec<(String, io::Result<()>)>,
+    /// the hashes of the index files which are removed because no store file refers to them
+    pub removed_index_files: Vec<String>,
+}
+
+/// the store file is being synced, the sync links the store file when it is done, so the other
+/// changes of it race with the sync
+#[derive(Debug)]
+pub struct FileSyncingError {
+    pub filename: String,
+}
+
+impl FileSyncingError {
+    /// whether the io error is caused by the syncing store file
+    pub fn is_cause_of(err: &io::Error) -> bool {
+        err.get_ref().map_or(false, |err| err.is::<Self>())
+    }
+}
+
+impl Display for FileSyncingError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "file {} is being synced, retry after the sync is done",
+            self.filename
+        )
+    }
+}
+
+impl Error for FileSyncingError {}
+
+impl From<FileSyncingError> for io::Error {
+    fn from(err: FileSyncingError) -> Self {
+        io::Error::new(ErrorKind::Other, err)
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct ListPeersDetail {
+    pub connected: Vec<ListPeerDetail>,
+    /// the peers which are being dialed
+    pub connecting: Vec<ConnectingPeerDetail>,
+    /// the status of each configured peer addr
+    pub configured: Vec<ConfiguredPeerDetail>,
+    /// the accepted discover addrs of the peers
+    pub discovered: Vec<DiscoveredPeerDetail>,
+}
+
+#[derive(Debug)]
+pub struct ConfiguredPeerDetail {
+    pub addr: String,
+    /// `None` when the addr doesn't contain the peer id
+    pub peer: Option<PeerId>,
+    pub status: PeerAddrStatus,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum PeerAddrStatus {
+    Connected,
+    Connecting,
+    /// neither connected nor being dialed, the addr is waiting for re-dialing after the dial
+    /// failure or the connection is closed
+    Unreachable,
+}
+
+#[derive(Debug)]
+pub struct DiscoveredPeerDetail {
+    pub peer: PeerId,
+    pub addrs: Vec<Multiaddr>,
+}
+
+#[derive(Debug)]
+pub struct ConnectingPeerDetail {
+    pub peer: PeerId,
+    pub addr: Multiaddr,
+}
+
 #[derive(Debug, Eq, PartialEq, Hash)]
 pub struct ListFileDetail {
     pub filename: String,
@@ -129,4 +743,20 @@ pub struct ListFileDetail {
     pub downloaded: bool,
     pub peers: Vec<PeerId>,
     pub size: u64,
+    /// whether the index file of the local file exists and is a regular file, `None` means it
+    /// isn't checked
+    pub healthy: Option<bool>,
+    /// the modified time of the index file, it is when the local file is added or synced, the
+    /// peer file doesn't have it
+    pub modified: Option<SystemTime>,
+}
+
+#[derive(Debug)]
+pub struct GetFileDetail<Content> {
+    pub hash: String,
+    /// the index file path which the store file links to
+    pub index_path: PathBuf,
+    /// the modified time of the index file
+    pub modified: Option<SystemTime>,
+    pub content: Content,
 }
@@ -1,32 +1,678 @@
 use std::borrow::Cow;
+use std::fmt::{Display, Formatter};
 use std::io::{Error, ErrorKind};
-use std::net::SocketAddr;
-use std::path::PathBuf;
-use std::{io, mem};
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Component, Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+use std::{env, fmt, io, mem, slice};
 
+use anyhow::anyhow;
+use byte_unit::Byte;
+use http::uri::Scheme;
+use http::Uri;
+use hyper::Client;
+use libp2p::{Multiaddr, PeerId};
 use serde::{Deserialize, Serialize};
 use tap::TapFallible;
 use tokio::fs;
+use tokio::fs::{File, OpenOptions};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::time;
 use tracing::{error, info, instrument};
 
+const EXAMPLE_CONFIG: &str = include_str!("../../example.yaml");
+
+/// the `canvaskit_url` value of serving the CanvasKit embedded in the node
+const BUNDLED_CANVASKIT: &str = "bundled";
+/// the CanvasKit dir of the flutter web build, relative to the web ui base href
+const BUNDLED_CANVASKIT_BASE_URL: &str = "canvaskit/";
+
+/// the max time of fetching the config from the url
+const CONFIG_FETCH_TIMEOUT: Duration = Duration::from_secs(30);
+
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct Config {
     pub index_dir: String,
+    /// the layout of the index files: `flat` or `hashed`, default is `flat`, `hashed` shards the
+    /// index files into `{index_dir}/ab/cd/{hash}` for the huge stores, the existing index files
+    /// are not moved when it is changed
+    pub store_layout: Option<String>,
+    /// how the index files are named: `hash` or `hash_extension`, default is `hash`,
+    /// `hash_extension` appends the store filename extension to the new index files, like
+    /// `{hash}.mp4`, the existing index files are not renamed
+    pub index_naming: Option<String>,
+    /// the dir of the in-progress downloads and uploads, default is `{index_dir}/.tmp`, a fast
+    /// scratch disk or tmpfs can be used
+    pub temp_dir: Option<String>,
+    /// the dir of the cached thumbnails, default is `{index_dir}/.thumbs`, the dir inside the
+    /// index dir must be hidden, so the scrub skips it
+    pub thumbnail_dir: Option<String>,
+    /// the upload temp files older than it are removed on startup, default is `1d`, the sync
+    /// temp files are always removed
+    pub stale_temp_file_age: Option<String>,
+    /// rehash the index files every interval to find the silent corruption, default is disabled
+    pub scrub_interval: Option<String>,
+    /// the max read rate of the scrub per second, like `16MiB`, default is `16MiB`
+    pub scrub_rate: Option<String>,
     pub store_dir: String,
+    /// how the store files link to the index files: `symlink` or `hardlink`, default is
+    /// `symlink`, `hardlink` makes the store files regular files for the clients which can't
+    /// follow the symlinks, like the SMB clients, the store dir and the index dir must be in the
+    /// same filesystem, the existing store files are not converted when it is changed
+    pub store_link: Option<String>,
     pub secret_key_path: String,
     pub public_key_path: String,
-    pub pre_share_key: String,
+    /// the pre-shared key of the private network, a list of keys can be set when rotating the
+    /// key, the first one is used by the outbound connections and the inbound connections may use
+    /// any of them, so the new key is appended on all nodes before any node moves it to the first
+    pub pre_share_key: PreShareKey,
     pub refresh_interval: String,
     pub sync_file_interval: String,
+    /// randomize the refresh_interval and the sync_file_interval by the percentage, like `10`
+    /// means ±10%, so the nodes started together don't announce together, default is 0, it must
+    /// be less than 100
+    pub interval_jitter: Option<u8>,
+    /// the min interval of publishing discovered peers, default is `5s`
+    pub discover_interval: Option<String>,
+    /// max number of the accepted discovered addrs of a peer, the globally routable addrs are
+    /// preferred when it is reached, default is 8
+    pub max_discover_addrs: Option<usize>,
+    /// accept the discovered loopback and link local addrs, default is false
+    pub allow_local_discover_addrs: Option<bool>,
+    /// max number of the concurrent inbound file requests of a peer, the excess requests are
+    /// responded busy and retried later by the peer, the requests of all connections of the peer
+    /// are counted together, default is 32
+    pub max_peer_inbound_requests: Option<usize>,
+    /// max number of the configured peer addrs, the add peers request exceeding it is rejected,
+    /// default is 256
+    pub max_peer_addrs: Option<usize>,
+    /// file hash algorithm: `sha256` or `blake3`, default is `sha256`, mixing algorithms across a
+    /// swarm won't dedup the same file
+    pub hash_algorithm: Option<String>,
+    /// read buffer size of hashing and copying the added file, like `4MiB`, default is `1MiB`,
+    /// the larger buffer speeds up adding the large files on the fast disk
+    pub hash_buffer_size: Option<String>,
+    /// max number of the pooled read buffers for serving file chunks, default is 16
+    pub buffer_pool_size: Option<usize>,
+    /// memory map the large index files when serving them, the added, uploaded and synced index
+    /// files are stored read only for it, the writable ones stored before are read normally,
+    /// default is false
+    pub enable_mmap: Option<bool>,
+    /// max number of the files which are synced concurrently, default is 4
+    pub max_sync_files: Option<usize>,
+    /// number of the chunk requests kept in flight for each syncing file, default is 4, the
+    /// larger value keeps the slow connections busy but buffers more chunks, it is independent
+    /// of `max_sync_files`
+    pub sync_read_ahead: Option<usize>,
+    /// the peer files larger than the size, like `10GiB`, are not synced automatically, they can
+    /// still be fetched on demand, default is unlimited
+    pub max_auto_sync_file_size: Option<String>,
+    /// the peer ids whose files are synced, the files advertised only by the other peers are
+    /// still fetched on demand but not synced, default is empty which syncs from all peers
+    pub sync_from_peers: Option<Vec<String>>,
+    /// reuse the unchanged content defined chunks of the previous local version when syncing a
+    /// changed file, the chunk digests of the added and synced files are computed ahead for the
+    /// peers, default is false
+    pub content_defined_chunking: Option<bool>,
+    /// max number of the established peer connections, the new connections are rejected when it
+    /// is reached, default is unlimited
+    pub max_connections: Option<u32>,
+    /// max number of the uploads which are written concurrently, the more uploads are rejected
+    /// with `503`, default is unlimited
+    pub max_uploads: Option<usize>,
+    /// the order of syncing the files: `smallest_first`, `largest_first` or `alphabetical`,
+    /// default is `smallest_first`
+    pub sync_order: Option<String>,
+    /// max number of the retries of a file which failed to sync, the file is synced at the next
+    /// sync interval after it, `0` disables the retry, default is 3
+    pub sync_retry_max_attempts: Option<u32>,
+    /// the delay of the first retry, it doubles for each failure, default is `10s`
+    pub sync_retry_backoff: Option<String>,
+    /// the filename patterns which are never announced or synced, `*` and `?` wildcards are
+    /// supported, default excludes the hidden files and the common junk files
+    pub exclude_patterns: Option<Vec<String>>,
+    /// only the store files matching the patterns are announced and served, the same wildcards
+    /// as `exclude_patterns` are supported, default is empty which shares all store files
+    pub share_only: Option<Vec<String>>,
+    /// reject adding the file whose name differs from a store file only by case, they collide on
+    /// the case insensitive filesystems, default is false which only warns
+    pub reject_case_conflict: Option<bool>,
+    /// hash the added file even when the caller gives its hash, the given hash is verified then,
+    /// disabling it trusts the given hash and skips hashing, which speeds up the bulk imports of
+    /// the huge files but a wrong hash names the index file wrongly and the peers fail to verify
+    /// the synced file, default is true
+    pub verify_on_add: Option<bool>,
+    /// reject adding, uploading and removing the files and changing the peers, the node still
+    /// syncs and serves the files, default is false
+    pub read_only: Option<bool>,
     pub peer_addrs: Vec<String>,
+    /// ignored when the listeners are passed by systemd socket activation
     pub http_listen: Vec<SocketAddr>,
+    /// the listen addrs which serve https with the `tls_cert_path` and `tls_key_path`, ignored
+    /// when the listeners are passed by systemd socket activation, the listen fds named `https`
+    /// serve https then
+    pub https_listen: Option<Vec<SocketAddr>>,
+    /// the pem file of the tls cert chain
+    pub tls_cert_path: Option<String>,
+    /// the pem file of the tls private key, pkcs8, rsa or ec key is supported
+    pub tls_key_path: Option<String>,
+    /// the plain http listen addrs which redirect all requests to the first `https_listen` port,
+    /// they are bound even when the listeners are passed by systemd socket activation
+    pub https_redirect_listen: Option<Vec<SocketAddr>>,
     pub swarm_listen: String,
     pub relay_server_addr: Option<String>,
+    /// don't listen the swarm_listen addr, only accept the connections through the relay server
+    /// and advertise the relay circuit addr, for the node behind the strict NAT, requires
+    /// relay_server_addr, default is false
+    pub relay_only: Option<bool>,
+    /// push the identify info to the connected peers when the listen or the external addrs
+    /// change, like the relay reservation is accepted, default is false, it adds some traffic
+    pub identify_push: Option<bool>,
+    pub dns_servers: Option<Vec<SocketAddr>>,
+    /// enable the plain tcp transport, default is true
+    pub enable_tcp: Option<bool>,
+    /// the listen backlog of the swarm tcp listen sockets, default is 1024
+    pub tcp_listen_backlog: Option<u32>,
+    /// set `SO_REUSEPORT` on the swarm listen sockets and dial from the listen ports, default is
+    /// false
+    pub tcp_port_reuse: Option<bool>,
+    /// the `IP_TTL` of the swarm sockets, default is the OS default
+    pub tcp_ttl: Option<u32>,
+    /// enable the websocket transport, default is true, at least one transport must be enabled
+    pub enable_websocket: Option<bool>,
+    /// noise handshake pattern: `xx` or `ix`, default is `xx`, `ix` saves a round trip but
+    /// exposes the identities more, all nodes must use the same pattern
+    pub noise_handshake: Option<String>,
+    /// the highest file protocol version: `v1` or `v2`, default is `v2`, the peers negotiate the
+    /// highest version both support, `v2` checksums the file chunks
+    pub max_file_protocol: Option<String>,
+    /// max time of reading nothing from a file request or response, like `30s`, default is `30s`,
+    /// the stalled transfer is aborted and its connection is closed, the whole request is still
+    /// limited by the request timeout
+    pub file_read_idle_timeout: Option<String>,
+    /// the ip family of the swarm addrs: `any`, `ipv4` or `ipv6`, default is `any`, the listen,
+    /// peer and discovered addrs of the other family are ignored, forcing `ipv4` avoids the
+    /// wasted dials on a network whose ipv6 is broken
+    pub ip_family: Option<String>,
+    /// http api path prefix, default is `/api`, the embedded web ui always calls the `/api`, so
+    /// the custom prefix requires the `ui_dir` built for it or the disabled web ui
+    pub api_base_path: Option<String>,
+    /// web ui path prefix, default is `/ui`, the embedded web ui is built with the `/ui/` base
+    /// href, so the custom prefix requires the `ui_dir` built for it or the disabled web ui
+    pub ui_base_path: Option<String>,
+    /// serve the embedded web ui, default is true, the headless node serves the api only
+    pub enable_ui: Option<bool>,
+    /// where the web ui loads the CanvasKit from, `bundled` serves the one embedded in the node
+    /// for the offline deployments, or the http url of the CanvasKit dir, default is the CDN url
+    /// set at build time
+    pub canvaskit_url: Option<String>,
+    /// serve the web ui from the dir instead of the embedded one, the `index.html` is read on
+    /// every request, so the updated web ui is served without restarting the node
+    pub ui_dir: Option<String>,
+    /// allow uploading files by the webdav `PUT`, the webdav is read only by default
+    pub webdav_writable: Option<bool>,
+    /// max number of the recent file downloads kept for `/access_log`, the downloader addr and
+    /// the file are also logged, the access log is disabled when it is unset for the privacy
+    pub access_log_size: Option<usize>,
+    /// the bearer token of the `/shutdown` api, the shutdown api is rejected when it is unset
+    pub api_token: Option<String>,
+    /// http api protocol: `auto`, `http1` or `http2`, default is `auto`, `http2` serves the
+    /// prior knowledge clients only and disables the websocket routes
+    pub http_protocol: Option<String>,
+    /// max number of the api commands queued for the node, default is `1`, when the queue is
+    /// full the api requests wait for the node
+    pub command_buffer_size: Option<usize>,
+    /// max time of the api requests waiting for the full queue, default is `5s`, the request
+    /// fails with `503` after it, so a busy node doesn't hang the api
+    pub command_send_timeout: Option<String>,
+    /// gossip message validation mode: `strict`, `permissive`, `anonymous` or `none`, default is
+    /// `strict`, all nodes should use the compatible mode
+    pub gossip_validation_mode: Option<String>,
+    /// the id mixed into the gossip topic names, the nodes with different ids never see the file
+    /// lists of each other even if they share the pre-shared key, the original topics are used
+    /// when it is unset
+    pub network_id: Option<String>,
+    /// http url which is posted `{filename, hash, size}` when a file finishes syncing
+    pub sync_complete_webhook: Option<String>,
+    /// the ip or host name put into the url played by the dlna renderer, such as the lan ip of a
+    /// node behind the NAT or in a container, the local ip which can reach the renderer is
+    /// detected when it is unset
+    pub dlna_advertise_host: Option<String>,
+    /// the http or https base url which the clients reach the node at, such as the url of a
+    /// reverse proxy, it is put into the feed links, the local address of the connection is used
+    /// when it is unset
+    pub public_url: Option<String>,
+}
+
+/// the single key or the list of the accepted keys
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(untagged)]
+pub enum PreShareKey {
+    Single(String),
+    /// the first key is used by the outbound connections
+    Rotating(Vec<String>),
+}
+
+impl PreShareKey {
+    pub fn keys(&self) -> &[String] {
+        match self {
+            PreShareKey::Single(key) => slice::from_ref(key),
+            PreShareKey::Rotating(keys) => keys,
+        }
+    }
+}
+
+impl Config {
+    /// check the config and collect all the problems, so the typos are found at once
+    pub fn validate(&self) -> Result<(), Vec<String>> {
+        let mut problems = vec![];
+
+        let intervals = [
+            ("refresh_interval", Some(&self.refresh_interval)),
+            ("sync_file_interval", Some(&self.sync_file_interval)),
+            ("discover_interval", self.discover_interval.as_ref()),
+            ("command_send_timeout", self.command_send_timeout.as_ref()),
+            ("stale_temp_file_age", self.stale_temp_file_age.as_ref()),
+            ("scrub_interval", self.scrub_interval.as_ref()),
+            ("sync_retry_backoff", self.sync_retry_backoff.as_ref()),
+            (
+                "file_read_idle_timeout",
+                self.file_read_idle_timeout.as_ref(),
+            ),
+        ];
+        for (name, interval) in intervals {
+            if let Some(interval) = interval {
+                if let Err(err) = humantime::parse_duration(interval) {
+                    problems.push(format!(
+                        "{name} {interval:?} is not a valid duration: {err}"
+                    ));
+                }
+            }
+        }
+
+        if let Err(err) = self.swarm_listen.parse::<Multiaddr>() {
+            problems.push(format!(
+                "swarm_listen {:?} is not a valid multiaddr: {err}",
+                self.swarm_listen
+            ));
+        }
+
+        // the listeners are passed by systemd socket activation
+        let https_listen = self.https_listen.as_deref().unwrap_or_default();
+        if self.http_listen.is_empty()
+            && https_listen.is_empty()
+            && env::var_os("LISTEN_FDS").is_none()
+        {
+            problems.push("http_listen is empty".to_string());
+        }
+
+        if !https_listen.is_empty() {
+            for (name, path) in [
+                ("tls_cert_path", &self.tls_cert_path),
+                ("tls_key_path", &self.tls_key_path),
+            ] {
+                match path {
+                    None => problems.push(format!("https_listen needs {name}")),
+                    Some(path) if !Path::new(path).exists() => {
+                        problems.push(format!("{name} {path:?} doesn't exist"))
+                    }
+                    Some(_) => {}
+                }
+            }
+        }
+
+        if !self
+            .https_redirect_listen
+            .as_deref()
+            .unwrap_or_default()
+            .is_empty()
+            && https_listen.is_empty()
+        {
+            problems.push("https_redirect_listen needs https_listen".to_string());
+        }
+
+        if dirs_overlap(Path::new(&self.store_dir), Path::new(&self.index_dir)) {
+            problems.push(format!(
+                "store_dir {:?} and index_dir {:?} can't be the same dir or contain each other, \
+                 the store files link to the index files",
+                self.store_dir, self.index_dir
+            ));
+        }
+
+        if !self.enable_tcp.unwrap_or(true) && !self.enable_websocket.unwrap_or(true) {
+            problems.push("enable_tcp and enable_websocket are both false".to_string());
+        }
+
+        if let Some(scrub_rate) = &self.scrub_rate {
+            if let Err(err) = parse_byte_rate(scrub_rate) {
+                problems.push(format!("scrub_rate {scrub_rate:?} is invalid: {err}"));
+            }
+        }
+
+        if let Some(size) = &self.max_auto_sync_file_size {
+            if let Err(err) = parse_byte_size(size) {
+                problems.push(format!(
+                    "max_auto_sync_file_size {size:?} is invalid: {err}"
+                ));
+            }
+        }
+
+        if let Some(size) = &self.hash_buffer_size {
+            if let Err(err) = parse_byte_size(size) {
+                problems.push(format!("hash_buffer_size {size:?} is invalid: {err}"));
+            }
+        }
+
+        for peer in self.sync_from_peers.iter().flatten() {
+            if let Err(err) = peer.parse::<PeerId>() {
+                problems.push(format!("sync_from_peers {peer:?} is invalid: {err}"));
+            }
+        }
+
+        if let Some(jitter) = self.interval_jitter {
+            if jitter >= 100 {
+                problems.push(format!("interval_jitter {jitter} must be less than 100"));
+            }
+        }
+
+        if self.max_discover_addrs == Some(0) {
+            problems.push("max_discover_addrs can't be zero".to_string());
+        }
+
+        if self.max_peer_inbound_requests == Some(0) {
+            problems.push("max_peer_inbound_requests can't be zero".to_string());
+        }
+
+        if self.max_peer_addrs == Some(0) {
+            problems.push("max_peer_addrs can't be zero".to_string());
+        }
+
+        if self.api_token.as_deref() == Some("") {
+            problems.push("api_token can't be empty".to_string());
+        }
+
+        if self.access_log_size == Some(0) {
+            problems.push("access_log_size can't be zero".to_string());
+        }
+
+        if self.tcp_ttl == Some(0) {
+            problems.push("tcp_ttl can't be zero".to_string());
+        }
+
+        if self.relay_only == Some(true) && self.relay_server_addr.is_none() {
+            problems.push("relay_only requires relay_server_addr".to_string());
+        }
+
+        // the prefixes are compiled into the embedded web ui
+        if self.enable_ui != Some(false) && self.ui_dir.is_none() {
+            let is_custom = |prefix: &Option<String>, default: &str| {
+                prefix
+                    .as_deref()
+                    .map_or(false, |prefix| prefix.trim_matches('/') != default)
+            };
+
+            if is_custom(&self.api_base_path, "api") || is_custom(&self.ui_base_path, "ui") {
+                problems.push(
+                    "the embedded web ui only supports the default api_base_path and ui_base_path, set ui_dir or disable enable_ui"
+                        .to_string(),
+                );
+            }
+        }
+
+        if let Some(thumbnail_dir) = &self.thumbnail_dir {
+            if let Err(err) = check_thumbnail_dir(
+                Path::new(thumbnail_dir),
+                Path::new(&self.index_dir),
+                Path::new(&self.store_dir),
+            ) {
+                problems.push(format!("thumbnail_dir {thumbnail_dir:?} is invalid: {err}"));
+            }
+        }
+
+        if let Some(ui_dir) = &self.ui_dir {
+            if !Path::new(ui_dir).is_dir() {
+                problems.push(format!("ui_dir {ui_dir:?} is not a dir"));
+            }
+        }
+
+        if let Some(url) = &self.canvaskit_url {
+            if let Err(err) = parse_canvaskit_url(url) {
+                problems.push(format!("canvaskit_url {url:?} is invalid: {err}"));
+            }
+        }
+
+        if let Some(url) = &self.sync_complete_webhook {
+            if let Err(err) = parse_webhook_url(url) {
+                problems.push(format!("sync_complete_webhook {url:?} is invalid: {err}"));
+            }
+        }
+
+        if let Some(network_id) = &self.network_id {
+            if let Err(err) = parse_network_id(network_id) {
+                problems.push(format!("network_id {network_id:?} is invalid: {err}"));
+            }
+        }
+
+        if let Some(host) = &self.dlna_advertise_host {
+            if let Err(err) = parse_dlna_advertise_host(host) {
+                problems.push(format!("dlna_advertise_host {host:?} is invalid: {err}"));
+            }
+        }
+
+        if let Some(url) = &self.public_url {
+            if let Err(err) = parse_public_url(url) {
+                problems.push(format!("public_url {url:?} is invalid: {err}"));
+            }
+        }
+
+        let pre_share_keys = self.pre_share_key.keys();
+        if pre_share_keys.is_empty() || pre_share_keys.iter().any(String::is_empty) {
+            problems.push("pre_share_key is empty".to_string());
+        }
+
+        for (name, path) in [
+            ("secret_key_path", &self.secret_key_path),
+            ("public_key_path", &self.public_key_path),
+        ] {
+            if !Path::new(path).exists() {
+                problems.push(format!("{name} {path:?} doesn't exist"));
+            }
+        }
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(problems)
+        }
+    }
+}
+
+/// whether the dirs are the same dir or one contains the other, the paths are compared lexically,
+/// the symlinks are not followed
+pub fn dirs_overlap(a: &Path, b: &Path) -> bool {
+    let normalize = |path: &Path| {
+        path.components()
+            .filter(|component| *component != Component::CurDir)
+            .collect::<PathBuf>()
+    };
+    let (a, b) = (normalize(a), normalize(b));
+
+    a.starts_with(&b) || b.starts_with(&a)
+}
+
+/// the thumbnails can't be put in the store dir, the dir inside the index dir must be hidden, so
+/// the scrub doesn't treat it as a shard dir
+fn check_thumbnail_dir(
+    thumbnail_dir: &Path,
+    index_dir: &Path,
+    store_dir: &Path,
+) -> anyhow::Result<()> {
+    if dirs_overlap(thumbnail_dir, store_dir) {
+        return Err(anyhow!("the store dir can't contain the thumbnails"));
+    }
+
+    if let Ok(relative_dir) = thumbnail_dir.strip_prefix(index_dir) {
+        match relative_dir.components().next() {
+            Some(Component::Normal(name)) if name.to_string_lossy().starts_with('.') => {}
+            _ => return Err(anyhow!("the dir inside the index dir must be hidden")),
+        }
+    }
+
+    Ok(())
+}
+
+/// parse the byte size per second like `16MiB`, the rate can't be zero
+pub fn parse_byte_rate(rate: &str) -> anyhow::Result<u64> {
+    parse_byte_size(rate)
+}
+
+/// parse the byte size like `10GiB`, the size can't be zero
+pub fn parse_byte_size(size: &str) -> anyhow::Result<u64> {
+    let size = Byte::from_str(size)
+        .map_err(|err| anyhow!("{err}"))?
+        .get_bytes();
+    if size == 0 {
+        return Err(anyhow!("size can't be zero"));
+    }
+
+    Ok(size)
+}
+
+/// parse the CanvasKit url into the base url used by the web ui, `bundled` is the CanvasKit
+/// embedded in the node
+pub fn parse_canvaskit_url(url: &str) -> anyhow::Result<String> {
+    if url == BUNDLED_CANVASKIT {
+        return Ok(BUNDLED_CANVASKIT_BASE_URL.to_string());
+    }
+
+    let uri = url.parse::<Uri>()?;
+    if uri.scheme() != Some(&Scheme::HTTP) && uri.scheme() != Some(&Scheme::HTTPS) {
+        return Err(anyhow!("only http and https url are supported"));
+    }
+
+    // the base url is a dir
+    if url.ends_with('/') {
+        Ok(url.to_string())
+    } else {
+        Ok(format!("{url}/"))
+    }
+}
+
+/// parse the webhook url, only the http url is supported
+pub fn parse_webhook_url(url: &str) -> anyhow::Result<Uri> {
+    let url = url.parse::<Uri>()?;
+    if url.scheme() != Some(&Scheme::HTTP) {
+        return Err(anyhow!("only http url is supported"));
+    }
+
+    Ok(url)
+}
+
+/// parse the network id, it is limited to the url safe chars, so it can't be confused with the
+/// separators of the topic names
+pub fn parse_network_id(network_id: &str) -> anyhow::Result<String> {
+    if network_id.is_empty() || network_id.len() > 64 {
+        return Err(anyhow!("network id should have 1 to 64 chars"));
+    }
+    if !network_id
+        .bytes()
+        .all(|b| b.is_ascii_alphanumeric() || matches!(b, b'-' | b'_' | b'.'))
+    {
+        return Err(anyhow!(
+            "network id should only contain ascii letters, digits, '-', '_' and '.'"
+        ));
+    }
+
+    Ok(network_id.to_string())
+}
+
+/// parse the dlna advertise host into the url host, the ipv6 is bracketed, the port is not
+/// allowed because the http port is used
+pub fn parse_dlna_advertise_host(host: &str) -> anyhow::Result<String> {
+    let ip = host
+        .strip_prefix('[')
+        .and_then(|host| host.strip_suffix(']'))
+        .unwrap_or(host);
+    match ip.parse::<IpAddr>() {
+        Ok(IpAddr::V4(ip)) => return Ok(ip.to_string()),
+        Ok(IpAddr::V6(ip)) => return Ok(format!("[{ip}]")),
+        Err(_) => {}
+    }
+
+    let valid_label = |label: &str| {
+        !label.is_empty()
+            && label.len() <= 63
+            && !label.starts_with('-')
+            && !label.ends_with('-')
+            && label
+                .bytes()
+                .all(|b| b.is_ascii_alphanumeric() || b == b'-')
+    };
+    if host.len() > 253 || !host.split('.').all(valid_label) {
+        return Err(anyhow!(
+            "host should be an ip or a host name without the port"
+        ));
+    }
+
+    Ok(host.to_string())
+}
+
+/// parse the public url into the base url of the links, the trailing `/` is trimmed so the
+/// paths can be appended
+pub fn parse_public_url(url: &str) -> anyhow::Result<String> {
+    let uri = url.parse::<Uri>()?;
+    if uri.scheme() != Some(&Scheme::HTTP) && uri.scheme() != Some(&Scheme::HTTPS) {
+        return Err(anyhow!("only http and https url are supported"));
+    }
+    if uri.query().is_some() {
+        return Err(anyhow!("query is not allowed"));
+    }
+
+    Ok(url.trim_end_matches('/').to_string())
+}
+
+/// where the config is loaded from
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum ConfigSource {
+    /// the `config.yaml` in the dir, the runtime config changes are written back
+    Dir(PathBuf),
+    /// `-`, read the config from stdin
+    Stdin,
+    /// fetch the config from the http url at startup
+    Url(Uri),
+}
+
+impl FromStr for ConfigSource {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "-" {
+            return Ok(ConfigSource::Stdin);
+        }
+
+        match s.parse::<Uri>() {
+            Ok(url) if url.scheme() == Some(&Scheme::HTTP) => Ok(ConfigSource::Url(url)),
+            Ok(url) if url.scheme().is_some() => Err(anyhow!("only http config url is supported")),
+            _ => Ok(ConfigSource::Dir(s.into())),
+        }
+    }
+}
+
+impl Display for ConfigSource {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigSource::Dir(config_dir) => write!(f, "{}", config_dir.display()),
+            ConfigSource::Stdin => f.write_str("stdin"),
+            ConfigSource::Url(url) => write!(f, "{url}"),
+        }
+    }
 }
 
 #[derive(Debug)]
 pub struct ConfigManager {
-    config_dir: PathBuf,
+    /// `None` when the config isn't loaded from a dir, the config is read-only
+    config_dir: Option<PathBuf>,
     config: Cow<'static, Config>,
 }
 
@@ -35,23 +681,125 @@ impl ConfigManager {
     const CONFIG_TMP_FILENAME: &'static str = "config.yaml.tmp";
 
     pub async fn new(config_dir: PathBuf) -> io::Result<Self> {
-        let cfg = fs::read(config_dir.join(Self::CONFIG_FILENAME)).await?;
-        let config = serde_yaml::from_slice::<Config>(&cfg)
-            .map_err(|err| Error::new(ErrorKind::Other, err))?;
+        let cfg_path = config_dir.join(Self::CONFIG_FILENAME);
+        let cfg = match fs::read(&cfg_path).await {
+            Err(err) if err.kind() == ErrorKind::NotFound => {
+                error!(?cfg_path, "config file not found");
+
+                return Err(Error::new(
+                    ErrorKind::NotFound,
+                    format!(
+                        "config file {cfg_path:?} not found, generate one by `{} gen-config {config_dir:?}`",
+                        env!("CARGO_PKG_NAME"),
+                    ),
+                ));
+            }
+
+            Err(err) => {
+                error!(%err, ?cfg_path, "read config file failed");
+
+                return Err(err);
+            }
+
+            Ok(cfg) => cfg,
+        };
+        let config = Self::parse_config(&cfg, &cfg_path.display())?;
+
+        Ok(Self {
+            config_dir: Some(config_dir),
+            config: Cow::Owned(config),
+        })
+    }
+
+    /// load the config from the source, the config of the stdin and the url sources can't be
+    /// changed at runtime
+    #[instrument(err)]
+    pub async fn open(source: ConfigSource) -> io::Result<Self> {
+        let cfg = match &source {
+            ConfigSource::Dir(config_dir) => return Self::new(config_dir.clone()).await,
+
+            ConfigSource::Stdin => {
+                let mut cfg = vec![];
+                tokio::io::stdin()
+                    .read_to_end(&mut cfg)
+                    .await
+                    .tap_err(|err| error!(%err, "read config from stdin failed"))?;
+
+                cfg
+            }
+
+            ConfigSource::Url(url) => fetch_config(url).await?,
+        };
+        let config = Self::parse_config(&cfg, &source)?;
+
+        info!(%source, "load read-only config done");
 
         Ok(Self {
-            config_dir,
+            config_dir: None,
             config: Cow::Owned(config),
         })
     }
 
+    fn parse_config(cfg: &[u8], source: &dyn Display) -> io::Result<Config> {
+        let config = serde_yaml::from_slice::<Config>(cfg)
+            .map_err(|err| Error::new(ErrorKind::Other, err))?;
+
+        if let Err(problems) = config.validate() {
+            error!(%source, ?problems, "invalid config");
+
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("invalid config {source}:\n  - {}", problems.join("\n  - ")),
+            ));
+        }
+
+        Ok(config)
+    }
+
+    /// write the example config into the `config_dir`, the existing config is never overwritten
+    #[instrument(err)]
+    pub async fn generate(config_dir: &Path) -> io::Result<PathBuf> {
+        fs::create_dir_all(config_dir)
+            .await
+            .tap_err(|err| error!(%err, ?config_dir, "create config dir failed"))?;
+
+        let cfg_path = config_dir.join(Self::CONFIG_FILENAME);
+        let mut file = OpenOptions::new()
+            .create_new(true)
+            .write(true)
+            .open(&cfg_path)
+            .await
+            .tap_err(|err| error!(%err, ?cfg_path, "create config file failed"))?;
+        file.write_all(EXAMPLE_CONFIG.as_bytes())
+            .await
+            .tap_err(|err| error!(%err, ?cfg_path, "write config file failed"))?;
+
+        info!(?cfg_path, "generate config file done");
+
+        Ok(cfg_path)
+    }
+
     #[instrument]
     pub fn load(&self) -> Cow<Config> {
         Cow::Borrowed(&self.config)
     }
 
+    /// check the config can be changed at runtime, it fails with [`ErrorKind::Unsupported`] when
+    /// the config is read-only
+    pub fn check_writable(&self) -> io::Result<()> {
+        match self.config_dir {
+            None => Err(read_only_error()),
+            Some(_) => Ok(()),
+        }
+    }
+
     #[instrument(err)]
     pub async fn swap(&mut self, config: Cow<'_, Config>) -> io::Result<Cow<Config>> {
+        let config_dir = match &self.config_dir {
+            None => return Err(read_only_error()),
+            Some(config_dir) => config_dir,
+        };
+
         let cfg_data = serde_yaml::to_string(&config).map_err(|err| {
             error!(%err, ?config, "marshal config failed");
 
@@ -60,14 +808,24 @@ impl ConfigManager {
 
         info!(?config, "marshal config done");
 
-        let cfg_tmp_path = self.config_dir.join(Self::CONFIG_TMP_FILENAME);
+        let cfg_tmp_path = config_dir.join(Self::CONFIG_TMP_FILENAME);
 
-        fs::write(&cfg_tmp_path, &cfg_data).await.tap_err(|err| {
-            error!(%err, ?cfg_tmp_path, %cfg_data, "write config failed");
+        let mut cfg_tmp_file = File::create(&cfg_tmp_path).await.tap_err(|err| {
+            error!(%err, ?cfg_tmp_path, "create temp config file failed");
+        })?;
+        cfg_tmp_file
+            .write_all(cfg_data.as_bytes())
+            .await
+            .tap_err(|err| {
+                error!(%err, ?cfg_tmp_path, %cfg_data, "write config failed");
+            })?;
+        // make sure the data is persisted before the rename is visible
+        cfg_tmp_file.sync_all().await.tap_err(|err| {
+            error!(%err, ?cfg_tmp_path, "sync temp config file failed");
         })?;
 
         info!(?cfg_tmp_path, %cfg_data, "write config done");
-        let cfg_path = self.config_dir.join(Self::CONFIG_FILENAME);
+        let cfg_path = config_dir.join(Self::CONFIG_FILENAME);
 
         fs::rename(&cfg_tmp_path, &cfg_path).await.tap_err(|err| {
             error!(
@@ -84,9 +842,272 @@ impl ConfigManager {
             "move temp config file to real config file done"
         );
 
+        // persist the rename
+        File::open(config_dir)
+            .await
+            .tap_err(|err| error!(%err, ?config_dir, "open config dir failed"))?
+            .sync_all()
+            .await
+            .tap_err(|err| error!(%err, ?config_dir, "sync config dir failed"))?;
+
+        info!(?config_dir, "sync config dir done");
+
         Ok(mem::replace(
             &mut self.config,
             Cow::Owned(config.into_owned()),
         ))
     }
 }
+
+fn read_only_error() -> Error {
+    Error::new(
+        ErrorKind::Unsupported,
+        "config is read-only because it isn't loaded from a config dir",
+    )
+}
+
+#[instrument(err)]
+async fn fetch_config(url: &Uri) -> io::Result<Vec<u8>> {
+    let client = Client::new();
+
+    let response = match time::timeout(CONFIG_FETCH_TIMEOUT, client.get(url.clone())).await {
+        Err(_) => {
+            error!("fetch config timeout");
+
+            return Err(Error::new(
+                ErrorKind::TimedOut,
+                format!("fetch config from {url} timeout"),
+            ));
+        }
+
+        Ok(Err(err)) => {
+            error!(%err, "fetch config failed");
+
+            return Err(Error::new(
+                ErrorKind::Other,
+                format!("fetch config from {url} failed: {err}"),
+            ));
+        }
+
+        Ok(Ok(response)) => response,
+    };
+
+    if !response.status().is_success() {
+        error!(status = %response.status(), "config response is not success");
+
+        return Err(Error::new(
+            ErrorKind::Other,
+            format!("fetch config from {url} failed: {}", response.status()),
+        ));
+    }
+
+    let cfg = hyper::body::to_bytes(response.into_body())
+        .await
+        .map_err(|err| {
+            error!(%err, "read config response body failed");
+
+            Error::new(ErrorKind::Other, err)
+        })?;
+
+    Ok(cfg.to_vec())
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::TempDir;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_generate_config() {
+        let dir = TempDir::new().unwrap();
+
+        let err = ConfigManager::new(dir.path().to_path_buf())
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+        assert!(err.to_string().contains("gen-config"));
+
+        ConfigManager::generate(dir.path()).await.unwrap();
+        let config_manager = ConfigManager::new(dir.path().to_path_buf()).await.unwrap();
+        assert_eq!(
+            config_manager.load().swarm_listen,
+            "/ip4/0.0.0.0/tcp/19999/ws"
+        );
+
+        // never overwrite the existing config
+        assert!(ConfigManager::generate(dir.path()).await.is_err());
+    }
+
+    #[test]
+    fn test_validate_config() {
+        let mut config = serde_yaml::from_str::<Config>(EXAMPLE_CONFIG).unwrap();
+        config.validate().unwrap();
+
+        config.refresh_interval = "10x".to_string();
+        config.swarm_listen = "0.0.0.0:19999".to_string();
+        config.http_listen.clear();
+        config.pre_share_key = PreShareKey::Rotating(vec![]);
+        config.secret_key_path = "not_exist.pem".to_string();
+        config.enable_tcp = Some(false);
+        config.enable_websocket = Some(false);
+        config.tcp_ttl = Some(0);
+        config.interval_jitter = Some(100);
+        config.max_discover_addrs = Some(0);
+        config.max_peer_inbound_requests = Some(0);
+        config.max_peer_addrs = Some(0);
+        config.access_log_size = Some(0);
+        config.max_auto_sync_file_size = Some("0B".to_string());
+        config.hash_buffer_size = Some("0B".to_string());
+        config.sync_from_peers = Some(vec!["not_peer_id".to_string()]);
+        config.canvaskit_url = Some("ftp://127.0.0.1/canvaskit".to_string());
+        config.sync_complete_webhook = Some("https://127.0.0.1/synced".to_string());
+        config.dlna_advertise_host = Some("127.0.0.1:8080".to_string());
+        config.public_url = Some("nas.local".to_string());
+        config.network_id = Some("home/office".to_string());
+        config.ui_dir = Some("not_exist".to_string());
+        config.https_redirect_listen = Some(vec!["127.0.0.1:80".parse().unwrap()]);
+        config.store_dir = format!("{}/", config.index_dir);
+
+        let problems = config.validate().unwrap_err();
+        assert_eq!(problems.len(), 23, "{problems:?}");
+    }
+
+    #[test]
+    fn test_validate_custom_prefix() {
+        let mut config = serde_yaml::from_str::<Config>(EXAMPLE_CONFIG).unwrap();
+        config.api_base_path = Some("/api/".to_string());
+        config.ui_base_path = Some("ui".to_string());
+        config.validate().unwrap();
+
+        config.api_base_path = Some("/share/api".to_string());
+        assert_eq!(config.validate().unwrap_err().len(), 1);
+
+        config.ui_dir = Some(env::temp_dir().to_string_lossy().into_owned());
+        config.validate().unwrap();
+
+        config.ui_dir = None;
+        config.enable_ui = Some(false);
+        config.validate().unwrap();
+    }
+
+    #[test]
+    fn test_parse_public_url() {
+        assert_eq!(
+            parse_public_url("https://nas.local/share/").unwrap(),
+            "https://nas.local/share"
+        );
+        assert_eq!(
+            parse_public_url("http://[::1]:8080").unwrap(),
+            "http://[::1]:8080"
+        );
+        assert!(parse_public_url("nas.local").is_err());
+        assert!(parse_public_url("ftp://nas.local").is_err());
+        assert!(parse_public_url("https://nas.local/?a=1").is_err());
+    }
+
+    #[test]
+    fn test_parse_network_id() {
+        assert_eq!(parse_network_id("home-1.lan_a").unwrap(), "home-1.lan_a");
+        assert!(parse_network_id("").is_err());
+        assert!(parse_network_id("home/discover").is_err());
+        assert!(parse_network_id("home office").is_err());
+        assert!(parse_network_id(&"a".repeat(65)).is_err());
+    }
+
+    #[test]
+    fn test_parse_dlna_advertise_host() {
+        assert_eq!(
+            parse_dlna_advertise_host("192.168.1.2").unwrap(),
+            "192.168.1.2"
+        );
+        assert_eq!(parse_dlna_advertise_host("fe80::1").unwrap(), "[fe80::1]");
+        assert_eq!(parse_dlna_advertise_host("[fe80::1]").unwrap(), "[fe80::1]");
+        assert_eq!(parse_dlna_advertise_host("nas.local").unwrap(), "nas.local");
+        assert!(parse_dlna_advertise_host("").is_err());
+        assert!(parse_dlna_advertise_host("nas.local:8080").is_err());
+        assert!(parse_dlna_advertise_host("http://nas.local").is_err());
+        assert!(parse_dlna_advertise_host("-nas.local").is_err());
+    }
+
+    #[test]
+    fn test_check_thumbnail_dir() {
+        let (index_dir, store_dir) = (Path::new("/tmp/index"), Path::new("/tmp/store"));
+
+        check_thumbnail_dir(Path::new("/tmp/index/.thumbs"), index_dir, store_dir).unwrap();
+        check_thumbnail_dir(Path::new("/tmp/thumbs"), index_dir, store_dir).unwrap();
+        check_thumbnail_dir(Path::new("/tmp/index"), index_dir, store_dir).unwrap_err();
+        check_thumbnail_dir(Path::new("/tmp/index/thumbs"), index_dir, store_dir).unwrap_err();
+        check_thumbnail_dir(Path::new("/tmp/store/.thumbs"), index_dir, store_dir).unwrap_err();
+    }
+
+    #[test]
+    fn test_dirs_overlap() {
+        assert!(dirs_overlap(Path::new("/tmp/a"), Path::new("/tmp/a/")));
+        assert!(dirs_overlap(Path::new("./a"), Path::new("a")));
+        assert!(dirs_overlap(Path::new("/tmp/a/index"), Path::new("/tmp/a")));
+        assert!(dirs_overlap(
+            Path::new("/tmp/a"),
+            Path::new("/tmp/./a/store")
+        ));
+        assert!(!dirs_overlap(Path::new("/tmp/a"), Path::new("/tmp/ab")));
+        assert!(!dirs_overlap(
+            Path::new("/tmp/index"),
+            Path::new("/tmp/store")
+        ));
+    }
+
+    #[test]
+    fn test_rotating_pre_share_key() {
+        let config =
+            EXAMPLE_CONFIG.replace("pre_share_key: share", "pre_share_key: [new_share, share]");
+        let config = serde_yaml::from_str::<Config>(&config).unwrap();
+        config.validate().unwrap();
+        assert_eq!(config.pre_share_key.keys(), ["new_share", "share"]);
+    }
+
+    #[tokio::test]
+    async fn test_swap_config() {
+        let dir = TempDir::new().unwrap();
+        ConfigManager::generate(dir.path()).await.unwrap();
+        let mut config_manager = ConfigManager::new(dir.path().to_path_buf()).await.unwrap();
+
+        let mut config = config_manager.load().into_owned();
+        config.peer_addrs.clear();
+        config_manager.swap(Cow::Owned(config)).await.unwrap();
+
+        let config_manager = ConfigManager::new(dir.path().to_path_buf()).await.unwrap();
+        assert!(config_manager.load().peer_addrs.is_empty());
+        assert!(!dir.path().join(ConfigManager::CONFIG_TMP_FILENAME).exists());
+    }
+
+    #[tokio::test]
+    async fn test_swap_read_only_config() {
+        let mut config_manager = ConfigManager {
+            config_dir: None,
+            config: Cow::Owned(serde_yaml::from_str::<Config>(EXAMPLE_CONFIG).unwrap()),
+        };
+
+        let config = config_manager.load().into_owned();
+        let err = config_manager.swap(Cow::Owned(config)).await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Unsupported);
+    }
+
+    #[test]
+    fn test_parse_config_source() {
+        assert_eq!("-".parse::<ConfigSource>().unwrap(), ConfigSource::Stdin);
+        assert_eq!(
+            "/etc/private_share".parse::<ConfigSource>().unwrap(),
+            ConfigSource::Dir("/etc/private_share".into())
+        );
+        assert_eq!(
+            "http://127.0.0.1/config.yaml"
+                .parse::<ConfigSource>()
+                .unwrap(),
+            ConfigSource::Url("http://127.0.0.1/config.yaml".parse().unwrap())
+        );
+        assert!("https://127.0.0.1/config.yaml"
+            .parse::<ConfigSource>()
+            .is_err());
+    }
+}
@@ -0,0 +1,104 @@
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::process;
+
+use anyhow::{anyhow, bail};
+use nix::errno::Errno;
+use nix::sys::signal;
+use nix::unistd::{self, Pid};
+use tap::TapFallible;
+use tracing::{error, info, warn};
+
+/// detach from the terminal and run in background, the working dir is kept so the relative paths
+/// in the config still work, stdin, stdout and stderr are redirected to `/dev/null`
+///
+/// it must be called before starting the tokio runtime, forking a multi-threaded process only
+/// keeps the calling thread
+pub fn daemonize() -> anyhow::Result<()> {
+    unistd::daemon(true, false).map_err(|err| anyhow!("daemonize failed: {err}"))
+}
+
+/// return error when a live process holds the pidfile, a stale pidfile is allowed
+pub fn check_pidfile(path: &Path) -> anyhow::Result<()> {
+    let content = match fs::read_to_string(path) {
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(()),
+        Err(err) => return Err(err.into()),
+        Ok(content) => content,
+    };
+
+    let pid = match content.trim().parse::<i32>() {
+        Err(err) => {
+            warn!(%err, ?path, "invalid pidfile content, treat it as stale");
+
+            return Ok(());
+        }
+
+        Ok(pid) => pid,
+    };
+
+    // signal 0 only checks the process exists, EPERM means it exists but is owned by others
+    match signal::kill(Pid::from_raw(pid), None) {
+        Ok(_) | Err(Errno::EPERM) => bail!("process {pid} already holds the pidfile {path:?}"),
+        Err(_) => {
+            info!(pid, ?path, "pidfile is stale");
+
+            Ok(())
+        }
+    }
+}
+
+/// the pidfile of the running process, it is removed when dropped
+#[derive(Debug)]
+pub struct PidFile {
+    path: PathBuf,
+}
+
+impl PidFile {
+    pub fn create(path: PathBuf) -> anyhow::Result<Self> {
+        check_pidfile(&path)?;
+
+        fs::write(&path, format!("{}\n", process::id()))
+            .tap_err(|err| error!(%err, ?path, "write pidfile failed"))?;
+
+        info!(?path, "write pidfile done");
+
+        Ok(Self { path })
+    }
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path)
+            .tap_err(|err| error!(%err, path = ?self.path, "remove pidfile failed"));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::TempDir;
+
+    use super::*;
+
+    #[test]
+    fn test_pidfile() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("private_share.pid");
+
+        let pidfile = PidFile::create(path.clone()).unwrap();
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            format!("{}\n", process::id())
+        );
+
+        // the current process is alive
+        assert!(PidFile::create(path.clone()).is_err());
+
+        drop(pidfile);
+        assert!(!path.exists());
+
+        // pid max is 2^22, so the process doesn't exist
+        fs::write(&path, "99999999\n").unwrap();
+        let _pidfile = PidFile::create(path).unwrap();
+    }
+}
@@ -4,13 +4,21 @@ use std::mem::ManuallyDrop;
 use std::os::unix::io::{AsRawFd, FromRawFd};
 
 use async_trait::async_trait;
-use bytes::{Buf, Bytes, BytesMut};
+use bytes::{Bytes, BytesMut};
+use nix::libc;
 use tokio::fs::File;
 use tokio::task;
 
 #[async_trait]
 pub trait AsyncFileExt {
-    async fn read_at(&self, buf: &mut [u8], offset: u64) -> Result<u64, Error>;
+    /// read at most `length` bytes into the spare capacity of `buf`, the read data is appended to
+    /// `buf`
+    async fn read_buf_at(
+        &self,
+        buf: BytesMut,
+        length: usize,
+        offset: u64,
+    ) -> Result<BytesMut, Error>;
 
     async fn write_at(&self, data: &[u8], offset: u64) -> Result<u64, Error>;
 
@@ -27,25 +35,33 @@ pub trait AsyncFileExt {
 
 #[async_trait]
 impl AsyncFileExt for File {
-    async fn read_at(&self, buf: &mut [u8], offset: u64) -> Result<u64, Error> {
-        use std::os::unix::fs::FileExt;
-
+    async fn read_buf_at(
+        &self,
+        mut buf: BytesMut,
+        length: usize,
+        offset: u64,
+    ) -> Result<BytesMut, Error> {
         let fd = self.as_raw_fd();
-        let std_file = unsafe { ManuallyDrop::new(StdFile::from_raw_fd(fd)) };
 
-        let mut bytes_mut = BytesMut::zeroed(buf.len());
+        buf.reserve(length);
 
-        let (mut bytes_mut, n) = task::spawn_blocking(move || {
-            let n = std_file.read_at(&mut bytes_mut, offset)?;
+        task::spawn_blocking(move || {
+            let len = buf.len();
+            let spare = &mut buf.spare_capacity_mut()[..length];
 
-            Ok::<_, Error>((bytes_mut, n))
-        })
-        .await
-        .unwrap()?;
+            // pread writes the spare capacity through the raw pointer, no reference to the
+            // uninitialized bytes is created, and only the read bytes are exposed by set_len
+            let n = unsafe { libc::pread(fd, spare.as_mut_ptr().cast(), spare.len(), offset as _) };
+            if n < 0 {
+                return Err(Error::last_os_error());
+            }
 
-        bytes_mut.copy_to_slice(&mut buf[..n]);
+            unsafe { buf.set_len(len + n as usize) };
 
-        Ok(n as _)
+            Ok(buf)
+        })
+        .await
+        .unwrap()
     }
 
     async fn write_at(&self, data: &[u8], offset: u64) -> Result<u64, Error> {
@@ -88,10 +104,22 @@ mod tests {
 
         file.write_all(b"test").await.unwrap();
 
-        let mut buf = [0; 2];
-        let n = file.read_at(&mut buf, 2).await.unwrap() as usize;
+        let buf = file.read_buf_at(BytesMut::new(), 2, 1).await.unwrap();
+        assert_eq!(buf.as_ref(), b"es");
+
+        // short read at the end of the file
+        let buf = file
+            .read_buf_at(BytesMut::from(&b"a"[..]), 8, 2)
+            .await
+            .unwrap();
+        assert_eq!(buf.as_ref(), b"ast");
 
-        assert_eq!(&b"st"[..n], &buf[..n]);
+        // nothing is exposed when reading after the end of the file
+        let buf = file
+            .read_buf_at(BytesMut::with_capacity(8), 8, 4)
+            .await
+            .unwrap();
+        assert!(buf.is_empty());
     }
 
     #[tokio::test]
@@ -0,0 +1,174 @@
+use std::io;
+use std::path::Path;
+use std::str::FromStr;
+
+use anyhow::anyhow;
+use bytes::BytesMut;
+use sha2::digest::FixedOutput;
+use sha2::{Digest, Sha256};
+use tokio::fs::File;
+use tokio::io::AsyncReadExt;
+use tokio::time;
+use tokio::time::{Duration, Instant};
+
+const BUF_SIZE: usize = 1024 * 1024; // 1MiB
+
+/// the file hash algorithm, the hash is used as the index filename and gossiped to the other
+/// peers, so all nodes in a swarm must use the same algorithm, mixing algorithms won't dedup the
+/// same file and the synced file can't be verified
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+pub enum HashAlgorithm {
+    #[default]
+    Sha256,
+    #[cfg(feature = "blake3")]
+    Blake3,
+}
+
+impl FromStr for HashAlgorithm {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "sha256" => Ok(HashAlgorithm::Sha256),
+
+            #[cfg(feature = "blake3")]
+            "blake3" => Ok(HashAlgorithm::Blake3),
+
+            #[cfg(not(feature = "blake3"))]
+            "blake3" => Err(anyhow!("blake3 hash algorithm requires the blake3 feature")),
+
+            _ => Err(anyhow!("unknown hash algorithm {s}")),
+        }
+    }
+}
+
+impl HashAlgorithm {
+    pub fn hasher(self) -> FileHasher {
+        match self {
+            HashAlgorithm::Sha256 => FileHasher::Sha256(Sha256::new()),
+
+            #[cfg(feature = "blake3")]
+            HashAlgorithm::Blake3 => FileHasher::Blake3(Box::new(blake3::Hasher::new())),
+        }
+    }
+
+    /// the length of the hex hash
+    pub fn hex_len(self) -> usize {
+        match self {
+            HashAlgorithm::Sha256 => 64,
+
+            #[cfg(feature = "blake3")]
+            HashAlgorithm::Blake3 => 64,
+        }
+    }
+
+    /// calculate the uppercase hex hash of the file
+    pub async fn hash_file(self, path: &Path) -> io::Result<String> {
+        let mut file = File::open(path).await?;
+        let mut buf = BytesMut::with_capacity(BUF_SIZE);
+        let mut hasher = self.hasher();
+
+        loop {
+            if file.read_buf(&mut buf).await? == 0 {
+                break;
+            }
+
+            hasher.update(&buf);
+            buf.clear();
+        }
+
+        Ok(hasher.finalize())
+    }
+
+    /// calculate the hash like [`hash_file`](Self::hash_file), but read at most `bytes_per_sec`
+    /// bytes per second, so a background check doesn't starve the other disk io
+    pub async fn hash_file_throttled(self, path: &Path, bytes_per_sec: u64) -> io::Result<String> {
+        let mut file = File::open(path).await?;
+        let mut buf = BytesMut::with_capacity(BUF_SIZE);
+        let mut hasher = self.hasher();
+        let start = Instant::now();
+        let mut read = 0;
+
+        loop {
+            let n = file.read_buf(&mut buf).await?;
+            if n == 0 {
+                break;
+            }
+
+            hasher.update(&buf);
+            buf.clear();
+
+            read += n as u64;
+            time::sleep_until(start + Duration::from_secs_f64(read as f64 / bytes_per_sec as f64))
+                .await;
+        }
+
+        Ok(hasher.finalize())
+    }
+}
+
+pub enum FileHasher {
+    Sha256(Sha256),
+    #[cfg(feature = "blake3")]
+    Blake3(Box<blake3::Hasher>),
+}
+
+impl FileHasher {
+    pub fn update(&mut self, data: &[u8]) {
+        match self {
+            FileHasher::Sha256(hasher) => hasher.update(data),
+
+            #[cfg(feature = "blake3")]
+            FileHasher::Blake3(hasher) => {
+                hasher.update(data);
+            }
+        }
+    }
+
+    /// finalize the hasher and return the uppercase hex hash
+    pub fn finalize(self) -> String {
+        match self {
+            FileHasher::Sha256(hasher) => hex::encode_upper(hasher.finalize_fixed()),
+
+            #[cfg(feature = "blake3")]
+            FileHasher::Blake3(hasher) => hex::encode_upper(hasher.finalize().as_bytes()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::NamedTempFile;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_hash_file() {
+        let file = NamedTempFile::new().unwrap();
+        tokio::fs::write(file.path(), b"hello").await.unwrap();
+
+        let hash = HashAlgorithm::Sha256.hash_file(file.path()).await.unwrap();
+
+        assert_eq!(hash, hex::encode_upper(Sha256::digest(b"hello")));
+        assert_eq!(hash.len(), HashAlgorithm::Sha256.hex_len());
+        assert_eq!(
+            "SHA256".parse::<HashAlgorithm>().unwrap(),
+            HashAlgorithm::Sha256
+        );
+        assert!("md5".parse::<HashAlgorithm>().is_err());
+
+        let throttled_hash = HashAlgorithm::Sha256
+            .hash_file_throttled(file.path(), 1024)
+            .await
+            .unwrap();
+        assert_eq!(throttled_hash, hash);
+
+        #[cfg(feature = "blake3")]
+        {
+            let hash = HashAlgorithm::Blake3.hash_file(file.path()).await.unwrap();
+
+            assert_eq!(hash, hex::encode_upper(blake3::hash(b"hello").as_bytes()));
+            assert_eq!(hash.len(), HashAlgorithm::Blake3.hex_len());
+        }
+    }
+}
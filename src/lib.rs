@@ -1,43 +1,89 @@
 #![feature(type_alias_impl_trait)]
 
+use std::collections::HashSet;
 use std::io;
 use std::io::ErrorKind;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::time::Duration;
 
 use clap::Parser;
-use futures_channel::mpsc;
-use futures_util::stream;
+use futures_channel::{mpsc, oneshot};
 use itertools::Itertools;
-use libp2p::pnet::PreSharedKey;
-use libp2p::Multiaddr;
+use libp2p::gossipsub::ValidationMode;
+use libp2p::{Multiaddr, PeerId};
 use sha2::{Digest, Sha256};
 use tokio::fs;
-use tokio_util::time::DelayQueue;
+use tokio::signal::unix::SignalKind;
+use tokio::sync::Notify;
+use tokio::{runtime, signal, time};
 use tracing::level_filters::LevelFilter;
-use tracing::{debug, subscriber};
+use tracing::{debug, error, info, subscriber, warn};
 use tracing_log::LogTracer;
 use tracing_subscriber::filter::Targets;
 use tracing_subscriber::layer::SubscriberExt;
 use tracing_subscriber::{fmt, Registry};
 
 use crate::args::{Cli, Mode};
-use crate::config::ConfigManager;
-use crate::manipulate::http::{MultiAddrListener, Server};
-use crate::node::config::Config as NodeConfig;
-use crate::node::Node;
-use crate::util::load_keypair;
+use crate::config::{
+    dirs_overlap, parse_byte_rate, parse_byte_size, parse_canvaskit_url, parse_dlna_advertise_host,
+    parse_network_id, parse_public_url, parse_webhook_url, ConfigManager,
+};
+use crate::daemon::PidFile;
+use crate::manipulate::http::{
+    https_redirect_port, load_tls_acceptor, serve_https_redirect, HttpProtocol, MultiAddrListener,
+    Server, DEFAULT_API_PREFIX, DEFAULT_COMMAND_SEND_TIMEOUT, DEFAULT_THUMBNAIL_DIR_NAME,
+    DEFAULT_UI_PREFIX,
+};
+use crate::node::config::{
+    parse_validation_mode, Config as NodeConfig, SyncRetry, TcpOptions, DEFAULT_BUFFER_POOL_SIZE,
+    DEFAULT_DISCOVER_INTERVAL, DEFAULT_FILE_READ_IDLE_TIMEOUT, DEFAULT_HASH_BUFFER_SIZE,
+    DEFAULT_MAX_DISCOVER_ADDRS, DEFAULT_MAX_PEER_ADDRS, DEFAULT_MAX_PEER_INBOUND_REQUESTS,
+    DEFAULT_MAX_SYNC_FILES, DEFAULT_SCRUB_RATE, DEFAULT_STALE_TEMP_FILE_AGE,
+    DEFAULT_SYNC_READ_AHEAD, DEFAULT_SYNC_RETRY_BACKOFF, DEFAULT_SYNC_RETRY_MAX_ATTEMPTS,
+    DEFAULT_TCP_LISTEN_BACKLOG, DEFAULT_TEMP_DIR_NAME,
+};
+use crate::node::pnet::PnetKeys;
+use crate::node::NodeBuilder;
+use crate::util::{load_keypair, ExcludePatterns, SharePatterns};
 
 mod args;
-mod command;
-mod config;
+mod client;
+pub mod command;
+pub mod config;
+mod daemon;
 mod ext;
+pub mod hash;
 mod manipulate;
-mod node;
+pub mod node;
 mod util;
 
-pub async fn run() -> anyhow::Result<()> {
+/// default max number of the queued api commands, the api requests wait when the node is busy
+const DEFAULT_COMMAND_BUFFER_SIZE: usize = 1;
+
+/// max time of waiting the in-flight http requests when shutting down
+const HTTP_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
+pub fn run() -> anyhow::Result<()> {
     let cli = Cli::parse();
+
+    if let Mode::Run(args) = &cli.mode {
+        if let Some(pidfile) = &args.pidfile {
+            daemon::check_pidfile(Path::new(pidfile))?;
+        }
+
+        // fork before the tokio runtime starts
+        if args.daemonize {
+            daemon::daemonize()?;
+        }
+    }
+
+    runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()?
+        .block_on(run_cli(cli))
+}
+
+async fn run_cli(cli: Cli) -> anyhow::Result<()> {
     let args = match cli.mode {
         Mode::GenPeerId {
             secret_key_path,
@@ -51,12 +97,28 @@ pub async fn run() -> anyhow::Result<()> {
             return Ok(());
         }
 
+        Mode::GenConfig { config_dir } => {
+            let cfg_path = ConfigManager::generate(Path::new(&config_dir)).await?;
+
+            println!("config is generated at {cfg_path:?}, edit it before running");
+
+            return Ok(());
+        }
+
+        Mode::Client(args) => return client::run(args).await,
+
         Mode::Run(args) => args,
     };
 
     init_log(args.debug);
 
-    let config_manager = ConfigManager::new(args.config_dir.into()).await?;
+    // write the pidfile after daemonizing, the pid is changed by forking
+    let _pidfile = args
+        .pidfile
+        .map(|pidfile| PidFile::create(pidfile.into()))
+        .transpose()?;
+
+    let config_manager = ConfigManager::open(args.config_dir.parse()?).await?;
     let config = config_manager.load();
     let swarm_addr = config.swarm_listen.parse::<Multiaddr>()?;
     let relay_server_addr = config
@@ -80,38 +142,327 @@ pub async fn run() -> anyhow::Result<()> {
 
     debug!(store_dir = %config.store_dir, index_dir = %config.index_dir, "pre create dir done");
 
-    let mut addr_queue = DelayQueue::with_capacity(peer_addrs.len());
-    for peer_addr in peer_addrs {
-        addr_queue.insert(peer_addr, Duration::from_secs(0));
-    }
-
-    let mut hasher = Sha256::new();
-    hasher.update(config.pre_share_key.as_bytes());
-    let pre_shared_key = PreSharedKey::new(hasher.finalize().into());
+    let handshake_keys = config
+        .pre_share_key
+        .keys()
+        .iter()
+        .map(|key| Sha256::digest(key.as_bytes()).into())
+        .collect();
 
     let node_config = NodeConfig {
         key: keypair,
         index_dir: config.index_dir.clone().into(),
+        store_layout: config
+            .store_layout
+            .as_deref()
+            .map(str::parse)
+            .transpose()?
+            .unwrap_or_default(),
+        index_naming: config
+            .index_naming
+            .as_deref()
+            .map(str::parse)
+            .transpose()?
+            .unwrap_or_default(),
+        temp_dir: config
+            .temp_dir
+            .as_ref()
+            .map(PathBuf::from)
+            .unwrap_or_else(|| Path::new(&config.index_dir).join(DEFAULT_TEMP_DIR_NAME)),
+        stale_temp_file_age: config
+            .stale_temp_file_age
+            .as_deref()
+            .map(humantime::parse_duration)
+            .transpose()?
+            .unwrap_or(DEFAULT_STALE_TEMP_FILE_AGE),
+        scrub_interval: config
+            .scrub_interval
+            .as_deref()
+            .map(humantime::parse_duration)
+            .transpose()?,
+        scrub_rate: config
+            .scrub_rate
+            .as_deref()
+            .map(parse_byte_rate)
+            .transpose()?
+            .unwrap_or(DEFAULT_SCRUB_RATE),
         store_dir: config.store_dir.clone().into(),
-        handshake_key: pre_shared_key,
+        store_link: config
+            .store_link
+            .as_deref()
+            .map(str::parse)
+            .transpose()?
+            .unwrap_or_default(),
+        handshake_keys: PnetKeys::new(handshake_keys),
+        gossip_validation_mode: config
+            .gossip_validation_mode
+            .as_deref()
+            .map(parse_validation_mode)
+            .transpose()?
+            .unwrap_or(ValidationMode::Strict),
+        network_id: config
+            .network_id
+            .as_deref()
+            .map(parse_network_id)
+            .transpose()?,
         refresh_store_interval: humantime::parse_duration(&config.refresh_interval)?,
         sync_file_interval: humantime::parse_duration(&config.sync_file_interval)?,
+        interval_jitter: config.interval_jitter.unwrap_or(0),
+        discover_interval: config
+            .discover_interval
+            .as_deref()
+            .map(humantime::parse_duration)
+            .transpose()?
+            .unwrap_or(DEFAULT_DISCOVER_INTERVAL),
+        max_discover_addrs: config
+            .max_discover_addrs
+            .unwrap_or(DEFAULT_MAX_DISCOVER_ADDRS),
+        allow_local_discover_addrs: config.allow_local_discover_addrs.unwrap_or(false),
+        max_peer_inbound_requests: config
+            .max_peer_inbound_requests
+            .unwrap_or(DEFAULT_MAX_PEER_INBOUND_REQUESTS),
+        max_peer_addrs: config.max_peer_addrs.unwrap_or(DEFAULT_MAX_PEER_ADDRS),
+        hash_algorithm: config
+            .hash_algorithm
+            .as_deref()
+            .map(str::parse)
+            .transpose()?
+            .unwrap_or_default(),
+        hash_buffer_size: config
+            .hash_buffer_size
+            .as_deref()
+            .map(parse_byte_size)
+            .transpose()?
+            .map(|size| size as usize)
+            .unwrap_or(DEFAULT_HASH_BUFFER_SIZE),
+        buffer_pool_size: config.buffer_pool_size.unwrap_or(DEFAULT_BUFFER_POOL_SIZE),
+        enable_mmap: config.enable_mmap.unwrap_or(false),
+        max_sync_files: config.max_sync_files.unwrap_or(DEFAULT_MAX_SYNC_FILES),
+        sync_read_ahead: config.sync_read_ahead.unwrap_or(DEFAULT_SYNC_READ_AHEAD),
+        max_auto_sync_file_size: config
+            .max_auto_sync_file_size
+            .as_deref()
+            .map(parse_byte_size)
+            .transpose()?,
+        sync_from_peers: config
+            .sync_from_peers
+            .iter()
+            .flatten()
+            .map(|peer| peer.parse::<PeerId>())
+            .try_collect::<_, HashSet<_>, _>()?,
+        content_defined_chunking: config.content_defined_chunking.unwrap_or(false),
+        max_connections: config.max_connections,
+        max_uploads: config.max_uploads,
+        sync_order: config
+            .sync_order
+            .as_deref()
+            .map(str::parse)
+            .transpose()?
+            .unwrap_or_default(),
+        sync_retry: SyncRetry {
+            max_attempts: config
+                .sync_retry_max_attempts
+                .unwrap_or(DEFAULT_SYNC_RETRY_MAX_ATTEMPTS),
+            backoff: config
+                .sync_retry_backoff
+                .as_deref()
+                .map(humantime::parse_duration)
+                .transpose()?
+                .unwrap_or(DEFAULT_SYNC_RETRY_BACKOFF),
+        },
+        exclude_patterns: config
+            .exclude_patterns
+            .clone()
+            .map(ExcludePatterns::new)
+            .unwrap_or_default(),
+        share_only: config
+            .share_only
+            .clone()
+            .map(SharePatterns::new)
+            .unwrap_or_default(),
+        reject_case_conflict: config.reject_case_conflict.unwrap_or(false),
+        verify_on_add: config.verify_on_add.unwrap_or(true),
+        read_only: config.read_only.unwrap_or(false),
+        sync_complete_webhook: config
+            .sync_complete_webhook
+            .as_deref()
+            .map(parse_webhook_url)
+            .transpose()?,
         enable_relay_behaviour: args.enable_relay_service,
         relay_server_addr,
+        relay_only: config.relay_only.unwrap_or(false),
+        identify_push: config.identify_push.unwrap_or(false),
+        dns_servers: config.dns_servers.clone(),
+        enable_tcp: config.enable_tcp.unwrap_or(true),
+        tcp_options: TcpOptions {
+            listen_backlog: config
+                .tcp_listen_backlog
+                .unwrap_or(DEFAULT_TCP_LISTEN_BACKLOG),
+            port_reuse: config.tcp_port_reuse.unwrap_or(false),
+            ttl: config.tcp_ttl,
+        },
+        enable_websocket: config.enable_websocket.unwrap_or(true),
+        noise_handshake: config
+            .noise_handshake
+            .as_deref()
+            .map(str::parse)
+            .transpose()?
+            .unwrap_or_default(),
+        max_file_protocol: config
+            .max_file_protocol
+            .as_deref()
+            .map(str::parse)
+            .transpose()?
+            .unwrap_or_default(),
+        file_read_idle_timeout: config
+            .file_read_idle_timeout
+            .as_deref()
+            .map(humantime::parse_duration)
+            .transpose()?
+            .unwrap_or(DEFAULT_FILE_READ_IDLE_TIMEOUT),
+        ip_family: config
+            .ip_family
+            .as_deref()
+            .map(str::parse)
+            .transpose()?
+            .unwrap_or_default(),
     };
 
-    let (command_sender, command_receiver) = mpsc::channel(1);
+    let (command_sender, command_receiver) = mpsc::channel(
+        config
+            .command_buffer_size
+            .unwrap_or(DEFAULT_COMMAND_BUFFER_SIZE),
+    );
 
+    let http_protocol = config
+        .http_protocol
+        .as_deref()
+        .map(str::parse::<HttpProtocol>)
+        .transpose()?
+        .unwrap_or_default();
+    let tls_acceptor = match (&config.tls_cert_path, &config.tls_key_path) {
+        (Some(cert_path), Some(key_path)) => {
+            Some(load_tls_acceptor(Path::new(cert_path), Path::new(key_path), http_protocol).await?)
+        }
+        _ => None,
+    };
+    let https_listen = config.https_listen.as_deref().unwrap_or_default();
     let multi_addr_listener =
-        MultiAddrListener::new(stream::iter(config.http_listen.iter().copied())).await?;
-    let mut node = Node::new(node_config, addr_queue, command_receiver, config_manager)?;
-    let http_server = Server::new(command_sender);
+        MultiAddrListener::new(&config.http_listen, https_listen, tls_acceptor).await?;
+    let https_redirect_listener = match (
+        config.https_redirect_listen.as_deref(),
+        https_redirect_port(https_listen),
+    ) {
+        (Some(addrs), Some(https_port)) if !addrs.is_empty() => {
+            Some((MultiAddrListener::bind(addrs).await?, https_port))
+        }
+        _ => None,
+    };
+    let http_server = Server::new(
+        command_sender,
+        config
+            .api_base_path
+            .as_deref()
+            .unwrap_or(DEFAULT_API_PREFIX),
+        config
+            .enable_ui
+            .unwrap_or(true)
+            .then(|| config.ui_base_path.as_deref().unwrap_or(DEFAULT_UI_PREFIX)),
+        config
+            .canvaskit_url
+            .as_deref()
+            .map(parse_canvaskit_url)
+            .transpose()?
+            .as_deref(),
+        config.ui_dir.as_deref().map(Path::new),
+        &config
+            .thumbnail_dir
+            .as_ref()
+            .map(PathBuf::from)
+            .unwrap_or_else(|| Path::new(&config.index_dir).join(DEFAULT_THUMBNAIL_DIR_NAME)),
+        config.webdav_writable.unwrap_or(false),
+        config.access_log_size,
+        config.api_token.as_deref(),
+        config
+            .dlna_advertise_host
+            .as_deref()
+            .map(parse_dlna_advertise_host)
+            .transpose()?
+            .as_deref(),
+        config
+            .public_url
+            .as_deref()
+            .map(parse_public_url)
+            .transpose()?
+            .as_deref(),
+        config
+            .command_send_timeout
+            .as_deref()
+            .map(humantime::parse_duration)
+            .transpose()?
+            .unwrap_or(DEFAULT_COMMAND_SEND_TIMEOUT),
+    )?;
+    let mut node = NodeBuilder::new(node_config, config_manager)
+        .listen_addr(swarm_addr)
+        .peer_addrs(peer_addrs)
+        .command_receiver(command_receiver)
+        .build()?;
+
+    let shutdown_notify = http_server.shutdown_notify();
+    let (http_shutdown_sender, http_shutdown_receiver) = oneshot::channel::<()>();
+    let http_task = tokio::spawn(async move {
+        http_server
+            .listen(multi_addr_listener, http_protocol, async {
+                let _ = http_shutdown_receiver.await;
+            })
+            .await
+    });
+    let https_redirect_task = https_redirect_listener.map(|(listener, https_port)| {
+        tokio::spawn(async move { serve_https_redirect(listener, https_port).await })
+    });
+
+    // the node cancels the syncing files and finishes the handling command before returning, so
+    // no partial index file or config is left
+    let result = node
+        .run(async {
+            let result = shutdown_signal(&shutdown_notify).await;
+
+            info!("receive shutdown signal, exit");
+
+            result
+        })
+        .await;
+
+    // close the http listeners and finish the in-flight requests, like the shutdown api response
+    let _ = http_shutdown_sender.send(());
+    if let Some(https_redirect_task) = https_redirect_task {
+        https_redirect_task.abort();
+    }
+    if time::timeout(HTTP_SHUTDOWN_TIMEOUT, http_task)
+        .await
+        .is_err()
+    {
+        warn!("wait http server shutdown timeout");
+    }
+
+    result
+}
+
+/// wait for SIGINT, SIGTERM or the shutdown api
+async fn shutdown_signal(shutdown_notify: &Notify) -> anyhow::Result<()> {
+    let mut terminate = signal::unix::signal(SignalKind::terminate())?;
 
-    tokio::spawn(async move { http_server.listen(multi_addr_listener).await });
+    tokio::select! {
+        result = signal::ctrl_c() => result?,
+        _ = terminate.recv() => {}
+        _ = shutdown_notify.notified() => {}
+    }
 
-    node.run(swarm_addr).await
+    Ok(())
 }
 
+/// create the store dir and the index dir, they are compared again after resolving the symlinks,
+/// the store files link to the index files, so they can't be the same dir or contain each other
 async fn pre_create_dir(store_dir: &Path, index_dir: &Path) -> io::Result<()> {
     if let Err(err) = fs::create_dir_all(store_dir).await {
         if err.kind() != ErrorKind::AlreadyExists {
@@ -124,6 +475,24 @@ async fn pre_create_dir(store_dir: &Path, index_dir: &Path) -> io::Result<()> {
         }
     }
 
+    let real_store_dir = fs::canonicalize(store_dir).await?;
+    let real_index_dir = fs::canonicalize(index_dir).await?;
+    if dirs_overlap(&real_store_dir, &real_index_dir) {
+        error!(
+            ?real_store_dir,
+            ?real_index_dir,
+            "store dir and index dir overlap"
+        );
+
+        return Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            format!(
+                "store dir {store_dir:?} and index dir {index_dir:?} can't be the same dir or \
+                 contain each other"
+            ),
+        ));
+    }
+
     Ok(())
 }
 
@@ -1,4 +1,3 @@
-#[tokio::main]
-async fn main() -> anyhow::Result<()> {
-    private_share::run().await
+fn main() -> anyhow::Result<()> {
+    private_share::run()
 }
@@ -0,0 +1,83 @@
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+use tracing::info;
+
+/// the recent file accesses of the http api, the oldest ones are dropped when it is full
+#[derive(Debug)]
+pub struct AccessLog {
+    capacity: usize,
+    entries: Mutex<VecDeque<AccessEntry>>,
+}
+
+#[derive(Debug, Clone)]
+pub struct AccessEntry {
+    pub time: SystemTime,
+    /// `None` when the connection doesn't have the remote addr, like the unix socket
+    pub remote: Option<SocketAddr>,
+    /// `None` when the file is requested by hash
+    pub filename: Option<String>,
+    pub hash: String,
+    /// the requested range header
+    pub range: Option<String>,
+}
+
+impl AccessLog {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            entries: Mutex::new(VecDeque::with_capacity(capacity)),
+        }
+    }
+
+    /// record the access, it is also written to the log with the `access_log` target
+    pub fn record(&self, entry: AccessEntry) {
+        info!(
+            target: "access_log",
+            remote = ?entry.remote,
+            filename = ?entry.filename,
+            hash = %entry.hash,
+            range = ?entry.range,
+            "file is accessed"
+        );
+
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() >= self.capacity {
+            entries.pop_front();
+        }
+        entries.push_back(entry);
+    }
+
+    /// the recorded accesses, the newest first
+    pub fn entries(&self) -> Vec<AccessEntry> {
+        self.entries.lock().unwrap().iter().rev().cloned().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_access_log() {
+        let access_log = AccessLog::new(2);
+        for hash in ["a", "b", "c"] {
+            access_log.record(AccessEntry {
+                time: SystemTime::now(),
+                remote: None,
+                filename: None,
+                hash: hash.to_string(),
+                range: None,
+            });
+        }
+
+        let hashes = access_log
+            .entries()
+            .into_iter()
+            .map(|entry| entry.hash)
+            .collect::<Vec<_>>();
+        assert_eq!(hashes, ["c", "b"]);
+    }
+}
@@ -1,48 +1,340 @@
-use std::io;
-use std::io::Error;
-use std::net::SocketAddr;
+use std::fmt::{Debug, Formatter};
+use std::io::{Error, ErrorKind};
+use std::net::{SocketAddr, TcpListener as StdTcpListener};
+use std::ops::Range;
+use std::os::unix::io::{FromRawFd, RawFd};
 use std::pin::Pin;
 use std::task::{Context, Poll};
+use std::time::Duration;
+use std::{env, fmt, io, process};
 
-use futures_util::{stream, Stream, StreamExt, TryStreamExt};
+use futures_rustls::server::TlsStream;
+use futures_rustls::TlsAcceptor;
+use futures_util::future::BoxFuture;
+use futures_util::stream::FuturesUnordered;
+use futures_util::{stream, FutureExt, Stream, StreamExt, TryStreamExt};
 use hyper::server::accept::Accept;
 use tap::TapFallible;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
 use tokio::net::{TcpListener, TcpStream};
+use tokio::time;
 use tokio_stream::wrappers::TcpListenerStream;
-use tracing::error;
+use tokio_util::compat::{Compat, FuturesAsyncReadCompatExt, TokioAsyncReadCompatExt};
+use tracing::{error, info, warn};
+
+/// the first fd passed by systemd socket activation
+const SD_LISTEN_FDS_START: RawFd = 3;
+const LISTEN_PID_ENV: &str = "LISTEN_PID";
+const LISTEN_FDS_ENV: &str = "LISTEN_FDS";
+const LISTEN_FDNAMES_ENV: &str = "LISTEN_FDNAMES";
+/// the systemd listen fd with this name serves https
+const HTTPS_FDNAME: &str = "https";
+/// the connection is dropped when the tls handshake isn't finished in time
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// the accepted connection, the connections of the tls listeners are served over https
+pub enum HttpConn {
+    Plain(TcpStream),
+    Tls(Box<Compat<TlsStream<Compat<TcpStream>>>>),
+}
+
+impl HttpConn {
+    pub fn tcp_stream(&self) -> &TcpStream {
+        match self {
+            HttpConn::Plain(tcp_stream) => tcp_stream,
+            HttpConn::Tls(tls_stream) => tls_stream.get_ref().get_ref().0.get_ref(),
+        }
+    }
+
+    pub fn is_tls(&self) -> bool {
+        matches!(self, HttpConn::Tls(_))
+    }
+}
+
+impl Debug for HttpConn {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("HttpConn")
+            .field("tcp_stream", self.tcp_stream())
+            .field("tls", &self.is_tls())
+            .finish()
+    }
+}
+
+impl AsyncRead for HttpConn {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            HttpConn::Plain(tcp_stream) => Pin::new(tcp_stream).poll_read(cx, buf),
+            HttpConn::Tls(tls_stream) => Pin::new(tls_stream.as_mut()).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for HttpConn {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            HttpConn::Plain(tcp_stream) => Pin::new(tcp_stream).poll_write(cx, buf),
+            HttpConn::Tls(tls_stream) => Pin::new(tls_stream.as_mut()).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            HttpConn::Plain(tcp_stream) => Pin::new(tcp_stream).poll_flush(cx),
+            HttpConn::Tls(tls_stream) => Pin::new(tls_stream.as_mut()).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            HttpConn::Plain(tcp_stream) => Pin::new(tcp_stream).poll_shutdown(cx),
+            HttpConn::Tls(tls_stream) => Pin::new(tls_stream.as_mut()).poll_shutdown(cx),
+        }
+    }
+}
+
+/// the tcp listener, the accepted connections are served over https when the `tls_acceptor` is
+/// set
+struct Listener {
+    stream: TcpListenerStream,
+    tls_acceptor: Option<TlsAcceptor>,
+}
+
+impl Stream for Listener {
+    type Item = io::Result<(TcpStream, Option<TlsAcceptor>)>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let tls_acceptor = self.tls_acceptor.clone();
+
+        self.stream
+            .poll_next_unpin(cx)
+            .map_ok(|tcp_stream| (tcp_stream, tls_acceptor))
+    }
+}
 
-#[derive(Debug)]
 pub struct MultiAddrListener {
-    listeners: Vec<TcpListenerStream>,
+    listeners: Vec<Listener>,
+    tls_handshakes: FuturesUnordered<BoxFuture<'static, io::Result<HttpConn>>>,
+}
+
+impl Debug for MultiAddrListener {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MultiAddrListener")
+            .field("listeners", &self.listeners.len())
+            .field("tls_handshakes", &self.tls_handshakes.len())
+            .finish()
+    }
 }
 
 impl MultiAddrListener {
-    pub async fn new<S: Stream<Item = SocketAddr>>(addrs: S) -> io::Result<Self> {
-        let st = addrs.then(|addr| async move {
+    /// use the listeners passed by systemd socket activation if exist, the listeners named `https`
+    /// serve https, otherwise bind the addrs, the `tls_addrs` serve https with the `tls_acceptor`
+    pub async fn new(
+        addrs: &[SocketAddr],
+        tls_addrs: &[SocketAddr],
+        tls_acceptor: Option<TlsAcceptor>,
+    ) -> io::Result<Self> {
+        if let Some(listeners) = systemd_listeners(tls_acceptor.as_ref())? {
+            info!(
+                count = listeners.len(),
+                "use systemd socket activation listeners"
+            );
+
+            return Ok(Self::from_listeners(listeners));
+        }
+
+        let mut listeners = bind_listeners(addrs, None).await?;
+        if !tls_addrs.is_empty() {
+            let tls_acceptor = tls_acceptor.ok_or_else(|| {
+                Error::new(ErrorKind::InvalidInput, "https listen needs the tls cert")
+            })?;
+
+            listeners.extend(bind_listeners(tls_addrs, Some(tls_acceptor)).await?);
+        }
+
+        Ok(Self::from_listeners(listeners))
+    }
+
+    /// bind the plain http addrs, the listeners passed by systemd socket activation are never used
+    pub async fn bind(addrs: &[SocketAddr]) -> io::Result<Self> {
+        Ok(Self::from_listeners(bind_listeners(addrs, None).await?))
+    }
+
+    fn from_listeners(listeners: Vec<Listener>) -> Self {
+        Self {
+            listeners,
+            tls_handshakes: FuturesUnordered::new(),
+        }
+    }
+}
+
+async fn bind_listeners(
+    addrs: &[SocketAddr],
+    tls_acceptor: Option<TlsAcceptor>,
+) -> io::Result<Vec<Listener>> {
+    let st = stream::iter(addrs.iter().copied()).then(|addr| {
+        let tls_acceptor = tls_acceptor.clone();
+
+        async move {
             let listener = TcpListener::bind(addr)
                 .await
                 .tap_err(|err| error!(%err, %addr, "bind tcp listener failed"))?;
 
-            Ok::<_, Error>(TcpListenerStream::new(listener))
-        });
+            info!(%addr, tls = tls_acceptor.is_some(), "http listener is bound");
+
+            Ok::<_, Error>(Listener {
+                stream: TcpListenerStream::new(listener),
+                tls_acceptor,
+            })
+        }
+    });
+
+    st.try_collect()
+        .await
+        .tap_err(|err| error!(%err, "collect tcp listeners failed"))
+}
+
+async fn tls_handshake(tcp_stream: TcpStream, tls_acceptor: TlsAcceptor) -> io::Result<HttpConn> {
+    let remote = tcp_stream.peer_addr().ok();
+
+    let tls_stream = time::timeout(
+        TLS_HANDSHAKE_TIMEOUT,
+        tls_acceptor.accept(tcp_stream.compat()),
+    )
+    .await
+    .map_err(|_| Error::new(ErrorKind::TimedOut, "tls handshake timeout"))
+    .and_then(|result| result)
+    .tap_err(|err| warn!(%err, ?remote, "tls handshake failed"))?;
+
+    Ok(HttpConn::Tls(Box::new(tls_stream.compat())))
+}
+
+/// take the listeners passed by systemd, the env vars are removed so the child processes won't
+/// inherit them
+fn systemd_listeners(tls_acceptor: Option<&TlsAcceptor>) -> io::Result<Option<Vec<Listener>>> {
+    let fds = match listen_fds(
+        env::var(LISTEN_PID_ENV).ok().as_deref(),
+        env::var(LISTEN_FDS_ENV).ok().as_deref(),
+        process::id(),
+    ) {
+        None => return Ok(None),
+        Some(fds) => fds,
+    };
+
+    let fd_names = env::var(LISTEN_FDNAMES_ENV).unwrap_or_default();
 
-        let listeners = st
-            .try_collect()
-            .await
-            .tap_err(|err| error!(%err, "collect tcp listeners failed"))?;
+    env::remove_var(LISTEN_PID_ENV);
+    env::remove_var(LISTEN_FDS_ENV);
+    env::remove_var(LISTEN_FDNAMES_ENV);
 
-        Ok(Self { listeners })
+    let mut fd_names = fd_names.split(':');
+    let listeners = fds
+        .map(|fd| {
+            let tls_acceptor = if fd_names.next() == Some(HTTPS_FDNAME) {
+                let tls_acceptor = tls_acceptor.cloned().ok_or_else(|| {
+                    error!(fd, "systemd https listen fd needs the tls cert");
+
+                    Error::new(ErrorKind::InvalidInput, "https listen needs the tls cert")
+                })?;
+
+                Some(tls_acceptor)
+            } else {
+                None
+            };
+
+            // Safety: systemd passes the opened listen sockets from SD_LISTEN_FDS_START, and they
+            // are owned by this process only
+            let listener = unsafe { StdTcpListener::from_raw_fd(fd) };
+            listener.set_nonblocking(true)?;
+
+            let listener = TcpListener::from_std(listener)
+                .tap_err(|err| error!(%err, fd, "convert systemd listen fd failed"))?;
+
+            Ok::<_, Error>(Listener {
+                stream: TcpListenerStream::new(listener),
+                tls_acceptor,
+            })
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    Ok(Some(listeners))
+}
+
+/// parse the systemd socket activation env vars, the fds are passed to this process only when
+/// `LISTEN_PID` is the pid
+fn listen_fds(
+    listen_pid: Option<&str>,
+    listen_fds: Option<&str>,
+    pid: u32,
+) -> Option<Range<RawFd>> {
+    if listen_pid?.parse::<u32>().ok()? != pid {
+        return None;
     }
+
+    let count = listen_fds?.parse::<RawFd>().ok()?;
+    if count <= 0 {
+        return None;
+    }
+
+    Some(SD_LISTEN_FDS_START..SD_LISTEN_FDS_START + count)
 }
 
 impl Accept for MultiAddrListener {
-    type Conn = TcpStream;
+    type Conn = HttpConn;
     type Error = Error;
 
+    /// the plain connections are returned directly, the tls connections are returned after the
+    /// tls handshake, the failed handshakes are dropped
     fn poll_accept(
-        mut self: Pin<&mut Self>,
+        self: Pin<&mut Self>,
         cx: &mut Context<'_>,
     ) -> Poll<Option<Result<Self::Conn, Self::Error>>> {
-        stream::select_all(self.listeners.iter_mut()).poll_next_unpin(cx)
+        let this = self.get_mut();
+
+        let listeners_done = loop {
+            match stream::select_all(this.listeners.iter_mut()).poll_next_unpin(cx) {
+                Poll::Pending => break false,
+                Poll::Ready(None) => break true,
+                Poll::Ready(Some(Err(err))) => return Poll::Ready(Some(Err(err))),
+                Poll::Ready(Some(Ok((tcp_stream, None)))) => {
+                    return Poll::Ready(Some(Ok(HttpConn::Plain(tcp_stream))));
+                }
+                Poll::Ready(Some(Ok((tcp_stream, Some(tls_acceptor))))) => {
+                    this.tls_handshakes
+                        .push(tls_handshake(tcp_stream, tls_acceptor).boxed());
+                }
+            }
+        };
+
+        loop {
+            match this.tls_handshakes.poll_next_unpin(cx) {
+                Poll::Ready(Some(Ok(conn))) => return Poll::Ready(Some(Ok(conn))),
+                // the failed handshake is logged, accept the next connection
+                Poll::Ready(Some(Err(_))) => continue,
+                Poll::Ready(None) if listeners_done => return Poll::Ready(None),
+                Poll::Ready(None) | Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_listen_fds() {
+        assert_eq!(listen_fds(Some("10"), Some("2"), 10), Some(3..5));
+        assert_eq!(listen_fds(Some("11"), Some("2"), 10), None);
+        assert_eq!(listen_fds(Some("10"), Some("0"), 10), None);
+        assert_eq!(listen_fds(None, Some("2"), 10), None);
+        assert_eq!(listen_fds(Some("10"), None, 10), None);
     }
 }
@@ -1,14 +1,19 @@
+use std::fmt::{Display, Formatter};
 use std::future::ready;
+use std::net::IpAddr;
 use std::time::Duration;
+use std::{error, fmt};
 
-use futures_util::{Stream, TryStreamExt};
+use futures_util::{Stream, StreamExt, TryStreamExt};
 use http::{StatusCode, Uri};
 use rupnp::ssdp::{SearchTarget, URN};
 use rupnp::Device;
 use tap::TapFallible;
 use tokio::net::UdpSocket;
 use tracing::{debug, error, info, instrument};
-use xml::escape::escape_str_attribute;
+use xml::escape::{escape_str_attribute, escape_str_pcdata};
+
+use crate::util::match_pattern;
 
 const AV_TRANSPORT: URN = URN::service("schemas-upnp-org", "AVTransport", 1);
 const PAYLOAD_PLAY: &str = r#"
@@ -18,6 +23,40 @@ const PAYLOAD_PLAY: &str = r#"
 const SET_AV_TRANSPORT_URI_ACTION: &str = "SetAVTransportURI";
 const PLAY_ACTION: &str = "Play";
 
+/// the failures of the renderer, it may go offline or change between the listing and the play
+#[derive(Debug)]
+pub enum RendererError {
+    /// the renderer doesn't provide the service anymore
+    MissingService { service: URN },
+    /// the renderer rejects the action or doesn't respond
+    Action {
+        action: &'static str,
+        err: rupnp::Error,
+    },
+}
+
+impl Display for RendererError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            RendererError::MissingService { service } => {
+                write!(f, "renderer doesn't provide service {service}")
+            }
+            RendererError::Action { action, err } => {
+                write!(f, "renderer action {action} failed: {err}")
+            }
+        }
+    }
+}
+
+impl error::Error for RendererError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            RendererError::MissingService { .. } => None,
+            RendererError::Action { err, .. } => Some(err),
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct TV {
     device: Device,
@@ -51,8 +90,9 @@ impl TV {
         self.device.url().to_string()
     }
 
-    #[instrument(err)]
-    pub async fn play(self, http_port: u16, url_path: &str) -> anyhow::Result<()> {
+    /// detect the local ip which can reach the dlna device by connecting an udp socket to it, no
+    /// packet is sent
+    async fn detect_local_ip(&self) -> anyhow::Result<IpAddr> {
         let url = self.device.url();
         let host = url.host().ok_or_else(|| {
             error!(%url, "dlna device url doesn't have host");
@@ -82,21 +122,56 @@ impl TV {
 
         info!(%local_ip, "get udp socket local ip done");
 
-        // we only need what local ip can be connected by dlna device
-        drop(udp_socket);
+        Ok(local_ip)
+    }
+
+    #[instrument(err)]
+    pub async fn play(
+        self,
+        tls: bool,
+        http_port: u16,
+        url_path: &str,
+        subtitle_url_path: Option<&str>,
+        advertise_host: Option<&str>,
+    ) -> anyhow::Result<()> {
+        let url = self.device.url();
+        let local_host = match advertise_host {
+            Some(advertise_host) => {
+                info!(advertise_host, "use the configured dlna advertise host");
+
+                advertise_host.to_string()
+            }
+
+            None => self.detect_local_ip().await?.to_string(),
+        };
+
+        let scheme = if tls { "https" } else { "http" };
+        let video_url = format!("{scheme}://{local_host}:{http_port}{url_path}");
+        let metadata = subtitle_url_path
+            .map(|subtitle_url_path| {
+                let subtitle_url =
+                    format!("{scheme}://{local_host}:{http_port}{subtitle_url_path}");
+
+                create_subtitle_metadata(&video_url, &subtitle_url)
+            })
+            .unwrap_or_default();
 
         let payload_setavtransport_uri = format!(
             r#"<InstanceID>0</InstanceID>
-        <CurrentURI>http://{local_ip}:{http_port}{}</CurrentURI>
-        <CurrentURIMetaData></CurrentURIMetaData>
+        <CurrentURI>{}</CurrentURI>
+        <CurrentURIMetaData>{}</CurrentURIMetaData>
         "#,
-            escape_str_attribute(url_path)
+            escape_str_attribute(&video_url),
+            escape_str_attribute(&metadata)
         );
 
-        let service = self
-            .device
-            .find_service(&AV_TRANSPORT)
-            .unwrap_or_else(|| panic!("checked device doesn't have AV_TRANSPORT service"));
+        let service = self.device.find_service(&AV_TRANSPORT).ok_or_else(|| {
+            error!(%url, "dlna device doesn't have AVTransport service");
+
+            RendererError::MissingService {
+                service: AV_TRANSPORT,
+            }
+        })?;
 
         let mut resp = service
             .action(
@@ -105,9 +180,14 @@ impl TV {
                 &payload_setavtransport_uri,
             )
             .await
-            .tap_err(
-                |err| error!(%err, %payload_setavtransport_uri, "set av transport uri failed"),
-            )?;
+            .map_err(|err| {
+                error!(%err, %payload_setavtransport_uri, "set av transport uri failed");
+
+                RendererError::Action {
+                    action: SET_AV_TRANSPORT_URI_ACTION,
+                    err,
+                }
+            })?;
 
         info!(%payload_setavtransport_uri, "set av transport uri done");
         debug!(?resp, "set av transport uri response");
@@ -115,7 +195,14 @@ impl TV {
         resp = service
             .action(url, PLAY_ACTION, PAYLOAD_PLAY)
             .await
-            .tap_err(|err| error!(%err, "play video failed"))?;
+            .map_err(|err| {
+                error!(%err, "play video failed");
+
+                RendererError::Action {
+                    action: PLAY_ACTION,
+                    err,
+                }
+            })?;
 
         info!("play video done");
         debug!(?resp, "play video response");
@@ -124,18 +211,83 @@ impl TV {
     }
 }
 
+/// create the DIDL-Lite metadata which advertises the subtitle, the `CaptionInfoEx` is used by
+/// samsung tv and the subtitle `res` is used by the others
+fn create_subtitle_metadata(video_url: &str, subtitle_url: &str) -> String {
+    let video_url = escape_str_pcdata(video_url);
+    let subtitle_url = escape_str_pcdata(subtitle_url);
+
+    format!(
+        r#"<DIDL-Lite xmlns="urn:schemas-upnp-org:metadata-1-0/DIDL-Lite/" xmlns:dc="http://purl.org/dc/elements/1.1/" xmlns:upnp="urn:schemas-upnp-org:metadata-1-0/upnp/" xmlns:sec="http://www.sec.co.kr/"><item id="0" parentID="-1" restricted="1"><dc:title>video</dc:title><upnp:class>object.item.videoItem</upnp:class><res protocolInfo="http-get:*:video/*:*">{video_url}</res><res protocolInfo="http-get:*:text/srt:*">{subtitle_url}</res><sec:CaptionInfoEx sec:type="srt">{subtitle_url}</sec:CaptionInfoEx></item></DIDL-Lite>"#
+    )
+}
+
+/// the filter of the discovered tvs, it is applied on top of the AVTransport service check
+#[derive(Debug, Default, Clone)]
+pub struct TvFilter {
+    /// the wildcard pattern of the friendly name, like `*Living Room*`, case insensitive
+    pub name_pattern: Option<String>,
+    /// the device type, like `MediaRenderer`, case insensitive
+    pub device_type: Option<String>,
+    /// the max number of the listed tvs, unlimited when it is `None`
+    pub max_count: Option<usize>,
+}
+
+impl TvFilter {
+    fn matches(&self, friendly_name: &str, device_type: &str) -> bool {
+        self.name_pattern
+            .as_deref()
+            .map_or(true, |pattern| match_pattern(pattern, friendly_name))
+            && self
+                .device_type
+                .as_deref()
+                .map_or(true, |typ| typ.eq_ignore_ascii_case(device_type))
+    }
+}
+
 #[instrument(err)]
-pub async fn list_tv(timeout: Duration) -> anyhow::Result<impl Stream<Item = anyhow::Result<TV>>> {
+pub async fn list_tv(
+    timeout: Duration,
+    filter: TvFilter,
+) -> anyhow::Result<impl Stream<Item = anyhow::Result<TV>>> {
     let device_stream = rupnp::discover(&SearchTarget::URN(AV_TRANSPORT), timeout)
         .await
         .tap_err(|err| error!(%err, "discover TV failed"))?;
 
+    let max_count = filter.max_count.unwrap_or(usize::MAX);
+
     Ok(device_stream
-        .try_filter_map(|device| {
-            ready(Ok(device
-                .find_service(&AV_TRANSPORT)
-                .is_some()
-                .then_some(TV { device })))
+        .try_filter_map(move |device| {
+            let matched = device.find_service(&AV_TRANSPORT).is_some()
+                && filter.matches(device.friendly_name(), device.device_type().typ());
+            if !matched {
+                debug!(
+                    friendly_name = device.friendly_name(),
+                    "skip unmatched device"
+                );
+            }
+
+            ready(Ok(matched.then_some(TV { device })))
         })
-        .map_err(anyhow::Error::from))
+        .map_err(anyhow::Error::from)
+        .take(max_count))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tv_filter() {
+        assert!(TvFilter::default().matches("Living Room TV", "MediaRenderer"));
+
+        let filter = TvFilter {
+            name_pattern: Some("*living room*".to_string()),
+            device_type: Some("mediarenderer".to_string()),
+            max_count: None,
+        };
+        assert!(filter.matches("Living Room TV", "MediaRenderer"));
+        assert!(!filter.matches("Bedroom TV", "MediaRenderer"));
+        assert!(!filter.matches("Living Room Speaker", "MediaServer"));
+    }
 }
@@ -0,0 +1,88 @@
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use http::StatusCode;
+use serde::Serialize;
+
+use super::request_id::current_request_id;
+
+/// the error response of all api handlers, it is serialized as `{error, message, code}` json so
+/// clients can parse it in the same way, the `request_id` is included when it is handled as a
+/// request
+#[derive(Debug)]
+pub struct ApiError {
+    status: StatusCode,
+    message: String,
+}
+
+impl ApiError {
+    pub fn new<S: Into<String>>(status: StatusCode, message: S) -> Self {
+        Self {
+            status,
+            message: message.into(),
+        }
+    }
+
+    pub fn status(&self) -> StatusCode {
+        self.status
+    }
+
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct ApiErrorBody {
+    error: String,
+    message: String,
+    code: u16,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    request_id: Option<String>,
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let error = self
+            .status
+            .canonical_reason()
+            .unwrap_or("unknown")
+            .to_lowercase()
+            .replace(' ', "_");
+
+        let body = ApiErrorBody {
+            error,
+            message: self.message,
+            code: self.status.as_u16(),
+            request_id: current_request_id(),
+        };
+
+        (self.status, Json(body)).into_response()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::Value;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_api_error_response() {
+        let response =
+            ApiError::new(StatusCode::NOT_FOUND, "file a.txt not exists").into_response();
+
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let body = serde_json::from_slice::<Value>(&body).unwrap();
+
+        assert_eq!(
+            body,
+            serde_json::json!({
+                "error": "not_found",
+                "message": "file a.txt not exists",
+                "code": 404,
+            })
+        );
+    }
+}
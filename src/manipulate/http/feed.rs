@@ -0,0 +1,102 @@
+use std::fmt::Write;
+
+use byte_unit::Byte;
+use libp2p::PeerId;
+use xml::escape::{escape_str_attribute, escape_str_pcdata};
+
+use super::encode_path_segment;
+use crate::command::ListFileDetail;
+
+/// render the shared files as a RSS 2.0 feed, every item has an enclosure which links to the
+/// get file url, so podcast apps and download managers can download it
+///
+/// `base_url` is like `http://127.0.0.1:19998`, `get_file_path` is the get file url path prefix
+/// without the filename, like `/api/get_file`
+pub fn render_feed(
+    peer_id: &PeerId,
+    base_url: &str,
+    feed_path: &str,
+    get_file_path: &str,
+    files: &[ListFileDetail],
+) -> String {
+    let mut feed = String::new();
+
+    // write into String never fails
+    let _ = write!(
+        feed,
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<rss version="2.0" xmlns:atom="http://www.w3.org/2005/Atom">
+<channel>
+<title>private share {peer_id}</title>
+<link>{base_url}</link>
+<description>files shared by {peer_id}</description>
+<atom:link href="{}" rel="self" type="application/rss+xml"/>
+"#,
+        escape_str_attribute(&format!("{base_url}{feed_path}"))
+    );
+
+    for file in files {
+        let url = format!(
+            "{base_url}{get_file_path}/{}",
+            encode_path_segment(&file.filename)
+        );
+        let content_type = mime_guess::from_path(&file.filename).first_or_octet_stream();
+        let size = Byte::from_bytes(file.size).get_appropriate_unit(true);
+
+        let _ = write!(
+            feed,
+            r#"<item>
+<title>{}</title>
+<link>{}</link>
+<guid isPermaLink="false">{}</guid>
+<description>{size}</description>
+<enclosure url="{}" length="{}" type="{}"/>
+</item>
+"#,
+            escape_str_pcdata(&file.filename),
+            escape_str_pcdata(&url),
+            file.hash,
+            escape_str_attribute(&url),
+            file.size,
+            escape_str_attribute(content_type.as_ref()),
+        );
+    }
+
+    feed.push_str("</channel>\n</rss>\n");
+
+    feed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_feed() {
+        let peer_id = PeerId::random();
+        let files = vec![ListFileDetail {
+            filename: "a & b.mp4".to_string(),
+            hash: "ABC".to_string(),
+            downloaded: true,
+            peers: vec![],
+            size: 2048,
+            healthy: None,
+            modified: None,
+        }];
+
+        let feed = render_feed(
+            &peer_id,
+            "http://127.0.0.1:19998",
+            "/api/feed.xml",
+            "/api/get_file",
+            &files,
+        );
+
+        assert!(feed.contains(&format!("<title>private share {peer_id}</title>")));
+        assert!(feed.contains("<title>a &amp; b.mp4</title>"));
+        assert!(feed.contains(
+            r#"<enclosure url="http://127.0.0.1:19998/api/get_file/a%20%26%20b.mp4" length="2048" type="video/mp4"/>"#
+        ));
+        assert!(feed.contains(r#"<guid isPermaLink="false">ABC</guid>"#));
+    }
+}
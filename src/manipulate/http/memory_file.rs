@@ -0,0 +1,164 @@
+use std::collections::HashMap;
+use std::future::{ready, Ready};
+use std::io;
+use std::io::{Cursor, ErrorKind, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use http_dir::fs::{FileExt, Filesystem, Metadata};
+use http_dir::ServeFile;
+use tokio::io::{AsyncRead, AsyncSeek, ReadBuf};
+
+use crate::command;
+
+/// A [`command::FileGetter`] which serves files from memory, so the get file flow can be tested
+/// without touching the filesystem
+#[derive(Debug, Clone, Default)]
+pub struct MemoryFileGetter {
+    filesystem: MemoryFilesystem,
+}
+
+impl MemoryFileGetter {
+    pub fn new<I: IntoIterator<Item = (PathBuf, Bytes)>>(files: I) -> Self {
+        Self {
+            filesystem: MemoryFilesystem {
+                files: Arc::new(files.into_iter().collect()),
+            },
+        }
+    }
+}
+
+#[async_trait]
+impl command::FileGetter for MemoryFileGetter {
+    type FileContent = ServeFile<MemoryFilesystem>;
+
+    async fn get_file(self, path: &Path) -> io::Result<Self::FileContent> {
+        if !self.filesystem.files.contains_key(path) {
+            return Err(io::Error::from(ErrorKind::NotFound));
+        }
+
+        Ok(ServeFile::new(path, self.filesystem))
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct MemoryFilesystem {
+    files: Arc<HashMap<PathBuf, Bytes>>,
+}
+
+impl MemoryFilesystem {
+    fn get(&self, path: &Path) -> io::Result<&Bytes> {
+        self.files
+            .get(path)
+            .ok_or_else(|| io::Error::from(ErrorKind::NotFound))
+    }
+}
+
+impl Filesystem for MemoryFilesystem {
+    type File = MemoryFile;
+    type OpenFile<'a> = Ready<io::Result<MemoryFile>>;
+    type IsDir<'a> = Ready<io::Result<bool>>;
+    type Metadata<'a> = Ready<io::Result<Metadata>>;
+
+    fn open<'a>(&'a mut self, path: &'a Path) -> Self::OpenFile<'a> {
+        ready(
+            self.get(path)
+                .map(|data| MemoryFile(Cursor::new(data.clone()))),
+        )
+    }
+
+    fn is_dir<'a>(&'a self, path: &'a Path) -> Self::IsDir<'a> {
+        ready(self.get(path).map(|_| false))
+    }
+
+    fn metadata<'a>(&'a self, path: &'a Path) -> Self::Metadata<'a> {
+        ready(self.get(path).map(|data| Metadata {
+            modified: None,
+            len: data.len() as _,
+        }))
+    }
+}
+
+pub struct MemoryFile(Cursor<Bytes>);
+
+impl AsyncRead for MemoryFile {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_read(cx, buf)
+    }
+}
+
+impl AsyncSeek for MemoryFile {
+    fn start_seek(mut self: Pin<&mut Self>, position: SeekFrom) -> io::Result<()> {
+        Pin::new(&mut self.0).start_seek(position)
+    }
+
+    fn poll_complete(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<u64>> {
+        Pin::new(&mut self.0).poll_complete(cx)
+    }
+}
+
+impl FileExt for MemoryFile {
+    type Metadata<'a> = Ready<io::Result<Metadata>>;
+
+    fn metadata(&self) -> Self::Metadata<'_> {
+        ready(Ok(Metadata {
+            modified: None,
+            len: self.0.get_ref().len() as _,
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::body::HttpBody;
+    use http::header::RANGE;
+    use http::{Request, StatusCode};
+    use tower::Service;
+
+    use super::*;
+    use crate::command::FileGetter;
+
+    #[tokio::test]
+    async fn test_memory_file_getter() {
+        let path = PathBuf::from("/store/test.txt");
+        let file_getter =
+            MemoryFileGetter::new([(path.clone(), Bytes::from_static(b"hello world"))]);
+
+        let mut file_content = file_getter.clone().get_file(&path).await.unwrap();
+        let mut response = file_content.call(Request::new(())).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.body_mut().data().await.unwrap().unwrap(),
+            &b"hello world"[..]
+        );
+
+        let mut file_content = file_getter.clone().get_file(&path).await.unwrap();
+        let request = Request::builder()
+            .header(RANGE, "bytes=6-")
+            .body(())
+            .unwrap();
+        let mut response = file_content.call(request).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(
+            response.body_mut().data().await.unwrap().unwrap(),
+            &b"world"[..]
+        );
+
+        let err = file_getter
+            .get_file(Path::new("/store/not_exists.txt"))
+            .await
+            .unwrap_err();
+
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+    }
+}
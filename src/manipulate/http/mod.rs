@@ -1,53 +1,91 @@
 use std::borrow::Cow;
 use std::fmt::{Debug, Display};
-use std::future::ready;
+use std::future::{ready, Future};
 use std::io;
 use std::io::ErrorKind;
-use std::time::Duration;
+use std::ops::Range;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
 
-use axum::body::BoxBody;
+use anyhow::anyhow;
+use axum::body::{BoxBody, Empty, Full, HttpBody, StreamBody};
 use axum::extract::ws::{close_code, CloseFrame, Message, WebSocket};
 use axum::extract::{
     ConnectInfo, DefaultBodyLimit, Multipart, Path, Query, State, WebSocketUpgrade,
 };
-use axum::response::Redirect;
+use axum::response::{IntoResponse, Redirect};
 use axum::routing::{get, post};
-use axum::{body, Json, Router};
+use axum::{body, middleware, Json, Router};
 use base64::prelude::BASE64_STANDARD;
 use base64::Engine;
 use byte_unit::Byte;
 use bytes::Bytes;
 use futures_channel::mpsc::Sender;
 use futures_channel::{mpsc, oneshot};
-use futures_util::{pin_mut, SinkExt, Stream, StreamExt, TryStreamExt};
-use http::{Request, Response, StatusCode, Uri};
+use futures_util::{pin_mut, stream, SinkExt, Stream, StreamExt, TryStreamExt};
+use http::header::{
+    ACCEPT_RANGES, AUTHORIZATION, CONTENT_DISPOSITION, CONTENT_LENGTH, CONTENT_RANGE, CONTENT_TYPE,
+    ETAG, IF_NONE_MATCH, LAST_MODIFIED, RANGE, SEC_WEBSOCKET_PROTOCOL,
+};
+use http::{HeaderMap, HeaderName, HeaderValue, Request, Response, StatusCode, Uri};
 use http_dir::ResponseBody;
 use itertools::Itertools;
-use libp2p::Multiaddr;
+use libp2p::{Multiaddr, PeerId};
+use sha2::{Digest, Sha256};
 use tap::{Tap, TapFallible};
-use tokio::{select, time};
+use tokio::sync::{broadcast, Notify};
+use tokio::{fs, select, time};
 use tokio_stream::wrappers::IntervalStream;
+use tokio_util::sync::CancellationToken;
 use tower::Service;
+use tower_http::compression::CompressionLayer;
 use tracing::{error, info, instrument, warn};
 
+use self::access_log::{AccessEntry, AccessLog};
 pub use self::addr_incoming::MultiAddrListener;
-use self::dlna::TV;
+use self::dlna::{RendererError, TvFilter, TV};
+use self::error::ApiError;
 use self::file::FileGetter;
+#[cfg(test)]
+pub use self::memory_file::MemoryFileGetter;
 use self::response::*;
+use self::rpc::{FileAvailabilityParams, RpcError, RpcRequest, RpcResponse};
 use self::socket_addr_peer::SocketAddrPeer;
 use self::static_router::StaticRouter;
-use crate::command::Command;
+pub use self::tls::{https_redirect_port, load_tls_acceptor, serve_https_redirect};
+use self::webdav::WEBDAV_PREFIX;
+use crate::command;
+use crate::command::{
+    Command, FileAvailability, FileEvent, FileSyncingError, GetFileDetail, ListFileDetail,
+    PingTarget, UploadRange,
+};
 
+mod access_log;
 mod addr_incoming;
 mod dlna;
+mod error;
+mod feed;
 mod file;
-mod response;
+#[cfg(test)]
+mod memory_file;
+mod partial_file;
+mod request_id;
+pub mod response;
+mod rpc;
 mod socket_addr_peer;
 mod static_resources;
 mod static_router;
-
-const API_PREFIX: &str = "/api";
-const UI_PREFIX: &str = "/ui";
+mod subtitle;
+#[cfg(feature = "thumbnail")]
+mod thumbnail;
+mod tls;
+mod webdav;
+
+pub const DEFAULT_API_PREFIX: &str = "/api";
+pub const DEFAULT_UI_PREFIX: &str = "/ui";
+pub const DEFAULT_THUMBNAIL_DIR_NAME: &str = ".thumbs";
+pub const DEFAULT_COMMAND_SEND_TIMEOUT: Duration = Duration::from_secs(5);
 const LIST_FILES_PATH: &str = "/list_files";
 const ADD_FILE_PATH: &str = "/add_file";
 const UPLOAD_FILE_PATH: &str = "/upload_file";
@@ -55,24 +93,227 @@ const LIST_PEERS_PATH: &str = "/list_peers";
 const GET_BANDWIDTH_PATH: &str = "/get_bandwidth";
 const ADD_PEERS_PATH: &str = "/add_peers";
 const REMOVE_PEERS_PATH: &str = "/remove_peers";
+const PING_PEER_PATH: &str = "/ping_peer";
+const REMOVE_FILES_PATH: &str = "/remove_files";
 const GET_FILE_PATH: &str = "/get_file/:filename";
+const GET_INDEX_FILE_PATH: &str = "/get_index_file/:hash";
 const LIST_TV_PATH: &str = "/list_tv";
 const PLAY_TV_PATH: &str = "/play_tv/:encoded_tv_url/:filename";
+const FEED_PATH: &str = "/feed.xml";
+const FILE_AVAILABILITY_PATH: &str = "/availability/:filename";
+const STATS_PATH: &str = "/stats";
+const DIAGNOSTICS_PATH: &str = "/diagnostics";
+const FILE_PEERS_PATH: &str = "/file_peers/:filename";
+const VERIFY_FILE_PATH: &str = "/verify/:filename";
+const SYNC_FILE_PATH: &str = "/sync_file/:filename";
+const SYNC_PLAN_PATH: &str = "/sync_plan";
+const SYNCS_PATH: &str = "/syncs";
+const MY_FILES_PATH: &str = "/my_files";
+const RPC_PATH: &str = "/rpc";
+const ACCESS_LOG_PATH: &str = "/access_log";
+const FILE_EVENTS_PATH: &str = "/file_events";
+const MANIFEST_PATH: &str = "/manifest";
+const IMPORT_MANIFEST_PATH: &str = "/manifest/import";
+const SHUTDOWN_PATH: &str = "/shutdown";
+
+/// the max time of producing the get file content, like `10s`
+static X_REQUEST_TIMEOUT: HeaderName = HeaderName::from_static("x-request-timeout");
+
+const MANIFEST_CONTENT_TYPE: &str = "application/x-protobuf";
+
+/// the subprotocol of the websocket routes, it is bumped when the message format changes
+const WEBSOCKET_PROTOCOL: &str = "private-share-v1";
+
+/// max time of waiting the requested range of the syncing file
+const PARTIAL_FILE_WAIT_TIMEOUT: Duration = Duration::from_secs(10);
+const PARTIAL_FILE_POLL_INTERVAL: Duration = Duration::from_millis(500);
+/// max bytes of a partial file response, the client requests the rest later
+const MAX_PARTIAL_SIZE: u64 = 8 * 1024 * 1024;
+#[cfg(feature = "thumbnail")]
+const THUMBNAIL_PATH: &str = "/thumbnail/:filename";
 
 type UploadFileReceiver = impl Stream<Item = io::Result<Bytes>> + Unpin + Send + 'static;
 
+/// the http protocol of the api server, http2 is negotiated by alpn on the https listeners, and
+/// only served to the clients with prior knowledge (h2c) on the plain listeners
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+pub enum HttpProtocol {
+    /// detect the http2 connection preface, otherwise serve http1
+    #[default]
+    Auto,
+    Http1,
+    /// the websocket routes are unavailable, the websocket upgrade needs http1
+    Http2,
+}
+
+impl FromStr for HttpProtocol {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "auto" => Ok(HttpProtocol::Auto),
+            "http1" => Ok(HttpProtocol::Http1),
+            "http2" => Ok(HttpProtocol::Http2),
+            _ => Err(anyhow!("unknown http protocol {s}")),
+        }
+    }
+}
+
+impl HttpProtocol {
+    fn configure<I, E>(
+        self,
+        builder: hyper::server::Builder<I, E>,
+    ) -> hyper::server::Builder<I, E> {
+        match self {
+            HttpProtocol::Auto => builder,
+            HttpProtocol::Http1 => builder.http1_only(true),
+            HttpProtocol::Http2 => builder.http2_only(true),
+        }
+    }
+
+    /// the alpn protocols of the https listeners
+    fn alpn_protocols(self) -> Vec<Vec<u8>> {
+        match self {
+            HttpProtocol::Auto => vec![b"h2".to_vec(), b"http/1.1".to_vec()],
+            HttpProtocol::Http1 => vec![b"http/1.1".to_vec()],
+            HttpProtocol::Http2 => vec![b"h2".to_vec()],
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct Server {
     command_sender: Sender<Command<UploadFileReceiver, FileGetter>>,
+    api_prefix: Arc<str>,
+    /// the embedded web ui is disabled when it is `None`
+    ui_prefix: Option<Arc<str>>,
+    /// the CanvasKit base url injected into the web ui, the build time one is used when it is
+    /// `None`
+    canvaskit_url: Option<Arc<str>>,
+    /// the dir of the web ui files, the embedded web ui is served when it is `None`
+    ui_dir: Option<Arc<std::path::Path>>,
+    /// the dir of the cached thumbnails
+    #[cfg_attr(not(feature = "thumbnail"), allow(dead_code))]
+    thumbnail_dir: Arc<std::path::Path>,
+    webdav_writable: bool,
+    /// the file access log is disabled when it is `None`
+    access_log: Option<Arc<AccessLog>>,
+    /// the bearer token of the shutdown api, the shutdown api is rejected when it is `None`
+    api_token: Option<Arc<str>>,
+    /// the host put into the url played by the dlna renderer, the local ip which can reach the
+    /// renderer is detected when it is `None`
+    dlna_advertise_host: Option<Arc<str>>,
+    /// the base url of the feed links, the local address of the connection is used when it is
+    /// `None`
+    public_url: Option<Arc<str>>,
+    /// notified when the shutdown api is called
+    shutdown: Arc<Notify>,
+    /// max time of waiting the node to accept a command
+    command_send_timeout: Duration,
 }
 
 impl Server {
-    pub fn new(command_sender: Sender<Command<UploadFileReceiver, FileGetter>>) -> Self {
-        Self { command_sender }
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        command_sender: Sender<Command<UploadFileReceiver, FileGetter>>,
+        api_prefix: &str,
+        ui_prefix: Option<&str>,
+        canvaskit_url: Option<&str>,
+        ui_dir: Option<&std::path::Path>,
+        thumbnail_dir: &std::path::Path,
+        webdav_writable: bool,
+        access_log_size: Option<usize>,
+        api_token: Option<&str>,
+        dlna_advertise_host: Option<&str>,
+        public_url: Option<&str>,
+        command_send_timeout: Duration,
+    ) -> io::Result<Self> {
+        Ok(Self {
+            command_sender,
+            api_prefix: normalize_prefix(api_prefix)?.into(),
+            ui_prefix: ui_prefix
+                .map(|ui_prefix| normalize_prefix(ui_prefix).map(Arc::from))
+                .transpose()?,
+            canvaskit_url: canvaskit_url.map(Arc::from),
+            ui_dir: ui_dir.map(Arc::from),
+            thumbnail_dir: Arc::from(thumbnail_dir),
+            webdav_writable,
+            access_log: access_log_size.map(|size| Arc::new(AccessLog::new(size))),
+            api_token: api_token.map(Arc::from),
+            dlna_advertise_host: dlna_advertise_host.map(Arc::from),
+            public_url: public_url.map(Arc::from),
+            shutdown: Arc::new(Notify::new()),
+            command_send_timeout,
+        })
+    }
+
+    /// the notify of the shutdown api, the node should shut down as receiving SIGTERM when it is
+    /// notified
+    pub fn shutdown_notify(&self) -> Arc<Notify> {
+        self.shutdown.clone()
+    }
+
+    /// serve the http api until the `shutdown` is done, the listeners are closed and the
+    /// in-flight requests are finished when shutting down
+    pub async fn listen<F>(
+        self,
+        incoming: MultiAddrListener,
+        http_protocol: HttpProtocol,
+        shutdown: F,
+    ) -> anyhow::Result<()>
+    where
+        F: Future<Output = ()>,
+    {
+        if http_protocol == HttpProtocol::Http2 {
+            warn!(
+                "http2 only server can't upgrade websocket, the websocket routes are unavailable"
+            );
+        }
+
+        let router = self.router();
+
+        http_protocol
+            .configure(axum::Server::builder(incoming))
+            .serve(router.into_make_service_with_connect_info::<SocketAddrPeer>())
+            .with_graceful_shutdown(shutdown)
+            .await?;
+
+        Ok(())
+    }
+
+    /// send the command to the node, the node handles the commands one by one, so the send waits
+    /// when the command channel is full, the node is treated as overloaded and `503` is returned
+    /// when the send waits longer than the `command_send_timeout`
+    async fn send_command(
+        &mut self,
+        command: Command<UploadFileReceiver, FileGetter>,
+    ) -> Result<(), ApiError> {
+        match time::timeout(self.command_send_timeout, self.command_sender.send(command)).await {
+            Err(_) => {
+                error!(timeout = ?self.command_send_timeout, "send command timeout, node is busy");
+
+                Err(ApiError::new(
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    "node is busy, try again later",
+                ))
+            }
+
+            Ok(Err(err)) => {
+                error!(%err, "send command failed");
+
+                Err(ApiError::new(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    err.to_string(),
+                ))
+            }
+
+            Ok(Ok(_)) => Ok(()),
+        }
     }
 
-    pub async fn listen(self, incoming: MultiAddrListener) -> anyhow::Result<()> {
-        let api_router =
+    fn router(self) -> Router {
+        // only json responses are compressed, file download and websocket routes are excluded
+        let compressed_router =
             Router::new()
                 .route(
                     LIST_FILES_PATH,
@@ -80,184 +321,395 @@ impl Server {
                         server.handle_list_files(body).await
                     }),
                 )
-                .merge(Router::new().route(
-                    ADD_FILE_PATH,
-                    post(|State(mut server): State<Server>, body| async move {
-                        server.handle_add_file(body).await
+                .route(
+                    LIST_PEERS_PATH,
+                    get(|State(mut server): State<Server>| async move {
+                        server.handle_list_peers().await
                     }),
-                ))
+                )
                 .route(
-                    UPLOAD_FILE_PATH,
-                    post(|State(mut server): State<Server>, body| async move {
-                        server.handle_upload_file(body).await
+                    FILE_AVAILABILITY_PATH,
+                    get(|State(mut server): State<Server>, path| async move {
+                        server.handle_get_file_availability(path).await
                     }),
                 )
                 .route(
-                    LIST_PEERS_PATH,
+                    STATS_PATH,
                     get(|State(mut server): State<Server>| async move {
-                        server.handle_list_peers().await
+                        server.handle_get_stats().await
+                    }),
+                )
+                .route(
+                    DIAGNOSTICS_PATH,
+                    get(|State(mut server): State<Server>| async move {
+                        server.handle_get_diagnostics().await
+                    }),
+                )
+                .route(
+                    FILE_PEERS_PATH,
+                    get(|State(mut server): State<Server>, path| async move {
+                        server.handle_get_file_peers(path).await
+                    }),
+                )
+                .route(
+                    MY_FILES_PATH,
+                    get(|State(mut server): State<Server>| async move {
+                        server.handle_list_my_files().await
                     }),
                 )
                 .route(
-                    GET_BANDWIDTH_PATH,
-                    get(|State(mut server): State<Server>, query, ws| async move {
-                        server.handle_get_bandwidth(query, ws).await
+                    SYNC_PLAN_PATH,
+                    get(|State(mut server): State<Server>| async move {
+                        server.handle_get_sync_plan().await
                     }),
                 )
                 .route(
-                    ADD_PEERS_PATH,
-                    post(|State(mut server): State<Server>, req| async move {
-                        server.handle_add_peers(req).await
+                    SYNCS_PATH,
+                    get(|State(mut server): State<Server>| async move {
+                        server.handle_list_syncs().await
                     }),
                 )
                 .route(
-                    REMOVE_PEERS_PATH,
-                    post(|State(mut server): State<Server>, req| async move {
-                        server.handle_remove_peers(req).await
+                    VERIFY_FILE_PATH,
+                    post(|State(mut server): State<Server>, path| async move {
+                        server.handle_verify_file(path).await
                     }),
                 )
                 .route(
-                    GET_FILE_PATH,
-                    get(
-                        |State(mut server): State<Server>, path, request| async move {
-                            server.handle_get_file(request, path).await
-                        },
-                    ),
+                    RPC_PATH,
+                    post(|State(mut server): State<Server>, body| async move {
+                        server.handle_rpc(body).await
+                    }),
                 )
                 .route(
-                    LIST_TV_PATH,
-                    get(|State(mut server): State<Server>, query, ws| async move {
-                        server.handle_list_tv(query, ws).await
+                    FEED_PATH,
+                    get(|State(mut server): State<Server>, connect_info| async move {
+                        server.handle_feed(connect_info).await
                     }),
                 )
                 .route(
-                    PLAY_TV_PATH,
-                    post(
-                        |State(mut server): State<Server>, url_path, connect_info| async move {
-                            server.handle_play_video(url_path, connect_info).await
-                        },
-                    ),
+                    ACCESS_LOG_PATH,
+                    get(|State(server): State<Server>| async move {
+                        server.handle_get_access_log()
+                    }),
                 )
-                .layer(DefaultBodyLimit::disable());
+                .layer(CompressionLayer::new());
+
+        let api_router = Router::new()
+            .merge(compressed_router)
+            .merge(Router::new().route(
+                ADD_FILE_PATH,
+                post(|State(mut server): State<Server>, body| async move {
+                    server.handle_add_file(body).await
+                }),
+            ))
+            .route(
+                SHUTDOWN_PATH,
+                post(|State(server): State<Server>, headers| async move {
+                    server.handle_shutdown(headers)
+                }),
+            )
+            .route(
+                UPLOAD_FILE_PATH,
+                post(
+                    |State(mut server): State<Server>, query, headers, body| async move {
+                        server.handle_upload_file(query, headers, body).await
+                    },
+                ),
+            )
+            .route(
+                GET_BANDWIDTH_PATH,
+                get(
+                    |State(mut server): State<Server>, query, headers, ws| async move {
+                        server.handle_get_bandwidth(query, headers, ws).await
+                    },
+                ),
+            )
+            .route(
+                FILE_EVENTS_PATH,
+                get(|State(mut server): State<Server>, headers, ws| async move {
+                    server.handle_file_events(headers, ws).await
+                }),
+            )
+            .route(
+                ADD_PEERS_PATH,
+                post(|State(mut server): State<Server>, req| async move {
+                    server.handle_add_peers(req).await
+                }),
+            )
+            .route(
+                REMOVE_PEERS_PATH,
+                post(|State(mut server): State<Server>, req| async move {
+                    server.handle_remove_peers(req).await
+                }),
+            )
+            .route(
+                PING_PEER_PATH,
+                post(|State(mut server): State<Server>, req| async move {
+                    server.handle_ping_peer(req).await
+                }),
+            )
+            .route(
+                SYNC_FILE_PATH,
+                post(|State(mut server): State<Server>, path| async move {
+                    server.handle_sync_file(path).await
+                }),
+            )
+            .route(
+                MANIFEST_PATH,
+                get(|State(mut server): State<Server>| async move {
+                    server.handle_export_manifest().await
+                }),
+            )
+            .route(
+                IMPORT_MANIFEST_PATH,
+                post(|State(mut server): State<Server>, body| async move {
+                    server.handle_import_manifest(body).await
+                }),
+            )
+            .route(
+                REMOVE_FILES_PATH,
+                post(|State(mut server): State<Server>, req| async move {
+                    server.handle_remove_files(req).await
+                }),
+            )
+            .route(
+                GET_FILE_PATH,
+                get(
+                    |State(mut server): State<Server>, path, query, request| async move {
+                        server.handle_get_file(request, path, query).await
+                    },
+                ),
+            )
+            .route(
+                GET_INDEX_FILE_PATH,
+                get(
+                    |State(mut server): State<Server>, path, request| async move {
+                        server.handle_get_index_file(request, path).await
+                    },
+                ),
+            )
+            .route(
+                LIST_TV_PATH,
+                get(
+                    |State(mut server): State<Server>, query, headers, ws| async move {
+                        server.handle_list_tv(query, headers, ws).await
+                    },
+                ),
+            )
+            .route(
+                PLAY_TV_PATH,
+                post(
+                    |State(mut server): State<Server>, url_path, connect_info| async move {
+                        server.handle_play_video(url_path, connect_info).await
+                    },
+                ),
+            )
+            .layer(DefaultBodyLimit::disable());
+
+        #[cfg(feature = "thumbnail")]
+        let api_router = api_router.route(
+            THUMBNAIL_PATH,
+            get(|State(mut server): State<Server>, path, query| async move {
+                server.handle_thumbnail(path, query).await
+            }),
+        );
 
+        let api_prefix = self.api_prefix.clone();
         let router = Router::new()
-            .nest(API_PREFIX, api_router)
-            .nest(UI_PREFIX, StaticRouter::default().into())
-            .fallback(|| ready(Redirect::temporary("/ui")))
-            .with_state(self);
-
-        axum::Server::builder(incoming)
-            .serve(router.into_make_service_with_connect_info::<SocketAddrPeer>())
-            .await?;
+            .nest(&api_prefix, api_router)
+            .nest(WEBDAV_PREFIX, webdav::router());
+
+        // a headless node serves the api only
+        let router = match self.ui_prefix.clone() {
+            None => router.fallback(|| {
+                ready(ApiError::new(StatusCode::NOT_FOUND, "not found").into_response())
+            }),
+
+            Some(ui_prefix) => router
+                .nest(
+                    &ui_prefix,
+                    StaticRouter::new(self.canvaskit_url.clone(), self.ui_dir.clone()).into(),
+                )
+                .fallback(move || ready(Redirect::temporary(&ui_prefix).into_response())),
+        };
 
-        Ok(())
+        router
+            .layer(middleware::from_fn(request_id::request_id))
+            .with_state(self)
     }
 
     #[instrument(skip(self))]
     async fn handle_list_files(
         &mut self,
         Query(query): Query<ListFilesQuery>,
-    ) -> Result<Json<ListResponse>, StatusCode> {
-        self.list_files(query.include_peer.unwrap_or(true)).await
+    ) -> Result<Json<ListResponse>, ApiError> {
+        self.list_files(
+            query.include_peer.unwrap_or(true),
+            query.check_health.unwrap_or(false),
+        )
+        .await
     }
 
     #[instrument(skip(self))]
-    async fn list_files(&mut self, include_peer: bool) -> Result<Json<ListResponse>, StatusCode> {
-        let (sender, receiver) = oneshot::channel();
+    async fn list_files(
+        &mut self,
+        include_peer: bool,
+        check_health: bool,
+    ) -> Result<Json<ListResponse>, ApiError> {
+        let details = self.list_file_details(include_peer, check_health).await?;
 
-        if let Err(err) = self
-            .command_sender
-            .send(Command::ListFiles {
-                include_peer,
-                result_sender: sender,
+        info!(include_peer, check_health, ?details, "list files done");
+
+        let subtitle_filenames = details
+            .iter()
+            .filter(|detail| subtitle::is_subtitle(&detail.filename))
+            .map(|detail| detail.filename.clone())
+            .collect::<Vec<_>>();
+
+        let files = details
+            .into_iter()
+            .map(|detail| {
+                let subtitles = subtitle::find_subtitles(
+                    &detail.filename,
+                    subtitle_filenames.iter().map(String::as_str),
+                );
+
+                ListFile {
+                    filename: detail.filename,
+                    hash: detail.hash,
+                    downloaded: detail.downloaded,
+                    peers: detail
+                        .peers
+                        .into_iter()
+                        .map(|peer| peer.to_base58())
+                        .collect(),
+                    size: Byte::from_bytes(detail.size)
+                        .get_appropriate_unit(true)
+                        .to_string(),
+                    subtitles,
+                    healthy: detail.healthy,
+                    modified: detail.modified.map(|modified| {
+                        modified
+                            .duration_since(SystemTime::UNIX_EPOCH)
+                            .unwrap_or_default()
+                            .as_secs()
+                    }),
+                }
             })
-            .await
-        {
-            error!(%err, "send command failed");
+            .collect();
+        let list_response = ListResponse { files };
 
-            return Err(StatusCode::INTERNAL_SERVER_ERROR);
-        }
+        Ok(Json(list_response))
+    }
+
+    #[instrument(skip(self))]
+    async fn list_file_details(
+        &mut self,
+        include_peer: bool,
+        check_health: bool,
+    ) -> Result<Vec<ListFileDetail>, ApiError> {
+        let (sender, receiver) = oneshot::channel();
+
+        self.send_command(Command::ListFiles {
+            include_peer,
+            check_health,
+            result_sender: sender,
+        })
+        .await?;
 
-        info!(include_peer, "send list files command done");
+        info!(include_peer, check_health, "send list files command done");
 
         let details = match receiver.await {
             Err(err) => {
                 error!(%err, "receiver list files result failed");
 
-                return Err(StatusCode::INTERNAL_SERVER_ERROR);
+                return Err(ApiError::new(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    err.to_string(),
+                ));
             }
 
             Ok(Err(err)) => {
                 error!(%err, "list files failed");
 
-                return Err(StatusCode::INTERNAL_SERVER_ERROR);
+                return Err(ApiError::new(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    err.to_string(),
+                ));
             }
 
             Ok(Ok(details)) => details,
         };
 
-        info!(include_peer, ?details, "list files done");
-
-        let files = details
-            .into_iter()
-            .map(|detail| ListFile {
-                filename: detail.filename,
-                hash: detail.hash,
-                downloaded: detail.downloaded,
-                peers: detail
-                    .peers
-                    .into_iter()
-                    .map(|peer| peer.to_base58())
-                    .collect(),
-                size: Byte::from_bytes(detail.size)
-                    .get_appropriate_unit(true)
-                    .to_string(),
-            })
-            .collect();
-        let list_response = ListResponse { files };
-
-        Ok(Json(list_response))
+        Ok(details)
     }
 
     #[instrument(skip(self))]
-    async fn handle_add_file(
-        &mut self,
-        Json(req): Json<AddFileRequest>,
-    ) -> Result<(), (StatusCode, String)> {
+    async fn handle_add_file(&mut self, Json(req): Json<AddFileRequest>) -> Result<(), ApiError> {
         let (sender, receiver) = oneshot::channel();
 
         let file_path = req.file_path.clone();
 
-        if let Err(err) = self
-            .command_sender
-            .send(Command::AddFile {
-                file_path: req.file_path.into(),
-                result_sender: sender,
-            })
-            .await
-        {
-            error!(%err, "send add file command failed");
-
-            return Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string()));
-        }
+        self.send_command(Command::AddFile {
+            file_path: req.file_path.into(),
+            hash: req.hash,
+            result_sender: sender,
+        })
+        .await?;
 
         match receiver.await {
             Err(err) => {
                 error!(%err, "receive add file result failed");
 
-                Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))
+                Err(ApiError::new(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    err.to_string(),
+                ))
+            }
+
+            Ok(Err(err)) if err.kind() == ErrorKind::PermissionDenied => {
+                error!(%err, %file_path, "node is read only");
+
+                Err(ApiError::new(StatusCode::FORBIDDEN, err.to_string()))
             }
 
             Ok(Err(err)) if err.kind() == ErrorKind::NotFound => {
                 error!(%file_path, "file not exists");
 
-                Err((StatusCode::NOT_FOUND, String::new()))
+                Err(ApiError::new(
+                    StatusCode::NOT_FOUND,
+                    format!("file {file_path} not exists"),
+                ))
+            }
+
+            Ok(Err(err)) if err.kind() == ErrorKind::AlreadyExists => {
+                error!(%err, %file_path, "filename conflicts with the store file");
+
+                Err(ApiError::new(StatusCode::CONFLICT, err.to_string()))
+            }
+
+            Ok(Err(err)) if FileSyncingError::is_cause_of(&err) => {
+                error!(%err, %file_path, "store file is being synced");
+
+                Err(ApiError::new(StatusCode::CONFLICT, err.to_string()))
+            }
+
+            Ok(Err(err))
+                if matches!(err.kind(), ErrorKind::InvalidData | ErrorKind::InvalidInput) =>
+            {
+                error!(%err, %file_path, "invalid file path or hash mismatch");
+
+                Err(ApiError::new(StatusCode::BAD_REQUEST, err.to_string()))
             }
 
             Ok(Err(err)) => {
                 error!(%err, %file_path, "add file failed");
 
-                Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))
+                Err(ApiError::new(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    err.to_string(),
+                ))
             }
 
             Ok(Ok(_)) => {
@@ -268,16 +720,61 @@ impl Server {
         }
     }
 
-    #[instrument(skip(self))]
-    async fn handle_upload_file(&mut self, mut req: Multipart) -> Result<(), (StatusCode, String)> {
+    #[instrument(skip(self, headers))]
+    async fn handle_upload_file(
+        &mut self,
+        Query(upload_file_query): Query<UploadFileQuery>,
+        headers: HeaderMap,
+        mut req: Multipart,
+    ) -> Result<(), ApiError> {
+        if let Some(hash) = &upload_file_query.hash {
+            if hash.is_empty() || !hash.bytes().all(|b| b.is_ascii_alphanumeric()) {
+                error!(%hash, "invalid hash");
+
+                return Err(ApiError::new(
+                    StatusCode::BAD_REQUEST,
+                    format!("invalid hash {hash}"),
+                ));
+            }
+        }
+
+        let range = match headers.get(CONTENT_RANGE) {
+            None => None,
+            Some(content_range) => {
+                let range = content_range
+                    .to_str()
+                    .ok()
+                    .and_then(partial_file::parse_content_range);
+                if range.is_none() {
+                    error!(?content_range, "invalid content range");
+
+                    return Err(ApiError::new(
+                        StatusCode::BAD_REQUEST,
+                        "invalid content range",
+                    ));
+                }
+
+                if upload_file_query.hash.is_none() {
+                    error!("resumable upload has no hash");
+
+                    return Err(ApiError::new(
+                        StatusCode::BAD_REQUEST,
+                        "resumable upload requires the hash query",
+                    ));
+                }
+
+                range
+            }
+        };
+
         let field = match req.next_field().await {
             Err(err) => {
                 error!(%err, "get next field failed");
 
-                return Err((StatusCode::BAD_REQUEST, err.to_string()));
+                return Err(ApiError::new(StatusCode::BAD_REQUEST, err.to_string()));
             }
 
-            Ok(None) => return Err((StatusCode::BAD_REQUEST, "empty content".to_string())),
+            Ok(None) => return Err(ApiError::new(StatusCode::BAD_REQUEST, "empty content")),
 
             Ok(Some(field)) => field,
         };
@@ -286,12 +783,29 @@ impl Server {
             None => {
                 error!("no filename found");
 
-                return Err((StatusCode::BAD_REQUEST, "no filename found".to_string()));
+                return Err(ApiError::new(StatusCode::BAD_REQUEST, "no filename found"));
             }
 
             Some(filename) => filename.to_string(),
         };
 
+        let data = field.map_err(|err| io::Error::new(ErrorKind::Other, err));
+
+        self.upload_file(filename, upload_file_query.hash, range, data)
+            .await
+    }
+
+    /// send the upload file command and forward the file data to the node
+    async fn upload_file<S>(
+        &mut self,
+        filename: String,
+        hash: Option<String>,
+        range: Option<UploadRange>,
+        data: S,
+    ) -> Result<(), ApiError>
+    where
+        S: Stream<Item = io::Result<Bytes>>,
+    {
         let (result_sender, result_receiver) = oneshot::channel();
         let (file_sender, file_stream) = mpsc::channel(1);
         let mut file_sender = file_sender.sink_map_err(|err| {
@@ -299,28 +813,28 @@ impl Server {
 
             io::Error::new(ErrorKind::Other, err)
         });
+        let cancel = CancellationToken::new();
+
+        self.send_command(Command::UploadFile {
+            filename: filename.to_string(),
+            hash,
+            range,
+            file_stream,
+            cancel: cancel.clone(),
+            result_sender,
+        })
+        .await?;
 
-        if let Err(err) = self
-            .command_sender
-            .send(Command::UploadFile {
-                filename: filename.to_string(),
-                hash: None,
-                file_stream,
-                result_sender,
-            })
-            .await
-        {
-            error!(%err, %filename, "send upload file command failed");
-
-            return Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string()));
-        }
+        // declared after the sender, so the upload is canceled before the sender is dropped when
+        // the request is dropped
+        let _cancel_guard = cancel.drop_guard();
 
-        if let Err(err) = field
+        if let Err(err) = data
             .map_ok(Ok::<_, io::Error>)
             .map_err(|err| {
                 error!(%err, %filename, "read upload file data failed");
 
-                io::Error::new(ErrorKind::Other, err)
+                err
             })
             .forward(&mut file_sender)
             .await
@@ -331,7 +845,7 @@ impl Server {
                 .send(Err(io::Error::new(ErrorKind::Other, err)))
                 .await;
 
-            return Err((StatusCode::INTERNAL_SERVER_ERROR, err_msg));
+            return Err(ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, err_msg));
         }
 
         info!(%filename, "read all upload file data done");
@@ -340,13 +854,61 @@ impl Server {
             Err(err) => {
                 error!(%err, %filename, "receive result failed");
 
-                Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))
+                Err(ApiError::new(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    err.to_string(),
+                ))
+            }
+
+            Ok(Err(err)) if err.kind() == ErrorKind::PermissionDenied => {
+                error!(%err, %filename, "node is read only");
+
+                Err(ApiError::new(StatusCode::FORBIDDEN, err.to_string()))
+            }
+
+            Ok(Err(err)) if err.kind() == ErrorKind::AlreadyExists => {
+                error!(%err, %filename, "filename conflicts with the store file");
+
+                Err(ApiError::new(StatusCode::CONFLICT, err.to_string()))
+            }
+
+            Ok(Err(err)) if FileSyncingError::is_cause_of(&err) => {
+                error!(%err, %filename, "store file is being synced");
+
+                Err(ApiError::new(StatusCode::CONFLICT, err.to_string()))
+            }
+
+            Ok(Err(err)) if err.kind() == ErrorKind::InvalidInput => {
+                error!(%err, %filename, "upload range is not satisfiable");
+
+                Err(ApiError::new(
+                    StatusCode::RANGE_NOT_SATISFIABLE,
+                    err.to_string(),
+                ))
+            }
+
+            Ok(Err(err)) if err.kind() == ErrorKind::InvalidData => {
+                error!(%err, %filename, "upload file hash mismatch");
+
+                Err(ApiError::new(StatusCode::BAD_REQUEST, err.to_string()))
+            }
+
+            Ok(Err(err)) if err.kind() == ErrorKind::WouldBlock => {
+                error!(%err, %filename, "too many uploads");
+
+                Err(ApiError::new(
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    err.to_string(),
+                ))
             }
 
             Ok(Err(err)) => {
                 error!(%err, %filename, "handle upload file command failed");
 
-                Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))
+                Err(ApiError::new(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    err.to_string(),
+                ))
             }
 
             Ok(Ok(_)) => {
@@ -358,135 +920,1158 @@ impl Server {
     }
 
     #[instrument(skip(self))]
-    async fn handle_list_peers(&mut self) -> Result<Json<ListPeersResponse>, StatusCode> {
-        let (result_sender, result_receiver) = oneshot::channel();
+    async fn handle_get_file_availability(
+        &mut self,
+        Path(filename): Path<String>,
+    ) -> Result<Json<FileAvailabilityResponse>, ApiError> {
+        let availability = match self.get_file_availability(&filename).await? {
+            None => {
+                error!(%filename, "file not found");
 
-        if let Err(err) = self
-            .command_sender
-            .send(Command::ListPeers { result_sender })
-            .await
-        {
-            error!(%err, "send list peers command failed");
+                return Err(ApiError::new(
+                    StatusCode::NOT_FOUND,
+                    format!("file {filename} not exists"),
+                ));
+            }
 
-            return Err(StatusCode::INTERNAL_SERVER_ERROR);
-        }
+            Some(availability) => availability,
+        };
 
-        let peers = match result_receiver.await {
+        info!(%filename, ?availability, "get file availability done");
+
+        let available_size = availability
+            .ranges
+            .iter()
+            .map(|range| range.end - range.start)
+            .sum::<u64>();
+        let eta_secs = availability
+            .bytes_per_sec
+            .filter(|bytes_per_sec| *bytes_per_sec > 0)
+            .map(|bytes_per_sec| {
+                let remaining = availability.size.saturating_sub(available_size);
+
+                (remaining + bytes_per_sec - 1) / bytes_per_sec
+            });
+
+        Ok(Json(FileAvailabilityResponse {
+            filename,
+            hash: availability.hash,
+            size: availability.size,
+            available_size,
+            complete: available_size >= availability.size,
+            bytes_per_sec: availability.bytes_per_sec,
+            eta_secs,
+            ranges: availability
+                .ranges
+                .into_iter()
+                .map(|range| (range.start, range.end))
+                .collect(),
+        }))
+    }
+
+    /// get the available byte ranges of the stored or syncing file
+    async fn get_file_availability(
+        &mut self,
+        filename: &str,
+    ) -> Result<Option<FileAvailability>, ApiError> {
+        let (result_sender, result_receiver) = oneshot::channel();
+
+        self.send_command(Command::GetFileAvailability {
+            filename: filename.to_string(),
+            result_sender,
+        })
+        .await?;
+
+        match result_receiver.await {
             Err(err) => {
                 error!(%err, "receive result failed");
 
-                return Err(StatusCode::INTERNAL_SERVER_ERROR);
+                Err(ApiError::new(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    err.to_string(),
+                ))
             }
 
-            Ok(peers) => {
-                info!(?peers, "list peers done");
+            Ok(Err(err)) => {
+                error!(%err, %filename, "get file availability failed");
 
-                peers
-                    .into_iter()
-                    .map(|peer| ListPeer {
-                        peer: peer.0.to_string(),
-                        connected_addrs: peer.1.into_iter().map(|addr| addr.to_string()).collect(),
-                    })
-                    .collect()
+                Err(ApiError::new(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    err.to_string(),
+                ))
             }
-        };
 
-        Ok(Json(ListPeersResponse { peers }))
+            Ok(Ok(availability)) => Ok(availability),
+        }
     }
 
     #[instrument(skip(self))]
-    async fn handle_get_bandwidth(
-        &mut self,
-        Query(get_bandwidth_query): Query<GetBandwidthQuery>,
-        ws: WebSocketUpgrade,
-    ) -> Response<BoxBody> {
-        let interval = get_bandwidth_query
-            .interval
-            .map(|interval| Duration::from_millis(interval as _))
-            .unwrap_or_else(|| Duration::from_secs(1));
-        let mut this = self.clone();
-
-        ws.on_upgrade(move |mut websocket| async move {
-            let mut interval_stream = IntervalStream::new(time::interval(interval));
-
-            loop {
-                select! {
-                    _ = interval_stream.next() => {
-                        this.send_bandwidth(&mut websocket).await;
-                    }
+    async fn handle_get_diagnostics(&mut self) -> Result<Json<DiagnosticsResponse>, ApiError> {
+        let (result_sender, result_receiver) = oneshot::channel();
 
-                    message = websocket.recv() => {
-                        if let Some(true) = handle_websocket_in_message(&mut websocket, message).await {
-                            let _ = websocket
-                                .close()
-                                .await
-                                .tap_err(|err| error!(%err, "graceful close websocket failed"))
-                                .tap(|_| info!("graceful close websocket done"));
+        self.send_command(Command::GetInternalDiagnostics { result_sender })
+            .await?;
 
-                            return;
-                        }
-                    }
-                }
-            }
-        })
+        let diagnostics = result_receiver.await.map_err(|err| {
+            error!(%err, "receive result failed");
+
+            ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, err.to_string())
+        })?;
+
+        info!(?diagnostics, "get internal diagnostics done");
+
+        Ok(Json(DiagnosticsResponse {
+            peer_stores: diagnostics.peer_stores,
+            disconnected_peer_stores: diagnostics.disconnected_peer_stores,
+            peer_store_files: diagnostics.peer_store_files,
+            file_get_requests: diagnostics.file_get_requests,
+            connected_peers: diagnostics.connected_peers,
+            peer_addr_connecting: diagnostics.peer_addr_connecting,
+            pending_peer_addrs: diagnostics.pending_peer_addrs,
+            discover_peers: diagnostics.discover_peers,
+            syncing_files: diagnostics.syncing_files,
+            sync_failures: diagnostics.sync_failures,
+            adding_files: diagnostics.adding_files,
+            cached_files: diagnostics.cached_files,
+            max_cached_files: diagnostics.max_cached_files,
+            cached_mapped_bytes: diagnostics.cached_mapped_bytes,
+        }))
     }
 
-    async fn send_bandwidth(&mut self, websocket: &mut WebSocket) {
+    #[instrument(skip(self))]
+    async fn handle_get_stats(&mut self) -> Result<Json<StatsResponse>, ApiError> {
         let (result_sender, result_receiver) = oneshot::channel();
 
-        if let Err(err) = self
-            .command_sender
-            .send(Command::GetBandwidth { result_sender })
+        self.send_command(Command::GetStats { result_sender })
+            .await?;
+
+        let stats = match result_receiver.await {
+            Err(err) => {
+                error!(%err, "receive result failed");
+
+                return Err(ApiError::new(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    err.to_string(),
+                ));
+            }
+
+            Ok(Err(err)) => {
+                error!(%err, "get stats failed");
+
+                return Err(ApiError::new(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    err.to_string(),
+                ));
+            }
+
+            Ok(Ok(stats)) => stats,
+        };
+
+        info!(?stats, "get stats done");
+
+        Ok(Json(StatsResponse {
+            peers: stats.peers,
+            local_files: stats.local_files,
+            remote_files: stats.remote_files,
+            shared_bytes: stats.shared_bytes,
+            syncing_files: stats.syncing_files,
+            inbound: stats.inbound,
+            outbound: stats.outbound,
+            last_scrub_time: stats.last_scrub_time.map(|time| {
+                time.duration_since(SystemTime::UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_secs()
+            }),
+            scrub_corrupted_files: stats.scrub_corrupted_files,
+            scrub_errors: stats.scrub_errors,
+            connection_errors: ConnectionErrorsResponse {
+                dial: stats.connection_errors.dial,
+                timeout: stats.connection_errors.timeout,
+                handshake: stats.connection_errors.handshake,
+                key_mismatch: stats.connection_errors.key_mismatch,
+                pnet: stats.connection_errors.pnet,
+                other: stats.connection_errors.other,
+            },
+            disk_space: DiskSpaceResponse {
+                index_dir: stats.index_dir_space.map(to_disk_space),
+                store_dir: stats.store_dir_space.map(to_disk_space),
+                temp_dir: stats.temp_dir_space.map(to_disk_space),
+            },
+            gossip: GossipStatsResponse {
+                file_topic: to_gossip_topic_stats(stats.file_topic),
+                discover_topic: to_gossip_topic_stats(stats.discover_topic),
+                all_mesh_peers: stats.all_mesh_peers,
+            },
+            next_refresh_store: stats.next_refresh_store.as_secs(),
+            next_sync_file: stats.next_sync_file.as_secs(),
+            relay: stats.relay.map(|relay| RelayStatsResponse {
+                reservations: relay.reservations,
+                circuits: relay.circuits,
+            }),
+            sync_counters: SyncCountersResponse {
+                synced_files: stats.sync_counters.synced_files,
+                failed_files: stats.sync_counters.failed_files,
+                downloaded_bytes: stats.sync_counters.downloaded_bytes,
+                served_bytes: stats.sync_counters.served_bytes,
+            },
+        }))
+    }
+
+    /// the json-rpc 2.0 endpoint, the batch request is supported, the methods are dispatched to
+    /// the same handlers of the rest routes
+    #[instrument(skip(self, body))]
+    async fn handle_rpc(&mut self, body: Bytes) -> Response<BoxBody> {
+        let value = match serde_json::from_slice::<serde_json::Value>(&body) {
+            Err(err) => {
+                error!(%err, "parse rpc request failed");
+
+                let err = RpcError::new(rpc::PARSE_ERROR, err.to_string());
+
+                return Json(RpcResponse::new(serde_json::Value::Null, Err(err))).into_response();
+            }
+
+            Ok(value) => value,
+        };
+
+        match value {
+            serde_json::Value::Array(values) if values.is_empty() => {
+                let err = RpcError::new(rpc::INVALID_REQUEST, "empty batch");
+
+                Json(RpcResponse::new(serde_json::Value::Null, Err(err))).into_response()
+            }
+
+            serde_json::Value::Array(values) => {
+                let mut responses = Vec::with_capacity(values.len());
+                for value in values {
+                    responses.extend(self.call_rpc(value).await);
+                }
+
+                // all requests are notifications
+                if responses.is_empty() {
+                    return StatusCode::NO_CONTENT.into_response();
+                }
+
+                Json(responses).into_response()
+            }
+
+            value => match self.call_rpc(value).await {
+                None => StatusCode::NO_CONTENT.into_response(),
+                Some(response) => Json(response).into_response(),
+            },
+        }
+    }
+
+    /// call the rpc method, return `None` when the request is a notification
+    async fn call_rpc(&mut self, value: serde_json::Value) -> Option<RpcResponse> {
+        let request = match RpcRequest::parse(value) {
+            Err(err) => {
+                error!(?err, "invalid rpc request");
+
+                return Some(RpcResponse::new(serde_json::Value::Null, Err(err)));
+            }
+
+            Ok(request) => request,
+        };
+
+        info!(method = %request.method, "call rpc method");
+
+        let result = match request.method.as_str() {
+            "list_files" => match request.params::<ListFilesQuery>() {
+                Err(err) => Err(err),
+                Ok(query) => self
+                    .list_files(
+                        query.include_peer.unwrap_or(true),
+                        query.check_health.unwrap_or(false),
+                    )
+                    .await
+                    .map_err(RpcError::from)
+                    .and_then(|Json(resp)| rpc::to_result(resp)),
+            },
+
+            "add_file" => match request.params() {
+                Err(err) => Err(err),
+                Ok(req) => self
+                    .handle_add_file(Json(req))
+                    .await
+                    .map_err(RpcError::from)
+                    .and_then(rpc::to_result),
+            },
+
+            "list_peers" => self
+                .handle_list_peers()
+                .await
+                .map_err(RpcError::from)
+                .and_then(|Json(resp)| rpc::to_result(resp)),
+
+            "add_peers" => match request.params() {
+                Err(err) => Err(err),
+                Ok(req) => self
+                    .handle_add_peers(Json(req))
+                    .await
+                    .map_err(RpcError::from)
+                    .and_then(rpc::to_result),
+            },
+
+            "remove_peers" => match request.params() {
+                Err(err) => Err(err),
+                Ok(req) => self
+                    .handle_remove_peers(Json(req))
+                    .await
+                    .map_err(RpcError::from)
+                    .and_then(rpc::to_result),
+            },
+
+            "ping_peer" => match request.params() {
+                Err(err) => Err(err),
+                Ok(req) => self
+                    .handle_ping_peer(Json(req))
+                    .await
+                    .map_err(RpcError::from)
+                    .and_then(|Json(resp)| rpc::to_result(resp)),
+            },
+
+            "get_file_availability" => match request.params::<FileAvailabilityParams>() {
+                Err(err) => Err(err),
+                Ok(params) => self
+                    .handle_get_file_availability(Path(params.filename))
+                    .await
+                    .map_err(RpcError::from)
+                    .and_then(|Json(resp)| rpc::to_result(resp)),
+            },
+
+            "get_stats" => self
+                .handle_get_stats()
+                .await
+                .map_err(RpcError::from)
+                .and_then(|Json(resp)| rpc::to_result(resp)),
+
+            "get_diagnostics" => self
+                .handle_get_diagnostics()
+                .await
+                .map_err(RpcError::from)
+                .and_then(|Json(resp)| rpc::to_result(resp)),
+
+            "list_syncs" => self
+                .handle_list_syncs()
+                .await
+                .map_err(RpcError::from)
+                .and_then(|Json(resp)| rpc::to_result(resp)),
+
+            "get_file_peers" => match request.params::<FileAvailabilityParams>() {
+                Err(err) => Err(err),
+                Ok(params) => self
+                    .handle_get_file_peers(Path(params.filename))
+                    .await
+                    .map_err(RpcError::from)
+                    .and_then(|Json(resp)| rpc::to_result(resp)),
+            },
+
+            method => Err(RpcError::new(
+                rpc::METHOD_NOT_FOUND,
+                format!("method {method} not found"),
+            )),
+        };
+
+        request.id.map(|id| RpcResponse::new(id, result))
+    }
+
+    /// check whether the peer is reachable before adding it, the peer isn't added to the config
+    #[instrument(skip(self))]
+    async fn handle_ping_peer(
+        &mut self,
+        Json(req): Json<PingPeerRequest>,
+    ) -> Result<Json<PingPeerResponse>, ApiError> {
+        let target = match PeerId::from_str(&req.peer) {
+            Ok(peer) => PingTarget::Peer(peer),
+            Err(_) => match Multiaddr::try_from(req.peer.as_str()) {
+                Err(err) => {
+                    error!(%err, ?req, "parse peer to peer id or multi addr failed");
+
+                    return Err(ApiError::new(
+                        StatusCode::BAD_REQUEST,
+                        format!("peer {} is neither a peer id nor a multi addr", req.peer),
+                    ));
+                }
+
+                Ok(addr) => PingTarget::Addr(addr),
+            },
+        };
+
+        let (result_sender, result_receiver) = oneshot::channel();
+
+        self.send_command(Command::PingPeer {
+            target,
+            result_sender,
+        })
+        .await?;
+
+        match result_receiver.await {
+            Err(err) => {
+                error!(%err, "receive result failed");
+
+                Err(ApiError::new(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    err.to_string(),
+                ))
+            }
+
+            Ok(Err(err)) if err.kind() == ErrorKind::InvalidInput => {
+                error!(%err, "invalid ping peer");
+
+                Err(ApiError::new(StatusCode::BAD_REQUEST, err.to_string()))
+            }
+
+            Ok(Err(err)) if err.kind() == ErrorKind::NotFound => {
+                error!(%err, "peer addr not found");
+
+                Err(ApiError::new(StatusCode::NOT_FOUND, err.to_string()))
+            }
+
+            Ok(Err(err)) => {
+                error!(%err, "ping peer failed");
+
+                Err(ApiError::new(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    err.to_string(),
+                ))
+            }
+
+            Ok(Ok(detail)) => {
+                info!(?detail, "ping peer done");
+
+                let (rtt_ms, error) = match detail.result {
+                    Ok(rtt) => (Some(rtt.as_secs_f64() * 1000.0), None),
+                    Err(err) => (None, Some(err)),
+                };
+
+                Ok(Json(PingPeerResponse {
+                    peer_id: detail.peer.to_string(),
+                    connected: detail.connected,
+                    reachable: rtt_ms.is_some(),
+                    rtt_ms,
+                    error,
+                }))
+            }
+        }
+    }
+
+    /// get the peers advertising the file, it helps to find out why a file isn't synced
+    #[instrument(skip(self))]
+    async fn handle_get_file_peers(
+        &mut self,
+        Path(filename): Path<String>,
+    ) -> Result<Json<FilePeersResponse>, ApiError> {
+        let (result_sender, result_receiver) = oneshot::channel();
+
+        self.send_command(Command::GetFilePeers {
+            filename: filename.clone(),
+            result_sender,
+        })
+        .await?;
+
+        let peers = result_receiver.await.map_err(|err| {
+            error!(%err, "receive result failed");
+
+            ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, err.to_string())
+        })?;
+
+        if peers.is_empty() {
+            error!(%filename, "no peer has the file");
+
+            return Err(ApiError::new(
+                StatusCode::NOT_FOUND,
+                format!("no peer has the file {filename}"),
+            ));
+        }
+
+        info!(%filename, ?peers, "get file peers done");
+
+        Ok(Json(FilePeersResponse {
+            filename,
+            peers: peers
+                .into_iter()
+                .map(|peer| FilePeer {
+                    peer: peer.peer.to_string(),
+                    hash: peer.hash,
+                    connected: peer.connected,
+                })
+                .collect(),
+        }))
+    }
+
+    #[instrument(skip(self))]
+    async fn handle_get_sync_plan(&mut self) -> Result<Json<SyncPlanResponse>, ApiError> {
+        let (result_sender, result_receiver) = oneshot::channel();
+
+        self.send_command(Command::GetSyncPlan { result_sender })
+            .await?;
+
+        let files = match result_receiver.await {
+            Err(err) => {
+                error!(%err, "receive result failed");
+
+                return Err(ApiError::new(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    err.to_string(),
+                ));
+            }
+
+            Ok(Err(err)) => {
+                error!(%err, "get sync plan failed");
+
+                return Err(ApiError::new(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    err.to_string(),
+                ));
+            }
+
+            Ok(Ok(files)) => files,
+        };
+
+        info!(?files, "get sync plan done");
+
+        Ok(Json(SyncPlanResponse {
+            total_size: files.iter().map(|file| file.size).sum(),
+            files: files
+                .into_iter()
+                .map(|file| SyncPlanFile {
+                    hash: file.hash,
+                    filenames: file.filenames,
+                    size: file.size,
+                    peers: file.peers.iter().map(ToString::to_string).collect(),
+                    syncing: file.syncing,
+                    failed_attempts: file.failed_attempts,
+                    last_error: file.last_error,
+                })
+                .collect(),
+        }))
+    }
+
+    #[instrument(skip(self))]
+    async fn handle_list_syncs(&mut self) -> Result<Json<SyncsResponse>, ApiError> {
+        let (result_sender, result_receiver) = oneshot::channel();
+
+        self.send_command(Command::ListSyncs { result_sender })
+            .await?;
+
+        let files = match result_receiver.await {
+            Err(err) => {
+                error!(%err, "receive result failed");
+
+                return Err(ApiError::new(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    err.to_string(),
+                ));
+            }
+
+            Ok(Err(err)) => {
+                error!(%err, "list syncs failed");
+
+                return Err(ApiError::new(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    err.to_string(),
+                ));
+            }
+
+            Ok(Ok(files)) => files,
+        };
+
+        info!(?files, "list syncs done");
+
+        Ok(Json(SyncsResponse {
+            files: files
+                .into_iter()
+                .map(|file| SyncEntry {
+                    hash: file.hash,
+                    filenames: file.filenames,
+                    size: file.size,
+                    synced: file.synced,
+                    bytes_per_sec: file.bytes_per_sec,
+                    peers: file.peers.iter().map(ToString::to_string).collect(),
+                    state: match file.state {
+                        command::SyncState::Active => SyncState::Active,
+                        command::SyncState::Retrying => SyncState::Retrying,
+                        command::SyncState::Queued => SyncState::Queued,
+                        command::SyncState::Failed => SyncState::Failed,
+                    },
+                    failed_attempts: file.failed_attempts,
+                    last_error: file.last_error,
+                    retry_in: file.retry_in.map(|retry_in| retry_in.as_secs()),
+                    sources: file
+                        .sources
+                        .into_iter()
+                        .map(|source| DownloadSource {
+                            peer_id: source.peer.to_string(),
+                            bytes: source.bytes,
+                        })
+                        .collect(),
+                })
+                .collect(),
+        }))
+    }
+
+    #[instrument(skip(self))]
+    async fn handle_list_my_files(&mut self) -> Result<Json<MyFilesResponse>, ApiError> {
+        let (result_sender, result_receiver) = oneshot::channel();
+
+        self.send_command(Command::ListMyFiles { result_sender })
+            .await?;
+
+        let files = match result_receiver.await {
+            Err(err) => {
+                error!(%err, "receive result failed");
+
+                return Err(ApiError::new(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    err.to_string(),
+                ));
+            }
+
+            Ok(Err(err)) => {
+                error!(%err, "list my files failed");
+
+                return Err(ApiError::new(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    err.to_string(),
+                ));
+            }
+
+            Ok(Ok(files)) => files,
+        };
+
+        info!(?files, "list my files done");
+
+        Ok(Json(MyFilesResponse {
+            files: files
+                .into_iter()
+                .map(|file| MyFile {
+                    filename: file.filename,
+                    hash: file.hash,
+                    size: file.size,
+                    index_path: file.index_path.to_string_lossy().into_owned(),
+                    excluded: file.excluded,
+                    verify_status: match file.verify_status {
+                        command::VerifyStatus::Unverified => VerifyStatus::Unverified,
+                        command::VerifyStatus::Verified => VerifyStatus::Verified,
+                        command::VerifyStatus::Corrupted => VerifyStatus::Corrupted,
+                    },
+                })
+                .collect(),
+        }))
+    }
+
+    #[instrument(skip(self))]
+    async fn handle_verify_file(
+        &mut self,
+        Path(filename): Path<String>,
+    ) -> Result<Json<VerifyFileResponse>, ApiError> {
+        let (result_sender, result_receiver) = oneshot::channel();
+
+        self.send_command(Command::VerifyFile {
+            filename: filename.clone(),
+            result_sender,
+        })
+        .await?;
+
+        match result_receiver.await {
+            Err(err) => {
+                error!(%err, "receive result failed");
+
+                Err(ApiError::new(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    err.to_string(),
+                ))
+            }
+
+            Ok(Err(err)) => {
+                error!(%err, %filename, "verify file failed");
+
+                Err(ApiError::new(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    err.to_string(),
+                ))
+            }
+
+            Ok(Ok(None)) => {
+                error!(%filename, "file not found");
+
+                Err(ApiError::new(
+                    StatusCode::NOT_FOUND,
+                    format!("file {filename} not exists"),
+                ))
+            }
+
+            Ok(Ok(Some(detail))) => {
+                info!(%filename, ?detail, "verify file done");
+
+                Ok(Json(VerifyFileResponse {
+                    filename,
+                    matched: detail.expected_hash == detail.computed_hash,
+                    expected_hash: detail.expected_hash,
+                    computed_hash: detail.computed_hash,
+                }))
+            }
+        }
+    }
+
+    /// start syncing the remote file now instead of waiting for the next sync
+    #[instrument(skip(self))]
+    async fn handle_sync_file(
+        &mut self,
+        Path(filename): Path<String>,
+    ) -> Result<Json<SyncFileResponse>, ApiError> {
+        let (result_sender, result_receiver) = oneshot::channel();
+
+        self.send_command(Command::SyncFile {
+            filename: filename.clone(),
+            result_sender,
+        })
+        .await?;
+
+        match result_receiver.await {
+            Err(err) => {
+                error!(%err, "receive result failed");
+
+                Err(ApiError::new(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    err.to_string(),
+                ))
+            }
+
+            Ok(Err(err)) if err.kind() == ErrorKind::NotFound => {
+                error!(%err, %filename, "no peer serves the file");
+
+                Err(ApiError::new(StatusCode::NOT_FOUND, err.to_string()))
+            }
+
+            Ok(Err(err)) => {
+                error!(%err, %filename, "sync file failed");
+
+                Err(ApiError::new(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    err.to_string(),
+                ))
+            }
+
+            Ok(Ok(detail)) => {
+                info!(%filename, ?detail, "start sync file done");
+
+                Ok(Json(SyncFileResponse {
+                    filename,
+                    hash: detail.hash,
+                    already_syncing: detail.already_syncing,
+                }))
+            }
+        }
+    }
+
+    /// the signed protobuf manifest of the local files, it is imported by the other node
+    #[instrument(skip(self))]
+    async fn handle_export_manifest(&mut self) -> Result<Response<BoxBody>, ApiError> {
+        let (result_sender, result_receiver) = oneshot::channel();
+
+        self.send_command(Command::ExportManifest { result_sender })
+            .await?;
+
+        match result_receiver.await {
+            Err(err) => {
+                error!(%err, "receive result failed");
+
+                Err(ApiError::new(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    err.to_string(),
+                ))
+            }
+
+            Ok(Err(err)) => {
+                error!(%err, "export manifest failed");
+
+                Err(ApiError::new(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    err.to_string(),
+                ))
+            }
+
+            Ok(Ok(manifest)) => {
+                info!(size = manifest.len(), "export manifest done");
+
+                Ok(([(CONTENT_TYPE, MANIFEST_CONTENT_TYPE)], manifest).into_response())
+            }
+        }
+    }
+
+    /// accept the manifest exported by the other node, its files are synced without waiting for
+    /// its file message
+    #[instrument(skip(self, manifest))]
+    async fn handle_import_manifest(
+        &mut self,
+        manifest: Bytes,
+    ) -> Result<Json<ImportManifestResponse>, ApiError> {
+        let (result_sender, result_receiver) = oneshot::channel();
+
+        self.send_command(Command::ImportManifest {
+            manifest,
+            result_sender,
+        })
+        .await?;
+
+        match result_receiver.await {
+            Err(err) => {
+                error!(%err, "receive result failed");
+
+                Err(ApiError::new(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    err.to_string(),
+                ))
+            }
+
+            Ok(Err(err))
+                if matches!(err.kind(), ErrorKind::InvalidData | ErrorKind::InvalidInput) =>
+            {
+                error!(%err, "invalid manifest");
+
+                Err(ApiError::new(StatusCode::BAD_REQUEST, err.to_string()))
+            }
+
+            Ok(Err(err)) => {
+                error!(%err, "import manifest failed");
+
+                Err(ApiError::new(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    err.to_string(),
+                ))
+            }
+
+            Ok(Ok(detail)) => {
+                info!(?detail, "import manifest done");
+
+                Ok(Json(ImportManifestResponse {
+                    peer_id: detail.peer.to_string(),
+                    files: detail.files,
+                    outdated: detail.outdated,
+                }))
+            }
+        }
+    }
+
+    #[instrument(skip(self))]
+    async fn handle_list_peers(&mut self) -> Result<Json<ListPeersResponse>, ApiError> {
+        let (result_sender, result_receiver) = oneshot::channel();
+
+        self.send_command(Command::ListPeers { result_sender })
+            .await?;
+
+        let peers = match result_receiver.await {
+            Err(err) => {
+                error!(%err, "receive result failed");
+
+                return Err(ApiError::new(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    err.to_string(),
+                ));
+            }
+
+            Ok(peers) => {
+                info!(?peers, "list peers done");
+
+                peers
+            }
+        };
+
+        Ok(Json(ListPeersResponse {
+            peers: peers
+                .connected
+                .into_iter()
+                .map(|peer| ListPeer {
+                    peer: peer.peer.to_string(),
+                    connected_addrs: peer
+                        .connected_addrs
+                        .into_iter()
+                        .map(|addr| addr.to_string())
+                        .collect(),
+                    connections: peer.connections,
+                    connected_since: peer
+                        .connected_since
+                        .duration_since(SystemTime::UNIX_EPOCH)
+                        .unwrap_or_default()
+                        .as_secs(),
+                    uptime_secs: peer.connected_since.elapsed().unwrap_or_default().as_secs(),
+                    protocol_version: peer
+                        .identity
+                        .as_ref()
+                        .map(|identity| identity.protocol_version.clone()),
+                    agent_version: peer
+                        .identity
+                        .as_ref()
+                        .map(|identity| identity.agent_version.clone()),
+                    protocols: peer
+                        .identity
+                        .map(|identity| identity.protocols)
+                        .unwrap_or_default(),
+                })
+                .collect(),
+            connecting: peers
+                .connecting
+                .into_iter()
+                .map(|peer| ConnectingPeer {
+                    peer: peer.peer.to_string(),
+                    addr: peer.addr.to_string(),
+                })
+                .collect(),
+            idle_addrs: peers
+                .configured
+                .iter()
+                .filter(|peer| peer.status == command::PeerAddrStatus::Unreachable)
+                .map(|peer| peer.addr.clone())
+                .collect(),
+            configured: peers
+                .configured
+                .into_iter()
+                .map(|peer| ConfiguredPeer {
+                    addr: peer.addr,
+                    peer: peer.peer.map(|peer| peer.to_string()),
+                    status: match peer.status {
+                        command::PeerAddrStatus::Connected => PeerAddrStatus::Connected,
+                        command::PeerAddrStatus::Connecting => PeerAddrStatus::Connecting,
+                        command::PeerAddrStatus::Unreachable => PeerAddrStatus::Unreachable,
+                    },
+                })
+                .collect(),
+            discovered: peers
+                .discovered
+                .into_iter()
+                .map(|peer| DiscoveredPeer {
+                    peer: peer.peer.to_string(),
+                    addrs: peer.addrs.iter().map(ToString::to_string).collect(),
+                })
+                .collect(),
+        }))
+    }
+
+    #[instrument(skip(self))]
+    async fn handle_feed(
+        &mut self,
+        ConnectInfo(addr_peer): ConnectInfo<SocketAddrPeer>,
+    ) -> Result<Response<BoxBody>, ApiError> {
+        // the host header is set by the client, so the links use the configured public url or
+        // the local address and the scheme of the listener which accepts the feed request
+        let base_url = match &self.public_url {
+            Some(public_url) => public_url.to_string(),
+
+            None => {
+                let local = addr_peer.local.ok_or_else(|| {
+                    error!("can't get local address");
+
+                    ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, "can't get local address")
+                })?;
+
+                format!("{}://{local}", if addr_peer.tls { "https" } else { "http" })
+            }
+        };
+
+        let (result_sender, result_receiver) = oneshot::channel();
+
+        self.send_command(Command::GetLocalPeerId { result_sender })
+            .await?;
+
+        let peer_id = result_receiver.await.map_err(|err| {
+            error!(%err, "receive result failed");
+
+            ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, err.to_string())
+        })?;
+
+        // only the files stored in local can be got
+        let details = self.list_file_details(false, false).await?;
+
+        let feed = feed::render_feed(
+            &peer_id,
+            &base_url,
+            &format!("{}{FEED_PATH}", self.api_prefix),
+            &format!(
+                "{}{}",
+                self.api_prefix,
+                GET_FILE_PATH.trim_end_matches("/:filename")
+            ),
+            &details,
+        );
+
+        info!(%peer_id, files = details.len(), "render feed done");
+
+        Ok(([(CONTENT_TYPE, "application/rss+xml; charset=utf-8")], feed).into_response())
+    }
+
+    #[instrument(skip(self))]
+    async fn handle_get_bandwidth(
+        &mut self,
+        Query(get_bandwidth_query): Query<GetBandwidthQuery>,
+        headers: HeaderMap,
+        ws: WebSocketUpgrade,
+    ) -> Response<BoxBody> {
+        if let Err(err) = check_websocket_protocol(&headers) {
+            return err.into_response();
+        }
+
+        let interval = get_bandwidth_query
+            .interval
+            .map(|interval| Duration::from_millis(interval as _))
+            .unwrap_or_else(|| Duration::from_secs(1));
+        let mut this = self.clone();
+
+        ws.protocols([WEBSOCKET_PROTOCOL])
+            .on_upgrade(move |mut websocket| async move {
+            let mut interval_stream = IntervalStream::new(time::interval(interval));
+
+            loop {
+                select! {
+                    _ = interval_stream.next() => {
+                        this.send_bandwidth(&mut websocket).await;
+                    }
+
+                    message = websocket.recv() => {
+                        if let Some(true) = handle_websocket_in_message(&mut websocket, message).await {
+                            let _ = websocket
+                                .close()
+                                .await
+                                .tap_err(|err| error!(%err, "graceful close websocket failed"))
+                                .tap(|_| info!("graceful close websocket done"));
+
+                            return;
+                        }
+                    }
+                }
+            }
+        })
+    }
+
+    async fn send_bandwidth(&mut self, websocket: &mut WebSocket) {
+        let (result_sender, result_receiver) = oneshot::channel();
+
+        if let Err(err) = self
+            .send_command(Command::GetBandwidth { result_sender })
             .await
         {
-            error!(%err, "send get bandwidth command failed");
-
-            websocket_close_with_err(websocket, err).await;
+            websocket_close_with_err(websocket, err.message()).await;
 
             return;
         }
 
         info!("send get bandwidth command done");
 
-        let (inbound, outbound) = match result_receiver.await {
+        let (inbound, outbound) = match result_receiver.await {
+            Err(err) => {
+                error!(%err, "receive result failed");
+
+                websocket_close_with_err(websocket, err).await;
+
+                return;
+            }
+
+            Ok(bandwidth) => bandwidth,
+        };
+
+        info!(inbound, outbound, "get bandwidth done");
+
+        let response = GetBandWidthResponse { inbound, outbound };
+        let response = match serde_json::to_string(&response) {
+            Err(err) => {
+                error!(%err, ?response, "marshal response failed");
+
+                websocket_close_with_err(websocket, err).await;
+
+                return;
+            }
+
+            Ok(resp) => resp,
+        };
+
+        info!(%response, "marshal response done");
+
+        if let Err(err) = websocket.send(Message::Text(response)).await {
+            error!(%err, inbound, outbound, "send bandwidth failed");
+        }
+    }
+
+    #[instrument(skip(self))]
+    async fn handle_file_events(
+        &mut self,
+        headers: HeaderMap,
+        ws: WebSocketUpgrade,
+    ) -> Response<BoxBody> {
+        if let Err(err) = check_websocket_protocol(&headers) {
+            return err.into_response();
+        }
+
+        let (result_sender, result_receiver) = oneshot::channel();
+
+        if let Err(err) = self
+            .send_command(Command::SubscribeFileEvents { result_sender })
+            .await
+        {
+            return err.into_response();
+        }
+
+        info!("send subscribe file events command done");
+
+        let mut events = match result_receiver.await {
             Err(err) => {
                 error!(%err, "receive result failed");
 
-                websocket_close_with_err(websocket, err).await;
-
-                return;
+                return ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, err.to_string())
+                    .into_response();
             }
 
-            Ok(bandwidth) => bandwidth,
+            Ok(events) => events,
         };
 
-        info!(inbound, outbound, "get bandwidth done");
+        ws.protocols([WEBSOCKET_PROTOCOL])
+            .on_upgrade(move |mut websocket| async move {
+                loop {
+                    select! {
+                        event = events.recv() => {
+                            let event = match event {
+                                Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                                    warn!(skipped, "file events subscriber lagged");
 
-        let response = GetBandWidthResponse { inbound, outbound };
-        let response = match serde_json::to_string(&response) {
-            Err(err) => {
-                error!(%err, ?response, "marshal response failed");
+                                    continue;
+                                }
 
-                websocket_close_with_err(websocket, err).await;
+                                Err(broadcast::error::RecvError::Closed) => {
+                                    info!("file events are closed");
 
-                return;
-            }
+                                    let _ = websocket
+                                        .close()
+                                        .await
+                                        .tap_err(|err| error!(%err, "graceful close websocket failed"));
 
-            Ok(resp) => resp,
-        };
+                                    return;
+                                }
 
-        info!(%response, "marshal response done");
+                                Ok(event) => event,
+                            };
 
-        if let Err(err) = websocket.send(Message::Text(response)).await {
-            error!(%err, inbound, outbound, "send bandwidth failed");
-        }
+                            if !send_file_event(&mut websocket, event).await {
+                                return;
+                            }
+                        }
+
+                        message = websocket.recv() => {
+                            if let Some(true) = handle_websocket_in_message(&mut websocket, message).await {
+                                let _ = websocket
+                                    .close()
+                                    .await
+                                    .tap_err(|err| error!(%err, "graceful close websocket failed"))
+                                    .tap(|_| info!("graceful close websocket done"));
+
+                                return;
+                            }
+                        }
+                    }
+                }
+            })
     }
 
     #[instrument(skip(self))]
-    async fn handle_add_peers(
-        &mut self,
-        Json(req): Json<AddPeersRequest>,
-    ) -> Result<(), (StatusCode, String)> {
+    async fn handle_add_peers(&mut self, Json(req): Json<AddPeersRequest>) -> Result<(), ApiError> {
         let peers = match req
             .peers
             .iter()
@@ -496,7 +2081,7 @@ impl Server {
             Err(err) => {
                 error!(%err, ?req, "parse peers to multi addr failed");
 
-                return Err((StatusCode::BAD_REQUEST, err.to_string()));
+                return Err(ApiError::new(StatusCode::BAD_REQUEST, err.to_string()));
             }
 
             Ok(peers) => peers,
@@ -506,30 +2091,46 @@ impl Server {
 
         let (result_sender, result_receiver) = oneshot::channel();
 
-        if let Err(err) = self
-            .command_sender
-            .send(Command::AddPeers {
-                peers,
-                result_sender,
-            })
-            .await
-        {
-            error!(%err, "send add peers command failed");
-
-            return Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string()));
-        }
+        self.send_command(Command::AddPeers {
+            peers,
+            result_sender,
+        })
+        .await?;
 
         match result_receiver.await {
             Err(err) => {
                 error!(%err, "receive result failed");
 
-                Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))
+                Err(ApiError::new(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    err.to_string(),
+                ))
+            }
+
+            Ok(Err(err))
+                if matches!(
+                    err.kind(),
+                    ErrorKind::Unsupported | ErrorKind::PermissionDenied
+                ) =>
+            {
+                error!(%err, "config or node is read-only");
+
+                Err(ApiError::new(StatusCode::FORBIDDEN, err.to_string()))
+            }
+
+            Ok(Err(err)) if err.kind() == ErrorKind::InvalidInput => {
+                error!(%err, "too many peers");
+
+                Err(ApiError::new(StatusCode::BAD_REQUEST, err.to_string()))
             }
 
             Ok(Err(err)) => {
                 error!(%err, "add peers failed");
 
-                Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))
+                Err(ApiError::new(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    err.to_string(),
+                ))
             }
 
             Ok(Ok(_)) => Ok(()),
@@ -540,7 +2141,7 @@ impl Server {
     async fn handle_remove_peers(
         &mut self,
         Json(req): Json<RemovePeersRequest>,
-    ) -> Result<(), (StatusCode, String)> {
+    ) -> Result<(), ApiError> {
         let peers = match req
             .peers
             .iter()
@@ -550,112 +2151,727 @@ impl Server {
             Err(err) => {
                 error!(%err, ?req, "parse peers to multi addr failed");
 
-                return Err((StatusCode::BAD_REQUEST, err.to_string()));
+                return Err(ApiError::new(StatusCode::BAD_REQUEST, err.to_string()));
+            }
+
+            Ok(peers) => peers,
+        };
+
+        info!(?peers, "parse peers done");
+
+        let (result_sender, result_receiver) = oneshot::channel();
+
+        self.send_command(Command::RemovePeers {
+            peers,
+            result_sender,
+        })
+        .await?;
+
+        match result_receiver.await {
+            Err(err) => {
+                error!(%err, "receive result failed");
+
+                Err(ApiError::new(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    err.to_string(),
+                ))
+            }
+
+            Ok(Err(err))
+                if matches!(
+                    err.kind(),
+                    ErrorKind::Unsupported | ErrorKind::PermissionDenied
+                ) =>
+            {
+                error!(%err, "config or node is read-only");
+
+                Err(ApiError::new(StatusCode::FORBIDDEN, err.to_string()))
+            }
+
+            Ok(Err(err)) => {
+                error!(%err, "remove peers failed");
+
+                Err(ApiError::new(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    err.to_string(),
+                ))
+            }
+
+            Ok(Ok(_)) => Ok(()),
+        }
+    }
+
+    #[instrument(skip(self))]
+    async fn handle_remove_files(
+        &mut self,
+        Json(req): Json<RemoveFilesRequest>,
+    ) -> Result<Json<RemoveFilesResponse>, ApiError> {
+        if req.filenames.is_empty() && req.pattern.is_none() {
+            error!("no filenames or pattern");
+
+            return Err(ApiError::new(
+                StatusCode::BAD_REQUEST,
+                "filenames or pattern is required",
+            ));
+        }
+
+        let (result_sender, result_receiver) = oneshot::channel();
+
+        self.send_command(Command::RemoveFiles {
+            filenames: req.filenames,
+            pattern: req.pattern,
+            result_sender,
+        })
+        .await?;
+
+        match result_receiver.await {
+            Err(err) => {
+                error!(%err, "receive result failed");
+
+                Err(ApiError::new(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    err.to_string(),
+                ))
+            }
+
+            Ok(Err(err)) if err.kind() == ErrorKind::PermissionDenied => {
+                error!(%err, "node is read only");
+
+                Err(ApiError::new(StatusCode::FORBIDDEN, err.to_string()))
+            }
+
+            Ok(Err(err)) => {
+                error!(%err, "remove files failed");
+
+                Err(ApiError::new(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    err.to_string(),
+                ))
+            }
+
+            Ok(Ok(detail)) => {
+                info!(?detail, "remove files done");
+
+                Ok(Json(RemoveFilesResponse {
+                    files: detail
+                        .files
+                        .into_iter()
+                        .map(|(filename, result)| RemovedFile {
+                            filename,
+                            removed: result.is_ok(),
+                            error: result.err().map(|err| err.to_string()),
+                        })
+                        .collect(),
+                    removed_index_files: detail.removed_index_files,
+                }))
+            }
+        }
+    }
+
+    /// get the file detail from the node, return not found error if the file doesn't exist
+    async fn get_file_detail(
+        &mut self,
+        filename: &str,
+    ) -> Result<GetFileDetail<<FileGetter as command::FileGetter>::FileContent>, ApiError> {
+        let (result_sender, result_receiver) = oneshot::channel();
+
+        self.request_file_detail(
+            Command::GetFile {
+                filename: filename.to_string(),
+                file_getter: FileGetter::default(),
+                result_sender,
+            },
+            result_receiver,
+            filename,
+        )
+        .await
+    }
+
+    /// get the index file detail from the node by hash, return not found error if the index file
+    /// doesn't exist
+    async fn get_index_file_detail(
+        &mut self,
+        hash: &str,
+    ) -> Result<GetFileDetail<<FileGetter as command::FileGetter>::FileContent>, ApiError> {
+        let (result_sender, result_receiver) = oneshot::channel();
+
+        self.request_file_detail(
+            Command::GetIndexFile {
+                hash: hash.to_string(),
+                file_getter: FileGetter::default(),
+                result_sender,
+            },
+            result_receiver,
+            hash,
+        )
+        .await
+    }
+
+    async fn request_file_detail(
+        &mut self,
+        command: Command<UploadFileReceiver, FileGetter>,
+        result_receiver: oneshot::Receiver<
+            io::Result<Option<GetFileDetail<<FileGetter as command::FileGetter>::FileContent>>>,
+        >,
+        filename: &str,
+    ) -> Result<GetFileDetail<<FileGetter as command::FileGetter>::FileContent>, ApiError> {
+        self.send_command(command).await?;
+
+        match result_receiver.await {
+            Err(err) => {
+                error!(%err, "receive result failed");
+
+                Err(ApiError::new(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    err.to_string(),
+                ))
+            }
+
+            Ok(Err(err)) if err.kind() == ErrorKind::InvalidInput => {
+                error!(%err, "get file failed");
+
+                Err(ApiError::new(StatusCode::BAD_REQUEST, err.to_string()))
+            }
+
+            Ok(Err(err)) => {
+                error!(%err, "get file failed");
+
+                Err(ApiError::new(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    err.to_string(),
+                ))
+            }
+
+            Ok(Ok(None)) => {
+                error!(%filename, "file not found");
+
+                Err(ApiError::new(
+                    StatusCode::NOT_FOUND,
+                    format!("file {filename} not exists"),
+                ))
+            }
+
+            Ok(Ok(Some(file_detail))) => Ok(file_detail),
+        }
+    }
+
+    #[instrument(skip(self))]
+    async fn handle_get_file(
+        &mut self,
+        request: Request<body::Body>,
+        Path(filename): Path<String>,
+        Query(get_file_query): Query<GetFileQuery>,
+    ) -> Result<Response<ResponseBody>, ApiError> {
+        let timeout = get_file_timeout(request.headers(), get_file_query.timeout.as_deref())?;
+        let download = get_file_query.download.unwrap_or(false);
+
+        let timeout = match timeout {
+            None => return self.get_file(request, &filename, download).await,
+            Some(timeout) => timeout,
+        };
+
+        // the content may be fetched from a stuck peer, don't hold the client forever
+        match time::timeout(timeout, self.get_file(request, &filename, download)).await {
+            Err(_) => {
+                warn!(%filename, ?timeout, "get file timeout");
+
+                Err(ApiError::new(
+                    StatusCode::GATEWAY_TIMEOUT,
+                    format!("file {filename} can't be served in {timeout:?}"),
+                ))
+            }
+
+            Ok(result) => result,
+        }
+    }
+
+    async fn get_file(
+        &mut self,
+        request: Request<body::Body>,
+        filename: &str,
+        download: bool,
+    ) -> Result<Response<ResponseBody>, ApiError> {
+        let file_detail = match self.get_file_detail(filename).await {
+            Err(err) if err.status() == StatusCode::NOT_FOUND => {
+                // the file may be still syncing, serve the downloaded ranges
+                if let Some(response) = self.get_partial_file(&request, filename).await? {
+                    return Ok(response);
+                }
+
+                // browser only supports vtt subtitle, convert the srt sidecar on the fly
+                return match subtitle::srt_filename_of_vtt(filename) {
+                    None => Err(err),
+                    Some(srt_filename) => self.get_srt_as_vtt(&srt_filename).await,
+                };
+            }
+
+            result => result?,
+        };
+
+        info!(%filename, hash = %file_detail.hash, "get file done");
+
+        self.record_access(&request, Some(filename), &file_detail.hash);
+
+        let mut response = serve_file_detail(request, file_detail).await?;
+
+        if response.status().is_success() {
+            if subtitle::is_vtt(filename) {
+                response.headers_mut().insert(
+                    CONTENT_TYPE,
+                    HeaderValue::from_static(subtitle::VTT_CONTENT_TYPE),
+                );
+            }
+
+            if download {
+                response
+                    .headers_mut()
+                    .insert(CONTENT_DISPOSITION, attachment_disposition(filename));
+            }
+        }
+
+        Ok(response)
+    }
+
+    /// serve the index file by hash without linking it into the store dir, so reading a file
+    /// never changes what is shared
+    #[instrument(skip(self))]
+    async fn handle_get_index_file(
+        &mut self,
+        request: Request<body::Body>,
+        Path(hash): Path<String>,
+    ) -> Result<Response<ResponseBody>, ApiError> {
+        let file_detail = self.get_index_file_detail(&hash).await?;
+
+        info!(%hash, index_path = ?file_detail.index_path, "get index file done");
+
+        self.record_access(&request, None, &hash);
+
+        serve_file_detail(request, file_detail).await
+    }
+
+    fn record_access(&self, request: &Request<body::Body>, filename: Option<&str>, hash: &str) {
+        if let Some(access_log) = &self.access_log {
+            access_log.record(AccessEntry {
+                time: SystemTime::now(),
+                remote: request
+                    .extensions()
+                    .get::<ConnectInfo<SocketAddrPeer>>()
+                    .and_then(|ConnectInfo(addr_peer)| addr_peer.remote),
+                filename: filename.map(ToString::to_string),
+                hash: hash.to_string(),
+                range: request
+                    .headers()
+                    .get(RANGE)
+                    .and_then(|range| range.to_str().ok())
+                    .map(ToString::to_string),
+            });
+        }
+    }
+
+    /// notify the node to shut down, the response is sent before the node exits
+    #[instrument(skip(self, headers))]
+    fn handle_shutdown(&self, headers: HeaderMap) -> Result<(), ApiError> {
+        // avoid the accidental remote shutdown of the node without the api token
+        let api_token = self.api_token.as_deref().ok_or_else(|| {
+            warn!("api token isn't configured, reject shutdown");
+
+            ApiError::new(
+                StatusCode::FORBIDDEN,
+                "shutdown is disabled without the api token",
+            )
+        })?;
+
+        let token = headers
+            .get(AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "));
+        match token {
+            // compare the digests, so the comparison time doesn't leak the token
+            Some(token) if Sha256::digest(token) == Sha256::digest(api_token) => {}
+
+            _ => {
+                warn!("invalid api token, reject shutdown");
+
+                return Err(ApiError::new(StatusCode::UNAUTHORIZED, "invalid api token"));
+            }
+        }
+
+        info!("shutdown is requested by api");
+
+        self.shutdown.notify_one();
+
+        Ok(())
+    }
+
+    #[instrument(skip(self))]
+    fn handle_get_access_log(&self) -> Result<Json<AccessLogResponse>, ApiError> {
+        let access_log = self
+            .access_log
+            .as_ref()
+            .ok_or_else(|| ApiError::new(StatusCode::NOT_FOUND, "access log is disabled"))?;
+
+        Ok(Json(AccessLogResponse {
+            entries: access_log
+                .entries()
+                .into_iter()
+                .map(|entry| AccessLogEntry {
+                    time: entry
+                        .time
+                        .duration_since(SystemTime::UNIX_EPOCH)
+                        .unwrap_or_default()
+                        .as_secs(),
+                    remote: entry.remote.map(|remote| remote.to_string()),
+                    filename: entry.filename,
+                    hash: entry.hash,
+                    range: entry.range,
+                })
+                .collect(),
+        }))
+    }
+
+    /// serve the downloaded ranges of the syncing file, the request waits a while when the
+    /// requested range is not downloaded yet, a range straddling the downloaded and missing data
+    /// only gets the downloaded part, the request without range streams the whole file, return
+    /// `None` when the file is unknown
+    #[instrument(skip(self, request))]
+    async fn get_partial_file(
+        &mut self,
+        request: &Request<body::Body>,
+        filename: &str,
+    ) -> Result<Option<Response<ResponseBody>>, ApiError> {
+        let start_time = Instant::now();
+        let mut fetched = false;
+
+        loop {
+            let availability = match self.get_file_availability(filename).await? {
+                None => return Ok(None),
+                Some(availability) => availability,
+            };
+
+            let range = match request.headers().get(RANGE) {
+                None => {
+                    info!(%filename, "partial file is requested without range, stream it");
+
+                    return self.stream_partial_file(filename, &availability).map(Some);
+                }
+
+                Some(range) => range
+                    .to_str()
+                    .ok()
+                    .and_then(|range| partial_file::parse_range(range, availability.size)),
+            };
+            let range = match range {
+                None => {
+                    error!(%filename, size = availability.size, "invalid range");
+
+                    let mut response =
+                        Response::new(Empty::new().map_err(|err| match err {}).boxed_unsync());
+                    *response.status_mut() = StatusCode::RANGE_NOT_SATISFIABLE;
+                    response.headers_mut().insert(
+                        CONTENT_RANGE,
+                        content_range_header(&format!("bytes */{}", availability.size))?,
+                    );
+
+                    return Ok(Some(response));
+                }
+
+                Some(range) => range,
+            };
+
+            if let Some(end) = partial_file::available_end(&availability.ranges, range.start) {
+                let range = range.start..range.end.min(end).min(range.start + MAX_PARTIAL_SIZE);
+
+                return self
+                    .partial_file_response(filename, &availability, range)
+                    .await
+                    .map(Some);
+            }
+
+            // fetch the range from the peers at once, don't wait for the sync
+            if !fetched {
+                fetched = true;
+
+                let range = range.start..range.end.min(range.start + MAX_PARTIAL_SIZE);
+                self.fetch_range(filename, range, PARTIAL_FILE_WAIT_TIMEOUT)
+                    .await?;
+
+                continue;
+            }
+
+            if start_time.elapsed() >= PARTIAL_FILE_WAIT_TIMEOUT {
+                warn!(%filename, ?range, "range is not downloaded yet");
+
+                return Err(ApiError::new(
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    "the range is not downloaded yet",
+                ));
             }
 
-            Ok(peers) => peers,
-        };
+            time::sleep(PARTIAL_FILE_POLL_INTERVAL).await;
+        }
+    }
+
+    /// stream the whole syncing file with the known size, so the clients show the progress, the
+    /// missing chunks are fetched from the peers on demand when the stream reaches them
+    fn stream_partial_file(
+        &self,
+        filename: &str,
+        availability: &FileAvailability,
+    ) -> Result<Response<ResponseBody>, ApiError> {
+        let size = availability.size;
+        let etag = HeaderValue::try_from(format!("\"{}\"", availability.hash)).map_err(|err| {
+            error!(%err, hash = %availability.hash, "create etag failed");
+
+            ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, err.to_string())
+        })?;
+        let content_type = mime_guess::from_path(filename).first_or_octet_stream();
+
+        let chunks = stream::try_unfold(
+            (
+                self.clone(),
+                filename.to_string(),
+                availability.hash.clone(),
+                0,
+            ),
+            move |(mut server, filename, hash, offset)| async move {
+                if offset >= size {
+                    return Ok(None);
+                }
+
+                let data = server
+                    .read_partial_chunk(&filename, &hash, offset)
+                    .await
+                    .map_err(|err| io::Error::new(ErrorKind::Other, err.message().to_string()))?;
+                let offset = offset + data.len() as u64;
+
+                Ok::<_, io::Error>(Some((data, (server, filename, hash, offset))))
+            },
+        );
+
+        info!(%filename, hash = %availability.hash, size, "stream partial file");
+
+        let mut response = Response::new(
+            StreamBody::new(chunks)
+                .map_err(|err| io::Error::new(ErrorKind::Other, err))
+                .boxed_unsync(),
+        );
+        let headers = response.headers_mut();
+        headers.insert(CONTENT_LENGTH, HeaderValue::from(size));
+        headers.insert(ACCEPT_RANGES, HeaderValue::from_static("bytes"));
+        headers.insert(ETAG, etag);
+        if let Ok(content_type) = HeaderValue::try_from(content_type.as_ref()) {
+            headers.insert(CONTENT_TYPE, content_type);
+        }
+
+        Ok(response)
+    }
+
+    /// read the data from `offset` of the streamed file, the missing chunks are fetched and
+    /// waited like the range request, the stream fails when the file is removed or changed
+    async fn read_partial_chunk(
+        &mut self,
+        filename: &str,
+        hash: &str,
+        offset: u64,
+    ) -> Result<Bytes, ApiError> {
+        let start_time = Instant::now();
+        let mut fetched = false;
+
+        loop {
+            let availability = self
+                .get_file_availability(filename)
+                .await?
+                .filter(|availability| availability.hash == hash)
+                .ok_or_else(|| {
+                    warn!(%filename, %hash, "streamed file is removed or changed");
+
+                    ApiError::new(StatusCode::NOT_FOUND, "the file is removed or changed")
+                })?;
+            let end = availability.size.min(offset + MAX_PARTIAL_SIZE);
+
+            match partial_file::available_end(&availability.ranges, offset) {
+                Some(available_end) => {
+                    let range = offset..end.min(available_end);
+                    match partial_file::read_range(&availability.path, range.clone()).await {
+                        // the file is just synced and moved, read it again from the new path
+                        Err(err) if err.kind() == ErrorKind::NotFound => {
+                            info!(%filename, path = ?availability.path, "partial file is moved");
+                        }
+
+                        Err(err) => {
+                            error!(%err, %filename, path = ?availability.path, "read partial file failed");
+
+                            return Err(ApiError::new(
+                                StatusCode::INTERNAL_SERVER_ERROR,
+                                err.to_string(),
+                            ));
+                        }
+
+                        Ok(data) if data.is_empty() => {
+                            warn!(%filename, ?range, "partial file is shorter than the availability");
+                        }
 
-        info!(?peers, "parse peers done");
+                        Ok(data) => {
+                            info!(%filename, ?range, "read streamed chunk done");
 
-        let (result_sender, result_receiver) = oneshot::channel();
+                            return Ok(data);
+                        }
+                    }
+                }
 
-        if let Err(err) = self
-            .command_sender
-            .send(Command::RemovePeers {
-                peers,
-                result_sender,
-            })
-            .await
-        {
-            error!(%err, "send remove peers command failed");
+                // fetch the range from the peers at once, don't wait for the sync
+                None if !fetched => {
+                    fetched = true;
 
-            return Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string()));
-        }
+                    self.fetch_range(filename, offset..end, PARTIAL_FILE_WAIT_TIMEOUT)
+                        .await?;
 
-        match result_receiver.await {
-            Err(err) => {
-                error!(%err, "receive result failed");
+                    continue;
+                }
 
-                Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))
+                None => {}
             }
 
-            Ok(Err(err)) => {
-                error!(%err, "remove peers failed");
+            if start_time.elapsed() >= PARTIAL_FILE_WAIT_TIMEOUT {
+                warn!(%filename, offset, "streamed chunk is not downloaded yet");
 
-                Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))
+                return Err(ApiError::new(
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    "the range is not downloaded yet",
+                ));
             }
 
-            Ok(Ok(_)) => Ok(()),
+            time::sleep(PARTIAL_FILE_POLL_INTERVAL).await;
         }
     }
 
-    #[instrument(skip(self))]
-    async fn handle_get_file(
+    /// ask the node to fetch the range from the peers and wait it at most `timeout`, the fetch
+    /// failure is only logged, the caller checks the availability again
+    async fn fetch_range(
         &mut self,
-        request: Request<body::Body>,
-        Path(filename): Path<String>,
-    ) -> Result<Response<ResponseBody>, (StatusCode, String)> {
+        filename: &str,
+        range: Range<u64>,
+        timeout: Duration,
+    ) -> Result<(), ApiError> {
         let (result_sender, result_receiver) = oneshot::channel();
 
-        if let Err(err) = self
-            .command_sender
-            .send(Command::GetFile {
-                filename: filename.clone(),
-                file_getter: FileGetter::default(),
-                result_sender,
-            })
-            .await
-        {
-            error!(%err, "send get file command failed");
+        self.send_command(Command::FetchRange {
+            filename: filename.to_string(),
+            range: range.clone(),
+            result_sender,
+        })
+        .await?;
 
-            return Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string()));
+        match time::timeout(timeout, result_receiver).await {
+            Err(_) => warn!(%filename, ?range, "fetch range timeout"),
+            Ok(Err(err)) => error!(%err, %filename, ?range, "receive fetch range result failed"),
+            Ok(Ok(Err(err))) => error!(%err, %filename, ?range, "fetch range failed"),
+            Ok(Ok(Ok(_))) => info!(%filename, ?range, "fetch range done"),
         }
 
-        let mut file_content = match result_receiver.await {
-            Err(err) => {
-                error!(%err, "receive result failed");
+        Ok(())
+    }
 
-                return Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string()));
+    async fn partial_file_response(
+        &mut self,
+        filename: &str,
+        availability: &FileAvailability,
+        range: Range<u64>,
+    ) -> Result<Response<ResponseBody>, ApiError> {
+        let data = match partial_file::read_range(&availability.path, range.clone()).await {
+            // the file is done and moved to the index dir, let the client retry
+            Err(err) if err.kind() == ErrorKind::NotFound => {
+                info!(%filename, path = ?availability.path, "partial file is moved");
+
+                return Err(ApiError::new(
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    "file is just synced, retry it",
+                ));
             }
 
-            Ok(Err(err)) => {
-                error!(%err, "get file failed");
+            Err(err) => {
+                error!(%err, %filename, path = ?availability.path, "read partial file failed");
 
-                return Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string()));
+                return Err(ApiError::new(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    err.to_string(),
+                ));
             }
 
-            Ok(Ok(None)) => {
-                error!(%filename, "file not found");
+            Ok(data) => data,
+        };
 
-                return Err((StatusCode::NOT_FOUND, String::new()));
-            }
+        info!(%filename, ?range, size = availability.size, "read partial file done");
+
+        let content_range = content_range_header(&format!(
+            "bytes {}-{}/{}",
+            range.start,
+            range.start + data.len() as u64 - 1,
+            availability.size
+        ))?;
+        let content_type = mime_guess::from_path(filename).first_or_octet_stream();
+
+        let mut response =
+            Response::new(Full::from(data).map_err(|err| match err {}).boxed_unsync());
+        *response.status_mut() = StatusCode::PARTIAL_CONTENT;
+        let headers = response.headers_mut();
+        headers.insert(CONTENT_RANGE, content_range);
+        headers.insert(ACCEPT_RANGES, HeaderValue::from_static("bytes"));
+        if let Ok(content_type) = HeaderValue::try_from(content_type.as_ref()) {
+            headers.insert(CONTENT_TYPE, content_type);
+        }
 
-            Ok(Ok(Some(file_content))) => file_content,
-        };
+        Ok(response)
+    }
 
-        info!(%filename, "get file done");
+    #[instrument(skip(self))]
+    async fn get_srt_as_vtt(
+        &mut self,
+        srt_filename: &str,
+    ) -> Result<Response<ResponseBody>, ApiError> {
+        let file_detail = self.get_file_detail(srt_filename).await?;
+        let srt = fs::read(&file_detail.index_path).await.map_err(|err| {
+            error!(%err, index_path = ?file_detail.index_path, "read srt subtitle failed");
 
-        let request = Request::from_parts(request.into_parts().0, ());
+            ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, err.to_string())
+        })?;
 
-        file_content.call(request).await.map_err(|err| {
-            error!(%err, "send file content failed");
+        let vtt = subtitle::srt_to_vtt(&String::from_utf8_lossy(&srt));
 
-            (StatusCode::INTERNAL_SERVER_ERROR, err.to_string())
-        })
+        info!(%srt_filename, "convert srt subtitle to vtt done");
+
+        let mut response =
+            Response::new(Full::from(vtt).map_err(|err| match err {}).boxed_unsync());
+        response.headers_mut().insert(
+            CONTENT_TYPE,
+            HeaderValue::from_static(subtitle::VTT_CONTENT_TYPE),
+        );
+
+        Ok(response)
     }
 
     #[instrument(skip(self))]
     async fn handle_list_tv(
         &mut self,
         Query(list_tv_query): Query<ListTVQuery>,
+        headers: HeaderMap,
         ws: WebSocketUpgrade,
     ) -> Response<BoxBody> {
+        if let Err(err) = check_websocket_protocol(&headers) {
+            return err.into_response();
+        }
+
         let mut timeout = Duration::from_millis(list_tv_query.timeout.unwrap_or(10000) as _);
+        // the relist keeps the filter of the query
+        let filter = TvFilter {
+            name_pattern: list_tv_query.name,
+            device_type: list_tv_query.device_type,
+            max_count: list_tv_query.max_count,
+        };
 
-        ws.on_upgrade(move |mut websocket| async move {
+        ws.protocols([WEBSOCKET_PROTOCOL]).on_upgrade(move |mut websocket| async move {
             loop {
-                let tv_stream = match dlna::list_tv(timeout).await {
+                let tv_stream = match dlna::list_tv(timeout, filter.clone()).await {
                     Err(err) => {
                         error!(%err, "list tv failed");
 
@@ -775,24 +2991,33 @@ impl Server {
         })
     }
 
+    /// the url path which the tv uses to get the file, it must respect the api prefix
+    fn get_file_url_path(&self, filename: &str) -> String {
+        format!(
+            "{}{}",
+            self.api_prefix,
+            GET_FILE_PATH.replace(":filename", filename)
+        )
+    }
+
     #[instrument(skip(self))]
     async fn handle_play_video(
         &mut self,
         Path((encoded_tv_url, filename)): Path<(String, String)>,
         ConnectInfo(addr_peer): ConnectInfo<SocketAddrPeer>,
-    ) -> Result<(), (StatusCode, String)> {
+    ) -> Result<(), ApiError> {
         let tv_url = match BASE64_STANDARD.decode(&encoded_tv_url) {
             Err(err) => {
                 error!(%err, %encoded_tv_url, "parse b64 encoded tv url failed");
 
-                return Err((StatusCode::BAD_REQUEST, err.to_string()));
+                return Err(ApiError::new(StatusCode::BAD_REQUEST, err.to_string()));
             }
 
             Ok(tv_url) => match String::from_utf8(tv_url) {
                 Err(err) => {
                     error!(%err, %encoded_tv_url, "encoded tv url is not valid utf8 string");
 
-                    return Err((StatusCode::BAD_REQUEST, err.to_string()));
+                    return Err(ApiError::new(StatusCode::BAD_REQUEST, err.to_string()));
                 }
 
                 Ok(tv_url) => tv_url,
@@ -803,7 +3028,7 @@ impl Server {
             Err(err) => {
                 error!(%err, %tv_url, "parse tv url failed");
 
-                return Err((StatusCode::BAD_REQUEST, err.to_string()));
+                return Err(ApiError::new(StatusCode::BAD_REQUEST, err.to_string()));
             }
 
             Ok(tv_url) => tv_url,
@@ -815,13 +3040,16 @@ impl Server {
             Err(err) => {
                 error!(%err, %tv_url, "get tv from url failed");
 
-                return Err((StatusCode::BAD_REQUEST, err.to_string()));
+                return Err(ApiError::new(StatusCode::BAD_REQUEST, err.to_string()));
             }
 
             Ok(None) => {
                 error!(%tv_url, "tv not exists");
 
-                return Err((StatusCode::NOT_FOUND, format!("tv {tv_url} not exists")));
+                return Err(ApiError::new(
+                    StatusCode::NOT_FOUND,
+                    format!("tv {tv_url} not exists"),
+                ));
             }
 
             Ok(Some(tv)) => tv,
@@ -829,28 +3057,67 @@ impl Server {
 
         info!(?tv, %tv_url, "get tv from url done");
 
-        let get_file_url_path = format!(
-            "{API_PREFIX}/{}",
-            GET_FILE_PATH.replace(":filename", &filename)
-        );
+        let get_file_url_path = self.get_file_url_path(&filename);
+
+        // subtitle is optional, play the video without subtitle if we can't get it
+        let subtitle_url_path = match self.list_file_details(false, false).await {
+            Err(err) => {
+                warn!(?err, %filename, "list files for subtitle failed");
+
+                None
+            }
+
+            Ok(details) => {
+                let subtitles = subtitle::find_subtitles(
+                    &filename,
+                    details.iter().map(|detail| detail.filename.as_str()),
+                );
+
+                // most tv only support srt subtitle
+                subtitles
+                    .iter()
+                    .find(|subtitle| !subtitle::is_vtt(subtitle))
+                    .or_else(|| subtitles.first())
+                    .map(|subtitle| self.get_file_url_path(subtitle))
+            }
+        };
 
         let port = addr_peer
             .local
             .ok_or_else(|| {
                 error!("can't get local tcp port");
 
-                (
+                ApiError::new(
                     StatusCode::INTERNAL_SERVER_ERROR,
-                    "can't get local tcp port".to_string(),
+                    "can't get local tcp port",
                 )
             })?
             .port();
 
-        match tv.play(port, &get_file_url_path).await {
+        match tv
+            .play(
+                addr_peer.tls,
+                port,
+                &get_file_url_path,
+                subtitle_url_path.as_deref(),
+                self.dlna_advertise_host.as_deref(),
+            )
+            .await
+        {
+            // the renderer may go offline or change after it is listed
+            Err(err) if err.is::<RendererError>() => {
+                error!(%err, port, %get_file_url_path, "renderer failed to play video");
+
+                Err(ApiError::new(StatusCode::BAD_GATEWAY, err.to_string()))
+            }
+
             Err(err) => {
                 error!(%err, port, %get_file_url_path, "play video failed");
 
-                Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))
+                Err(ApiError::new(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    err.to_string(),
+                ))
             }
 
             Ok(_) => {
@@ -862,6 +3129,226 @@ impl Server {
     }
 }
 
+fn to_disk_space(disk_space: command::DiskSpace) -> DiskSpace {
+    DiskSpace {
+        free: disk_space.free,
+        total: disk_space.total,
+    }
+}
+
+fn to_gossip_topic_stats(stats: command::GossipTopicStats) -> GossipTopicStats {
+    GossipTopicStats {
+        mesh_peers: stats.mesh_peers,
+        subscribed_peers: stats.subscribed_peers,
+    }
+}
+
+/// normalize the route prefix to the `/a/b` form, the root prefix is not allowed because the api
+/// and ui routers are nested under it
+fn normalize_prefix(prefix: &str) -> io::Result<String> {
+    let prefix = prefix.trim_matches('/');
+    if prefix.is_empty() {
+        error!("route prefix can't be empty or root");
+
+        return Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            "route prefix can't be empty or root",
+        ));
+    }
+
+    Ok(format!("/{prefix}"))
+}
+
+/// check the `If-None-Match` header value contains the etag, weak comparison is used as RFC 7232
+/// required
+fn content_range_header(content_range: &str) -> Result<HeaderValue, ApiError> {
+    HeaderValue::try_from(content_range).map_err(|err| {
+        error!(%err, %content_range, "create content range header failed");
+
+        ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, err.to_string())
+    })
+}
+
+/// serve the file content, the hash is used as the etag, so the unmodified file isn't sent again,
+/// the index file modified time is the last modified time, the in memory content doesn't have it
+async fn serve_file_detail(
+    request: Request<body::Body>,
+    file_detail: GetFileDetail<<FileGetter as command::FileGetter>::FileContent>,
+) -> Result<Response<ResponseBody>, ApiError> {
+    let etag = HeaderValue::try_from(format!("\"{}\"", file_detail.hash)).map_err(|err| {
+        error!(%err, hash = %file_detail.hash, "create etag failed");
+
+        ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, err.to_string())
+    })?;
+    let last_modified = file_detail
+        .modified
+        .and_then(|modified| HeaderValue::try_from(httpdate::fmt_http_date(modified)).ok());
+
+    if request
+        .headers()
+        .get(IF_NONE_MATCH)
+        .map(|if_none_match| etag_matches(if_none_match, &etag))
+        .unwrap_or(false)
+    {
+        info!(hash = %file_detail.hash, ?etag, "file not modified");
+
+        let mut response = Response::new(Empty::new().map_err(|err| match err {}).boxed_unsync());
+        *response.status_mut() = StatusCode::NOT_MODIFIED;
+        response.headers_mut().insert(ETAG, etag);
+        if let Some(last_modified) = last_modified {
+            response.headers_mut().insert(LAST_MODIFIED, last_modified);
+        }
+
+        return Ok(response);
+    }
+
+    let request = Request::from_parts(request.into_parts().0, ());
+    let mut file_content = file_detail.content;
+
+    let mut response = file_content.call(request).await.map_err(|err| {
+        error!(%err, "send file content failed");
+
+        ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, err.to_string())
+    })?;
+
+    if response.status().is_success() {
+        response.headers_mut().insert(ETAG, etag);
+        if let Some(last_modified) = last_modified {
+            response.headers_mut().insert(LAST_MODIFIED, last_modified);
+        }
+    }
+
+    Ok(response)
+}
+
+fn etag_matches(if_none_match: &HeaderValue, etag: &HeaderValue) -> bool {
+    let if_none_match = match if_none_match.to_str() {
+        Err(_) => return false,
+        Ok(if_none_match) => if_none_match,
+    };
+    let etag = match etag.to_str() {
+        Err(_) => return false,
+        Ok(etag) => etag.trim_start_matches("W/"),
+    };
+
+    if_none_match.trim() == "*"
+        || if_none_match
+            .split(',')
+            .map(|tag| tag.trim().trim_start_matches("W/"))
+            .any(|tag| tag == etag)
+}
+
+/// the max time of producing the get file content, the query takes precedence over the
+/// `x-request-timeout` header, no timeout when neither is set
+fn get_file_timeout(
+    headers: &HeaderMap,
+    query_timeout: Option<&str>,
+) -> Result<Option<Duration>, ApiError> {
+    let timeout = match query_timeout {
+        Some(timeout) => timeout,
+        None => match headers.get(&X_REQUEST_TIMEOUT) {
+            None => return Ok(None),
+            Some(timeout) => timeout.to_str().map_err(|err| {
+                ApiError::new(StatusCode::BAD_REQUEST, format!("invalid timeout: {err}"))
+            })?,
+        },
+    };
+
+    match humantime::parse_duration(timeout) {
+        Err(err) => Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            format!("invalid timeout {timeout:?}: {err}"),
+        )),
+
+        Ok(timeout) if timeout.is_zero() => Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "timeout can't be zero",
+        )),
+
+        Ok(timeout) => Ok(Some(timeout)),
+    }
+}
+
+/// create the `Content-Disposition: attachment` header value, non-ASCII filename is encoded by
+/// RFC 5987 `filename*`, and an ASCII fallback `filename` is kept for old clients
+fn attachment_disposition(filename: &str) -> HeaderValue {
+    let fallback_filename = filename
+        .chars()
+        .map(|c| {
+            if c.is_ascii() && !c.is_ascii_control() && c != '"' && c != '\\' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect::<String>();
+
+    let encoded_filename = filename
+        .bytes()
+        .map(|b| {
+            if b.is_ascii_alphanumeric() || b"!#$&+-.^_`|~".contains(&b) {
+                (b as char).to_string()
+            } else {
+                format!("%{b:02X}")
+            }
+        })
+        .collect::<String>();
+
+    HeaderValue::try_from(format!(
+        "attachment; filename=\"{fallback_filename}\"; filename*=UTF-8''{encoded_filename}"
+    ))
+    .unwrap_or_else(|err| panic!("checked content disposition is invalid: {err}"))
+}
+
+/// percent encode all bytes except the RFC 3986 unreserved chars
+pub fn encode_path_segment(segment: &str) -> String {
+    segment
+        .bytes()
+        .map(|b| {
+            if b.is_ascii_alphanumeric() || b"-._~".contains(&b) {
+                (b as char).to_string()
+            } else {
+                format!("%{b:02X}")
+            }
+        })
+        .collect()
+}
+
+/// send the file event to the websocket, return false when the websocket is broken
+#[instrument(skip(websocket))]
+async fn send_file_event(websocket: &mut WebSocket, event: FileEvent) -> bool {
+    let response = match event {
+        FileEvent::PeerFileRemoved {
+            peer,
+            filename,
+            hash,
+        } => FileEventResponse::PeerFileRemoved {
+            peer: peer.to_string(),
+            filename,
+            hash,
+        },
+    };
+    let response = match serde_json::to_string(&response) {
+        Err(err) => {
+            error!(%err, ?response, "marshal file event failed");
+
+            websocket_close_with_err(websocket, err).await;
+
+            return false;
+        }
+
+        Ok(response) => response,
+    };
+
+    if let Err(err) = websocket.send(Message::Text(response)).await {
+        error!(%err, "send file event failed");
+
+        return false;
+    }
+
+    true
+}
+
 #[instrument]
 async fn handle_websocket_in_message(
     websocket: &mut WebSocket,
@@ -905,6 +3392,27 @@ async fn handle_websocket_in_message(
     }
 }
 
+/// the client must offer the websocket subprotocol, the incompatible client is rejected before the
+/// upgrade instead of receiving the messages it can't parse
+fn check_websocket_protocol(headers: &HeaderMap) -> Result<(), ApiError> {
+    let offered = headers
+        .get_all(SEC_WEBSOCKET_PROTOCOL)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|protocol| protocol.trim() == WEBSOCKET_PROTOCOL);
+    if !offered {
+        error!(?headers, "websocket protocol is not offered");
+
+        return Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            format!("websocket protocol {WEBSOCKET_PROTOCOL} is required"),
+        ));
+    }
+
+    Ok(())
+}
+
 #[instrument]
 async fn websocket_close_with_err<E: Debug + Display>(websocket: &mut WebSocket, err: E) {
     if let Err(err) = websocket
@@ -917,3 +3425,646 @@ async fn websocket_close_with_err<E: Debug + Display>(websocket: &mut WebSocket,
         error!(%err, "send close frame failed");
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use tempfile::TempDir;
+    use tokio::fs;
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::command::FileGetter as _;
+
+    #[test]
+    fn test_check_websocket_protocol() {
+        let mut headers = HeaderMap::new();
+        assert!(check_websocket_protocol(&headers).is_err());
+
+        headers.insert(
+            SEC_WEBSOCKET_PROTOCOL,
+            HeaderValue::from_static("private-share-v0"),
+        );
+        assert!(check_websocket_protocol(&headers).is_err());
+
+        headers.insert(
+            SEC_WEBSOCKET_PROTOCOL,
+            HeaderValue::from_static("private-share-v0, private-share-v1"),
+        );
+        assert!(check_websocket_protocol(&headers).is_ok());
+    }
+
+    #[test]
+    fn test_etag_matches() {
+        let etag = HeaderValue::from_static("\"ABC\"");
+
+        assert!(etag_matches(&HeaderValue::from_static("\"ABC\""), &etag));
+        assert!(etag_matches(&HeaderValue::from_static("W/\"ABC\""), &etag));
+        assert!(etag_matches(
+            &HeaderValue::from_static("\"DEF\", \"ABC\""),
+            &etag
+        ));
+        assert!(etag_matches(&HeaderValue::from_static("*"), &etag));
+        assert!(!etag_matches(&HeaderValue::from_static("\"DEF\""), &etag));
+    }
+
+    #[test]
+    fn test_attachment_disposition() {
+        assert_eq!(
+            attachment_disposition("test.mp4"),
+            "attachment; filename=\"test.mp4\"; filename*=UTF-8''test.mp4"
+        );
+        assert_eq!(
+            attachment_disposition("测试 \"a\".mp4"),
+            "attachment; filename=\"__ _a_.mp4\"; \
+             filename*=UTF-8''%E6%B5%8B%E8%AF%95%20%22a%22.mp4"
+        );
+    }
+
+    #[test]
+    fn test_normalize_prefix() {
+        assert_eq!(normalize_prefix("/api").unwrap(), "/api");
+        assert_eq!(
+            normalize_prefix("private-share/api/").unwrap(),
+            "/private-share/api"
+        );
+        assert!(normalize_prefix("/").is_err());
+        assert!(normalize_prefix("").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_get_file_url_path_with_custom_prefix() {
+        let dir = TempDir::new_in(env::temp_dir()).unwrap();
+        fs::write(dir.path().join("test.mp4"), b"video")
+            .await
+            .unwrap();
+
+        let store_dir = dir.path().to_path_buf();
+        let (command_sender, mut command_receiver) = mpsc::channel(1);
+        let server = Server::new(
+            command_sender,
+            "/private-share/api/",
+            Some(DEFAULT_UI_PREFIX),
+            None,
+            None,
+            std::path::Path::new(DEFAULT_THUMBNAIL_DIR_NAME),
+            false,
+            None,
+            None,
+            None,
+            None,
+            DEFAULT_COMMAND_SEND_TIMEOUT,
+        )
+        .unwrap();
+
+        // the tv will get the file by this path
+        let get_file_url_path = server.get_file_url_path("test.mp4");
+        assert_eq!(get_file_url_path, "/private-share/api/get_file/test.mp4");
+
+        tokio::spawn(async move {
+            if let Some(Command::GetFile {
+                filename,
+                file_getter,
+                result_sender,
+            }) = command_receiver.next().await
+            {
+                let result = file_getter
+                    .get_file(&store_dir.join(filename))
+                    .await
+                    .map(|content| {
+                        Some(GetFileDetail {
+                            hash: "HASH".to_string(),
+                            index_path: store_dir.join("HASH"),
+                            modified: Some(SystemTime::UNIX_EPOCH + Duration::from_secs(86400)),
+                            content,
+                        })
+                    });
+
+                let _ = result_sender.send(result);
+            }
+        });
+
+        let request = Request::get(get_file_url_path)
+            .body(body::Body::empty())
+            .unwrap();
+        let response = server.router().oneshot(request).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers()[LAST_MODIFIED],
+            "Fri, 02 Jan 1970 00:00:00 GMT"
+        );
+        assert_eq!(
+            hyper::body::to_bytes(response.into_body()).await.unwrap(),
+            &b"video"[..]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_get_partial_file() {
+        let dir = TempDir::new_in(env::temp_dir()).unwrap();
+        let tmp_path = dir.path().join("HASH");
+        fs::write(&tmp_path, b"video").await.unwrap();
+
+        let (command_sender, mut command_receiver) = mpsc::channel(1);
+        let server = Server::new(
+            command_sender,
+            DEFAULT_API_PREFIX,
+            Some(DEFAULT_UI_PREFIX),
+            None,
+            None,
+            std::path::Path::new(DEFAULT_THUMBNAIL_DIR_NAME),
+            false,
+            None,
+            None,
+            None,
+            None,
+            DEFAULT_COMMAND_SEND_TIMEOUT,
+        )
+        .unwrap();
+
+        let ranges = Arc::new(std::sync::Mutex::new(vec![0..3, 4..5]));
+        tokio::spawn(async move {
+            while let Some(cmd) = command_receiver.next().await {
+                match cmd {
+                    Command::GetFile { result_sender, .. } => {
+                        let _ = result_sender.send(Ok(None));
+                    }
+
+                    Command::GetFileAvailability { result_sender, .. } => {
+                        let _ = result_sender.send(Ok(Some(FileAvailability {
+                            hash: "HASH".to_string(),
+                            size: 10,
+                            ranges: ranges.lock().unwrap().clone(),
+                            path: tmp_path.clone(),
+                            bytes_per_sec: None,
+                        })));
+                    }
+
+                    Command::FetchRange { result_sender, .. } => {
+                        fs::write(&tmp_path, b"video clip").await.unwrap();
+                        *ranges.lock().unwrap() = vec![0..10];
+
+                        let _ = result_sender.send(Ok(()));
+                    }
+
+                    _ => unreachable!(),
+                }
+            }
+        });
+
+        let get_file_url_path = server.get_file_url_path("test.mp4");
+        let get_range = |range: &'static str| {
+            Request::get(&get_file_url_path)
+                .header(RANGE, range)
+                .body(body::Body::empty())
+                .unwrap()
+        };
+
+        // only the downloaded part of the range is returned
+        let response = server
+            .clone()
+            .router()
+            .oneshot(get_range("bytes=1-"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(response.headers()[CONTENT_RANGE], "bytes 1-2/10");
+        assert_eq!(
+            hyper::body::to_bytes(response.into_body()).await.unwrap(),
+            &b"id"[..]
+        );
+
+        let response = server
+            .clone()
+            .router()
+            .oneshot(get_range("bytes=10-"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::RANGE_NOT_SATISFIABLE);
+        assert_eq!(response.headers()[CONTENT_RANGE], "bytes */10");
+
+        let response = server
+            .router()
+            .oneshot(
+                Request::get(&get_file_url_path)
+                    .body(body::Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[CONTENT_LENGTH], "10");
+        assert_eq!(response.headers()[ETAG], "\"HASH\"");
+        assert_eq!(
+            hyper::body::to_bytes(response.into_body()).await.unwrap(),
+            &b"video clip"[..]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_disable_ui() {
+        let (command_sender, _command_receiver) = mpsc::channel(1);
+        let server = Server::new(
+            command_sender,
+            DEFAULT_API_PREFIX,
+            None,
+            None,
+            None,
+            std::path::Path::new(DEFAULT_THUMBNAIL_DIR_NAME),
+            false,
+            None,
+            None,
+            None,
+            None,
+            DEFAULT_COMMAND_SEND_TIMEOUT,
+        )
+        .unwrap();
+
+        for path in ["/", DEFAULT_UI_PREFIX] {
+            let response = server
+                .clone()
+                .router()
+                .oneshot(Request::get(path).body(body::Body::empty()).unwrap())
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::NOT_FOUND, "{path}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_request_id() {
+        let (command_sender, _command_receiver) = mpsc::channel(1);
+        let server = Server::new(
+            command_sender,
+            DEFAULT_API_PREFIX,
+            None,
+            None,
+            None,
+            std::path::Path::new(DEFAULT_THUMBNAIL_DIR_NAME),
+            false,
+            None,
+            None,
+            None,
+            None,
+            DEFAULT_COMMAND_SEND_TIMEOUT,
+        )
+        .unwrap();
+
+        let response = server
+            .clone()
+            .router()
+            .oneshot(
+                Request::get("/")
+                    .header(&request_id::X_REQUEST_ID, "support-123")
+                    .body(body::Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.headers()[&request_id::X_REQUEST_ID], "support-123");
+
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let body = serde_json::from_slice::<serde_json::Value>(&body).unwrap();
+        assert_eq!(body["request_id"], "support-123");
+
+        let response = server
+            .router()
+            .oneshot(
+                Request::get("/")
+                    .header(&request_id::X_REQUEST_ID, "invalid id")
+                    .body(body::Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        let request_id = response.headers()[&request_id::X_REQUEST_ID]
+            .to_str()
+            .unwrap();
+        assert_eq!(request_id.len(), 16);
+    }
+
+    #[tokio::test]
+    async fn test_shutdown() {
+        let (command_sender, _command_receiver) = mpsc::channel(1);
+        let shutdown = |api_token: Option<&str>, token: Option<&str>| {
+            let server = Server::new(
+                command_sender.clone(),
+                DEFAULT_API_PREFIX,
+                None,
+                None,
+                None,
+                std::path::Path::new(DEFAULT_THUMBNAIL_DIR_NAME),
+                false,
+                None,
+                api_token,
+                None,
+                None,
+                DEFAULT_COMMAND_SEND_TIMEOUT,
+            )
+            .unwrap();
+            let shutdown_notify = server.shutdown_notify();
+
+            let mut request = Request::post(format!("{DEFAULT_API_PREFIX}{SHUTDOWN_PATH}"));
+            if let Some(token) = token {
+                request = request.header(AUTHORIZATION, format!("Bearer {token}"));
+            }
+            let request = request.body(body::Body::empty()).unwrap();
+
+            (server.router().oneshot(request), shutdown_notify)
+        };
+
+        let (response, _) = shutdown(None, Some("secret"));
+        assert_eq!(response.await.unwrap().status(), StatusCode::FORBIDDEN);
+
+        let (response, _) = shutdown(Some("secret"), Some("wrong"));
+        assert_eq!(response.await.unwrap().status(), StatusCode::UNAUTHORIZED);
+
+        let (response, shutdown_notify) = shutdown(Some("secret"), Some("secret"));
+        assert_eq!(response.await.unwrap().status(), StatusCode::OK);
+        time::timeout(Duration::from_secs(1), shutdown_notify.notified())
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_busy_node() {
+        // the command is never received, the channel is full after the first send
+        let (command_sender, _command_receiver) = mpsc::channel(0);
+        let server = Server::new(
+            command_sender,
+            DEFAULT_API_PREFIX,
+            Some(DEFAULT_UI_PREFIX),
+            None,
+            None,
+            std::path::Path::new(DEFAULT_THUMBNAIL_DIR_NAME),
+            false,
+            None,
+            None,
+            None,
+            None,
+            Duration::from_millis(100),
+        )
+        .unwrap();
+
+        let list_peers = || {
+            Request::get(format!("{DEFAULT_API_PREFIX}{LIST_PEERS_PATH}"))
+                .body(body::Body::empty())
+                .unwrap()
+        };
+
+        // the first request waits for the result, the command is queued in the channel
+        let router = server.clone().router();
+        tokio::spawn(async move { router.oneshot(list_peers()).await });
+        time::sleep(Duration::from_millis(50)).await;
+
+        let response = server.router().oneshot(list_peers()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+    }
+
+    #[tokio::test]
+    async fn test_get_file_timeout() {
+        let (command_sender, mut command_receiver) = mpsc::channel(1);
+        let server = Server::new(
+            command_sender,
+            DEFAULT_API_PREFIX,
+            Some(DEFAULT_UI_PREFIX),
+            None,
+            None,
+            std::path::Path::new(DEFAULT_THUMBNAIL_DIR_NAME),
+            false,
+            None,
+            None,
+            None,
+            None,
+            DEFAULT_COMMAND_SEND_TIMEOUT,
+        )
+        .unwrap();
+
+        // the file content is never produced, like a stuck peer fetch
+        tokio::spawn(async move {
+            let mut result_senders = vec![];
+            while let Some(cmd) = command_receiver.next().await {
+                if let Command::GetFile { result_sender, .. } = cmd {
+                    result_senders.push(result_sender);
+                }
+            }
+        });
+
+        let get_file = |query: &str, timeout_header: Option<&str>| {
+            let mut request =
+                Request::get(format!("{DEFAULT_API_PREFIX}/get_file/test.mp4{query}"));
+            if let Some(timeout) = timeout_header {
+                request = request.header(&X_REQUEST_TIMEOUT, timeout);
+            }
+
+            request.body(body::Body::empty()).unwrap()
+        };
+
+        let response = server
+            .clone()
+            .router()
+            .oneshot(get_file("?timeout=100ms", None))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::GATEWAY_TIMEOUT);
+
+        let response = server
+            .clone()
+            .router()
+            .oneshot(get_file("", Some("100ms")))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::GATEWAY_TIMEOUT);
+
+        let response = server
+            .router()
+            .oneshot(get_file("?timeout=soon", None))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_read_only_node() {
+        let (command_sender, mut command_receiver) = mpsc::channel(1);
+        let server = Server::new(
+            command_sender,
+            DEFAULT_API_PREFIX,
+            None,
+            None,
+            None,
+            std::path::Path::new(DEFAULT_THUMBNAIL_DIR_NAME),
+            false,
+            None,
+            None,
+            None,
+            None,
+            DEFAULT_COMMAND_SEND_TIMEOUT,
+        )
+        .unwrap();
+
+        // like the command handler of a read only node
+        tokio::spawn(async move {
+            let read_only = || io::Error::new(ErrorKind::PermissionDenied, "node is read only");
+            while let Some(cmd) = command_receiver.next().await {
+                match cmd {
+                    Command::AddFile { result_sender, .. } => {
+                        let _ = result_sender.send(Err(read_only()));
+                    }
+                    Command::RemoveFiles { result_sender, .. } => {
+                        let _ = result_sender.send(Err(read_only()));
+                    }
+                    _ => {}
+                }
+            }
+        });
+
+        for (path, body) in [
+            (ADD_FILE_PATH, r#"{"file_path":"/tmp/test.mp4"}"#),
+            (REMOVE_FILES_PATH, r#"{"filenames":["test.mp4"]}"#),
+        ] {
+            let response = server
+                .clone()
+                .router()
+                .oneshot(
+                    Request::post(format!("{DEFAULT_API_PREFIX}{path}"))
+                        .header(CONTENT_TYPE, "application/json")
+                        .body(body::Body::from(body))
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::FORBIDDEN, "{path}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_http2_prior_knowledge() {
+        let (command_sender, mut command_receiver) = mpsc::channel(1);
+        let server = Server::new(
+            command_sender,
+            DEFAULT_API_PREFIX,
+            Some(DEFAULT_UI_PREFIX),
+            None,
+            None,
+            std::path::Path::new(DEFAULT_THUMBNAIL_DIR_NAME),
+            false,
+            None,
+            None,
+            None,
+            None,
+            DEFAULT_COMMAND_SEND_TIMEOUT,
+        )
+        .unwrap();
+
+        tokio::spawn(async move {
+            while let Some(cmd) = command_receiver.next().await {
+                if let Command::ListPeers { result_sender } = cmd {
+                    let _ = result_sender.send(Default::default());
+                }
+            }
+        });
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let http_server = HttpProtocol::Http2
+            .configure(axum::Server::from_tcp(listener).unwrap())
+            .serve(server.router().into_make_service());
+        tokio::spawn(http_server);
+
+        let client = hyper::Client::builder()
+            .http2_only(true)
+            .build_http::<body::Body>();
+        let response = client
+            .get(
+                format!("http://{addr}{DEFAULT_API_PREFIX}{LIST_PEERS_PATH}")
+                    .parse()
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.version(), http::Version::HTTP_2);
+    }
+
+    #[tokio::test]
+    async fn test_rpc_batch() {
+        let (command_sender, mut command_receiver) = mpsc::channel(1);
+        let server = Server::new(
+            command_sender,
+            DEFAULT_API_PREFIX,
+            Some(DEFAULT_UI_PREFIX),
+            None,
+            None,
+            std::path::Path::new(DEFAULT_THUMBNAIL_DIR_NAME),
+            false,
+            None,
+            None,
+            None,
+            None,
+            DEFAULT_COMMAND_SEND_TIMEOUT,
+        )
+        .unwrap();
+
+        tokio::spawn(async move {
+            while let Some(cmd) = command_receiver.next().await {
+                match cmd {
+                    Command::ListPeers { result_sender } => {
+                        let _ = result_sender.send(Default::default());
+                    }
+
+                    _ => unreachable!(),
+                }
+            }
+        });
+
+        let request = Request::post(format!("{DEFAULT_API_PREFIX}{RPC_PATH}"))
+            .header(CONTENT_TYPE, "application/json")
+            .body(body::Body::from(
+                serde_json::json!([
+                    { "jsonrpc": "2.0", "method": "list_peers", "id": 1 },
+                    { "jsonrpc": "2.0", "method": "unknown", "id": 2 },
+                    { "jsonrpc": "2.0", "method": "list_peers" },
+                    { "method": "list_peers", "id": 3 },
+                ])
+                .to_string(),
+            ))
+            .unwrap();
+        let response = server.router().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let body = serde_json::from_slice::<serde_json::Value>(&body).unwrap();
+
+        // the notification has no response
+        assert_eq!(
+            body,
+            serde_json::json!([
+                {
+                    "jsonrpc": "2.0",
+                    "result": {
+                        "peers": [],
+                        "connecting": [],
+                        "idle_addrs": [],
+                        "configured": [],
+                        "discovered": [],
+                    },
+                    "id": 1,
+                },
+                {
+                    "jsonrpc": "2.0",
+                    "error": { "code": rpc::METHOD_NOT_FOUND, "message": "method unknown not found" },
+                    "id": 2,
+                },
+                {
+                    "jsonrpc": "2.0",
+                    "error": { "code": rpc::INVALID_REQUEST, "message": "missing field `jsonrpc`" },
+                    "id": null,
+                },
+            ])
+        );
+    }
+}
@@ -0,0 +1,125 @@
+use std::io::SeekFrom;
+use std::ops::Range;
+use std::path::Path;
+
+use bytes::Bytes;
+use tokio::fs::File;
+use tokio::io;
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+use crate::command::UploadRange;
+
+/// parse the single `bytes` range of the `Range` header, the multiple ranges are not supported,
+/// return `None` when the range is invalid or unsatisfiable
+pub fn parse_range(value: &str, size: u64) -> Option<Range<u64>> {
+    let (start, end) = value.trim().strip_prefix("bytes=")?.split_once('-')?;
+    let (start, end) = (start.trim(), end.trim());
+
+    let range = if start.is_empty() {
+        // the suffix range `bytes=-n` is the last n bytes
+        let suffix = end.parse::<u64>().ok().filter(|suffix| *suffix > 0)?;
+
+        size.saturating_sub(suffix)..size
+    } else {
+        let start = start.parse::<u64>().ok()?;
+        let end = if end.is_empty() {
+            size
+        } else {
+            end.parse::<u64>().ok()?.saturating_add(1).min(size)
+        };
+
+        start..end
+    };
+
+    (range.start < size && !range.is_empty()).then_some(range)
+}
+
+/// parse the `Content-Range` header `bytes start-end/total` of the resumable upload, the end of
+/// the returned range is exclusive, return `None` when the range is invalid
+pub fn parse_content_range(value: &str) -> Option<UploadRange> {
+    let (range, total) = value.trim().strip_prefix("bytes ")?.split_once('/')?;
+    let (start, end) = range.split_once('-')?;
+    let start = start.trim().parse::<u64>().ok()?;
+    let end = end.trim().parse::<u64>().ok()?.checked_add(1)?;
+    let total = total.trim().parse::<u64>().ok()?;
+
+    (start < end && end <= total).then_some(UploadRange { start, end, total })
+}
+
+/// the end of the continuous available data from `offset`, `None` when `offset` is not
+/// available, the `ranges` must be sorted and not overlapped
+pub fn available_end(ranges: &[Range<u64>], offset: u64) -> Option<u64> {
+    let index = ranges.partition_point(|range| range.end <= offset);
+
+    ranges
+        .get(index)
+        .filter(|range| range.start <= offset)
+        .map(|range| range.end)
+}
+
+/// read the range of the file which may be still syncing
+pub async fn read_range(path: &Path, range: Range<u64>) -> io::Result<Bytes> {
+    let mut file = File::open(path).await?;
+    file.seek(SeekFrom::Start(range.start)).await?;
+
+    let length = range.end - range.start;
+    let mut buf = Vec::with_capacity(length as _);
+    file.take(length).read_to_end(&mut buf).await?;
+
+    Ok(buf.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_range() {
+        assert_eq!(parse_range("bytes=0-", 100), Some(0..100));
+        assert_eq!(parse_range("bytes=10-19", 100), Some(10..20));
+        assert_eq!(parse_range("bytes=90-200", 100), Some(90..100));
+        assert_eq!(parse_range("bytes=-10", 100), Some(90..100));
+        assert_eq!(parse_range("bytes=-200", 100), Some(0..100));
+        assert_eq!(parse_range("bytes=100-", 100), None);
+        assert_eq!(parse_range("bytes=20-10", 100), None);
+        assert_eq!(parse_range("bytes=0-1,5-6", 100), None);
+        assert_eq!(parse_range("items=0-1", 100), None);
+    }
+
+    #[test]
+    fn test_parse_content_range() {
+        assert_eq!(
+            parse_content_range("bytes 0-9/100"),
+            Some(UploadRange {
+                start: 0,
+                end: 10,
+                total: 100
+            })
+        );
+        assert_eq!(
+            parse_content_range("bytes 90-99/100"),
+            Some(UploadRange {
+                start: 90,
+                end: 100,
+                total: 100
+            })
+        );
+        assert_eq!(parse_content_range("bytes 90-100/100"), None);
+        assert_eq!(parse_content_range("bytes 10-9/100"), None);
+        assert_eq!(parse_content_range("bytes 0-9/*"), None);
+        assert_eq!(parse_content_range("bytes */100"), None);
+        assert_eq!(parse_content_range("items 0-9/100"), None);
+    }
+
+    #[test]
+    fn test_available_end() {
+        let ranges = [0..10, 20..30];
+
+        assert_eq!(available_end(&ranges, 0), Some(10));
+        assert_eq!(available_end(&ranges, 9), Some(10));
+        assert_eq!(available_end(&ranges, 10), None);
+        assert_eq!(available_end(&ranges, 25), Some(30));
+        assert_eq!(available_end(&ranges, 30), None);
+        assert_eq!(available_end(&[], 0), None);
+    }
+}
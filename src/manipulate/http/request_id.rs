@@ -0,0 +1,73 @@
+use axum::body::BoxBody;
+use axum::middleware::Next;
+use http::header::HeaderName;
+use http::{HeaderValue, Request, Response};
+use rand::distributions::{Alphanumeric, DistString};
+use tracing::{info_span, Instrument};
+
+pub static X_REQUEST_ID: HeaderName = HeaderName::from_static("x-request-id");
+
+/// the max length of the request id from the client, a longer one is replaced
+const MAX_REQUEST_ID_LEN: usize = 64;
+
+tokio::task_local! {
+    static REQUEST_ID: String;
+}
+
+/// the id of the request which is being handled, `None` outside the request handling
+pub fn current_request_id() -> Option<String> {
+    REQUEST_ID.try_with(Clone::clone).ok()
+}
+
+/// read the request id from the `X-Request-Id` header or generate one, the request is handled in
+/// a span with the id, and the id is echoed back in the response header
+pub async fn request_id<B>(request: Request<B>, next: Next<B>) -> Response<BoxBody> {
+    let request_id = request
+        .headers()
+        .get(&X_REQUEST_ID)
+        .and_then(|value| value.to_str().ok())
+        .filter(|value| is_valid_request_id(value))
+        .map(ToOwned::to_owned)
+        .unwrap_or_else(|| Alphanumeric.sample_string(&mut rand::thread_rng(), 16));
+
+    let span = info_span!(
+        "request",
+        %request_id,
+        method = %request.method(),
+        uri = %request.uri()
+    );
+
+    let mut response = REQUEST_ID
+        .scope(request_id.clone(), next.run(request))
+        .instrument(span)
+        .await;
+
+    // the valid request id is always a valid header value
+    response.headers_mut().insert(
+        X_REQUEST_ID.clone(),
+        HeaderValue::from_str(&request_id).unwrap(),
+    );
+
+    response
+}
+
+fn is_valid_request_id(value: &str) -> bool {
+    !value.is_empty()
+        && value.len() <= MAX_REQUEST_ID_LEN
+        && value
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || matches!(b, b'-' | b'_' | b'.'))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_valid_request_id() {
+        assert!(is_valid_request_id("abc-123_4.5"));
+        assert!(!is_valid_request_id(""));
+        assert!(!is_valid_request_id("a b"));
+        assert!(!is_valid_request_id(&"a".repeat(MAX_REQUEST_ID_LEN + 1)));
+    }
+}
@@ -1,38 +1,125 @@
 use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Serialize, Deserialize)]
 pub struct ListResponse {
     pub files: Vec<ListFile>,
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Serialize, Deserialize)]
 pub struct ListFile {
     pub filename: String,
     pub hash: String,
     pub downloaded: bool,
     pub peers: Vec<String>,
     pub size: String,
+    /// the sidecar subtitle filenames of the video
+    pub subtitles: Vec<String>,
+    /// `false` means the index file of the local file is missing or isn't a regular file, it is
+    /// only set when the health is checked
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub healthy: Option<bool>,
+    /// unix timestamp in seconds of when the local file is added or synced, the peer file doesn't
+    /// have it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub modified: Option<u64>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Serialize, Deserialize)]
 pub struct AddFileRequest {
     pub file_path: String,
+    /// the known hash of the file, the node skips hashing when `verify_on_add` is disabled
+    #[serde(default)]
+    pub hash: Option<String>,
 }
 
 #[derive(Debug, Deserialize)]
 pub struct ListFilesQuery {
     pub include_peer: Option<bool>,
+    /// check the index files of the local files, default is false
+    pub check_health: Option<bool>,
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Deserialize)]
+pub struct GetFileQuery {
+    pub download: Option<bool>,
+    /// the max time of producing the file content like `10s`, the `x-request-timeout` header
+    /// is used when it is not set
+    pub timeout: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UploadFileQuery {
+    pub hash: Option<String>,
+}
+
+#[cfg(feature = "thumbnail")]
+#[derive(Debug, Deserialize)]
+pub struct ThumbnailQuery {
+    pub size: Option<u32>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
 pub struct ListPeersResponse {
     pub peers: Vec<ListPeer>,
+    /// the peers which are being dialed
+    #[serde(default)]
+    pub connecting: Vec<ConnectingPeer>,
+    /// the configured peer addrs which are neither connected nor being dialed
+    #[serde(default)]
+    pub idle_addrs: Vec<String>,
+    /// the status of each configured peer addr
+    #[serde(default)]
+    pub configured: Vec<ConfiguredPeer>,
+    /// the accepted discover addrs of the peers
+    #[serde(default)]
+    pub discovered: Vec<DiscoveredPeer>,
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DiscoveredPeer {
+    pub peer: String,
+    pub addrs: Vec<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ConfiguredPeer {
+    pub addr: String,
+    /// it is empty when the addr doesn't contain the peer id
+    #[serde(default)]
+    pub peer: Option<String>,
+    pub status: PeerAddrStatus,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PeerAddrStatus {
+    Connected,
+    Connecting,
+    Unreachable,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ConnectingPeer {
+    pub peer: String,
+    pub addr: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
 pub struct ListPeer {
     pub peer: String,
     pub connected_addrs: Vec<String>,
+    #[serde(default)]
+    pub connections: usize,
+    /// unix timestamp in seconds when the peer is connected
+    pub connected_since: u64,
+    pub uptime_secs: u64,
+    /// the identify info, they are empty when the peer isn't identified yet
+    #[serde(default)]
+    pub protocol_version: Option<String>,
+    #[serde(default)]
+    pub agent_version: Option<String>,
+    #[serde(default)]
+    pub protocols: Vec<String>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -46,19 +133,78 @@ pub struct GetBandWidthResponse {
     pub outbound: u64,
 }
 
-#[derive(Debug, Deserialize)]
+/// the file event pushed by the file events websocket
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum FileEventResponse {
+    /// the peer stops advertising the file
+    PeerFileRemoved {
+        peer: String,
+        filename: String,
+        hash: String,
+    },
+}
+
+#[derive(Debug, Serialize, Deserialize)]
 pub struct AddPeersRequest {
     pub peers: Vec<String>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Serialize, Deserialize)]
 pub struct RemovePeersRequest {
     pub peers: Vec<String>,
 }
 
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PingPeerRequest {
+    /// the peer id or the multiaddr with the peer id
+    pub peer: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PingPeerResponse {
+    pub peer_id: String,
+    /// the peer was connected before the ping
+    pub connected: bool,
+    pub reachable: bool,
+    /// the round trip time in milliseconds
+    pub rtt_ms: Option<f64>,
+    /// why the peer is unreachable
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RemoveFilesRequest {
+    #[serde(default)]
+    pub filenames: Vec<String>,
+    /// `*` matches any chars and `?` matches one char, the matching is ascii case insensitive
+    pub pattern: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RemoveFilesResponse {
+    pub files: Vec<RemovedFile>,
+    /// the hashes of the index files which are removed because no store file refers to them
+    pub removed_index_files: Vec<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RemovedFile {
+    pub filename: String,
+    pub removed: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
 #[derive(Debug, Deserialize)]
 pub struct ListTVQuery {
     pub timeout: Option<u32>,
+    /// the wildcard pattern of the tv friendly name, case insensitive
+    pub name: Option<String>,
+    /// the device type of the tv, like `MediaRenderer`
+    pub device_type: Option<String>,
+    /// the max number of the listed tvs, default is unlimited
+    pub max_count: Option<usize>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -71,3 +217,297 @@ pub struct ListTVResponse {
     pub friend_name: String,
     pub encoded_url: String,
 }
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct FileAvailabilityResponse {
+    pub filename: String,
+    pub hash: String,
+    pub size: u64,
+    pub available_size: u64,
+    pub complete: bool,
+    /// the recent download rate of the syncing file
+    #[serde(default)]
+    pub bytes_per_sec: Option<u64>,
+    /// the estimated seconds to finish the sync at the recent rate
+    #[serde(default)]
+    pub eta_secs: Option<u64>,
+    /// the available `[start, end)` byte ranges
+    pub ranges: Vec<(u64, u64)>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct FilePeersResponse {
+    pub filename: String,
+    pub peers: Vec<FilePeer>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct FilePeer {
+    pub peer: String,
+    pub hash: String,
+    /// the peer is connected now, otherwise it is only advertised
+    pub connected: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SyncFileResponse {
+    pub filename: String,
+    pub hash: String,
+    /// the file was already syncing, no new sync is started
+    pub already_syncing: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ImportManifestResponse {
+    /// the node which exported the manifest
+    pub peer_id: String,
+    pub files: usize,
+    /// the manifest is older than the accepted file message of the node, it is ignored
+    pub outdated: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct VerifyFileResponse {
+    pub filename: String,
+    /// the hash in the store symlink target
+    pub expected_hash: String,
+    /// the hash of the index file content
+    pub computed_hash: String,
+    pub matched: bool,
+}
+
+/// the files which the next sync would download, in the sync order
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SyncPlanResponse {
+    pub files: Vec<SyncPlanFile>,
+    /// the total size of the files, the downloaded chunks of the syncing files are counted too
+    pub total_size: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SyncPlanFile {
+    pub hash: String,
+    pub filenames: Vec<String>,
+    pub size: u64,
+    /// the peers which would serve the file
+    pub peers: Vec<String>,
+    pub syncing: bool,
+    /// the number of the failed sync attempts, the file keeps failing when it exceeds the
+    /// `sync_retry_max_attempts`
+    #[serde(default)]
+    pub failed_attempts: u32,
+    #[serde(default)]
+    pub last_error: Option<String>,
+}
+
+/// the download manager view, the syncing files and the files waiting for a sync attempt
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SyncsResponse {
+    pub files: Vec<SyncEntry>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SyncEntry {
+    pub hash: String,
+    pub filenames: Vec<String>,
+    pub size: u64,
+    /// the downloaded bytes, they are kept for the next attempt
+    pub synced: u64,
+    /// only the active file has it
+    #[serde(default)]
+    pub bytes_per_sec: Option<u64>,
+    /// the peers which advertise the file
+    pub peers: Vec<String>,
+    pub state: SyncState,
+    #[serde(default)]
+    pub failed_attempts: u32,
+    #[serde(default)]
+    pub last_error: Option<String>,
+    /// seconds until the retry of the retrying file
+    #[serde(default)]
+    pub retry_in: Option<u64>,
+    /// the bytes downloaded from each peer, the peer serving the most comes first
+    #[serde(default)]
+    pub sources: Vec<DownloadSource>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DownloadSource {
+    pub peer_id: String,
+    pub bytes: u64,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SyncState {
+    /// downloading now
+    Active,
+    /// the last attempt failed, it is synced again after the retry backoff
+    Retrying,
+    /// waiting for a free sync slot or the next sync tick
+    Queued,
+    /// the last attempt failed and no retry is scheduled, it waits for the next sync tick
+    Failed,
+}
+
+/// the store files of the node, the peer files are not included
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MyFilesResponse {
+    pub files: Vec<MyFile>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MyFile {
+    pub filename: String,
+    pub hash: String,
+    pub size: u64,
+    pub index_path: String,
+    /// the file matches the exclude patterns, it is not announced to the peers
+    pub excluded: bool,
+    pub verify_status: VerifyStatus,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum VerifyStatus {
+    Unverified,
+    Verified,
+    Corrupted,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct StatsResponse {
+    pub peers: usize,
+    pub local_files: usize,
+    pub remote_files: usize,
+    pub shared_bytes: u64,
+    pub syncing_files: usize,
+    pub inbound: u64,
+    pub outbound: u64,
+    /// unix timestamp in seconds when the last index files scrub finished
+    #[serde(default)]
+    pub last_scrub_time: Option<u64>,
+    /// the hashes of the index files which are found corrupted by the last scrub
+    #[serde(default)]
+    pub scrub_corrupted_files: Vec<String>,
+    #[serde(default)]
+    pub scrub_errors: Vec<String>,
+    #[serde(default)]
+    pub connection_errors: ConnectionErrorsResponse,
+    /// the dirs may be on different mounts, so the space is reported per dir
+    #[serde(default)]
+    pub disk_space: DiskSpaceResponse,
+    #[serde(default)]
+    pub gossip: GossipStatsResponse,
+    /// seconds until the next refresh store tick publishes the local files
+    #[serde(default)]
+    pub next_refresh_store: u64,
+    /// seconds until the next sync file tick picks up the missing peer files
+    #[serde(default)]
+    pub next_sync_file: u64,
+    /// `None` when the relay service is disabled
+    #[serde(default)]
+    pub relay: Option<RelayStatsResponse>,
+    #[serde(default)]
+    pub sync_counters: SyncCountersResponse,
+}
+
+/// the cumulative sync and serving counters since the node started, they reset on restart
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct SyncCountersResponse {
+    pub synced_files: u64,
+    /// the failed sync rounds, a retried file is counted once per failure
+    pub failed_files: u64,
+    pub downloaded_bytes: u64,
+    pub served_bytes: u64,
+}
+
+/// the load of the relay service, the relayed bytes are not counted
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct RelayStatsResponse {
+    /// the connected peers which listen through this node
+    pub reservations: usize,
+    /// the circuits relayed since the node started
+    pub circuits: u64,
+}
+
+/// the entry numbers of the node internal states, the growing numbers of an idle node mean leaks
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DiagnosticsResponse {
+    pub peer_stores: usize,
+    /// the peer stores whose peers aren't connected now
+    pub disconnected_peer_stores: usize,
+    pub peer_store_files: usize,
+    pub file_get_requests: usize,
+    pub connected_peers: usize,
+    pub peer_addr_connecting: usize,
+    pub pending_peer_addrs: usize,
+    pub discover_peers: usize,
+    pub syncing_files: usize,
+    pub sync_failures: usize,
+    pub adding_files: usize,
+    pub cached_files: usize,
+    pub max_cached_files: usize,
+    pub cached_mapped_bytes: u64,
+}
+
+/// the gossip peers, the explicit peers receive the messages without joining the mesh
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct GossipStatsResponse {
+    pub file_topic: GossipTopicStats,
+    pub discover_topic: GossipTopicStats,
+    pub all_mesh_peers: usize,
+}
+
+#[derive(Debug, Default, Copy, Clone, Serialize, Deserialize)]
+pub struct GossipTopicStats {
+    pub mesh_peers: usize,
+    pub subscribed_peers: usize,
+}
+
+/// the space of the filesystems backing the node dirs, `None` means the query failed
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct DiskSpaceResponse {
+    pub index_dir: Option<DiskSpace>,
+    pub store_dir: Option<DiskSpace>,
+    pub temp_dir: Option<DiskSpace>,
+}
+
+/// the space in bytes, the free space is available to the unprivileged users
+#[derive(Debug, Copy, Clone, Serialize, Deserialize)]
+pub struct DiskSpace {
+    pub free: u64,
+    pub total: u64,
+}
+
+/// the number of the failed connections by the category since the node started
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct ConnectionErrorsResponse {
+    pub dial: u64,
+    pub timeout: u64,
+    pub handshake: u64,
+    /// the handshakes garbled by the different pre-shared keys
+    #[serde(default)]
+    pub key_mismatch: u64,
+    /// the pnet handshake failures, a mismatched pre-shared key usually fails the later
+    /// handshake instead
+    pub pnet: u64,
+    pub other: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AccessLogResponse {
+    /// the newest first
+    pub entries: Vec<AccessLogEntry>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AccessLogEntry {
+    /// unix timestamp in seconds
+    pub time: u64,
+    pub remote: Option<String>,
+    /// it is empty when the file is requested by hash
+    pub filename: Option<String>,
+    pub hash: String,
+    pub range: Option<String>,
+}
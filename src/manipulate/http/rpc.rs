@@ -0,0 +1,162 @@
+use http::StatusCode;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use super::error::ApiError;
+
+pub const PARSE_ERROR: i64 = -32700;
+pub const INVALID_REQUEST: i64 = -32600;
+pub const METHOD_NOT_FOUND: i64 = -32601;
+pub const INVALID_PARAMS: i64 = -32602;
+pub const INTERNAL_ERROR: i64 = -32603;
+/// the api errors which are not caused by the params
+pub const SERVER_ERROR: i64 = -32000;
+
+const JSONRPC_VERSION: &str = "2.0";
+
+#[derive(Debug, Deserialize)]
+pub struct RpcRequest {
+    pub jsonrpc: String,
+    pub method: String,
+    #[serde(default)]
+    pub params: Value,
+    /// the request is a notification when it is not set, no response is sent back
+    pub id: Option<Value>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct RpcResponse {
+    jsonrpc: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    result: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<RpcError>,
+    id: Value,
+}
+
+impl RpcResponse {
+    pub fn new(id: Value, result: Result<Value, RpcError>) -> Self {
+        let (result, error) = match result {
+            Err(err) => (None, Some(err)),
+            Ok(result) => (Some(result), None),
+        };
+
+        Self {
+            jsonrpc: JSONRPC_VERSION,
+            result,
+            error,
+            id,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct RpcError {
+    code: i64,
+    message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    data: Option<Value>,
+}
+
+impl RpcError {
+    pub fn new<S: Into<String>>(code: i64, message: S) -> Self {
+        Self {
+            code,
+            message: message.into(),
+            data: None,
+        }
+    }
+}
+
+/// the bad request api error is caused by the params, the http status is kept in the data
+impl From<ApiError> for RpcError {
+    fn from(err: ApiError) -> Self {
+        let code = if err.status() == StatusCode::BAD_REQUEST {
+            INVALID_PARAMS
+        } else {
+            SERVER_ERROR
+        };
+
+        Self {
+            code,
+            message: err.message().to_string(),
+            data: Some(json!({ "status": err.status().as_u16() })),
+        }
+    }
+}
+
+impl RpcRequest {
+    /// check the request is a valid json-rpc 2.0 request
+    pub fn parse(value: Value) -> Result<Self, RpcError> {
+        let request = serde_json::from_value::<Self>(value)
+            .map_err(|err| RpcError::new(INVALID_REQUEST, err.to_string()))?;
+        if request.jsonrpc != JSONRPC_VERSION {
+            return Err(RpcError::new(
+                INVALID_REQUEST,
+                format!("unsupported jsonrpc version {}", request.jsonrpc),
+            ));
+        }
+
+        Ok(request)
+    }
+
+    /// the omitted params are treated as an empty object
+    pub fn params<T: DeserializeOwned>(&self) -> Result<T, RpcError> {
+        let params = match &self.params {
+            Value::Null => json!({}),
+            params => params.clone(),
+        };
+
+        serde_json::from_value(params).map_err(|err| RpcError::new(INVALID_PARAMS, err.to_string()))
+    }
+}
+
+/// the params of the `get_file_availability` and `get_file_peers` methods
+#[derive(Debug, Deserialize)]
+pub struct FileAvailabilityParams {
+    pub filename: String,
+}
+
+pub fn to_result<T: Serialize>(result: T) -> Result<Value, RpcError> {
+    serde_json::to_value(result).map_err(|err| RpcError::new(INTERNAL_ERROR, err.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::manipulate::http::response::ListFilesQuery;
+
+    #[test]
+    fn test_parse_request() {
+        let request = RpcRequest::parse(json!({
+            "jsonrpc": "2.0",
+            "method": "list_files",
+            "id": 1,
+        }))
+        .unwrap();
+        assert_eq!(request.id, Some(json!(1)));
+        assert_eq!(
+            request.params::<ListFilesQuery>().unwrap().include_peer,
+            None
+        );
+
+        let err =
+            RpcRequest::parse(json!({ "jsonrpc": "1.0", "method": "list_files" })).unwrap_err();
+        assert_eq!(err.code, INVALID_REQUEST);
+
+        let err = RpcRequest::parse(json!({ "jsonrpc": "2.0" })).unwrap_err();
+        assert_eq!(err.code, INVALID_REQUEST);
+    }
+
+    #[test]
+    fn test_api_error_to_rpc_error() {
+        let err = RpcError::from(ApiError::new(StatusCode::BAD_REQUEST, "bad addr"));
+        assert_eq!(err.code, INVALID_PARAMS);
+        assert_eq!(err.data, Some(json!({ "status": 400 })));
+
+        let err = RpcError::from(ApiError::new(StatusCode::NOT_FOUND, "not exists"));
+        assert_eq!(err.code, SERVER_ERROR);
+        assert_eq!(err.message, "not exists");
+    }
+}
@@ -1,19 +1,25 @@
 use std::net::SocketAddr;
 
 use axum::extract::connect_info::Connected;
-use tokio::net::TcpStream;
+
+use super::addr_incoming::HttpConn;
 
 #[derive(Debug, Clone)]
 pub struct SocketAddrPeer {
     pub local: Option<SocketAddr>,
     pub remote: Option<SocketAddr>,
+    /// the connection is served over https
+    pub tls: bool,
 }
 
-impl Connected<&TcpStream> for SocketAddrPeer {
-    fn connect_info(target: &TcpStream) -> Self {
+impl Connected<&HttpConn> for SocketAddrPeer {
+    fn connect_info(target: &HttpConn) -> Self {
+        let tcp_stream = target.tcp_stream();
+
         Self {
-            local: target.local_addr().ok(),
-            remote: target.peer_addr().ok(),
+            local: tcp_stream.local_addr().ok(),
+            remote: tcp_stream.peer_addr().ok(),
+            tls: target.is_tls(),
         }
     }
 }
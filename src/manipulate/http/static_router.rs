@@ -1,33 +1,196 @@
 use std::future::{ready, Ready};
-use std::io::Error;
+use std::io::{Error, ErrorKind};
+use std::path::Path;
+use std::sync::Arc;
 
 use axum::body::HttpBody;
-use axum::routing::get_service;
+use axum::response::{Html, IntoResponse, Response};
+use axum::routing::{get, get_service};
 use axum::Router;
 use http::StatusCode;
+use http_dir::fs::disk::DiskFilesystem;
 use http_dir::fs::include_dir::IncludeDirFilesystem;
 use http_dir::ServeDir;
+use tokio::fs;
+use tracing::error;
 
 use super::static_resources::WEB_RESOURCES_DIR;
 
+/// the placeholder of the CanvasKit base url in the `index.html`, it is a js string literal
+const CANVASKIT_BASE_URL_PLACEHOLDER: &str = "\"$CANVASKIT_BASE_URL\"";
+
 #[derive(Debug, Default)]
-pub struct StaticRouter;
+pub struct StaticRouter {
+    /// the CanvasKit base url injected into the `index.html`
+    canvaskit_url: Option<Arc<str>>,
+    /// serve the web ui files in the dir instead of the embedded ones
+    ui_dir: Option<Arc<Path>>,
+}
+
+impl StaticRouter {
+    pub fn new(canvaskit_url: Option<Arc<str>>, ui_dir: Option<Arc<Path>>) -> Self {
+        Self {
+            canvaskit_url,
+            ui_dir,
+        }
+    }
+}
 
 impl<S, B> From<StaticRouter> for Router<S, B>
 where
     B: HttpBody + Send + 'static,
     S: Clone + Send + Sync + 'static,
 {
-    fn from(_: StaticRouter) -> Self {
+    fn from(static_router: StaticRouter) -> Self {
+        if let Some(ui_dir) = static_router.ui_dir {
+            return ui_dir_router(ui_dir, static_router.canvaskit_url);
+        }
+
         let filesystem = IncludeDirFilesystem::new(WEB_RESOURCES_DIR.clone());
 
         let assets_service =
             get_service(ServeDir::new(filesystem).precompressed_br()).handle_error(handle_error);
 
-        Router::new().nest_service("/", assets_service)
+        let index_html = match WEB_RESOURCES_DIR
+            .get_file("index.html")
+            .and_then(|file| file.contents_utf8())
+        {
+            None => return Router::new().nest_service("/", assets_service),
+
+            Some(index_html) => Html(render_index_html(
+                index_html,
+                static_router.canvaskit_url.as_deref(),
+            )),
+        };
+
+        let index = move || ready(index_html.clone());
+
+        // the index.html is rendered when serving, so the precompressed one is skipped
+        Router::new()
+            .route("/", get(index.clone()))
+            .route("/index.html", get(index))
+            .fallback_service(assets_service)
     }
 }
 
+/// serve the web ui files in the dir, the `index.html` is read on every request, so the updated
+/// web ui is served without restarting the node
+fn ui_dir_router<S, B>(ui_dir: Arc<Path>, canvaskit_url: Option<Arc<str>>) -> Router<S, B>
+where
+    B: HttpBody + Send + 'static,
+    S: Clone + Send + Sync + 'static,
+{
+    let filesystem = DiskFilesystem::new(ui_dir.to_path_buf());
+    let assets_service =
+        get_service(ServeDir::new(filesystem).precompressed_br()).handle_error(handle_error);
+
+    let index = move || {
+        let ui_dir = ui_dir.clone();
+        let canvaskit_url = canvaskit_url.clone();
+
+        async move { read_index_html(&ui_dir, canvaskit_url.as_deref()).await }
+    };
+
+    Router::new()
+        .route("/", get(index.clone()))
+        .route("/index.html", get(index))
+        .fallback_service(assets_service)
+}
+
+async fn read_index_html(ui_dir: &Path, canvaskit_url: Option<&str>) -> Response {
+    let index_html_path = ui_dir.join("index.html");
+    match fs::read_to_string(&index_html_path).await {
+        Err(err) if err.kind() == ErrorKind::NotFound => StatusCode::NOT_FOUND.into_response(),
+
+        Err(err) => {
+            error!(%err, ?index_html_path, "read index.html failed");
+
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+
+        Ok(index_html) => Html(render_index_html(&index_html, canvaskit_url)).into_response(),
+    }
+}
+
+/// replace the CanvasKit base url placeholder, the web ui uses the build time url when the
+/// placeholder is replaced by `null`
+fn render_index_html(index_html: &str, canvaskit_url: Option<&str>) -> String {
+    let canvaskit_url = match canvaskit_url {
+        None => "null".to_string(),
+        Some(url) => serde_json::to_string(url).unwrap(),
+    };
+
+    index_html.replace(CANVASKIT_BASE_URL_PLACEHOLDER, &canvaskit_url)
+}
+
 fn handle_error(_: Error) -> Ready<StatusCode> {
     ready(StatusCode::INTERNAL_SERVER_ERROR)
 }
+
+#[cfg(test)]
+mod tests {
+    use axum::body::Body;
+    use http::Request;
+    use tempfile::TempDir;
+    use tower::ServiceExt;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_ui_dir_router() {
+        let dir = TempDir::new().unwrap();
+        let ui_dir = Arc::<Path>::from(dir.path());
+        let router = || {
+            Router::<(), Body>::from(StaticRouter::new(
+                Some(Arc::from("canvaskit/")),
+                Some(ui_dir.clone()),
+            ))
+        };
+        let get_body = |path: &'static str| {
+            let router = router();
+
+            async move {
+                let response = router
+                    .oneshot(Request::get(path).body(Body::empty()).unwrap())
+                    .await
+                    .unwrap();
+                let status = response.status();
+                let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+
+                (status, body)
+            }
+        };
+
+        assert_eq!(get_body("/").await.0, StatusCode::NOT_FOUND);
+
+        fs::write(
+            dir.path().join("index.html"),
+            r#"var canvasKitBaseUrl = "$CANVASKIT_BASE_URL";"#,
+        )
+        .await
+        .unwrap();
+        fs::write(dir.path().join("main.js"), "main").await.unwrap();
+
+        let (status, body) = get_body("/").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, r#"var canvasKitBaseUrl = "canvaskit/";"#);
+
+        let (status, body) = get_body("/main.js").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, "main");
+    }
+
+    #[test]
+    fn test_render_index_html() {
+        let index_html = r#"var canvasKitBaseUrl = "$CANVASKIT_BASE_URL";"#;
+
+        assert_eq!(
+            render_index_html(index_html, Some("canvaskit/")),
+            r#"var canvasKitBaseUrl = "canvaskit/";"#
+        );
+        assert_eq!(
+            render_index_html(index_html, None),
+            "var canvasKitBaseUrl = null;"
+        );
+    }
+}
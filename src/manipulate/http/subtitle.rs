@@ -0,0 +1,133 @@
+use std::path::Path;
+
+const SUBTITLE_EXTENSIONS: [&str; 2] = ["srt", "vtt"];
+pub const VTT_CONTENT_TYPE: &str = "text/vtt; charset=utf-8";
+
+/// split the filename to stem and lowercase extension
+fn split_filename(filename: &str) -> Option<(&str, String)> {
+    let path = Path::new(filename);
+    let stem = path.file_stem()?.to_str()?;
+    let extension = path.extension()?.to_str()?.to_lowercase();
+
+    Some((stem, extension))
+}
+
+pub fn is_subtitle(filename: &str) -> bool {
+    split_filename(filename)
+        .map(|(_, extension)| SUBTITLE_EXTENSIONS.contains(&extension.as_str()))
+        .unwrap_or(false)
+}
+
+pub fn is_vtt(filename: &str) -> bool {
+    split_filename(filename)
+        .map(|(_, extension)| extension == "vtt")
+        .unwrap_or(false)
+}
+
+/// the srt sidecar filename of the vtt subtitle, it is used to convert srt on the fly when the vtt
+/// subtitle doesn't exist
+pub fn srt_filename_of_vtt(filename: &str) -> Option<String> {
+    let (stem, _) = split_filename(filename).filter(|(_, extension)| extension == "vtt")?;
+
+    Some(format!("{stem}.srt"))
+}
+
+/// find the sidecar subtitles of the video, the subtitle has the same basename as the video, a
+/// language tag is allowed, like `movie.mp4` -> `movie.srt` and `movie.en.vtt`
+pub fn find_subtitles<'a, I>(filename: &str, filenames: I) -> Vec<String>
+where
+    I: IntoIterator<Item = &'a str>,
+{
+    if is_subtitle(filename) {
+        return vec![];
+    }
+
+    let video_stem = match split_filename(filename) {
+        None => return vec![],
+        Some((stem, _)) => stem,
+    };
+
+    let mut subtitles = filenames
+        .into_iter()
+        .filter(|subtitle| is_subtitle(subtitle))
+        .filter(|subtitle| {
+            split_filename(subtitle)
+                .map(|(stem, _)| {
+                    stem == video_stem
+                        || stem
+                            .strip_prefix(video_stem)
+                            .map(|tag| tag.starts_with('.'))
+                            .unwrap_or(false)
+                })
+                .unwrap_or(false)
+        })
+        .map(ToString::to_string)
+        .collect::<Vec<_>>();
+
+    subtitles.sort();
+
+    subtitles
+}
+
+/// convert srt subtitle to WebVTT, browsers only support WebVTT in the `<track>` element
+pub fn srt_to_vtt(srt: &str) -> String {
+    let srt = srt.trim_start_matches('\u{feff}');
+    let mut vtt = String::with_capacity(srt.len() + 8);
+    vtt.push_str("WEBVTT\n\n");
+
+    for line in srt.lines() {
+        if line.contains("-->") {
+            // srt uses `,` as the millisecond separator but WebVTT uses `.`
+            vtt.push_str(&line.replace(',', "."));
+        } else {
+            vtt.push_str(line);
+        }
+
+        vtt.push('\n');
+    }
+
+    vtt
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_find_subtitles() {
+        let filenames = [
+            "movie.mp4",
+            "movie.srt",
+            "movie.en.VTT",
+            "movie2.srt",
+            "movie.txt",
+            "other.mp4",
+        ];
+
+        assert_eq!(
+            find_subtitles("movie.mp4", filenames),
+            vec!["movie.en.VTT".to_string(), "movie.srt".to_string()]
+        );
+        assert!(find_subtitles("other.mp4", filenames).is_empty());
+        assert!(find_subtitles("movie.srt", filenames).is_empty());
+    }
+
+    #[test]
+    fn test_srt_filename_of_vtt() {
+        assert_eq!(
+            srt_filename_of_vtt("movie.en.vtt"),
+            Some("movie.en.srt".to_string())
+        );
+        assert_eq!(srt_filename_of_vtt("movie.srt"), None);
+    }
+
+    #[test]
+    fn test_srt_to_vtt() {
+        let srt = "\u{feff}1\r\n00:00:01,000 --> 00:00:02,500\r\nhello, world\r\n";
+
+        assert_eq!(
+            srt_to_vtt(srt),
+            "WEBVTT\n\n1\n00:00:01.000 --> 00:00:02.500\nhello, world\n"
+        );
+    }
+}
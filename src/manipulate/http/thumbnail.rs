@@ -0,0 +1,214 @@
+use std::io;
+use std::io::{Cursor, ErrorKind};
+use std::path::Path as FsPath;
+
+use axum::body::BoxBody;
+use axum::extract::{Path, Query};
+use axum::response::IntoResponse;
+use http::header::{CACHE_CONTROL, CONTENT_TYPE};
+use http::{Response, StatusCode};
+use image::{DynamicImage, ImageOutputFormat};
+use mime_guess::mime;
+use rand::distributions::{Alphanumeric, DistString};
+use tap::TapFallible;
+use tokio::{fs, task};
+use tracing::{error, info, instrument, warn};
+
+use super::error::ApiError;
+use super::response::ThumbnailQuery;
+use super::Server;
+
+const DEFAULT_THUMBNAIL_SIZE: u32 = 256;
+const MIN_THUMBNAIL_SIZE: u32 = 16;
+const MAX_THUMBNAIL_SIZE: u32 = 1024;
+const JPEG_QUALITY: u8 = 80;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum ThumbnailFormat {
+    Jpeg,
+    Png,
+}
+
+impl ThumbnailFormat {
+    /// png and gif may contain transparent pixels, keep them as png
+    fn from_mime(mime: &mime::Mime) -> Self {
+        if *mime == mime::IMAGE_PNG || *mime == mime::IMAGE_GIF {
+            Self::Png
+        } else {
+            Self::Jpeg
+        }
+    }
+
+    fn extension(self) -> &'static str {
+        match self {
+            ThumbnailFormat::Jpeg => "jpg",
+            ThumbnailFormat::Png => "png",
+        }
+    }
+
+    fn content_type(self) -> &'static str {
+        match self {
+            ThumbnailFormat::Jpeg => "image/jpeg",
+            ThumbnailFormat::Png => "image/png",
+        }
+    }
+}
+
+impl Server {
+    #[instrument(skip(self))]
+    pub(super) async fn handle_thumbnail(
+        &mut self,
+        Path(filename): Path<String>,
+        Query(thumbnail_query): Query<ThumbnailQuery>,
+    ) -> Result<Response<BoxBody>, ApiError> {
+        let mime = mime_guess::from_path(&filename).first_or_octet_stream();
+        if mime.type_() != mime::IMAGE {
+            error!(%filename, %mime, "file is not an image");
+
+            return Err(ApiError::new(
+                StatusCode::NOT_FOUND,
+                format!("file {filename} is not an image"),
+            ));
+        }
+
+        let size = thumbnail_query
+            .size
+            .unwrap_or(DEFAULT_THUMBNAIL_SIZE)
+            .clamp(MIN_THUMBNAIL_SIZE, MAX_THUMBNAIL_SIZE);
+        let format = ThumbnailFormat::from_mime(&mime);
+
+        let file_detail = self.get_file_detail(&filename).await?;
+        let thumbnail_dir = &self.thumbnail_dir;
+        let thumbnail_path = thumbnail_dir.join(format!(
+            "{}_{size}.{}",
+            file_detail.hash,
+            format.extension()
+        ));
+
+        let thumbnail = match fs::read(&thumbnail_path).await {
+            Ok(thumbnail) => {
+                info!(%filename, ?thumbnail_path, "thumbnail cache hit");
+
+                thumbnail
+            }
+
+            Err(err) if err.kind() == ErrorKind::NotFound => {
+                let data = fs::read(&file_detail.index_path).await.map_err(|err| {
+                    error!(%err, index_path = ?file_detail.index_path, "read image file failed");
+
+                    ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, err.to_string())
+                })?;
+
+                // decode and resize image is cpu heavy, don't block the async runtime
+                let thumbnail =
+                    task::spawn_blocking(move || generate_thumbnail(&data, size, format))
+                        .await
+                        .map_err(|err| {
+                            error!(%err, "join generate thumbnail task failed");
+
+                            ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, err.to_string())
+                        })?
+                        .map_err(|err| {
+                            error!(%err, %filename, "generate thumbnail failed");
+
+                            ApiError::new(StatusCode::UNSUPPORTED_MEDIA_TYPE, err.to_string())
+                        })?;
+
+                info!(%filename, size, "generate thumbnail done");
+
+                // cache failure doesn't affect the response
+                let _ = cache_thumbnail(thumbnail_dir, &thumbnail_path, &thumbnail)
+                    .await
+                    .tap_err(|err| warn!(%err, ?thumbnail_path, "cache thumbnail failed"));
+
+                thumbnail
+            }
+
+            Err(err) => {
+                error!(%err, ?thumbnail_path, "read thumbnail cache failed");
+
+                return Err(ApiError::new(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    err.to_string(),
+                ));
+            }
+        };
+
+        Ok((
+            [
+                (CONTENT_TYPE, format.content_type()),
+                (CACHE_CONTROL, "max-age=86400"),
+            ],
+            thumbnail,
+        )
+            .into_response())
+    }
+}
+
+fn generate_thumbnail(data: &[u8], size: u32, format: ThumbnailFormat) -> io::Result<Vec<u8>> {
+    let image =
+        image::load_from_memory(data).map_err(|err| io::Error::new(ErrorKind::InvalidData, err))?;
+    let thumbnail = image.thumbnail(size, size);
+
+    let mut buf = Cursor::new(Vec::new());
+    match format {
+        ThumbnailFormat::Png => thumbnail.write_to(&mut buf, ImageOutputFormat::Png),
+
+        // jpeg doesn't support alpha channel
+        ThumbnailFormat::Jpeg => DynamicImage::ImageRgb8(thumbnail.to_rgb8())
+            .write_to(&mut buf, ImageOutputFormat::Jpeg(JPEG_QUALITY)),
+    }
+    .map_err(|err| io::Error::new(ErrorKind::Other, err))?;
+
+    Ok(buf.into_inner())
+}
+
+/// write the thumbnail into a temp file then rename it, so a half written thumbnail will never
+/// be read
+async fn cache_thumbnail(
+    thumbnail_dir: &FsPath,
+    thumbnail_path: &FsPath,
+    thumbnail: &[u8],
+) -> io::Result<()> {
+    fs::create_dir_all(thumbnail_dir).await?;
+
+    let tmp_path = thumbnail_dir.join(format!(
+        ".tmp.{}",
+        Alphanumeric.sample_string(&mut rand::thread_rng(), 16)
+    ));
+    fs::write(&tmp_path, thumbnail).await?;
+
+    if let Err(err) = fs::rename(&tmp_path, thumbnail_path).await {
+        let _ = fs::remove_file(&tmp_path).await;
+
+        return Err(err);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{ImageBuffer, Rgba};
+
+    use super::*;
+
+    #[test]
+    fn test_generate_thumbnail() {
+        let image =
+            DynamicImage::ImageRgba8(ImageBuffer::from_pixel(512, 256, Rgba([255, 0, 0, 128])));
+        let mut data = Cursor::new(Vec::new());
+        image.write_to(&mut data, ImageOutputFormat::Png).unwrap();
+        let data = data.into_inner();
+
+        for format in [ThumbnailFormat::Png, ThumbnailFormat::Jpeg] {
+            let thumbnail = generate_thumbnail(&data, 128, format).unwrap();
+            let thumbnail = image::load_from_memory(&thumbnail).unwrap();
+
+            assert_eq!(thumbnail.width(), 128);
+            assert_eq!(thumbnail.height(), 64);
+        }
+
+        assert!(generate_thumbnail(b"not an image", 128, ThumbnailFormat::Png).is_err());
+    }
+}
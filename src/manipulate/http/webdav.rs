@@ -0,0 +1,265 @@
+use std::fmt::Write;
+use std::io;
+use std::io::ErrorKind;
+
+use axum::body::{Body, BoxBody};
+use axum::extract::{Path, Query, State};
+use axum::response::IntoResponse;
+use axum::routing::any;
+use axum::Router;
+use futures_util::TryStreamExt;
+use http::header::{ALLOW, CONTENT_TYPE};
+use http::{HeaderName, Request, Response, StatusCode};
+use tracing::{error, info, instrument};
+use xml::escape::escape_str_pcdata;
+
+use super::error::ApiError;
+use super::response::GetFileQuery;
+use super::{encode_path_segment, Server};
+use crate::command::ListFileDetail;
+
+pub const WEBDAV_PREFIX: &str = "/webdav";
+
+const PROPFIND_METHOD: &str = "PROPFIND";
+static DEPTH: HeaderName = HeaderName::from_static("depth");
+static DAV: HeaderName = HeaderName::from_static("dav");
+
+pub fn router() -> Router<Server> {
+    Router::new()
+        .route(
+            "/",
+            any(|State(mut server): State<Server>, request| async move {
+                server.handle_webdav(None, request).await
+            }),
+        )
+        .route(
+            "/:filename",
+            any(
+                |State(mut server): State<Server>, Path(filename), request| async move {
+                    server.handle_webdav(Some(filename), request).await
+                },
+            ),
+        )
+}
+
+impl Server {
+    /// handle the webdav request, the store dir is mapped as a flat collection, only the local
+    /// stored files are listed
+    #[instrument(skip(self, request))]
+    async fn handle_webdav(
+        &mut self,
+        filename: Option<String>,
+        request: Request<Body>,
+    ) -> Result<Response<BoxBody>, ApiError> {
+        let method = request.method().clone();
+
+        info!(%method, ?filename, "handle webdav request");
+
+        match (method.as_str(), filename) {
+            ("OPTIONS", _) => Ok((
+                [(DAV.clone(), "1"), (ALLOW, self.webdav_allow_methods())],
+                (),
+            )
+                .into_response()),
+
+            (PROPFIND_METHOD, filename) => {
+                let depth = request
+                    .headers()
+                    .get(&DEPTH)
+                    .and_then(|depth| depth.to_str().ok())
+                    .unwrap_or("infinity");
+
+                self.handle_webdav_propfind(filename, depth != "0").await
+            }
+
+            ("GET" | "HEAD", Some(filename)) => Ok(self
+                .handle_get_file(
+                    request,
+                    Path(filename),
+                    Query(GetFileQuery {
+                        download: None,
+                        timeout: None,
+                    }),
+                )
+                .await?
+                .into_response()),
+
+            ("PUT", Some(filename)) => {
+                if !self.webdav_writable {
+                    error!(%filename, "webdav is read only");
+
+                    return Err(ApiError::new(StatusCode::FORBIDDEN, "webdav is read only"));
+                }
+
+                let data = request
+                    .into_body()
+                    .map_err(|err| io::Error::new(ErrorKind::Other, err));
+
+                self.upload_file(filename, None, None, data).await?;
+
+                Ok(StatusCode::CREATED.into_response())
+            }
+
+            (_, filename) => {
+                error!(%method, ?filename, "webdav method not allowed");
+
+                Err(ApiError::new(
+                    StatusCode::METHOD_NOT_ALLOWED,
+                    format!("method {method} not allowed"),
+                ))
+            }
+        }
+    }
+
+    async fn handle_webdav_propfind(
+        &mut self,
+        filename: Option<String>,
+        include_children: bool,
+    ) -> Result<Response<BoxBody>, ApiError> {
+        let details = self.list_file_details(false, false).await?;
+
+        let multi_status = match filename {
+            None => render_multi_status(true, if include_children { &details } else { &[] }),
+
+            Some(filename) => {
+                let detail = details
+                    .into_iter()
+                    .find(|detail| detail.filename == filename)
+                    .ok_or_else(|| {
+                        error!(%filename, "file not exists");
+
+                        ApiError::new(StatusCode::NOT_FOUND, format!("file {filename} not exists"))
+                    })?;
+
+                render_multi_status(false, &[detail])
+            }
+        };
+
+        Ok((
+            StatusCode::MULTI_STATUS,
+            [(CONTENT_TYPE, "application/xml; charset=utf-8")],
+            multi_status,
+        )
+            .into_response())
+    }
+
+    fn webdav_allow_methods(&self) -> &'static str {
+        if self.webdav_writable {
+            "OPTIONS, PROPFIND, GET, HEAD, PUT"
+        } else {
+            "OPTIONS, PROPFIND, GET, HEAD"
+        }
+    }
+}
+
+/// render the PROPFIND multi status response, the root collection is included when
+/// `include_collection` is true
+fn render_multi_status(include_collection: bool, files: &[ListFileDetail]) -> String {
+    let mut multi_status = String::from(
+        r#"<?xml version="1.0" encoding="utf-8"?>
+<D:multistatus xmlns:D="DAV:">
+"#,
+    );
+
+    // write into String never fails
+    if include_collection {
+        let _ = writeln!(
+            multi_status,
+            r#"<D:response><D:href>{WEBDAV_PREFIX}/</D:href><D:propstat><D:prop><D:resourcetype><D:collection/></D:resourcetype></D:prop><D:status>HTTP/1.1 200 OK</D:status></D:propstat></D:response>"#
+        );
+    }
+
+    for file in files {
+        let content_type = mime_guess::from_path(&file.filename).first_or_octet_stream();
+
+        let _ = writeln!(
+            multi_status,
+            r#"<D:response><D:href>{WEBDAV_PREFIX}/{}</D:href><D:propstat><D:prop><D:displayname>{}</D:displayname><D:resourcetype/><D:getcontentlength>{}</D:getcontentlength><D:getcontenttype>{}</D:getcontenttype><D:getetag>"{}"</D:getetag></D:prop><D:status>HTTP/1.1 200 OK</D:status></D:propstat></D:response>"#,
+            encode_path_segment(&file.filename),
+            escape_str_pcdata(&file.filename),
+            file.size,
+            escape_str_pcdata(content_type.as_ref()),
+            file.hash,
+        );
+    }
+
+    multi_status.push_str("</D:multistatus>\n");
+
+    multi_status
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_channel::mpsc;
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::manipulate::http::{
+        DEFAULT_API_PREFIX, DEFAULT_COMMAND_SEND_TIMEOUT, DEFAULT_THUMBNAIL_DIR_NAME,
+        DEFAULT_UI_PREFIX,
+    };
+
+    #[tokio::test]
+    async fn test_webdav_options() {
+        let (command_sender, _command_receiver) = mpsc::channel(1);
+        let server = Server::new(
+            command_sender,
+            DEFAULT_API_PREFIX,
+            Some(DEFAULT_UI_PREFIX),
+            None,
+            None,
+            std::path::Path::new(DEFAULT_THUMBNAIL_DIR_NAME),
+            false,
+            None,
+            None,
+            None,
+            None,
+            DEFAULT_COMMAND_SEND_TIMEOUT,
+        )
+        .unwrap();
+
+        let request = Request::builder()
+            .method("OPTIONS")
+            .uri(WEBDAV_PREFIX)
+            .body(Body::empty())
+            .unwrap();
+        let response = server.clone().router().oneshot(request).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[&DAV], "1");
+        assert_eq!(response.headers()[ALLOW], "OPTIONS, PROPFIND, GET, HEAD");
+
+        let request = Request::builder()
+            .method("PUT")
+            .uri(format!("{WEBDAV_PREFIX}/a.txt"))
+            .body(Body::from("hello"))
+            .unwrap();
+        let response = server.router().oneshot(request).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    }
+
+    #[test]
+    fn test_render_multi_status() {
+        let files = vec![ListFileDetail {
+            filename: "a b.txt".to_string(),
+            hash: "ABC".to_string(),
+            downloaded: true,
+            peers: vec![],
+            size: 11,
+            healthy: None,
+            modified: None,
+        }];
+
+        let multi_status = render_multi_status(true, &files);
+
+        assert!(multi_status.contains("<D:href>/webdav/</D:href>"));
+        assert!(multi_status.contains("<D:href>/webdav/a%20b.txt</D:href>"));
+        assert!(multi_status.contains("<D:getcontentlength>11</D:getcontentlength>"));
+        assert!(multi_status.contains("<D:getcontenttype>text/plain</D:getcontenttype>"));
+
+        let multi_status = render_multi_status(false, &files);
+
+        assert!(!multi_status.contains("<D:href>/webdav/</D:href>"));
+    }
+}
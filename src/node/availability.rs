@@ -0,0 +1,343 @@
+use std::cmp::Reverse;
+use std::collections::{HashMap, VecDeque};
+use std::ops::Range;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use libp2p::PeerId;
+
+use crate::command::DownloadSource;
+use crate::node::file_sync::MAX_FILE_CHUNK_SIZE;
+
+/// the recent chunk completions in the window are used to compute the download rate
+const RATE_WINDOW: Duration = Duration::from_secs(10);
+/// the weight of the latest window rate in the smoothed rate
+const RATE_SMOOTHING: f64 = 0.3;
+
+/// the sorted byte ranges, the ranges never overlap or touch each other
+#[derive(Debug, Default, Clone, Eq, PartialEq)]
+pub struct RangeSet {
+    ranges: Vec<Range<u64>>,
+}
+
+impl RangeSet {
+    pub fn insert(&mut self, range: Range<u64>) {
+        if range.is_empty() {
+            return;
+        }
+
+        // the first range which may be merged with the new range
+        let start = self.ranges.partition_point(|r| r.end < range.start);
+        // the first range after the new range
+        let end = self.ranges.partition_point(|r| r.start <= range.end);
+
+        let merged = if start == end {
+            range
+        } else {
+            self.ranges[start].start.min(range.start)..self.ranges[end - 1].end.max(range.end)
+        };
+
+        self.ranges.splice(start..end, [merged]);
+    }
+
+    pub fn contains(&self, range: &Range<u64>) -> bool {
+        if range.is_empty() {
+            return true;
+        }
+
+        let index = self.ranges.partition_point(|r| r.end <= range.start);
+
+        self.ranges
+            .get(index)
+            .map(|r| r.start <= range.start && range.end <= r.end)
+            .unwrap_or(false)
+    }
+
+    pub fn ranges(&self) -> &[Range<u64>] {
+        &self.ranges
+    }
+
+    /// encode the fully available sync chunks of the file as a bitfield, the first chunk is the
+    /// highest bit of the first byte
+    pub fn to_bitfield(&self, size: u64) -> Vec<u8> {
+        let chunks = chunk_count(size);
+        let mut bitfield = vec![0; ((chunks + 7) / 8) as _];
+        for index in 0..chunks {
+            if self.contains(&chunk_range(index, size)) {
+                bitfield[(index / 8) as usize] |= 0x80 >> (index % 8);
+            }
+        }
+
+        bitfield
+    }
+
+    /// decode the bitfield created by [`RangeSet::to_bitfield`], the bits out of the file are
+    /// ignored
+    pub fn from_bitfield(bitfield: &[u8], size: u64) -> Self {
+        let mut range_set = Self::default();
+        for index in 0..chunk_count(size) {
+            let available = bitfield
+                .get((index / 8) as usize)
+                .map(|byte| byte & (0x80 >> (index % 8)) != 0)
+                .unwrap_or(false);
+            if available {
+                range_set.insert(chunk_range(index, size));
+            }
+        }
+
+        range_set
+    }
+}
+
+fn chunk_count(size: u64) -> u64 {
+    (size + MAX_FILE_CHUNK_SIZE - 1) / MAX_FILE_CHUNK_SIZE
+}
+
+fn chunk_range(index: u64, size: u64) -> Range<u64> {
+    let start = index * MAX_FILE_CHUNK_SIZE;
+
+    start..(start + MAX_FILE_CHUNK_SIZE).min(size)
+}
+
+/// the download rate of a file, it is computed from the chunk completions in a sliding window,
+/// and smoothed by the exponential moving average so it doesn't jump between chunks
+#[derive(Debug, Default)]
+struct DownloadRate {
+    /// the completion time and size of the recent chunks
+    samples: VecDeque<(Instant, u64)>,
+    /// bytes per second
+    smoothed: Option<f64>,
+}
+
+impl DownloadRate {
+    fn record(&mut self, now: Instant, bytes: u64) {
+        self.samples.push_back((now, bytes));
+        while self
+            .samples
+            .front()
+            .map_or(false, |(time, _)| now.duration_since(*time) > RATE_WINDOW)
+        {
+            self.samples.pop_front();
+        }
+
+        // the bytes of the first sample are downloaded before the window starts
+        let (start, _) = self.samples[0];
+        let elapsed = now.duration_since(start).as_secs_f64();
+        if elapsed <= 0.0 {
+            return;
+        }
+
+        let bytes = self
+            .samples
+            .iter()
+            .skip(1)
+            .map(|(_, bytes)| bytes)
+            .sum::<u64>();
+        let rate = bytes as f64 / elapsed;
+
+        self.smoothed = Some(match self.smoothed {
+            None => rate,
+            Some(smoothed) => smoothed + RATE_SMOOTHING * (rate - smoothed),
+        });
+    }
+
+    fn bytes_per_sec(&self) -> Option<u64> {
+        self.smoothed.map(|rate| rate as u64)
+    }
+}
+
+/// the available byte ranges of the syncing index files, it is shared with the chunk tasks
+#[derive(Debug, Default, Clone)]
+pub struct Availability {
+    files: Arc<Mutex<HashMap<String, RangeSet>>>,
+    rates: Arc<Mutex<HashMap<String, DownloadRate>>>,
+    /// the bytes downloaded from each peer, keyed by the hash
+    sources: Arc<Mutex<HashMap<String, HashMap<PeerId, u64>>>>,
+}
+
+impl Availability {
+    pub fn insert(&self, hash: &str, range: Range<u64>) {
+        self.files
+            .lock()
+            .unwrap()
+            .entry(hash.to_string())
+            .or_default()
+            .insert(range);
+    }
+
+    pub fn contains(&self, hash: &str, range: &Range<u64>) -> bool {
+        self.files
+            .lock()
+            .unwrap()
+            .get(hash)
+            .map(|range_set| range_set.contains(range))
+            .unwrap_or(false)
+    }
+
+    pub fn get(&self, hash: &str) -> Option<RangeSet> {
+        self.files.lock().unwrap().get(hash).cloned()
+    }
+
+    /// the hashes and available ranges of all syncing files
+    pub fn snapshot(&self) -> Vec<(String, RangeSet)> {
+        self.files
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(hash, range_set)| (hash.clone(), range_set.clone()))
+            .collect()
+    }
+
+    /// record a downloaded chunk of the file for computing its download rate and the bytes
+    /// served by each peer
+    pub fn record_download(&self, hash: &str, peer_id: PeerId, bytes: u64) {
+        self.rates
+            .lock()
+            .unwrap()
+            .entry(hash.to_string())
+            .or_default()
+            .record(Instant::now(), bytes);
+
+        *self
+            .sources
+            .lock()
+            .unwrap()
+            .entry(hash.to_string())
+            .or_default()
+            .entry(peer_id)
+            .or_default() += bytes;
+    }
+
+    /// the bytes of the file downloaded from each peer, the peer serving the most comes first,
+    /// the reused local chunks aren't counted
+    pub fn download_sources(&self, hash: &str) -> Vec<DownloadSource> {
+        let mut sources = self
+            .sources
+            .lock()
+            .unwrap()
+            .get(hash)
+            .map(|sources| {
+                sources
+                    .iter()
+                    .map(|(peer, bytes)| DownloadSource {
+                        peer: *peer,
+                        bytes: *bytes,
+                    })
+                    .collect::<Vec<_>>()
+            })
+            .unwrap_or_default();
+        sources.sort_by_key(|source| Reverse(source.bytes));
+
+        sources
+    }
+
+    /// the smoothed download rate of the file in bytes per second, `None` until enough chunks
+    /// are downloaded
+    pub fn download_rate(&self, hash: &str) -> Option<u64> {
+        self.rates
+            .lock()
+            .unwrap()
+            .get(hash)
+            .and_then(DownloadRate::bytes_per_sec)
+    }
+
+    /// forget the file when it is dropped or moved to the index dir
+    pub fn remove(&self, hash: &str) {
+        self.files.lock().unwrap().remove(hash);
+        self.rates.lock().unwrap().remove(hash);
+        self.sources.lock().unwrap().remove(hash);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_range_set() {
+        let mut range_set = RangeSet::default();
+
+        range_set.insert(10..20);
+        range_set.insert(30..40);
+        range_set.insert(0..0);
+        assert_eq!(range_set.ranges(), [10..20, 30..40]);
+
+        // adjacent ranges are merged
+        range_set.insert(20..25);
+        assert_eq!(range_set.ranges(), [10..25, 30..40]);
+
+        range_set.insert(0..5);
+        assert_eq!(range_set.ranges(), [0..5, 10..25, 30..40]);
+
+        // cover multiple ranges
+        range_set.insert(3..35);
+        assert_eq!(range_set.ranges(), [Range { start: 0, end: 40 }]);
+
+        assert!(range_set.contains(&(0..40)));
+        assert!(range_set.contains(&(10..20)));
+        assert!(!range_set.contains(&(30..41)));
+        assert!(!range_set.contains(&(40..50)));
+    }
+
+    #[test]
+    fn test_bitfield() {
+        let size = MAX_FILE_CHUNK_SIZE * 9 + 1;
+        let mut range_set = RangeSet::default();
+        range_set.insert(0..MAX_FILE_CHUNK_SIZE);
+        // the partial chunk is not available
+        range_set.insert(MAX_FILE_CHUNK_SIZE * 2..MAX_FILE_CHUNK_SIZE * 2 + 1);
+        range_set.insert(MAX_FILE_CHUNK_SIZE * 8..size);
+
+        let bitfield = range_set.to_bitfield(size);
+        assert_eq!(bitfield, [0b1000_0000, 0b1100_0000]);
+
+        let decoded = RangeSet::from_bitfield(&bitfield, size);
+        assert_eq!(
+            decoded.ranges(),
+            [0..MAX_FILE_CHUNK_SIZE, MAX_FILE_CHUNK_SIZE * 8..size]
+        );
+
+        assert_eq!(RangeSet::from_bitfield(&[], size), RangeSet::default());
+    }
+
+    #[test]
+    fn test_download_sources() {
+        let (a, b) = (PeerId::random(), PeerId::random());
+        let availability = Availability::default();
+        availability.record_download("HASH", a, 10);
+        availability.record_download("HASH", b, 30);
+        availability.record_download("HASH", a, 5);
+
+        assert_eq!(
+            availability.download_sources("HASH"),
+            [
+                DownloadSource { peer: b, bytes: 30 },
+                DownloadSource { peer: a, bytes: 15 },
+            ]
+        );
+
+        availability.remove("HASH");
+        assert!(availability.download_sources("HASH").is_empty());
+    }
+
+    #[test]
+    fn test_download_rate() {
+        let start = Instant::now();
+        let mut rate = DownloadRate::default();
+
+        rate.record(start, 100);
+        assert_eq!(rate.bytes_per_sec(), None);
+
+        rate.record(start + Duration::from_secs(1), 100);
+        assert_eq!(rate.bytes_per_sec(), Some(100));
+
+        // a burst is smoothed
+        rate.record(start + Duration::from_secs(2), 1100);
+        assert_eq!(rate.bytes_per_sec(), Some(250));
+
+        // the samples out of the window are dropped
+        rate.record(start + Duration::from_secs(30), 100);
+        assert_eq!(rate.bytes_per_sec(), Some(250));
+        assert_eq!(rate.samples.len(), 1);
+    }
+}
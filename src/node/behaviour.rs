@@ -1,18 +1,24 @@
 use std::collections::hash_map::DefaultHasher;
+use std::future::Future;
 use std::hash::{Hash, Hasher};
 use std::io;
 use std::io::{Error, ErrorKind};
+use std::pin::Pin;
+use std::str::FromStr;
+use std::task::{ready, Context, Poll};
 use std::time::Duration;
 
+use anyhow::anyhow;
 use async_trait::async_trait;
 use bytes::Bytes;
 use either::Either;
 use futures_util::{AsyncRead, AsyncWrite, AsyncWriteExt};
 use libp2p::core::upgrade::{read_length_prefixed, write_length_prefixed};
 use libp2p::core::ProtocolName;
+use libp2p::gossipsub::error::PublishError;
 use libp2p::gossipsub::{
     Gossipsub, GossipsubConfigBuilder, GossipsubMessage, MessageAuthenticity, MessageId,
-    Sha256Topic, ValidationMode,
+    Sha256Topic, TopicHash, ValidationMode,
 };
 use libp2p::identity::Keypair;
 use libp2p::request_response::{
@@ -21,26 +27,53 @@ use libp2p::request_response::{
 use libp2p::swarm::{dummy, keep_alive, NetworkBehaviour};
 use libp2p::{identify, ping};
 use libp2p_auto_relay::{endpoint, relay};
-use once_cell::sync::Lazy;
 use prost::Message;
+use sha2::{Digest, Sha256};
 use tap::TapFallible;
-use tracing::{error, info, instrument};
+use tokio::time;
+use tokio::time::{Instant, Sleep};
+use tracing::{error, info, instrument, warn};
+
+use crate::node::buffer_pool::BufferPool;
+use crate::node::chunking::ChunkDigest;
+use crate::node::message::FileMessage;
 
 /// max 16MiB
 pub const MAX_CHUNK_SIZE: usize = 16 * 1024 * 1024;
+/// the max size of a gossip rpc, a larger published message is rejected
+const MAX_GOSSIP_TRANSMIT_SIZE: usize = 64 * 1024;
+/// the max size of a file message, the rpc also carries the topic, the source and the signature
+/// of the gossip message
+pub const MAX_FILE_MESSAGE_SIZE: usize = MAX_GOSSIP_TRANSMIT_SIZE - 2 * 1024;
 const IDENTIFY_PROTOCOL: &str = "private-share-identify/0.1.0";
+/// the peers see the software version by identify
+const AGENT_VERSION: &str = concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION"));
 
-pub static FILE_SHARE_TOPIC: Lazy<Sha256Topic> = Lazy::new(|| {
-    const TOPIC: &str = "private-share";
-
-    Sha256Topic::new(TOPIC)
-});
+const FILE_SHARE_TOPIC: &str = "private-share";
 
-pub static DISCOVER_SHARE_TOPIC: Lazy<Sha256Topic> = Lazy::new(|| {
-    const TOPIC: &str = "private-share/discover";
+/// the gossip topics of the share network, the nodes with different network ids never see the
+/// messages of each other
+#[derive(Debug, Clone)]
+pub struct ShareTopics {
+    pub file: Sha256Topic,
+    pub discover: Sha256Topic,
+}
 
-    Sha256Topic::new(TOPIC)
-});
+impl ShareTopics {
+    /// the topics without the network id are the original ones, so the nodes without it keep
+    /// talking to each other
+    pub fn new(network_id: Option<&str>) -> Self {
+        let file = match network_id {
+            None => FILE_SHARE_TOPIC.to_string(),
+            Some(network_id) => format!("{FILE_SHARE_TOPIC}@{network_id}"),
+        };
+
+        Self {
+            discover: Sha256Topic::new(format!("{file}/discover")),
+            file: Sha256Topic::new(file),
+        }
+    }
+}
 
 #[derive(NetworkBehaviour)]
 pub struct Behaviour {
@@ -54,42 +87,53 @@ pub struct Behaviour {
 }
 
 impl Behaviour {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         key: Keypair,
+        validation_mode: ValidationMode,
         enable_relay_behaviour: bool,
+        identify_push: bool,
         endpoint_behaviour: Option<endpoint::Behaviour>,
+        max_file_protocol: FileProtocol,
+        file_read_idle_timeout: Duration,
+        buffer_pool: BufferPool,
+        topics: &ShareTopics,
     ) -> anyhow::Result<Self> {
         let public_key = key.public();
+        let message_authenticity = message_authenticity(&validation_mode, key);
+        let file_topic = topics.file.hash();
 
         let gossipsub_config = GossipsubConfigBuilder::default()
             // This is set to aid debugging by not cluttering the log space
             .heartbeat_interval(Duration::from_secs(10))
             // This sets the kind of message validation. The default is Strict (enforce message signing)
-            .validation_mode(ValidationMode::Strict)
+            .validation_mode(validation_mode)
             // content-address messages. No two messages of the same content will be propagated.
-            .message_id_fn(create_gossip_message_id)
+            .message_id_fn(move |message| create_gossip_message_id(&file_topic, message))
+            .max_transmit_size(MAX_GOSSIP_TRANSMIT_SIZE)
             .build()
             .map_err(|err| anyhow::anyhow!("{}", err))?;
 
-        let mut gossipsub = Gossipsub::new(MessageAuthenticity::Signed(key), gossipsub_config)
+        let mut gossipsub = Gossipsub::new(message_authenticity, gossipsub_config)
             .map_err(|err| anyhow::anyhow!("{}", err))?;
 
-        gossipsub.subscribe(&FILE_SHARE_TOPIC)?;
-        gossipsub.subscribe(&DISCOVER_SHARE_TOPIC)?;
+        gossipsub.subscribe(&topics.file)?;
+        gossipsub.subscribe(&topics.discover)?;
 
         Ok(Self {
             gossip: gossipsub,
             request_respond: RequestResponse::new(
-                FileCodec,
-                [(FileProtocol, ProtocolSupport::Full)],
+                FileCodec::new(file_read_idle_timeout, buffer_pool),
+                max_file_protocol.supported_protocols(),
                 RequestResponseConfig::default(),
             ),
             keepalive: Default::default(),
             ping: Default::default(),
-            identify: identify::Behaviour::new(identify::Config::new(
-                IDENTIFY_PROTOCOL.to_string(),
-                public_key,
-            )),
+            identify: identify::Behaviour::new(
+                identify::Config::new(IDENTIFY_PROTOCOL.to_string(), public_key)
+                    .with_agent_version(AGENT_VERSION.to_string())
+                    .with_push_listen_addr_updates(identify_push),
+            ),
             relay: enable_relay_behaviour
                 .then(|| Either::Right(Default::default()))
                 .unwrap_or(Either::Left(dummy::Behaviour {})),
@@ -100,23 +144,143 @@ impl Behaviour {
     }
 }
 
-fn create_gossip_message_id(message: &GossipsubMessage) -> MessageId {
+/// Anonymous validation mode rejects messages which contain author or signature, so the published
+/// messages must be anonymous too, the others publish signed messages
+fn message_authenticity(validation_mode: &ValidationMode, key: Keypair) -> MessageAuthenticity {
+    match validation_mode {
+        ValidationMode::Anonymous => MessageAuthenticity::Anonymous,
+        _ => MessageAuthenticity::Signed(key),
+    }
+}
+
+/// publish the message to the topic, the node may have no connected peer yet, and the message id
+/// is content addressed, so the same content may be published again before it leaves the
+/// duplicate cache, neither is an error
+pub fn publish_message(
+    gossip: &mut Gossipsub,
+    topic: &Sha256Topic,
+    data: Vec<u8>,
+) -> Result<(), PublishError> {
+    match gossip.publish(topic.clone(), data) {
+        Err(PublishError::InsufficientPeers) => {
+            info!(?topic, "no peer connected, skip publishing");
+
+            Ok(())
+        }
+
+        Err(PublishError::Duplicate) => {
+            info!(
+                ?topic,
+                "same message is published recently, skip publishing"
+            );
+
+            Ok(())
+        }
+
+        Err(err) => Err(err),
+
+        Ok(_) => {
+            info!(?topic, "publish message done");
+
+            Ok(())
+        }
+    }
+}
+
+/// the file message id ignores the volatile `refresh_time` and signature, so the refreshes of an
+/// unchanged file list are deduplicated while a changed list always gets a new id, a list which
+/// is changed back is propagated again when the duplicate cache expires, the parts of a split
+/// list keep the `refresh_time`, otherwise the unchanged parts are dropped and the receivers
+/// can't reassemble the list
+fn create_gossip_message_id(file_topic: &TopicHash, message: &GossipsubMessage) -> MessageId {
     let mut s = DefaultHasher::new();
-    message.data.hash(&mut s);
+
+    let file_message = (message.topic == *file_topic)
+        .then(|| FileMessage::decode(message.data.as_slice()).ok())
+        .flatten();
+    match file_message {
+        None => message.data.hash(&mut s),
+
+        Some(file_message) => {
+            message.source.hash(&mut s);
+            file_message.peer_id.hash(&mut s);
+            for file in &file_message.file_list {
+                file.encode_to_vec().hash(&mut s);
+            }
+            if file_message.total_parts > 0 {
+                file_message.refresh_time.hash(&mut s);
+                file_message.part.hash(&mut s);
+                file_message.total_parts.hash(&mut s);
+            }
+        }
+    }
+
     MessageId::from(s.finish().to_string())
 }
 
-#[derive(Clone)]
-pub struct FileProtocol;
+/// the versions of the file request protocol, the dialer proposes the supported versions from
+/// the highest, so the highest common version is negotiated and the old peers still use `V1`
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq, Ord, PartialOrd)]
+pub enum FileProtocol {
+    V1,
+    /// the response carries the sha256 checksum of the chunk, the corrupted chunk is rejected
+    /// before it is written
+    #[default]
+    V2,
+}
+
+impl FileProtocol {
+    const ALL: [FileProtocol; 2] = [FileProtocol::V1, FileProtocol::V2];
+
+    /// the versions up to `self`, the highest version is the first
+    fn supported_protocols(self) -> Vec<(FileProtocol, ProtocolSupport)> {
+        FileProtocol::ALL
+            .into_iter()
+            .rev()
+            .filter(|protocol| *protocol <= self)
+            .map(|protocol| (protocol, ProtocolSupport::Full))
+            .collect()
+    }
+}
 
 impl ProtocolName for FileProtocol {
     fn protocol_name(&self) -> &[u8] {
-        "/file-share/1".as_bytes()
+        match self {
+            FileProtocol::V1 => "/file-share/1".as_bytes(),
+            FileProtocol::V2 => "/file-share/2".as_bytes(),
+        }
+    }
+}
+
+impl FromStr for FileProtocol {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "v1" => Ok(FileProtocol::V1),
+            "v2" => Ok(FileProtocol::V2),
+            _ => Err(anyhow!("unknown file protocol {s}")),
+        }
     }
 }
 
 #[derive(Debug, Clone)]
-pub struct FileCodec;
+pub struct FileCodec {
+    /// the stalled transfer is aborted when nothing is read for it, the codec error closes the
+    /// connection, so the request fails with the peer and request id
+    read_idle_timeout: Duration,
+    /// the content of the encoded response is returned to it for the next file reads
+    buffer_pool: BufferPool,
+}
+
+impl FileCodec {
+    pub fn new(read_idle_timeout: Duration, buffer_pool: BufferPool) -> Self {
+        Self {
+            read_idle_timeout,
+            buffer_pool,
+        }
+    }
+}
 
 #[async_trait]
 impl RequestResponseCodec for FileCodec {
@@ -133,7 +297,8 @@ impl RequestResponseCodec for FileCodec {
     where
         T: AsyncRead + Unpin + Send,
     {
-        let data = read_length_prefixed(io, MAX_CHUNK_SIZE)
+        let mut io = IdleReader::new(io, self.read_idle_timeout);
+        let data = read_length_prefixed(&mut io, MAX_CHUNK_SIZE)
             .await
             .tap_err(|err| error!(%err, "read request failed"))?;
         if data.is_empty() {
@@ -151,16 +316,17 @@ impl RequestResponseCodec for FileCodec {
         })?)
     }
 
-    #[instrument(err, skip(self, _protocol, io))]
+    #[instrument(err, skip(self, io))]
     async fn read_response<T>(
         &mut self,
-        _protocol: &Self::Protocol,
+        protocol: &Self::Protocol,
         io: &mut T,
     ) -> io::Result<Self::Response>
     where
         T: AsyncRead + Unpin + Send,
     {
-        let data = read_length_prefixed(io, MAX_CHUNK_SIZE)
+        let mut io = IdleReader::new(io, self.read_idle_timeout);
+        let data = read_length_prefixed(&mut io, MAX_CHUNK_SIZE)
             .await
             .tap_err(|err| error!(%err, "read response failed"))?;
         if data.is_empty() {
@@ -171,11 +337,23 @@ impl RequestResponseCodec for FileCodec {
 
         info!("read response data done");
 
-        Ok(FileResponse::decode(data.as_slice()).map_err(|err| {
-            error!(%err, "decode file response failed");
+        match protocol {
+            FileProtocol::V1 => Ok(FileResponse::decode(data.as_slice()).map_err(|err| {
+                error!(%err, "decode file response failed");
 
-            Error::new(ErrorKind::Other, err)
-        })?)
+                Error::new(ErrorKind::Other, err)
+            })?),
+
+            FileProtocol::V2 => {
+                let response = ChecksumFileResponse::decode(data.as_slice()).map_err(|err| {
+                    error!(%err, "decode checksum file response failed");
+
+                    Error::new(ErrorKind::Other, err)
+                })?;
+
+                response.verify()
+            }
+        }
     }
 
     #[instrument(err, skip(self, _protocol, io, req))]
@@ -201,17 +379,29 @@ impl RequestResponseCodec for FileCodec {
         Ok(())
     }
 
-    #[instrument(err, skip(self, _protocol, io, resp))]
+    #[instrument(err, skip(self, io, resp))]
     async fn write_response<T>(
         &mut self,
-        _protocol: &Self::Protocol,
+        protocol: &Self::Protocol,
         io: &mut T,
         resp: Self::Response,
     ) -> io::Result<()>
     where
         T: AsyncWrite + Unpin + Send,
     {
-        let data = resp.encode_to_vec();
+        let (data, content) = match protocol {
+            FileProtocol::V1 => (resp.encode_to_vec(), resp.content),
+            FileProtocol::V2 => {
+                let resp = ChecksumFileResponse::new(resp);
+
+                (resp.encode_to_vec(), resp.content)
+            }
+        };
+
+        // the content is copied into the encoded data, so its buffer can be read into again
+        if let Some(content) = content {
+            self.buffer_pool.put_content(content);
+        }
 
         write_length_prefixed(io, data)
             .await
@@ -225,6 +415,49 @@ impl RequestResponseCodec for FileCodec {
     }
 }
 
+/// fail the read with [`ErrorKind::TimedOut`] when the inner reader makes no progress for the
+/// timeout, the timer is reset by every read data
+struct IdleReader<'a, T> {
+    inner: &'a mut T,
+    timeout: Duration,
+    sleep: Pin<Box<Sleep>>,
+}
+
+impl<'a, T> IdleReader<'a, T> {
+    fn new(inner: &'a mut T, timeout: Duration) -> Self {
+        Self {
+            inner,
+            timeout,
+            sleep: Box::pin(time::sleep(timeout)),
+        }
+    }
+}
+
+impl<T: AsyncRead + Unpin> AsyncRead for IdleReader<'_, T> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+
+        if let Poll::Ready(result) = Pin::new(&mut *this.inner).poll_read(cx, buf) {
+            this.sleep.as_mut().reset(Instant::now() + this.timeout);
+
+            return Poll::Ready(result);
+        }
+
+        ready!(this.sleep.as_mut().poll(cx));
+
+        warn!(timeout = ?this.timeout, "file transfer read idle timeout");
+
+        Poll::Ready(Err(Error::new(
+            ErrorKind::TimedOut,
+            format!("read nothing for {:?}", this.timeout),
+        )))
+    }
+}
+
 #[derive(Message, Clone)]
 pub struct FileRequest {
     #[prost(string, tag = "1")]
@@ -238,10 +471,316 @@ pub struct FileRequest {
 
     #[prost(uint64, tag = "4")]
     pub length: u64,
+
+    /// request the content defined chunk digests of the whole file instead of the content, the
+    /// request has zero length so the old peers which ignore it respond the empty content
+    #[prost(bool, tag = "5")]
+    pub chunk_digests: bool,
 }
 
 #[derive(Message, Clone)]
 pub struct FileResponse {
     #[prost(bytes = "bytes", optional, tag = "1")]
     pub content: Option<Bytes>,
+
+    /// the peer rejected the request because it has too many inbound requests from us, the
+    /// request can be retried later
+    #[prost(bool, tag = "3")]
+    pub busy: bool,
+
+    /// the content defined chunk digests of the file, it is only set for the chunk digests
+    /// request
+    #[prost(message, repeated, tag = "4")]
+    pub chunk_digests: Vec<ChunkDigest>,
+}
+
+/// the [`FileResponse`] of [`FileProtocol::V2`], the `content` keeps the same tag
+#[derive(Message, Clone)]
+struct ChecksumFileResponse {
+    #[prost(bytes = "bytes", optional, tag = "1")]
+    content: Option<Bytes>,
+
+    /// the sha256 of the `content`, it is empty when there is no content
+    #[prost(bytes = "bytes", tag = "2")]
+    checksum: Bytes,
+
+    #[prost(bool, tag = "3")]
+    busy: bool,
+
+    #[prost(message, repeated, tag = "4")]
+    chunk_digests: Vec<ChunkDigest>,
+}
+
+impl ChecksumFileResponse {
+    fn new(resp: FileResponse) -> Self {
+        let checksum = resp
+            .content
+            .as_ref()
+            .map(|content| Bytes::from(Sha256::digest(content).to_vec()))
+            .unwrap_or_default();
+
+        Self {
+            content: resp.content,
+            checksum,
+            busy: resp.busy,
+            chunk_digests: resp.chunk_digests,
+        }
+    }
+
+    fn verify(self) -> io::Result<FileResponse> {
+        if let Some(content) = &self.content {
+            if self.checksum != Sha256::digest(content).as_slice() {
+                error!(len = content.len(), "file response checksum mismatch");
+
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    "file response checksum mismatch",
+                ));
+            }
+        }
+
+        Ok(FileResponse {
+            content: self.content,
+            busy: self.busy,
+            chunk_digests: self.chunk_digests,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::node::config::DEFAULT_FILE_READ_IDLE_TIMEOUT;
+    use crate::node::message::File;
+
+    /// the peer which opens the substream and sends nothing
+    struct StalledReader;
+
+    impl AsyncRead for StalledReader {
+        fn poll_read(
+            self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            _buf: &mut [u8],
+        ) -> Poll<io::Result<usize>> {
+            Poll::Pending
+        }
+    }
+
+    #[test]
+    fn test_create_behaviour_with_validation_mode() {
+        for validation_mode in [
+            ValidationMode::Strict,
+            ValidationMode::Permissive,
+            ValidationMode::Anonymous,
+            ValidationMode::None,
+        ] {
+            let is_anonymous = matches!(validation_mode, ValidationMode::Anonymous);
+            let authenticity = message_authenticity(&validation_mode, Keypair::generate_ed25519());
+
+            assert_eq!(authenticity.is_anonymous(), is_anonymous);
+
+            Behaviour::new(
+                Keypair::generate_ed25519(),
+                validation_mode,
+                false,
+                false,
+                None,
+                FileProtocol::default(),
+                DEFAULT_FILE_READ_IDLE_TIMEOUT,
+                BufferPool::new(0),
+                &ShareTopics::new(None),
+            )
+            .unwrap();
+        }
+    }
+
+    #[test]
+    fn test_share_topics() {
+        let topics = ShareTopics::new(None);
+        assert_eq!(topics.file.hash(), Sha256Topic::new("private-share").hash());
+        assert_eq!(
+            topics.discover.hash(),
+            Sha256Topic::new("private-share/discover").hash()
+        );
+
+        let home_topics = ShareTopics::new(Some("home"));
+        assert_ne!(home_topics.file.hash(), topics.file.hash());
+        assert_ne!(home_topics.discover.hash(), topics.discover.hash());
+        assert_ne!(
+            home_topics.file.hash(),
+            ShareTopics::new(Some("office")).file.hash()
+        );
+    }
+
+    #[test]
+    fn test_supported_file_protocols() {
+        let protocols = |max_file_protocol: FileProtocol| {
+            max_file_protocol
+                .supported_protocols()
+                .into_iter()
+                .map(|(protocol, _)| protocol)
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            protocols(FileProtocol::V2),
+            [FileProtocol::V2, FileProtocol::V1]
+        );
+        assert_eq!(protocols(FileProtocol::V1), [FileProtocol::V1]);
+    }
+
+    #[tokio::test]
+    async fn test_file_response_checksum() {
+        let response = || FileResponse {
+            content: Some(Bytes::from_static(b"chunk")),
+            busy: false,
+            chunk_digests: vec![],
+        };
+
+        for protocol in FileProtocol::ALL {
+            let mut io = futures_util::io::Cursor::new(vec![]);
+            FileCodec::new(DEFAULT_FILE_READ_IDLE_TIMEOUT, BufferPool::new(0))
+                .write_response(&protocol, &mut io, response())
+                .await
+                .unwrap();
+
+            io.set_position(0);
+            let read_response = FileCodec::new(DEFAULT_FILE_READ_IDLE_TIMEOUT, BufferPool::new(0))
+                .read_response(&protocol, &mut io)
+                .await
+                .unwrap();
+            assert_eq!(read_response.content, response().content);
+        }
+
+        let mut checksum_response = ChecksumFileResponse::new(response());
+        checksum_response.content = Some(Bytes::from_static(b"other"));
+        assert_eq!(
+            checksum_response.verify().unwrap_err().kind(),
+            ErrorKind::InvalidData
+        );
+
+        // the v1 response has no checksum, so it is rejected by v2
+        let mut io = futures_util::io::Cursor::new(vec![]);
+        FileCodec::new(DEFAULT_FILE_READ_IDLE_TIMEOUT, BufferPool::new(0))
+            .write_response(&FileProtocol::V1, &mut io, response())
+            .await
+            .unwrap();
+        io.set_position(0);
+        assert_eq!(
+            FileCodec::new(DEFAULT_FILE_READ_IDLE_TIMEOUT, BufferPool::new(0))
+                .read_response(&FileProtocol::V2, &mut io)
+                .await
+                .unwrap_err()
+                .kind(),
+            ErrorKind::InvalidData
+        );
+    }
+
+    #[tokio::test]
+    async fn test_reuse_encoded_response_buffer() {
+        for protocol in FileProtocol::ALL {
+            let buffer_pool = BufferPool::new(1);
+            let mut buf = buffer_pool.get();
+            buf.reserve(1024);
+            buf.extend_from_slice(b"chunk");
+            let content = buf.freeze();
+            let ptr = content.as_ptr();
+
+            let response = FileResponse {
+                content: Some(content),
+                busy: false,
+                chunk_digests: vec![],
+            };
+            let mut io = futures_util::io::Cursor::new(vec![]);
+            FileCodec::new(DEFAULT_FILE_READ_IDLE_TIMEOUT, buffer_pool.clone())
+                .write_response(&protocol, &mut io, response)
+                .await
+                .unwrap();
+
+            // the next read borrows the allocation of the encoded response
+            let mut buf = buffer_pool.get();
+            assert!(buf.is_empty());
+            buf.reserve(1024);
+            assert_eq!(buf.as_ptr(), ptr);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_read_idle_timeout() {
+        let mut codec = FileCodec::new(Duration::from_millis(50), BufferPool::new(0));
+
+        assert_eq!(
+            codec
+                .read_request(&FileProtocol::V2, &mut StalledReader)
+                .await
+                .unwrap_err()
+                .kind(),
+            ErrorKind::TimedOut
+        );
+        assert_eq!(
+            codec
+                .read_response(&FileProtocol::V2, &mut StalledReader)
+                .await
+                .unwrap_err()
+                .kind(),
+            ErrorKind::TimedOut
+        );
+    }
+
+    #[test]
+    fn test_file_message_id_ignores_refresh_time() {
+        let topics = ShareTopics::new(None);
+        let key = Keypair::generate_ed25519();
+        let gossip_message = |file_size, refresh_time, total_parts| {
+            let mut message = FileMessage {
+                peer_id: key.public().to_peer_id().to_base58(),
+                file_list: vec![File {
+                    filename: "test.txt".to_string(),
+                    hash: "HASH".to_string(),
+                    file_size,
+                    chunks: None,
+                }],
+                refresh_time,
+                signature: vec![],
+                public_key: vec![],
+                part: 0,
+                total_parts,
+            };
+            message.sign(&key).unwrap();
+
+            GossipsubMessage {
+                source: Some(key.public().to_peer_id()),
+                data: message.encode_to_vec(),
+                sequence_number: Some(refresh_time),
+                topic: topics.file.hash(),
+            }
+        };
+        let message_id =
+            |message: &GossipsubMessage| create_gossip_message_id(&topics.file.hash(), message);
+
+        assert_eq!(
+            message_id(&gossip_message(1, 1, 0)),
+            message_id(&gossip_message(1, 2, 0))
+        );
+        assert_ne!(
+            message_id(&gossip_message(1, 1, 0)),
+            message_id(&gossip_message(2, 1, 0))
+        );
+
+        // the unchanged part of a split list is propagated with the changed parts
+        assert_ne!(
+            message_id(&gossip_message(1, 1, 2)),
+            message_id(&gossip_message(1, 2, 2))
+        );
+
+        // the other messages are addressed by the whole content
+        let mut discover_message = gossip_message(1, 1, 0);
+        discover_message.topic = topics.discover.hash();
+        let mut other_discover_message = gossip_message(1, 2, 0);
+        other_discover_message.topic = topics.discover.hash();
+        assert_ne!(
+            message_id(&discover_message),
+            message_id(&other_discover_message)
+        );
+    }
 }
@@ -0,0 +1,80 @@
+use std::sync::{Arc, Mutex};
+
+use bytes::{Bytes, BytesMut};
+
+/// a small pool of read buffers which are reused across file requests
+///
+/// the read buffer is frozen as the response content, the codec returns the content to the pool
+/// after the response is encoded, the allocation is reused only when nothing else holds the
+/// content, so concurrent requests never share the same bytes
+#[derive(Debug, Clone)]
+pub struct BufferPool {
+    buffers: Arc<Mutex<Vec<BytesMut>>>,
+    max_buffers: usize,
+}
+
+impl BufferPool {
+    pub fn new(max_buffers: usize) -> Self {
+        Self {
+            buffers: Arc::new(Mutex::new(Vec::with_capacity(max_buffers))),
+            max_buffers,
+        }
+    }
+
+    /// borrow an empty buffer from the pool
+    pub fn get(&self) -> BytesMut {
+        self.buffers.lock().unwrap().pop().unwrap_or_default()
+    }
+
+    /// return the buffer to the pool, it is dropped when the pool is full
+    pub fn put(&self, mut buf: BytesMut) {
+        let mut buffers = self.buffers.lock().unwrap();
+        if buffers.len() >= self.max_buffers {
+            return;
+        }
+
+        buf.clear();
+        buffers.push(buf);
+    }
+
+    /// return the content of the encoded response to the pool, it is dropped when the content is
+    /// still shared
+    pub fn put_content(&self, content: Bytes) {
+        if let Ok(buf) = content.try_into_mut() {
+            self.put(buf);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reuse_buffer() {
+        let pool = BufferPool::new(1);
+
+        let mut buf = pool.get();
+        buf.reserve(1024);
+        buf.extend_from_slice(b"test");
+        let content = buf.freeze();
+        let ptr = content.as_ptr();
+
+        // the content is still shared, the borrowed buffer must not overwrite it
+        let shared = content.clone();
+        pool.put_content(content);
+        assert!(pool.buffers.lock().unwrap().is_empty());
+        assert_eq!(shared.as_ref(), b"test");
+
+        // the content is encoded and nothing else holds it, the allocation is reused
+        pool.put_content(shared);
+        let mut buf = pool.get();
+        assert!(buf.is_empty());
+        buf.reserve(1024);
+        assert_eq!(buf.as_ptr(), ptr);
+
+        pool.put(buf);
+        pool.put(BytesMut::new());
+        assert_eq!(pool.buffers.lock().unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,290 @@
+use std::io;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{anyhow, bail};
+use bytes::Bytes;
+use futures_channel::mpsc;
+use futures_channel::mpsc::{Receiver, Sender};
+use futures_util::Stream;
+use libp2p::bandwidth::BandwidthSinks;
+use libp2p::multiaddr::Protocol;
+use libp2p::{Multiaddr, PeerId};
+use tap::TapFallible;
+use tokio_util::time::DelayQueue;
+use tracing::{error, info, warn};
+
+use crate::command;
+use crate::command::Command;
+use crate::config::ConfigManager;
+use crate::node::config::Config;
+use crate::node::connection_errors::ConnectionErrors;
+use crate::node::{create_transport, BoxedTransport, Node};
+
+/// build a ready to run [`Node`], the config is validated before creating the node
+pub struct NodeBuilder<FileStream, FileGetter>
+where
+    FileStream: Stream<Item = io::Result<Bytes>> + Unpin + Send + 'static,
+    FileGetter: command::FileGetter + Send + 'static,
+{
+    config: Config,
+    config_manager: ConfigManager,
+    listen_addrs: Vec<Multiaddr>,
+    peer_addrs: Vec<Multiaddr>,
+    command_receiver: Option<Receiver<Command<FileStream, FileGetter>>>,
+    transport: Option<(BoxedTransport, Arc<BandwidthSinks>)>,
+}
+
+impl<FileStream, FileGetter> NodeBuilder<FileStream, FileGetter>
+where
+    FileStream: Stream<Item = io::Result<Bytes>> + Unpin + Send + 'static,
+    FileGetter: command::FileGetter + Send + 'static,
+{
+    pub fn new(config: Config, config_manager: ConfigManager) -> Self {
+        Self {
+            config,
+            config_manager,
+            listen_addrs: vec![],
+            peer_addrs: vec![],
+            command_receiver: None,
+            transport: None,
+        }
+    }
+
+    /// add a swarm listen addr, the node will listen on it when building
+    pub fn listen_addr(mut self, addr: Multiaddr) -> Self {
+        self.listen_addrs.push(addr);
+
+        self
+    }
+
+    /// add peer addrs which the node will connect to after running
+    pub fn peer_addrs<I: IntoIterator<Item = Multiaddr>>(mut self, addrs: I) -> Self {
+        self.peer_addrs.extend(addrs);
+
+        self
+    }
+
+    /// inject the command receiver, the node is driven by the commands sent by the other side
+    pub fn command_receiver(mut self, receiver: Receiver<Command<FileStream, FileGetter>>) -> Self {
+        self.command_receiver.replace(receiver);
+
+        self
+    }
+
+    /// create a command channel for the node and return the sender side
+    pub fn command_channel(self, buffer: usize) -> (Self, Sender<Command<FileStream, FileGetter>>) {
+        let (sender, receiver) = mpsc::channel(buffer);
+
+        (self.command_receiver(receiver), sender)
+    }
+
+    /// use a prepared transport instead of creating it from the config, the transport related
+    /// fields in [`Config`] are ignored
+    #[cfg(test)]
+    pub(crate) fn transport(
+        mut self,
+        transport: BoxedTransport,
+        bandwidth_sinks: Arc<BandwidthSinks>,
+    ) -> Self {
+        self.transport.replace((transport, bandwidth_sinks));
+
+        self
+    }
+
+    pub fn build(self) -> anyhow::Result<Node<FileStream, FileGetter>> {
+        self.validate()
+            .tap_err(|err| error!(%err, "invalid node config"))?;
+
+        let command_receiver = self
+            .command_receiver
+            .ok_or_else(|| anyhow!("command receiver is not set"))?;
+
+        let mut peer_addr_receiver = DelayQueue::with_capacity(self.peer_addrs.len());
+        for peer_addr in self.peer_addrs {
+            peer_addr_receiver.insert(peer_addr, Duration::from_secs(0));
+        }
+
+        // the relay only node listens through the relay server, the relay circuit addr is the only
+        // addr advertised to the peers
+        let listen_addrs = match &self.config.relay_server_addr {
+            Some(relay_server_addr) if self.config.relay_only => vec![relay_circuit_addr(
+                relay_server_addr,
+                self.config.key.public().to_peer_id(),
+            )],
+            _ => self.listen_addrs,
+        };
+        let ip_family = self.config.ip_family;
+        let listen_addrs = listen_addrs
+            .into_iter()
+            .filter(|addr| {
+                let allowed = ip_family.allows(addr);
+                if !allowed {
+                    warn!(%addr, ?ip_family, "listen addr is not of the ip family, skip it");
+                }
+
+                allowed
+            })
+            .collect::<Vec<_>>();
+        if listen_addrs.is_empty() {
+            bail!("no swarm listen addr of the ip family {ip_family:?}");
+        }
+
+        let connection_errors = ConnectionErrors::default();
+        let custom_transport = self.transport.is_some();
+        let (transport, bandwidth_sinks, endpoint_behaviour) = match self.transport {
+            None => create_transport(
+                self.config.key.clone(),
+                self.config.handshake_keys.clone(),
+                self.config.relay_server_addr.clone(),
+                self.config.dns_servers.clone(),
+                self.config.enable_tcp,
+                self.config.enable_websocket,
+                self.config.tcp_options,
+                self.config.noise_handshake,
+                self.config.relay_only,
+                connection_errors.clone(),
+            )?,
+            Some((transport, bandwidth_sinks)) => (transport, bandwidth_sinks, None),
+        };
+
+        log_startup_summary(
+            &self.config,
+            &listen_addrs,
+            peer_addr_receiver.len(),
+            custom_transport,
+        );
+
+        let mut node = Node::with_transport(
+            self.config,
+            transport,
+            bandwidth_sinks,
+            connection_errors,
+            endpoint_behaviour,
+            peer_addr_receiver,
+            command_receiver,
+            self.config_manager,
+        )?;
+
+        for addr in listen_addrs {
+            node.swarm
+                .listen_on(addr.clone())
+                .tap_err(|err| error!(%err, %addr, "swarm listen failed"))?;
+        }
+
+        Ok(node)
+    }
+
+    fn validate(&self) -> anyhow::Result<()> {
+        if self.listen_addrs.is_empty() {
+            bail!("no swarm listen addr");
+        }
+
+        if self.config.handshake_keys.is_empty() {
+            bail!("no pre-shared key");
+        }
+
+        if self.config.refresh_store_interval.is_zero() {
+            bail!("refresh store interval can't be zero");
+        }
+
+        if self.config.sync_file_interval.is_zero() {
+            bail!("sync file interval can't be zero");
+        }
+
+        if self.config.discover_interval.is_zero() {
+            bail!("discover interval can't be zero");
+        }
+
+        if self.config.max_sync_files == 0 {
+            bail!("max sync files can't be zero");
+        }
+
+        if self.config.sync_read_ahead == 0 {
+            bail!("sync read ahead can't be zero");
+        }
+
+        if self
+            .config
+            .scrub_interval
+            .map_or(false, |interval| interval.is_zero())
+        {
+            bail!("scrub interval can't be zero");
+        }
+
+        if self.config.scrub_rate == 0 {
+            bail!("scrub rate can't be zero");
+        }
+
+        if self.config.relay_only && self.config.relay_server_addr.is_none() {
+            bail!("relay only mode requires the relay server addr");
+        }
+
+        if self.config.max_connections == Some(0) {
+            bail!("max connections can't be zero");
+        }
+
+        if !self.config.enable_tcp && !self.config.enable_websocket {
+            bail!("at least one of tcp and websocket transports must be enabled");
+        }
+
+        if self.config.index_dir == self.config.store_dir {
+            bail!("index dir and store dir can't be the same dir");
+        }
+
+        Ok(())
+    }
+}
+
+/// log the effective config in one line, so the operators can confirm what the node loaded, the
+/// keys are never logged, only the number of the pre-shared keys is, the fields are limited by
+/// the max fields of a tracing event
+fn log_startup_summary(
+    config: &Config,
+    listen_addrs: &[Multiaddr],
+    peer_addrs: usize,
+    custom_transport: bool,
+) {
+    info!(
+        peer_id = %config.key.public().to_peer_id(),
+        ?listen_addrs,
+        peer_addrs,
+        relay_server_addr = ?config.relay_server_addr,
+        relay_only = config.relay_only,
+        enable_relay_behaviour = config.enable_relay_behaviour,
+        index_dir = ?config.index_dir,
+        store_dir = ?config.store_dir,
+        temp_dir = ?config.temp_dir,
+        store_layout = ?config.store_layout,
+        index_naming = ?config.index_naming,
+        store_link = ?config.store_link,
+        refresh_store_interval = ?config.refresh_store_interval,
+        sync_file_interval = ?config.sync_file_interval,
+        discover_interval = ?config.discover_interval,
+        scrub_interval = ?config.scrub_interval,
+        max_sync_files = config.max_sync_files,
+        max_connections = ?config.max_connections,
+        max_uploads = ?config.max_uploads,
+        hash_algorithm = ?config.hash_algorithm,
+        gossip_validation_mode = ?config.gossip_validation_mode,
+        pre_shared_keys = config.handshake_keys.len(),
+        custom_transport,
+        enable_tcp = config.enable_tcp,
+        enable_websocket = config.enable_websocket,
+        tcp_options = ?config.tcp_options,
+        noise_handshake = ?config.noise_handshake,
+        dns_servers = ?config.dns_servers,
+        ip_family = ?config.ip_family,
+        identify_push = config.identify_push,
+        sync_complete_webhook = config.sync_complete_webhook.is_some(),
+        "node starts"
+    );
+}
+
+/// the addr of dialing the peer through the relay server
+fn relay_circuit_addr(relay_server_addr: &Multiaddr, peer_id: PeerId) -> Multiaddr {
+    relay_server_addr
+        .clone()
+        .with(Protocol::P2pCircuit)
+        .with(Protocol::P2p(peer_id.into()))
+}
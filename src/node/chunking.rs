@@ -0,0 +1,411 @@
+use std::collections::{HashMap, HashSet};
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::{ErrorKind, Read};
+use std::num::NonZeroUsize;
+use std::ops::Range;
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use bytes::Bytes;
+use lru::LruCache;
+use prost::Message;
+use sha2::{Digest, Sha256};
+use tap::TapFallible;
+use tokio::task;
+use tracing::{error, info, warn};
+
+use crate::node::config::index_file_hash;
+use crate::node::store_link::StoreLink;
+
+/// the content defined chunk is never shorter than it, except the last chunk of the file
+const MIN_CHUNK_SIZE: u64 = 256 * 1024;
+/// the chunk is cut here even if no boundary is found
+const MAX_CHUNK_SIZE: u64 = 4 * 1024 * 1024;
+/// a boundary is found when the low bits of the rolling hash are zero, the average chunk size is
+/// about 1MiB after the min size
+const BOUNDARY_MASK: u64 = (1 << 20) - 1;
+const READ_BUFFER_SIZE: usize = 64 * 1024;
+/// max number of the index files whose chunk digests are cached, the least recently used one is
+/// evicted first
+// Safety: 256 > 0
+const MAX_CACHED_FILES: NonZeroUsize = unsafe { NonZeroUsize::new_unchecked(256) };
+
+/// the random gear table of the rolling hash, all nodes must use the same table so the same
+/// content is cut at the same boundaries
+const GEAR: [u64; 256] = gear_table();
+
+/// fill the gear table by the splitmix64
+const fn gear_table() -> [u64; 256] {
+    let mut table = [0; 256];
+    let mut state = 0x7072_6976_6174_6573_u64;
+    let mut i = 0;
+    while i < table.len() {
+        state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        table[i] = z ^ (z >> 31);
+        i += 1;
+    }
+
+    table
+}
+
+/// the sha256 of a content defined chunk of the index file
+#[derive(Message, Clone, PartialEq)]
+pub struct ChunkDigest {
+    #[prost(uint64, tag = "1")]
+    pub offset: u64,
+
+    #[prost(uint64, tag = "2")]
+    pub length: u64,
+
+    #[prost(bytes = "bytes", tag = "3")]
+    pub hash: Bytes,
+}
+
+impl ChunkDigest {
+    pub fn range(&self) -> Range<u64> {
+        self.offset..self.offset + self.length
+    }
+}
+
+/// find the content defined chunk boundaries by the gear rolling hash, an insertion or a deletion
+/// only changes the chunks around it, the later chunks are cut at the same content
+#[derive(Debug, Default)]
+struct Chunker {
+    hash: u64,
+    /// the length of the current chunk
+    length: u64,
+}
+
+impl Chunker {
+    /// return the length of the `data` prefix which ends the current chunk, `None` means the
+    /// whole `data` belongs to the current chunk
+    fn next_boundary(&mut self, data: &[u8]) -> Option<usize> {
+        for (index, byte) in data.iter().enumerate() {
+            self.hash = (self.hash << 1).wrapping_add(GEAR[*byte as usize]);
+            self.length += 1;
+
+            if (self.length >= MIN_CHUNK_SIZE && self.hash & BOUNDARY_MASK == 0)
+                || self.length >= MAX_CHUNK_SIZE
+            {
+                self.hash = 0;
+                self.length = 0;
+
+                return Some(index + 1);
+            }
+        }
+
+        None
+    }
+}
+
+/// split the content into the content defined chunks and digest them, it blocks on reading
+pub fn chunk_digests<R: Read>(mut reader: R) -> io::Result<Vec<ChunkDigest>> {
+    let mut chunker = Chunker::default();
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0; READ_BUFFER_SIZE];
+    let mut digests = vec![];
+    let mut chunk_start = 0;
+    let mut offset = 0;
+
+    loop {
+        let n = match reader.read(&mut buf) {
+            Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err),
+            Ok(n) => n,
+        };
+        if n == 0 {
+            break;
+        }
+
+        let mut data = &buf[..n];
+        while let Some(end) = chunker.next_boundary(data) {
+            hasher.update(&data[..end]);
+            offset += end as u64;
+
+            digests.push(ChunkDigest {
+                offset: chunk_start,
+                length: offset - chunk_start,
+                hash: Bytes::from(hasher.finalize_reset().to_vec()),
+            });
+
+            chunk_start = offset;
+            data = &data[end..];
+        }
+
+        hasher.update(data);
+        offset += data.len() as u64;
+    }
+
+    if offset > chunk_start {
+        digests.push(ChunkDigest {
+            offset: chunk_start,
+            length: offset - chunk_start,
+            hash: Bytes::from(hasher.finalize().to_vec()),
+        });
+    }
+
+    Ok(digests)
+}
+
+/// digest the content defined chunks of the file, it blocks on reading
+pub fn file_chunk_digests(path: &Path) -> io::Result<Vec<ChunkDigest>> {
+    let file = File::open(path).tap_err(|err| error!(%err, ?path, "open chunking file failed"))?;
+
+    chunk_digests(file).tap_err(|err| error!(%err, ?path, "digest file chunks failed"))
+}
+
+/// the chunk digests of the index files by the hash, a file is digested once and the later
+/// chunk digests requests of it are responded from the cache
+#[derive(Debug, Clone)]
+pub struct ChunkDigestCache {
+    /// digest the added and synced files ahead, otherwise a file is digested on its first request
+    precompute: bool,
+    digests: Arc<Mutex<LruCache<String, Arc<[ChunkDigest]>>>>,
+}
+
+impl ChunkDigestCache {
+    pub fn new(precompute: bool) -> Self {
+        Self {
+            precompute,
+            digests: Arc::new(Mutex::new(LruCache::new(MAX_CACHED_FILES))),
+        }
+    }
+
+    pub fn get(&self, hash: &str) -> Option<Arc<[ChunkDigest]>> {
+        self.digests.lock().unwrap().get(hash).cloned()
+    }
+
+    /// digest the index file unless it is cached, it blocks on reading
+    pub fn digest_file(&self, hash: &str, index_path: &Path) -> io::Result<Arc<[ChunkDigest]>> {
+        if let Some(digests) = self.get(hash) {
+            return Ok(digests);
+        }
+
+        let digests = Arc::<[ChunkDigest]>::from(file_chunk_digests(index_path)?);
+        self.digests
+            .lock()
+            .unwrap()
+            .put(hash.to_string(), digests.clone());
+
+        info!(%hash, ?index_path, chunks = digests.len(), "cache file chunk digests done");
+
+        Ok(digests)
+    }
+
+    /// digest the added or synced index file in a blocking task when precompute is enabled, the
+    /// failure is only logged, the file is digested again on its first request
+    pub fn precompute(&self, hash: String, index_path: PathBuf) {
+        if !self.precompute {
+            return;
+        }
+
+        let cache = self.clone();
+        task::spawn_blocking(move || {
+            if let Err(err) = cache.digest_file(&hash, &index_path) {
+                warn!(%err, %hash, ?index_path, "precompute file chunk digests failed");
+            }
+        });
+    }
+
+    /// precompute the chunk digests of the index file which the store file links to
+    pub async fn precompute_store_file(
+        &self,
+        index_dir: &Path,
+        store_link: StoreLink,
+        store_file_path: &Path,
+    ) {
+        if !self.precompute {
+            return;
+        }
+
+        match store_link.read_link(index_dir, store_file_path).await {
+            Err(err) => warn!(%err, ?store_file_path, "read store link failed, skip chunk digests"),
+
+            Ok(index_path) => {
+                if let Some(hash) = index_file_hash(&index_path) {
+                    self.precompute(hash, index_path);
+                }
+            }
+        }
+    }
+
+    /// forget the digests of the removed or corrupted index file
+    pub fn remove(&self, hash: &str) {
+        self.digests.lock().unwrap().pop(hash);
+    }
+
+    /// only keep the digests of the index files which are still shared
+    pub fn retain(&self, shared_hashes: &HashSet<&str>) {
+        let mut digests = self.digests.lock().unwrap();
+        let unshared_hashes = digests
+            .iter()
+            .filter(|(hash, _)| !shared_hashes.contains(hash.as_str()))
+            .map(|(hash, _)| hash.clone())
+            .collect::<Vec<_>>();
+
+        for hash in unshared_hashes {
+            digests.pop(&hash);
+        }
+    }
+}
+
+/// copy the chunks of the local file which the peer file also has into the temp index file at
+/// the peer chunk offsets, return the copied ranges of the peer file, it blocks on reading and
+/// writing
+pub fn reuse_local_chunks(
+    local_path: &Path,
+    tmp_path: &Path,
+    peer_digests: &[ChunkDigest],
+) -> io::Result<Vec<Range<u64>>> {
+    let local_digests = file_chunk_digests(local_path)?
+        .into_iter()
+        .map(|digest| ((digest.hash.clone(), digest.length), digest.offset))
+        .collect::<HashMap<_, _>>();
+
+    let local_file = File::open(local_path)
+        .tap_err(|err| error!(%err, ?local_path, "open local chunks file failed"))?;
+    let tmp_file = OpenOptions::new()
+        .write(true)
+        .open(tmp_path)
+        .tap_err(|err| error!(%err, ?tmp_path, "open temp index file failed"))?;
+
+    let mut buf = vec![];
+    let mut reused = vec![];
+    for peer_digest in peer_digests {
+        let local_offset = match local_digests.get(&(peer_digest.hash.clone(), peer_digest.length))
+        {
+            None => continue,
+            Some(local_offset) => *local_offset,
+        };
+
+        buf.resize(peer_digest.length as _, 0);
+        local_file.read_exact_at(&mut buf, local_offset)?;
+        tmp_file.write_all_at(&buf, peer_digest.offset)?;
+
+        reused.push(peer_digest.range());
+    }
+
+    info!(
+        ?local_path,
+        reused = reused.len(),
+        chunks = peer_digests.len(),
+        "reuse local chunks done"
+    );
+
+    Ok(reused)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use tempfile::TempDir;
+
+    use super::*;
+
+    /// the xorshift bytes, so the chunk boundaries are stable across the test runs
+    fn test_data(len: usize) -> Vec<u8> {
+        let mut state = 0x2545_f491_4f6c_dd1d_u64;
+
+        (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+
+                (state >> 56) as u8
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_chunk_digests_after_insertion() {
+        let data = test_data(8 * 1024 * 1024);
+
+        let digests = chunk_digests(Cursor::new(&data)).unwrap();
+        assert!(digests.len() > 1);
+        assert_eq!(
+            digests.iter().map(|digest| digest.length).sum::<u64>(),
+            data.len() as u64
+        );
+        assert!(digests.iter().all(|digest| digest.length <= MAX_CHUNK_SIZE));
+
+        // the chunks after the inserted bytes are cut at the same content
+        let mut inserted = data[..100].to_vec();
+        inserted.extend_from_slice(b"inserted");
+        inserted.extend_from_slice(&data[100..]);
+        let inserted_digests = chunk_digests(Cursor::new(&inserted)).unwrap();
+        let shared = inserted_digests
+            .iter()
+            .filter(|digest| {
+                digests
+                    .iter()
+                    .any(|old| old.hash == digest.hash && old.length == digest.length)
+            })
+            .count();
+        assert!(shared >= digests.len() - 2, "{shared}/{}", digests.len());
+    }
+
+    #[test]
+    fn test_chunk_digest_cache() {
+        let dir = TempDir::new().unwrap();
+        let index_path = dir.path().join("index");
+        std::fs::write(&index_path, test_data(1024 * 1024)).unwrap();
+
+        let cache = ChunkDigestCache::new(false);
+        assert!(cache.get("hash").is_none());
+        let digests = cache.digest_file("hash", &index_path).unwrap();
+
+        // the cached digests are responded after the index file is gone
+        std::fs::remove_file(&index_path).unwrap();
+        assert_eq!(cache.digest_file("hash", &index_path).unwrap(), digests);
+
+        cache.remove("hash");
+        assert!(cache.get("hash").is_none());
+        cache.digest_file("hash", &index_path).unwrap_err();
+
+        // the unshared files are forgotten and the least recently used file is evicted
+        let digests = Arc::<[ChunkDigest]>::from(vec![]);
+        for i in 0..MAX_CACHED_FILES.get() + 1 {
+            cache
+                .digests
+                .lock()
+                .unwrap()
+                .put(i.to_string(), digests.clone());
+        }
+        assert!(cache.get("0").is_none());
+        assert!(cache.get("1").is_some());
+
+        cache.retain(&HashSet::from(["1"]));
+        assert_eq!(cache.digests.lock().unwrap().len(), 1);
+        assert!(cache.get("1").is_some());
+    }
+
+    #[test]
+    fn test_reuse_local_chunks() {
+        let dir = TempDir::new().unwrap();
+        let data = test_data(4 * 1024 * 1024);
+        let local_path = dir.path().join("local");
+        std::fs::write(&local_path, &data).unwrap();
+
+        let mut peer_data = b"prefix".to_vec();
+        peer_data.extend_from_slice(&data);
+        let peer_digests = chunk_digests(Cursor::new(&peer_data)).unwrap();
+        let tmp_path = dir.path().join("tmp");
+        std::fs::write(&tmp_path, b"").unwrap();
+
+        let reused = reuse_local_chunks(&local_path, &tmp_path, &peer_digests).unwrap();
+        assert!(!reused.is_empty());
+
+        let tmp_data = std::fs::read(&tmp_path).unwrap();
+        for range in reused {
+            let range = range.start as usize..range.end as usize;
+            assert_eq!(tmp_data[range.clone()], peer_data[range]);
+        }
+    }
+}
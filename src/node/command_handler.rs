@@ -2,51 +2,141 @@ use std::borrow::Cow;
 use std::collections::hash_map::Entry;
 use std::collections::{HashMap, HashSet};
 use std::ffi::OsString;
-use std::fs::Metadata;
-use std::io::{Error, ErrorKind, SeekFrom};
+use std::io::{Error, ErrorKind};
 use std::mem;
+use std::ops::Range;
 use std::os::unix::fs::MetadataExt;
 use std::path::{Path, PathBuf};
-use std::time::Duration;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
 
 use bytes::{Bytes, BytesMut};
 use derive_builder::Builder;
+use futures_channel::oneshot;
 use futures_channel::oneshot::Sender;
-use futures_util::{stream, Stream, StreamExt, TryStreamExt};
+use futures_util::{future, stream, Stream, StreamExt, TryStreamExt};
 use itertools::Itertools;
 use libp2p::bandwidth::BandwidthSinks;
+use libp2p::gossipsub::{Gossipsub, TopicHash};
+use libp2p::identity::Keypair;
+use libp2p::swarm::dial_opts::DialOpts;
 use libp2p::{Multiaddr, PeerId, Swarm};
+use nix::sys::statvfs::statvfs;
+use prost::Message as _;
 use rand::distributions::{Alphanumeric, DistString};
-use sha2::digest::FixedOutput;
 use sha2::{Digest, Sha256};
 use tap::TapFallible;
 use tokio::fs;
 use tokio::fs::{File, OpenOptions};
 use tokio::io;
-use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::sync::{broadcast, Semaphore};
+use tokio::{select, task};
+use tokio_util::sync::CancellationToken;
 use tokio_util::time::DelayQueue;
 use tracing::{error, info, instrument, warn};
 
 use crate::command;
-use crate::command::{Command, ListFileDetail};
+use crate::command::{
+    Command, ConfiguredPeerDetail, ConnectingPeerDetail, DiscoveredPeerDetail, DiskSpace,
+    FileAvailability, FileEvent, FilePeerDetail, FileSyncingError, GetFileDetail, GossipTopicStats,
+    ImportManifestDetail, InternalDiagnostics, ListFileDetail, ListPeerDetail, ListPeersDetail,
+    MyFileDetail, NodeStats, PeerAddrStatus, PingPeerDetail, PingTarget, RemoveFilesDetail,
+    SyncEntry, SyncPlanFile, UploadRange, VerifyFileDetail, VerifyStatus,
+};
 use crate::config::ConfigManager;
-use crate::node::behaviour::Behaviour;
-use crate::node::PeerNodeStore;
-use crate::util::{collect_filenames, create_temp_dir};
-
-const BUF_SIZE: usize = 1024 * 1024; // 1MiB
+use crate::hash::HashAlgorithm;
+use crate::node::availability::Availability;
+use crate::node::behaviour::{Behaviour, ShareTopics};
+use crate::node::chunking::ChunkDigestCache;
+use crate::node::config::{index_file_hash, IndexNaming, StoreLayout, SyncOrder};
+use crate::node::connection_errors::ConnectionErrors;
+use crate::node::event_handler::{drop_unsafe_files, send_peer_files_removed};
+use crate::node::file_cache::FileCache;
+use crate::node::file_get_requests::FileGetRequests;
+use crate::node::file_sync::{
+    fetch_chunk, list_syncs, open_temp_index_file, plan_sync, SyncFailure, MAX_FILE_CHUNK_SIZE,
+};
+use crate::node::jitter_ticker::JitterTicker;
+use crate::node::message::{File as MessageFile, FileMessage, Peer};
+use crate::node::peer_failures::PeerFailures;
+use crate::node::peer_pings::PeerPings;
+use crate::node::relay_stats::RelayStats;
+use crate::node::scrub::SharedScrubStatus;
+use crate::node::store_link::StoreLink;
+use crate::node::sync_counters::SyncCounters;
+use crate::node::{ConnectedPeer, FileMessageStatus, FileRequest, PeerNodeStore};
+use crate::util::{
+    collect_filenames, create_parent_dir, create_temp_dir, find_case_conflict, match_pattern,
+    move_file, normalize_filename, set_readonly, ExcludePatterns, SharePatterns,
+};
+
+/// the store file paths which are being added, with the result senders of the coalesced adds
+pub type AddingFiles = Arc<Mutex<HashMap<PathBuf, Vec<Sender<io::Result<()>>>>>>;
 
 #[derive(Builder)]
 #[builder(pattern = "owned")]
 pub struct CommandHandler<'a> {
     index_dir: &'a Path,
+    store_layout: StoreLayout,
+    index_naming: IndexNaming,
+    /// the dir of the in-progress downloads and uploads
+    temp_dir: &'a Path,
     store_dir: &'a Path,
+    store_link: StoreLink,
     peer_stores: &'a mut HashMap<PeerId, PeerNodeStore>,
-    connected_peer: &'a HashMap<PeerId, HashSet<Multiaddr>>,
+    connected_peer: &'a HashMap<PeerId, ConnectedPeer>,
+    /// the signed discover addrs of the peers
+    discover_peers: &'a HashMap<PeerId, Vec<Peer>>,
+    /// the peers which are being dialed
+    peer_addr_connecting: &'a HashMap<PeerId, Multiaddr>,
+    /// the ping peer commands which are waiting for the ping
+    peer_pings: &'a mut PeerPings,
     bandwidth_sinks: &'a BandwidthSinks,
     config_manager: &'a mut ConfigManager,
     peer_addr_receiver: &'a mut DelayQueue<Multiaddr>,
     swarm: &'a mut Swarm<Behaviour>,
+    /// the gossip topics of the share network
+    topics: &'a ShareTopics,
+    hash_algorithm: HashAlgorithm,
+    /// read buffer size of hashing and copying the added file
+    hash_buffer_size: usize,
+    reject_case_conflict: bool,
+    /// hash the added file even when its hash is given, otherwise the given hash is trusted
+    verify_on_add: bool,
+    /// reject the commands which change the store files or the peers
+    read_only: bool,
+    availability: &'a Availability,
+    file_get_requests: &'a mut FileGetRequests,
+    peer_failures: &'a PeerFailures,
+    adding_files: &'a AddingFiles,
+    /// the permits of the upload tasks, `None` means unlimited
+    upload_permits: Option<&'a Arc<Semaphore>>,
+    /// the filenames of the files which are syncing now, keyed by the hash
+    syncing_files: &'a HashMap<String, Vec<String>>,
+    /// the failures of the files which aren't synced yet, keyed by the hash
+    sync_failures: &'a HashMap<String, SyncFailure>,
+    exclude_patterns: &'a ExcludePatterns,
+    /// only the store files matching it are announced and exported
+    share_only: &'a SharePatterns,
+    max_auto_sync_file_size: Option<u64>,
+    sync_from_peers: &'a HashSet<PeerId>,
+    sync_order: SyncOrder,
+    scrub_status: &'a SharedScrubStatus,
+    connection_errors: &'a ConnectionErrors,
+    relay_stats: &'a RelayStats,
+    sync_counters: &'a SyncCounters,
+    /// digest the chunks of the added and uploaded files for the peers
+    chunk_digest_cache: &'a ChunkDigestCache,
+    cache_files: &'a FileCache,
+    refresh_store_ticker: &'a JitterTicker,
+    /// the imported manifest is synced at once
+    sync_file_ticker: &'a mut JitterTicker,
+    file_events: &'a broadcast::Sender<FileEvent>,
+    /// the add peers command exceeding it is rejected
+    max_peer_addrs: usize,
+    /// sign the exported manifest
+    key: &'a Keypair,
 }
 
 impl<'a> CommandHandler<'a> {
@@ -61,9 +151,16 @@ impl<'a> CommandHandler<'a> {
         match cmd {
             Command::AddFile {
                 file_path,
+                hash,
                 result_sender,
             } => {
-                self.handle_add_file_command(&file_path, result_sender)
+                if let Err(err) = self.check_read_only() {
+                    let _ = result_sender.send(Err(err));
+
+                    return;
+                }
+
+                self.handle_add_file_command(&file_path, hash, result_sender)
                     .await;
 
                 info!(?file_path, "handling add file command");
@@ -71,24 +168,35 @@ impl<'a> CommandHandler<'a> {
 
             Command::ListFiles {
                 include_peer,
+                check_health,
                 result_sender,
             } => {
-                self.handle_list_files_command(include_peer, result_sender)
+                self.handle_list_files_command(include_peer, check_health, result_sender)
                     .await;
 
-                info!(include_peer, "handle list file command done");
+                info!(include_peer, check_health, "handle list file command done");
             }
 
             Command::UploadFile {
                 filename,
                 hash,
+                range,
                 file_stream,
+                cancel,
                 result_sender,
             } => {
+                if let Err(err) = self.check_read_only() {
+                    let _ = result_sender.send(Err(err));
+
+                    return;
+                }
+
                 self.handle_upload_file_command(
                     &filename,
                     hash.as_deref(),
+                    range,
                     file_stream,
+                    cancel,
                     result_sender,
                 )
                 .await;
@@ -112,6 +220,12 @@ impl<'a> CommandHandler<'a> {
                 peers,
                 result_sender,
             } => {
+                if let Err(err) = self.check_read_only() {
+                    let _ = result_sender.send(Err(err));
+
+                    return;
+                }
+
                 self.handle_add_peers_command(peers, result_sender).await;
 
                 info!("handle add peers command done");
@@ -121,11 +235,47 @@ impl<'a> CommandHandler<'a> {
                 peers,
                 result_sender,
             } => {
+                if let Err(err) = self.check_read_only() {
+                    let _ = result_sender.send(Err(err));
+
+                    return;
+                }
+
                 self.handle_remove_peers_command(peers, result_sender).await;
 
                 info!("handle remove peers command done");
             }
 
+            Command::RemoveFiles {
+                filenames,
+                pattern,
+                result_sender,
+            } => {
+                if let Err(err) = self.check_read_only() {
+                    let _ = result_sender.send(Err(err));
+
+                    return;
+                }
+
+                let result = remove_store_files(
+                    self.index_dir,
+                    self.store_dir,
+                    self.store_link,
+                    self.syncing_files,
+                    filenames,
+                    pattern.as_deref(),
+                )
+                .await;
+                if let Ok(detail) = &result {
+                    for hash in &detail.removed_index_files {
+                        self.chunk_digest_cache.remove(hash);
+                    }
+                }
+                let _ = result_sender.send(result);
+
+                info!("handle remove files command done");
+            }
+
             Command::GetFile {
                 filename,
                 file_getter,
@@ -136,251 +286,497 @@ impl<'a> CommandHandler<'a> {
 
                 info!("handle get file command done");
             }
-        }
-    }
 
-    #[instrument(skip(self))]
-    async fn handle_add_file_command(
-        &mut self,
-        file_path: &Path,
-        result_sender: Sender<io::Result<()>>,
-    ) {
-        let filename = match file_path.file_name() {
-            Some(filename) => filename,
-            None => {
-                let _ = result_sender.send(Err(Error::new(
-                    ErrorKind::InvalidInput,
-                    format!("path {file_path:?} may not a file"),
-                )));
+            Command::GetIndexFile {
+                hash,
+                file_getter,
+                result_sender,
+            } => {
+                self.handle_get_index_file_command(hash, file_getter, result_sender)
+                    .await;
 
-                return;
+                info!("handle get index file command done");
             }
-        };
 
-        info!(?filename, "get filename done");
+            Command::GetLocalPeerId { result_sender } => {
+                let _ = result_sender.send(*self.swarm.local_peer_id());
 
-        let mut file = match File::open(&file_path).await {
-            Err(err) => {
-                error!(%err, ?file_path, "open file failed");
+                info!("handle get local peer id command done");
+            }
 
-                let _ = result_sender.send(Err(err));
+            Command::GetFileAvailability {
+                filename,
+                result_sender,
+            } => {
+                self.handle_get_file_availability_command(filename, result_sender)
+                    .await;
 
-                return;
+                info!("handle get file availability command done");
             }
 
-            Ok(file) => file,
-        };
+            Command::FetchRange {
+                filename,
+                range,
+                result_sender,
+            } => {
+                self.handle_fetch_range_command(filename, range, result_sender)
+                    .await;
 
-        info!(?file_path, "open file done");
+                info!("handle fetch range command done");
+            }
 
-        let mut buf = BytesMut::with_capacity(BUF_SIZE);
-        let mut hasher = Sha256::new();
+            Command::GetStats { result_sender } => {
+                self.handle_get_stats_command(result_sender).await;
 
-        loop {
-            let n = match file.read_buf(&mut buf).await {
-                Err(err) => {
-                    error!(%err, "read file failed");
+                info!("handle get stats command done");
+            }
 
-                    let _ = result_sender.send(Err(err));
+            Command::GetFilePeers {
+                filename,
+                result_sender,
+            } => {
+                self.handle_get_file_peers_command(filename, result_sender);
 
-                    return;
-                }
+                info!("handle get file peers command done");
+            }
 
-                Ok(n) => n,
-            };
-            if n == 0 {
-                break;
+            Command::VerifyFile {
+                filename,
+                result_sender,
+            } => {
+                self.handle_verify_file_command(filename, result_sender)
+                    .await;
+
+                info!("handle verify file command done");
             }
 
-            hasher.update(&buf[..]);
-            buf.clear();
-        }
+            Command::GetSyncPlan { result_sender } => {
+                self.handle_get_sync_plan_command(result_sender).await;
 
-        let hash = hasher.finalize_fixed();
-        let hash = hex::encode_upper(hash);
+                info!("handle get sync plan command done");
+            }
 
-        info!(%hash, "calculate file hash done");
+            Command::ListSyncs { result_sender } => {
+                self.handle_list_syncs_command(result_sender).await;
 
-        let index_path = self.index_dir.join(hash);
-        match fs::metadata(&index_path).await {
-            Err(err) if err.kind() != ErrorKind::NotFound => {
-                error!(%err, ?index_path, "check index file exists failed");
+                info!("handle list syncs command done");
+            }
 
-                let _ = result_sender.send(Err(err));
+            Command::SubscribeFileEvents { result_sender } => {
+                let _ = result_sender.send(self.file_events.subscribe());
 
-                return;
+                info!("handle subscribe file events command done");
             }
 
-            Err(_) => {
-                info!(?index_path, "index file not exists, create it");
+            Command::ListMyFiles { result_sender } => {
+                let _ = result_sender.send(self.list_my_files().await);
 
-                if let Err(err) = file.seek(SeekFrom::Start(0)).await {
-                    error!(%err, "seek file to start failed");
+                info!("handle list my files command done");
+            }
 
-                    let _ = result_sender.send(Err(err));
+            // the node handles it with the sync scheduler
+            Command::SyncFile {
+                filename,
+                result_sender,
+            } => {
+                error!(%filename, "sync file command is not handled by the node");
 
-                    return;
-                }
+                let _ = result_sender.send(Err(Error::new(
+                    ErrorKind::Unsupported,
+                    "sync file command must be handled by the node",
+                )));
+            }
 
-                let mut index_file = match OpenOptions::new()
-                    .create_new(true)
-                    .write(true)
-                    .open(&index_path)
-                    .await
-                {
-                    Err(err) => {
-                        error!(%err, ?index_path, "create index file failed");
+            Command::GetInternalDiagnostics { result_sender } => {
+                let diagnostics = self.internal_diagnostics();
 
-                        let _ = result_sender.send(Err(err));
+                info!(?diagnostics, "get internal diagnostics done");
 
-                        return;
-                    }
+                let _ = result_sender.send(diagnostics);
+            }
 
-                    Ok(index_file) => index_file,
-                };
+            Command::ExportManifest { result_sender } => {
+                let _ = result_sender.send(self.export_manifest().await);
 
-                let copied = match io::copy(&mut file, &mut index_file).await {
-                    Err(err) => {
-                        error!(%err, ?index_path, "copy file to index store failed");
+                info!("handle export manifest command done");
+            }
 
-                        let _ = result_sender.send(Err(err));
+            Command::ImportManifest {
+                manifest,
+                result_sender,
+            } => {
+                let _ = result_sender.send(self.import_manifest(&manifest));
 
-                        return;
-                    }
+                info!("handle import manifest command done");
+            }
 
-                    Ok(n) => n,
-                };
+            Command::PingPeer {
+                target,
+                result_sender,
+            } => {
+                self.handle_ping_peer_command(target, result_sender);
 
-                info!(?index_path, copied, "copy file to index store done");
+                info!("handle ping peer command done");
             }
+        }
+    }
 
-            Ok(metadata) if !metadata.is_file() => {
-                error!(
-                    ?index_path,
-                    "index file is not a file, index store may be broken"
-                );
+    /// the unsplit file message of the local files, it isn't published, the importer accepts it
+    /// like a gossip message of this node
+    #[instrument(err, skip(self))]
+    async fn export_manifest(&self) -> io::Result<Bytes> {
+        let file_list = self
+            .collect_local_files(false)
+            .await?
+            .into_iter()
+            .filter(|detail| self.share_only.is_shared(&detail.filename))
+            .map(|detail| MessageFile {
+                filename: detail.filename,
+                hash: detail.hash,
+                file_size: detail.size,
+                chunks: None,
+            })
+            .collect::<Vec<_>>();
+        let files = file_list.len();
+
+        let mut message = FileMessage {
+            peer_id: self.swarm.local_peer_id().to_base58(),
+            file_list,
+            refresh_time: SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap()
+                .as_micros() as _,
+            signature: vec![],
+            public_key: vec![],
+            part: 0,
+            total_parts: 0,
+        };
+        message.sign(self.key).map_err(|err| {
+            error!(%err, "sign manifest failed");
 
-                let _ = result_sender.send(Err(Error::new(
-                    ErrorKind::Other,
-                    format!("index file {index_path:?} is not a file, index store may be broken"),
-                )));
+            Error::new(ErrorKind::Other, err.to_string())
+        })?;
 
-                return;
-            }
+        info!(files, "export manifest done");
 
-            Ok(_) => {}
+        Ok(message.encode_to_vec().into())
+    }
+
+    /// accept the verified manifest as the file message of its node, and sync the files at the
+    /// next tick which is due now
+    #[instrument(err, skip(self, manifest))]
+    fn import_manifest(&mut self, manifest: &[u8]) -> io::Result<ImportManifestDetail> {
+        let mut message = FileMessage::decode(manifest).map_err(|err| {
+            error!(%err, "decode manifest failed");
+
+            Error::new(
+                ErrorKind::InvalidData,
+                format!("decode manifest failed: {err}"),
+            )
+        })?;
+        let peer = message.verify().map_err(|err| {
+            warn!(%err, peer_id = %message.peer_id, "verify manifest failed");
+
+            Error::new(
+                ErrorKind::InvalidData,
+                format!("verify manifest failed: {err}"),
+            )
+        })?;
+        if peer == *self.swarm.local_peer_id() {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "the manifest is exported by this node",
+            ));
+        }
+        if message.total_parts != 0 {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "the manifest must not be split",
+            ));
         }
 
-        let store_file_path = self.store_dir.join(filename);
+        drop_unsafe_files(peer, &mut message.file_list);
+
+        let files = message.file_list.len();
+        let outdated = match self
+            .peer_stores
+            .entry(peer)
+            .or_default()
+            .accept_file_message(message)
+        {
+            FileMessageStatus::Applied(removed) => {
+                send_peer_files_removed(self.file_events, peer, removed);
+                self.sync_file_ticker.tick_now();
+
+                false
+            }
+
+            _ => {
+                info!(%peer, "manifest is older than the accepted file message of the peer");
 
-        if let Err(err) = fs::remove_file(&store_file_path).await {
-            if err.kind() != ErrorKind::NotFound {
-                error!(%err, ?store_file_path, "try remove store file failed");
+                true
+            }
+        };
+
+        info!(%peer, files, outdated, "import manifest done");
+
+        Ok(ImportManifestDetail {
+            peer,
+            files,
+            outdated,
+        })
+    }
+
+    fn internal_diagnostics(&self) -> InternalDiagnostics {
+        InternalDiagnostics {
+            peer_stores: self.peer_stores.len(),
+            disconnected_peer_stores: self
+                .peer_stores
+                .keys()
+                .filter(|peer_id| !self.connected_peer.contains_key(peer_id))
+                .count(),
+            peer_store_files: self
+                .peer_stores
+                .values()
+                .map(|peer_store| peer_store.files.len())
+                .sum(),
+            file_get_requests: self.file_get_requests.len(),
+            connected_peers: self.connected_peer.len(),
+            peer_addr_connecting: self.peer_addr_connecting.len(),
+            pending_peer_addrs: self.peer_addr_receiver.len(),
+            discover_peers: self.discover_peers.len(),
+            syncing_files: self.syncing_files.len(),
+            sync_failures: self.sync_failures.len(),
+            adding_files: self.adding_files.lock().unwrap().len(),
+            cached_files: self.cache_files.len(),
+            max_cached_files: self.cache_files.capacity(),
+            cached_mapped_bytes: self.cache_files.mapped_bytes(),
+        }
+    }
 
+    /// the hashing runs in a task, so a large file doesn't block the node
+    #[instrument(skip(self, result_sender))]
+    async fn handle_verify_file_command(
+        &mut self,
+        filename: String,
+        result_sender: Sender<io::Result<Option<VerifyFileDetail>>>,
+    ) {
+        let store_filenames = match collect_filenames(self.store_dir).await {
+            Err(err) => {
                 let _ = result_sender.send(Err(err));
 
                 return;
             }
 
-            info!(?store_file_path, "remove store file done");
-        }
+            Ok(filenames) => filenames,
+        };
 
-        if let Err(err) = fs::symlink(&index_path, &store_file_path).await {
-            error!(%err, ?store_file_path, "create symlink failed");
+        let filename = OsString::from(normalize_filename(&filename));
+        if !store_filenames.contains(&filename) {
+            error!(?filename, "file not found");
 
-            let _ = result_sender.send(Err(err));
+            let _ = result_sender.send(Ok(None));
 
             return;
         }
 
-        info!(?store_file_path, "create symlink done");
+        let store_file_path = self.store_dir.join(filename);
+        let index_dir = self.index_dir.to_path_buf();
+        let store_link = self.store_link;
+        let hash_algorithm = self.hash_algorithm;
+        let chunk_digest_cache = self.chunk_digest_cache.clone();
+        tokio::spawn(async move {
+            let result = async {
+                let index_file_path = store_link
+                    .read_link(&index_dir, &store_file_path)
+                    .await
+                    .tap_err(|err| error!(%err, ?store_file_path, "read store link failed"))?;
+                let expected_hash = index_file_hash(&index_file_path).ok_or_else(|| {
+                    error!(?index_file_path, "index file doesn't contain filename");
+
+                    Error::new(
+                        ErrorKind::Other,
+                        format!("index file {index_file_path:?} doesn't contain filename"),
+                    )
+                })?;
+
+                let computed_hash = hash_algorithm
+                    .hash_file(&index_file_path)
+                    .await
+                    .tap_err(|err| error!(%err, ?index_file_path, "hash index file failed"))?;
+
+                if computed_hash != expected_hash {
+                    warn!(
+                        ?index_file_path, %expected_hash, %computed_hash,
+                        "index file hash mismatch"
+                    );
+
+                    // the cached chunk digests may not match the corrupted content
+                    chunk_digest_cache.remove(&expected_hash);
+                } else {
+                    info!(?index_file_path, %expected_hash, "verify index file hash done");
+                }
 
-        let _ = result_sender.send(Ok(()));
+                Ok(Some(VerifyFileDetail {
+                    expected_hash,
+                    computed_hash,
+                }))
+            }
+            .await;
+
+            let _ = result_sender.send(result);
+        });
     }
 
     #[instrument(skip(self))]
-    async fn handle_list_files_command(
+    async fn handle_add_file_command(
         &mut self,
-        include_peer: bool,
-        result_sender: Sender<io::Result<Vec<ListFileDetail>>>,
+        file_path: &Path,
+        hash: Option<String>,
+        result_sender: Sender<io::Result<()>>,
     ) {
-        let store_dir = self.store_dir;
-        let store_filenames = match collect_filenames(store_dir).await {
+        let hash = match hash
+            .map(|hash| normalize_known_hash(&hash, self.hash_algorithm))
+            .transpose()
+        {
             Err(err) => {
                 let _ = result_sender.send(Err(err));
 
                 return;
             }
 
-            Ok(filenames) => filenames,
+            Ok(hash) => hash,
         };
 
-        let mut list_file_details: HashSet<ListFileDetail> =
-            match stream::iter(store_filenames.iter())
-                .then(|filename| async move {
-                    let store_file_path = store_dir.join(filename);
+        let filename = match file_path.file_name() {
+            Some(filename) => filename,
+            None => {
+                let _ = result_sender.send(Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("path {file_path:?} may not a file"),
+                )));
 
-                    let index_file_path = fs::read_link(&store_file_path)
-                        .await
-                        .tap_err(|err| error!(%err, ?store_file_path, "read symlink failed"))?;
+                return;
+            }
+        };
+        // keep the non utf-8 filename as is
+        let filename = filename
+            .to_str()
+            .map(|filename| OsString::from(normalize_filename(filename)))
+            .unwrap_or_else(|| filename.to_owned());
 
-                    info!(?store_file_path, ?index_file_path, "read symlink done");
+        info!(?filename, "get filename done");
 
-                    let index_filename = index_file_path.file_name().ok_or_else(|| {
-                        error!(?index_file_path, "index file doesn't contain filename");
+        if let Err(err) = self.check_case_conflict(&filename.to_string_lossy()).await {
+            let _ = result_sender.send(Err(err));
 
-                        Error::new(
-                            ErrorKind::Other,
-                            format!("index file {index_file_path:?} doesn't contain filename"),
-                        )
-                    })?;
+            return;
+        }
 
-                    info!(
-                        ?store_file_path,
-                        ?index_file_path,
-                        ?index_filename,
-                        "get index filename done"
-                    );
+        if let Err(err) = self.check_syncing(&filename.to_string_lossy()) {
+            let _ = result_sender.send(Err(err));
 
-                    let metadata = fs::metadata(&store_file_path)
-                        .await
-                        .tap_err(|err| error!(%err, "get store file metadata failed"))?;
+            return;
+        }
 
-                    Ok::<_, Error>((filename, index_filename.to_owned(), metadata))
-                })
-                .map_ok(
-                    |(filename, hash, metadata): (&OsString, OsString, Metadata)| ListFileDetail {
-                        filename: filename.to_string_lossy().to_string(),
-                        hash: hash.to_string_lossy().to_string(),
-                        downloaded: true,
-                        peers: vec![],
-                        size: metadata.size(),
-                    },
-                )
-                .try_collect()
-                .await
-            {
-                Err(err) => {
-                    let _ = result_sender.send(Err(err));
+        // the later add of the same store file waits for the in-flight one
+        let store_file_path = self.store_dir.join(&filename);
+        {
+            let mut adding_files = self.adding_files.lock().unwrap();
+            if let Some(waiters) = adding_files.get_mut(&store_file_path) {
+                info!(
+                    ?store_file_path,
+                    "the file is being added, coalesce with it"
+                );
 
-                    return;
-                }
+                waiters.push(result_sender);
 
-                Ok(filenames_with_hash) => filenames_with_hash,
-            };
+                return;
+            }
 
-        info!(?list_file_details, "collect local files done");
+            adding_files.insert(store_file_path.clone(), vec![]);
+        }
 
-        if !include_peer {
-            info!("no need include peer");
+        let file_path = file_path.to_path_buf();
+        let index_dir = self.index_dir.to_path_buf();
+        let store_layout = self.store_layout;
+        let index_naming = self.index_naming;
+        let store_link = self.store_link;
+        let temp_dir = self.temp_dir.to_owned();
+        let hash_algorithm = self.hash_algorithm;
+        let hash_buffer_size = self.hash_buffer_size;
+        let verify_on_add = self.verify_on_add;
+        let adding_files = self.adding_files.clone();
+        let chunk_digest_cache = self.chunk_digest_cache.clone();
+        tokio::spawn(async move {
+            let result = add_file(
+                &file_path,
+                &store_file_path,
+                hash.as_deref(),
+                verify_on_add,
+                hash_algorithm,
+                hash_buffer_size,
+                &index_dir,
+                &temp_dir,
+                store_layout,
+                index_naming,
+                store_link,
+            )
+            .await;
+            if result.is_ok() {
+                chunk_digest_cache
+                    .precompute_store_file(&index_dir, store_link, &store_file_path)
+                    .await;
+            }
 
-            let _ = result_sender.send(Ok(list_file_details.into_iter().collect()));
+            let waiters = adding_files
+                .lock()
+                .unwrap()
+                .remove(&store_file_path)
+                .unwrap_or_default();
+            for waiter in waiters {
+                let result = match &result {
+                    Err(err) => Err(Error::new(err.kind(), err.to_string())),
+                    Ok(_) => Ok(()),
+                };
+                let _ = waiter.send(result);
+            }
 
-            return;
-        }
+            let _ = result_sender.send(result);
+        });
 
-        let exists_files = list_file_details
-            .iter()
+        info!("start add file task");
+    }
+
+    #[instrument(skip(self))]
+    async fn handle_list_files_command(
+        &mut self,
+        include_peer: bool,
+        check_health: bool,
+        result_sender: Sender<io::Result<Vec<ListFileDetail>>>,
+    ) {
+        let mut list_file_details = match self.collect_local_files(check_health).await {
+            Err(err) => {
+                let _ = result_sender.send(Err(err));
+
+                return;
+            }
+
+            Ok(list_file_details) => list_file_details,
+        };
+
+        info!(?list_file_details, "collect local files done");
+
+        if !include_peer {
+            info!("no need include peer");
+
+            let _ = result_sender.send(Ok(list_file_details.into_iter().collect()));
+
+            return;
+        }
+
+        let exists_files = list_file_details
+            .iter()
             .map(|detail| (detail.filename.clone(), detail.hash.clone()))
             .collect::<HashSet<_>>();
 
@@ -406,6 +802,8 @@ impl<'a> CommandHandler<'a> {
                 downloaded: false,
                 peers: vec![peer_id],
                 size,
+                healthy: None,
+                modified: None,
             });
 
         list_file_details.extend(peer_list_file_details);
@@ -438,21 +836,223 @@ impl<'a> CommandHandler<'a> {
         let _ = result_sender.send(Ok(list_file_details.into_iter().collect()));
     }
 
+    /// collect the files in the store dir, they are all downloaded, when `check_health` is set,
+    /// the file whose index file is missing or isn't a regular file is collected as unhealthy
+    /// instead of failing the collection
+    async fn collect_local_files(&self, check_health: bool) -> io::Result<HashSet<ListFileDetail>> {
+        let index_dir = self.index_dir;
+        let store_dir = self.store_dir;
+        let store_link = self.store_link;
+        let store_filenames = collect_filenames(store_dir).await?;
+
+        stream::iter(store_filenames.iter())
+            .then(|filename| async move {
+                let store_file_path = store_dir.join(filename);
+
+                let index_file_path = match store_link.read_link(index_dir, &store_file_path).await
+                {
+                    // the tampered store file is never listed, it is warned by the read link
+                    Err(err) if err.kind() == ErrorKind::InvalidData => return Ok(None),
+
+                    Err(err) => {
+                        error!(%err, ?store_file_path, "read store link failed");
+
+                        return Err(err);
+                    }
+
+                    Ok(index_file_path) => index_file_path,
+                };
+
+                info!(?store_file_path, ?index_file_path, "read store link done");
+
+                let hash = index_file_hash(&index_file_path).ok_or_else(|| {
+                    error!(?index_file_path, "index file doesn't contain filename");
+
+                    Error::new(
+                        ErrorKind::Other,
+                        format!("index file {index_file_path:?} doesn't contain filename"),
+                    )
+                })?;
+
+                info!(
+                    ?store_file_path,
+                    ?index_file_path,
+                    ?hash,
+                    "get index file hash done"
+                );
+
+                if !check_health {
+                    let metadata = fs::metadata(&store_file_path)
+                        .await
+                        .tap_err(|err| error!(%err, "get store file metadata failed"))?;
+
+                    return Ok::<_, Error>(Some((
+                        filename,
+                        hash,
+                        metadata.size(),
+                        None,
+                        metadata.modified().ok(),
+                    )));
+                }
+
+                let (size, healthy, modified) = match fs::metadata(&index_file_path).await {
+                    Err(err) if err.kind() != ErrorKind::NotFound => {
+                        error!(%err, ?index_file_path, "get index file metadata failed");
+
+                        return Err(err);
+                    }
+
+                    Err(_) => {
+                        warn!(?store_file_path, ?index_file_path, "index file is missing");
+
+                        (0, false, None)
+                    }
+
+                    Ok(metadata) if !metadata.is_file() => {
+                        warn!(
+                            ?store_file_path,
+                            ?index_file_path,
+                            "index file is not a regular file"
+                        );
+
+                        (0, false, None)
+                    }
+
+                    Ok(metadata) => (metadata.size(), true, metadata.modified().ok()),
+                };
+
+                Ok(Some((filename, hash, size, Some(healthy), modified)))
+            })
+            .try_filter_map(future::ok)
+            .map_ok(
+                |(filename, hash, size, healthy, modified): (
+                    &OsString,
+                    String,
+                    u64,
+                    Option<bool>,
+                    Option<SystemTime>,
+                )| {
+                    ListFileDetail {
+                        filename: filename.to_string_lossy().to_string(),
+                        hash,
+                        downloaded: true,
+                        peers: vec![],
+                        size,
+                        healthy,
+                        modified,
+                    }
+                },
+            )
+            .try_collect()
+            .await
+    }
+
+    /// the verify status is derived from the last scrub, the index file modified after the scrub
+    /// is unverified
+    #[instrument(err, skip(self))]
+    async fn list_my_files(&self) -> io::Result<Vec<MyFileDetail>> {
+        let local_files = self.collect_local_files(false).await?;
+        let scrub_status = self.scrub_status.lock().unwrap().clone();
+
+        let mut my_files = Vec::with_capacity(local_files.len());
+        for detail in local_files {
+            let store_file_path = self.store_dir.join(&detail.filename);
+            let index_path = self
+                .store_link
+                .read_link(self.index_dir, &store_file_path)
+                .await
+                .tap_err(|err| error!(%err, ?store_file_path, "read store link failed"))?;
+            let modified = fs::metadata(&index_path)
+                .await
+                .and_then(|metadata| metadata.modified())
+                .tap_err(|err| error!(%err, ?index_path, "get index file modified time failed"))?;
+
+            let verify_status = if scrub_status.corrupted_files.contains(&detail.hash) {
+                VerifyStatus::Corrupted
+            } else if scrub_status
+                .last_scrub_time
+                .map_or(false, |last_scrub_time| modified <= last_scrub_time)
+            {
+                VerifyStatus::Verified
+            } else {
+                VerifyStatus::Unverified
+            };
+
+            my_files.push(MyFileDetail {
+                excluded: self.exclude_patterns.is_excluded(&detail.filename)
+                    || !self.share_only.is_shared(&detail.filename),
+                filename: detail.filename,
+                hash: detail.hash,
+                size: detail.size,
+                index_path,
+                verify_status,
+            });
+        }
+
+        my_files.sort_by(|a, b| a.filename.cmp(&b.filename));
+
+        Ok(my_files)
+    }
+
     #[instrument(skip(self, file_stream))]
     async fn handle_upload_file_command<FileStream>(
         &mut self,
         filename: &str,
         hash: Option<&str>,
+        range: Option<UploadRange>,
         file_stream: FileStream,
+        cancel: CancellationToken,
         result_sender: Sender<io::Result<()>>,
     ) where
         FileStream: Stream<Item = io::Result<Bytes>> + Unpin + Send + 'static,
     {
+        let filename = &normalize_filename(filename);
+
+        // the hash is joined to the temp dir and the index dir, reject the path traversal
+        if let Some(hash) = hash {
+            if hash.is_empty() || !hash.bytes().all(|b| b.is_ascii_alphanumeric()) {
+                error!(%hash, "invalid hash");
+
+                let _ = result_sender.send(Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("invalid hash {hash}"),
+                )));
+
+                return;
+            }
+        }
+
+        if let Err(err) = self.check_case_conflict(filename).await {
+            let _ = result_sender.send(Err(err));
+
+            return;
+        }
+
+        if let Err(err) = self.check_syncing(filename) {
+            let _ = result_sender.send(Err(err));
+
+            return;
+        }
+
         let store_path = self.store_dir.join(filename);
         if let Some(hash) = hash {
             info!("command has hash");
 
-            let index_path = self.index_dir.join(hash);
+            let index_path = match self
+                .index_naming
+                .resolve_index_path(self.store_layout, self.index_dir, hash, filename)
+                .await
+            {
+                Err(err) => {
+                    error!(%err, %hash, "resolve index file path failed");
+
+                    let _ = result_sender.send(Err(err));
+
+                    return;
+                }
+
+                Ok(index_path) => index_path,
+            };
 
             match fs::metadata(&index_path).await {
                 Err(err) if err.kind() != ErrorKind::NotFound => {
@@ -466,7 +1066,11 @@ impl<'a> CommandHandler<'a> {
                 Ok(_) => {
                     info!(?index_path, "index file exists");
 
-                    if let Err(err) = fs::symlink(&index_path, &store_path).await {
+                    if let Err(err) = self
+                        .store_link
+                        .link(self.index_dir, &index_path, &store_path)
+                        .await
+                    {
                         if err.kind() == ErrorKind::AlreadyExists {
                             info!(?store_path, ?index_path, "store file exists");
 
@@ -475,14 +1079,14 @@ impl<'a> CommandHandler<'a> {
                             return;
                         }
 
-                        error!(%err, ?store_path, ?index_path, "create symlink failed");
+                        error!(%err, ?store_path, ?index_path, "create store link failed");
 
                         let _ = result_sender.send(Err(err));
 
                         return;
                     }
 
-                    info!(?store_path, ?index_path, "create symlink done");
+                    info!(?store_path, ?index_path, "create store link done");
 
                     let _ = result_sender.send(Ok(()));
 
@@ -493,212 +1097,1286 @@ impl<'a> CommandHandler<'a> {
             }
         }
 
+        // the permit is held by the upload task until the upload is stored or failed
+        let permit = match self
+            .upload_permits
+            .map(|permits| permits.clone().try_acquire_owned())
+        {
+            Some(Err(_)) => {
+                error!("too many uploads");
+
+                let _ = result_sender.send(Err(Error::new(
+                    ErrorKind::WouldBlock,
+                    "too many uploads, try again later",
+                )));
+
+                return;
+            }
+
+            Some(Ok(permit)) => Some(permit),
+            None => None,
+        };
+
+        if let Some(range) = range {
+            let hash = match hash {
+                None => {
+                    error!(?range, "resumable upload has no hash");
+
+                    let _ = result_sender.send(Err(Error::new(
+                        ErrorKind::InvalidInput,
+                        "resumable upload requires the hash",
+                    )));
+
+                    return;
+                }
+
+                Some(hash) => hash.to_owned(),
+            };
+
+            let filename = filename.to_owned();
+            let index_dir = self.index_dir.to_owned();
+            let store_layout = self.store_layout;
+            let index_naming = self.index_naming;
+            let temp_dir = self.temp_dir.to_owned();
+            let store_dir = self.store_dir.to_owned();
+            let store_link = self.store_link;
+            let hash_algorithm = self.hash_algorithm;
+            let chunk_digest_cache = self.chunk_digest_cache.clone();
+            tokio::spawn(async move {
+                let result = upload_file_range(
+                    &filename,
+                    &hash,
+                    range,
+                    hash_algorithm,
+                    &index_dir,
+                    store_layout,
+                    index_naming,
+                    &temp_dir,
+                    &store_dir,
+                    store_link,
+                    file_stream,
+                    &cancel,
+                )
+                .await;
+                drop(permit);
+                if result.is_ok() {
+                    chunk_digest_cache
+                        .precompute_store_file(&index_dir, store_link, &store_dir.join(&filename))
+                        .await;
+                }
+
+                let _ = result_sender.send(result);
+            });
+
+            info!("start upload file range task");
+
+            return;
+        }
+
         let filename = filename.to_owned();
         let hash = hash.map(ToOwned::to_owned);
         let index_dir = self.index_dir.to_owned();
+        let store_layout = self.store_layout;
+        let index_naming = self.index_naming;
+        let temp_dir = self.temp_dir.to_owned();
         let store_dir = self.store_dir.to_owned();
+        let store_link = self.store_link;
+        let hash_algorithm = self.hash_algorithm;
+        let chunk_digest_cache = self.chunk_digest_cache.clone();
+        let (upload_result_sender, upload_result_receiver) = oneshot::channel();
         tokio::spawn(async move {
+            let store_file_path = store_dir.join(&filename);
             upload_file(
                 &filename,
                 hash.as_deref(),
-                index_dir,
+                hash_algorithm,
+                index_dir.clone(),
+                store_layout,
+                index_naming,
+                temp_dir,
                 store_dir,
+                store_link,
                 file_stream,
-                result_sender,
+                &cancel,
+                upload_result_sender,
             )
             .await;
+
+            // release the permit before the result is sent, so the next upload of the client
+            // isn't rejected
+            drop(permit);
+            if let Ok(result) = upload_result_receiver.await {
+                if result.is_ok() {
+                    chunk_digest_cache
+                        .precompute_store_file(&index_dir, store_link, &store_file_path)
+                        .await;
+                }
+
+                let _ = result_sender.send(result);
+            }
         });
 
         info!("start upload file task");
     }
 
-    #[instrument(skip(self))]
-    fn handle_list_peers_command(
-        &mut self,
-        result_sender: Sender<Vec<(PeerId, HashSet<Multiaddr>)>>,
-    ) {
-        let peers = self
-            .connected_peer
-            .iter()
-            .map(|(peer, addrs)| (*peer, addrs.clone()))
-            .collect();
+    /// warn when the store has a filename which differs from `filename` only by case, return
+    /// [`ErrorKind::AlreadyExists`] error when `reject_case_conflict` is enabled
+    #[instrument(err, skip(self))]
+    async fn check_case_conflict(&self, filename: &str) -> io::Result<()> {
+        let store_filenames = collect_filenames(self.store_dir).await?;
+        let conflict_filename = match find_case_conflict(&store_filenames, filename) {
+            None => return Ok(()),
+            Some(conflict_filename) => conflict_filename,
+        };
 
-        info!(?peers, "collect connected peers done");
+        warn!(
+            filename,
+            ?conflict_filename,
+            "filename differs from the store file only by case"
+        );
+
+        if self.reject_case_conflict {
+            return Err(Error::new(
+                ErrorKind::AlreadyExists,
+                format!(
+                    "filename {filename} conflicts with the store file {}, they differ only by case, rename it before adding",
+                    conflict_filename.to_string_lossy()
+                ),
+            ));
+        }
 
-        let _ = result_sender.send(peers);
+        Ok(())
     }
 
-    #[instrument(skip(self))]
-    fn handle_get_bandwidth_command(&mut self, result_sender: Sender<(u64, u64)>) {
-        let inbound = self.bandwidth_sinks.total_inbound();
-        let outbound = self.bandwidth_sinks.total_outbound();
+    /// return [`FileSyncingError`] when the store file is being synced, the sync links the store
+    /// file when it is done, so the other changes of it race with the sync
+    #[instrument(err, skip(self))]
+    fn check_syncing(&self, filename: &str) -> io::Result<()> {
+        if is_syncing(self.syncing_files, filename) {
+            return Err(FileSyncingError {
+                filename: filename.to_string(),
+            }
+            .into());
+        }
 
-        info!(inbound, outbound, "get inbound and outbound done");
+        Ok(())
+    }
 
-        let _ = result_sender.send((inbound, outbound));
+    /// return [`ErrorKind::PermissionDenied`] error when the node is read only, the store files
+    /// and the peers are managed out of band
+    #[instrument(err, skip(self))]
+    fn check_read_only(&self) -> io::Result<()> {
+        if self.read_only {
+            return Err(Error::new(
+                ErrorKind::PermissionDenied,
+                "node is read only, the files and the peers can't be changed",
+            ));
+        }
+
+        Ok(())
     }
 
-    #[instrument(skip(self))]
-    async fn handle_add_peers_command(
+    /// wait for the ping of the peer, the peer which isn't connected is dialed by the addr or its
+    /// discovered addrs, the connection is kept if the peer is configured
+    #[instrument(skip(self, result_sender))]
+    fn handle_ping_peer_command(
         &mut self,
-        mut peers: Vec<Multiaddr>,
-        result_sender: Sender<io::Result<()>>,
+        target: PingTarget,
+        result_sender: Sender<io::Result<PingPeerDetail>>,
     ) {
-        let mut config = self.config_manager.load();
-        peers = peers
-            .into_iter()
-            .filter(|peer| {
-                if config.peer_addrs.contains(&peer.to_string()) {
-                    info!(%peer, "peer is added, ignore");
+        let (peer, addrs) = match target {
+            PingTarget::Peer(peer) => {
+                let addrs = self
+                    .discover_peers
+                    .get(&peer)
+                    .into_iter()
+                    .flatten()
+                    .filter_map(|discover_peer| {
+                        Multiaddr::try_from(discover_peer.addr.clone()).ok()
+                    })
+                    .collect::<Vec<_>>();
+
+                (peer, addrs)
+            }
 
-                    false
-                } else {
-                    true
+            PingTarget::Addr(addr) => match PeerId::try_from_multiaddr(&addr) {
+                None => {
+                    error!(%addr, "addr doesn't contain peer id");
+
+                    let _ = result_sender.send(Err(Error::new(
+                        ErrorKind::InvalidInput,
+                        format!("addr {addr} doesn't contain peer id"),
+                    )));
+
+                    return;
                 }
-            })
-            .collect::<Vec<_>>();
-        if peers.is_empty() {
-            let _ = result_sender.send(Ok(()));
 
-            return;
-        }
+                Some(peer) => (peer, vec![addr]),
+            },
+        };
 
-        for peer in peers {
-            info!(%peer, "add peer to peer addr receiver done");
+        if peer == *self.swarm.local_peer_id() {
+            let _ = result_sender.send(Err(Error::new(
+                ErrorKind::InvalidInput,
+                "can't ping the local node",
+            )));
 
-            config.to_mut().peer_addrs.push(peer.to_string());
-            self.peer_addr_receiver.insert(peer, Duration::from_secs(0));
+            return;
         }
 
-        let result = self
-            .config_manager
-            .swap(Cow::Owned(config.into_owned()))
-            .await
-            .tap_ok(|_| info!("swap config done"))
-            .tap_err(|err| error!(%err, "swap config failed"))
-            .map(|_| ());
+        // the connected peer is pinged periodically, wait for the next one
+        let connected = self.connected_peer.contains_key(&peer);
+        if connected || self.peer_pings.is_pending(&peer) {
+            self.peer_pings.start(peer, connected, false, result_sender);
 
-        let _ = result_sender.send(result);
-    }
+            return;
+        }
 
-    #[instrument(skip(self))]
-    async fn handle_remove_peers_command(
-        &mut self,
-        mut peers: Vec<Multiaddr>,
-        result_sender: Sender<io::Result<()>>,
-    ) {
-        let mut config = self.config_manager.load();
-        peers = peers
-            .into_iter()
-            .filter(|peer| {
-                if !config.peer_addrs.contains(&peer.to_string()) {
-                    info!(%peer, "peer not exists, ignore");
+        if addrs.is_empty() {
+            error!(%peer, "no known addr of peer");
 
-                    false
-                } else {
-                    true
-                }
-            })
-            .collect::<Vec<_>>();
-        if peers.is_empty() {
-            let _ = result_sender.send(Ok(()));
+            let _ = result_sender.send(Err(Error::new(
+                ErrorKind::NotFound,
+                format!("no known addr of peer {peer}, ping it by the addr"),
+            )));
 
             return;
         }
 
-        for peer in &peers {
-            let peer_id = match PeerId::try_from_multiaddr(peer) {
-                None => {
-                    error!(%peer, "peer doesn't contain peer id");
+        // the node keeps the connection of the configured peer as it dials the peer itself
+        let transient =
+            !self.peer_addr_connecting.contains_key(&peer) && !self.is_configured_peer(&peer);
 
-                    let _ = result_sender.send(Err(Error::new(
-                        ErrorKind::InvalidData,
-                        format!("peer {peer} doesn't contain peer id"),
-                    )));
+        if let Err(err) = self
+            .swarm
+            .dial(DialOpts::peer_id(peer).addresses(addrs).build())
+        {
+            error!(%err, %peer, "dial peer for ping failed");
 
-                    return;
-                }
+            let _ = result_sender.send(Ok(PingPeerDetail {
+                peer,
+                connected: false,
+                result: Err(err.to_string()),
+            }));
 
-                Some(peer_id) => peer_id,
-            };
+            return;
+        }
 
-            info!(%peer_id, %peer, "get peer id from peer done");
+        info!(%peer, transient, "dial peer for ping");
 
-            // disconnect peer and remove from each behaviour
-            let _ = self.swarm.disconnect_peer_id(peer_id);
-            let behaviour = self.swarm.behaviour_mut();
-            behaviour.gossip.remove_explicit_peer(&peer_id);
-            behaviour.request_respond.remove_address(&peer_id, peer);
+        self.peer_pings.start(peer, false, transient, result_sender);
+    }
 
-            // remove peer from config
-            config
+    fn is_configured_peer(&self, peer: &PeerId) -> bool {
+        self.config_manager.load().peer_addrs.iter().any(|addr| {
+            addr.parse::<Multiaddr>()
+                .ok()
+                .and_then(|addr| PeerId::try_from_multiaddr(&addr))
+                .as_ref()
+                == Some(peer)
+        })
+    }
+
+    /// the stored file is fully available, the syncing file reports the downloaded ranges
+    #[instrument(skip(self, result_sender))]
+    async fn handle_get_file_availability_command(
+        &mut self,
+        filename: String,
+        result_sender: Sender<io::Result<Option<FileAvailability>>>,
+    ) {
+        let store_dir = self.store_dir;
+        let store_filenames = match collect_filenames(store_dir).await {
+            Err(err) => {
+                let _ = result_sender.send(Err(err));
+
+                return;
+            }
+
+            Ok(filenames) => filenames,
+        };
+
+        if store_filenames.contains(&OsString::from(&filename)) {
+            let store_file_path = store_dir.join(&filename);
+            let metadata = match fs::metadata(&store_file_path).await {
+                Err(err) => {
+                    error!(%err, ?store_file_path, "get store file metadata failed");
+
+                    let _ = result_sender.send(Err(err));
+
+                    return;
+                }
+
+                Ok(metadata) => metadata,
+            };
+            let hash = match self
+                .store_link
+                .read_link(self.index_dir, &store_file_path)
+                .await
+            {
+                Err(err) => {
+                    error!(%err, ?store_file_path, "read store link failed");
+
+                    let _ = result_sender.send(Err(err));
+
+                    return;
+                }
+
+                Ok(index_file_path) => index_file_hash(&index_file_path).unwrap_or_default(),
+            };
+
+            info!(%filename, %hash, "file is stored");
+
+            let size = metadata.size();
+            let _ = result_sender.send(Ok(Some(FileAvailability {
+                hash,
+                size,
+                ranges: (size > 0).then_some(0..size).into_iter().collect(),
+                path: store_file_path,
+                bytes_per_sec: None,
+            })));
+
+            return;
+        }
+
+        let file = self.peer_stores.values().find_map(|peer_store| {
+            let hash = peer_store.files.get(&filename)?;
+            let size = *peer_store.index.get(hash)?;
+
+            Some((hash.clone(), size))
+        });
+        let (hash, size) = match file {
+            None => {
+                info!(%filename, "file not found");
+
+                let _ = result_sender.send(Ok(None));
+
+                return;
+            }
+
+            Some(file) => file,
+        };
+
+        let ranges = self
+            .availability
+            .get(&hash)
+            .map(|range_set| range_set.ranges().to_vec())
+            .unwrap_or_default();
+
+        let bytes_per_sec = self.availability.download_rate(&hash);
+
+        info!(%filename, %hash, ?ranges, ?bytes_per_sec, "get syncing file availability done");
+
+        let path = self.temp_dir.join(&hash);
+        let _ = result_sender.send(Ok(Some(FileAvailability {
+            hash,
+            size,
+            ranges,
+            path,
+            bytes_per_sec,
+        })));
+    }
+
+    /// fetch the missing chunks covering the range, the result is sent when all chunks are done
+    #[instrument(skip(self, result_sender))]
+    async fn handle_fetch_range_command(
+        &mut self,
+        filename: String,
+        range: Range<u64>,
+        result_sender: Sender<io::Result<()>>,
+    ) {
+        let mut file = None;
+        let mut peers = vec![];
+        for (peer_id, peer_store) in self.peer_stores.iter() {
+            let hash = match peer_store.files.get(&filename) {
+                None => continue,
+                Some(hash) => hash,
+            };
+            if let Some(size) = peer_store.index.get(hash) {
+                file.get_or_insert_with(|| (hash.clone(), *size));
+                peers.push(*peer_id);
+            }
+        }
+        let (hash, size) = match file {
+            None => {
+                error!(%filename, "no peer has the file");
+
+                let _ = result_sender.send(Err(Error::new(
+                    ErrorKind::NotFound,
+                    format!("no peer has the file {filename}"),
+                )));
+
+                return;
+            }
+
+            Some(file) => file,
+        };
+
+        let tmp_index_file = match open_temp_index_file(self.temp_dir, &hash).await {
+            Err(err) => {
+                let _ = result_sender.send(Err(err));
+
+                return;
+            }
+
+            Ok(file) => Arc::new(file),
+        };
+
+        // align to the sync chunks, so the sync can skip the fetched chunks
+        let mut offset = range.start / MAX_FILE_CHUNK_SIZE * MAX_FILE_CHUNK_SIZE;
+        let mut futs = vec![];
+        while offset < range.end.min(size) {
+            let chunk = offset..(offset + MAX_FILE_CHUNK_SIZE).min(size);
+            // the peers which are still syncing the file may not have the chunk
+            let holders = peers
+                .iter()
+                .copied()
+                .filter(|peer_id| self.peer_stores[peer_id].has_chunk(&hash, &chunk))
+                .collect::<Vec<_>>();
+            if !self.availability.contains(&hash, &chunk) && !holders.is_empty() {
+                let peer_id = *self.peer_failures.choose_peer(&holders).unwrap();
+                let file_request = FileRequest {
+                    filename: filename.clone(),
+                    hash: hash.clone(),
+                    offset,
+                    length: MAX_FILE_CHUNK_SIZE,
+                    chunk_digests: false,
+                };
+
+                futs.push(fetch_chunk(
+                    self.swarm,
+                    self.file_get_requests,
+                    self.availability.clone(),
+                    self.sync_counters.clone(),
+                    peer_id,
+                    file_request,
+                    size,
+                    tmp_index_file.clone(),
+                ));
+            }
+
+            offset += MAX_FILE_CHUNK_SIZE;
+        }
+
+        info!(%filename, %hash, ?range, chunks = futs.len(), "fetching range chunks");
+
+        tokio::spawn(async move {
+            for fut in futs {
+                if let Err(err) = fut.await.unwrap() {
+                    let _ = result_sender.send(Err(Error::new(ErrorKind::Other, err)));
+
+                    return;
+                }
+            }
+
+            let _ = result_sender.send(Ok(()));
+        });
+    }
+
+    #[instrument(skip(self))]
+    async fn handle_get_stats_command(&mut self, result_sender: Sender<io::Result<NodeStats>>) {
+        let local_files = match self.collect_local_files(false).await {
+            Err(err) => {
+                let _ = result_sender.send(Err(err));
+
+                return;
+            }
+
+            Ok(local_files) => local_files,
+        };
+
+        let local_file_keys = local_files
+            .iter()
+            .map(|detail| (detail.filename.as_str(), detail.hash.as_str()))
+            .collect::<HashSet<_>>();
+        let remote_files = self
+            .peer_stores
+            .values()
+            .flat_map(|peer_store| {
+                peer_store
+                    .files
+                    .iter()
+                    .map(|(filename, hash)| (filename.as_str(), hash.as_str()))
+            })
+            .filter(|file| !local_file_keys.contains(file))
+            .collect::<HashSet<_>>();
+        let shared_bytes = local_files
+            .iter()
+            .map(|detail| (detail.hash.as_str(), detail.size))
+            .collect::<HashMap<_, _>>()
+            .values()
+            .sum();
+
+        let scrub_status = self.scrub_status.lock().unwrap().clone();
+        let stats = NodeStats {
+            peers: self.connected_peer.len(),
+            local_files: local_files.len(),
+            remote_files: remote_files.len(),
+            shared_bytes,
+            syncing_files: self.syncing_files.len(),
+            inbound: self.bandwidth_sinks.total_inbound(),
+            outbound: self.bandwidth_sinks.total_outbound(),
+            last_scrub_time: scrub_status.last_scrub_time,
+            scrub_corrupted_files: scrub_status.corrupted_files,
+            scrub_errors: scrub_status.errors,
+            connection_errors: self.connection_errors.stats(),
+            index_dir_space: disk_space(self.index_dir).await,
+            store_dir_space: disk_space(self.store_dir).await,
+            temp_dir_space: disk_space(self.temp_dir).await,
+            file_topic: gossip_topic_stats(
+                &self.swarm.behaviour().gossip,
+                &self.topics.file.hash(),
+            ),
+            discover_topic: gossip_topic_stats(
+                &self.swarm.behaviour().gossip,
+                &self.topics.discover.hash(),
+            ),
+            all_mesh_peers: self.swarm.behaviour().gossip.all_mesh_peers().count(),
+            next_refresh_store: self.refresh_store_ticker.remaining(),
+            next_sync_file: self.sync_file_ticker.remaining(),
+            relay: self.relay_stats.stats(),
+            sync_counters: self.sync_counters.stats(),
+        };
+
+        info!(?stats, "collect node stats done");
+
+        let _ = result_sender.send(Ok(stats));
+    }
+
+    #[instrument(skip(self, result_sender))]
+    async fn handle_get_sync_plan_command(
+        &mut self,
+        result_sender: Sender<io::Result<Vec<SyncPlanFile>>>,
+    ) {
+        let result = plan_sync(
+            self.index_dir,
+            self.store_dir,
+            self.store_link,
+            self.peer_stores,
+            self.exclude_patterns,
+            self.max_auto_sync_file_size,
+            self.sync_from_peers,
+            self.syncing_files,
+            self.sync_failures,
+            self.sync_order,
+        )
+        .await;
+
+        let _ = result_sender.send(result);
+    }
+
+    #[instrument(skip(self, result_sender))]
+    async fn handle_list_syncs_command(
+        &mut self,
+        result_sender: Sender<io::Result<Vec<SyncEntry>>>,
+    ) {
+        let result = list_syncs(
+            self.index_dir,
+            self.store_dir,
+            self.store_link,
+            self.peer_stores,
+            self.exclude_patterns,
+            self.max_auto_sync_file_size,
+            self.sync_from_peers,
+            self.availability,
+            self.syncing_files,
+            self.sync_failures,
+            self.sync_order,
+        )
+        .await;
+
+        let _ = result_sender.send(result);
+    }
+
+    #[instrument(skip(self, result_sender))]
+    fn handle_get_file_peers_command(
+        &mut self,
+        filename: String,
+        result_sender: Sender<Vec<FilePeerDetail>>,
+    ) {
+        let peers = self
+            .peer_stores
+            .iter()
+            .filter_map(|(peer_id, peer_store)| {
+                let hash = peer_store.files.get(&filename)?;
+
+                Some(FilePeerDetail {
+                    peer: *peer_id,
+                    hash: hash.clone(),
+                    connected: self.connected_peer.contains_key(peer_id),
+                })
+            })
+            .collect::<Vec<_>>();
+
+        info!(?peers, "collect file peers done");
+
+        let _ = result_sender.send(peers);
+    }
+
+    #[instrument(skip(self))]
+    fn handle_list_peers_command(&mut self, result_sender: Sender<ListPeersDetail>) {
+        let connected = self
+            .connected_peer
+            .iter()
+            .map(|(peer, connected_peer)| ListPeerDetail {
+                peer: *peer,
+                connected_addrs: connected_peer.addrs.keys().cloned().collect(),
+                connections: connected_peer.connections(),
+                connected_since: connected_peer.connected_since,
+                identity: connected_peer.identity.clone(),
+            })
+            .collect::<Vec<_>>();
+
+        let connecting = self
+            .peer_addr_connecting
+            .iter()
+            .filter(|(peer, _)| !self.connected_peer.contains_key(peer))
+            .map(|(peer, addr)| ConnectingPeerDetail {
+                peer: *peer,
+                addr: addr.clone(),
+            })
+            .collect::<Vec<_>>();
+
+        let configured = self
+            .config_manager
+            .load()
+            .peer_addrs
+            .iter()
+            .map(|addr| {
+                let parsed_addr = addr.parse::<Multiaddr>().ok();
+                let peer = parsed_addr.as_ref().and_then(PeerId::try_from_multiaddr);
+
+                // the configured addr without the peer id is matched by the addr
+                let status = match peer {
+                    Some(peer) if self.connected_peer.contains_key(&peer) => {
+                        PeerAddrStatus::Connected
+                    }
+                    Some(peer) if self.peer_addr_connecting.contains_key(&peer) => {
+                        PeerAddrStatus::Connecting
+                    }
+                    Some(_) => PeerAddrStatus::Unreachable,
+
+                    None if parsed_addr.as_ref().map_or(false, |parsed_addr| {
+                        self.connected_peer
+                            .values()
+                            .any(|connected_peer| connected_peer.addrs.contains_key(parsed_addr))
+                    }) =>
+                    {
+                        PeerAddrStatus::Connected
+                    }
+                    None if self
+                        .peer_addr_connecting
+                        .values()
+                        .any(|connecting_addr| connecting_addr.to_string() == *addr) =>
+                    {
+                        PeerAddrStatus::Connecting
+                    }
+                    None => PeerAddrStatus::Unreachable,
+                };
+
+                ConfiguredPeerDetail {
+                    addr: addr.clone(),
+                    peer,
+                    status,
+                }
+            })
+            .collect::<Vec<_>>();
+
+        let discovered = self
+            .discover_peers
+            .iter()
+            .map(|(peer, signed_peers)| DiscoveredPeerDetail {
+                peer: *peer,
+                addrs: signed_peers
+                    .iter()
+                    .filter_map(|signed_peer| Multiaddr::try_from(signed_peer.addr.clone()).ok())
+                    .collect(),
+            })
+            .collect::<Vec<_>>();
+
+        info!(
+            ?connected,
+            ?connecting,
+            ?configured,
+            ?discovered,
+            "collect peers done"
+        );
+
+        let _ = result_sender.send(ListPeersDetail {
+            connected,
+            connecting,
+            configured,
+            discovered,
+        });
+    }
+
+    #[instrument(skip(self))]
+    fn handle_get_bandwidth_command(&mut self, result_sender: Sender<(u64, u64)>) {
+        let inbound = self.bandwidth_sinks.total_inbound();
+        let outbound = self.bandwidth_sinks.total_outbound();
+
+        info!(inbound, outbound, "get inbound and outbound done");
+
+        let _ = result_sender.send((inbound, outbound));
+    }
+
+    #[instrument(skip(self))]
+    async fn handle_add_peers_command(
+        &mut self,
+        mut peers: Vec<Multiaddr>,
+        result_sender: Sender<io::Result<()>>,
+    ) {
+        // the peers can't be persisted, don't change them only in memory
+        if let Err(err) = self.config_manager.check_writable() {
+            error!(%err, "config is read-only");
+
+            let _ = result_sender.send(Err(err));
+
+            return;
+        }
+
+        let mut config = self.config_manager.load();
+        peers = peers
+            .into_iter()
+            .filter(|peer| {
+                if config.peer_addrs.contains(&peer.to_string()) {
+                    info!(%peer, "peer is added, ignore");
+
+                    false
+                } else {
+                    true
+                }
+            })
+            .collect::<Vec<_>>();
+        if peers.is_empty() {
+            let _ = result_sender.send(Ok(()));
+
+            return;
+        }
+
+        if config.peer_addrs.len() + peers.len() > self.max_peer_addrs {
+            error!(
+                existing = config.peer_addrs.len(),
+                adding = peers.len(),
+                max_peer_addrs = self.max_peer_addrs,
+                "too many peer addrs"
+            );
+
+            let _ = result_sender.send(Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "too many peer addrs, {} exist, adding {} exceeds the max {}",
+                    config.peer_addrs.len(),
+                    peers.len(),
+                    self.max_peer_addrs
+                ),
+            )));
+
+            return;
+        }
+
+        for peer in peers {
+            info!(%peer, "add peer to peer addr receiver done");
+
+            config.to_mut().peer_addrs.push(peer.to_string());
+            self.peer_addr_receiver.insert(peer, Duration::from_secs(0));
+        }
+
+        let result = self
+            .config_manager
+            .swap(Cow::Owned(config.into_owned()))
+            .await
+            .tap_ok(|_| info!("swap config done"))
+            .tap_err(|err| error!(%err, "swap config failed"))
+            .map(|_| ());
+
+        let _ = result_sender.send(result);
+    }
+
+    #[instrument(skip(self))]
+    async fn handle_remove_peers_command(
+        &mut self,
+        mut peers: Vec<Multiaddr>,
+        result_sender: Sender<io::Result<()>>,
+    ) {
+        // the peers can't be persisted, don't change them only in memory
+        if let Err(err) = self.config_manager.check_writable() {
+            error!(%err, "config is read-only");
+
+            let _ = result_sender.send(Err(err));
+
+            return;
+        }
+
+        let mut config = self.config_manager.load();
+        peers = peers
+            .into_iter()
+            .filter(|peer| {
+                if !config.peer_addrs.contains(&peer.to_string()) {
+                    info!(%peer, "peer not exists, ignore");
+
+                    false
+                } else {
+                    true
+                }
+            })
+            .collect::<Vec<_>>();
+        if peers.is_empty() {
+            let _ = result_sender.send(Ok(()));
+
+            return;
+        }
+
+        for peer in &peers {
+            let peer_id = match PeerId::try_from_multiaddr(peer) {
+                None => {
+                    error!(%peer, "peer doesn't contain peer id");
+
+                    let _ = result_sender.send(Err(Error::new(
+                        ErrorKind::InvalidData,
+                        format!("peer {peer} doesn't contain peer id"),
+                    )));
+
+                    return;
+                }
+
+                Some(peer_id) => peer_id,
+            };
+
+            info!(%peer_id, %peer, "get peer id from peer done");
+
+            // disconnect peer and remove from each behaviour
+            let _ = self.swarm.disconnect_peer_id(peer_id);
+            let behaviour = self.swarm.behaviour_mut();
+            behaviour.gossip.remove_explicit_peer(&peer_id);
+            behaviour.request_respond.remove_address(&peer_id, peer);
+
+            // remove peer from config
+            config
                 .to_mut()
                 .peer_addrs
                 .retain(|exist_peer| *exist_peer != peer.to_string());
 
-            // remove peer store info
-            self.peer_stores.remove(&peer_id);
+            // remove peer store info
+            self.peer_stores.remove(&peer_id);
+
+            info!(%peer_id, %peer, "remove peer done");
+        }
+
+        let result = self
+            .config_manager
+            .swap(Cow::Owned(config.into_owned()))
+            .await
+            .tap_ok(|_| info!("swap config done"))
+            .tap_err(|err| error!(%err, "swap config failed"))
+            .map(|_| ());
+
+        let _ = result_sender.send(result);
+    }
+
+    #[instrument(skip(self, file_getter, result_sender))]
+    async fn handle_get_file_command<FileGetter>(
+        &mut self,
+        filename: String,
+        file_getter: FileGetter,
+        result_sender: Sender<io::Result<Option<GetFileDetail<FileGetter::FileContent>>>>,
+    ) where
+        FileGetter: command::FileGetter + Send + 'static,
+    {
+        let store_dir = self.store_dir;
+        let store_filenames = match collect_filenames(store_dir).await {
+            Err(err) => {
+                let _ = result_sender.send(Err(err));
+
+                return;
+            }
+
+            Ok(filenames) => filenames,
+        };
+
+        let filename = OsString::from(filename);
+        if !store_filenames.contains(&filename) {
+            error!(?filename, "file not found");
+
+            let _ = result_sender.send(Ok(None));
+
+            return;
+        }
+
+        info!(?filename, "check file done and file exists");
+
+        let store_file_path = store_dir.join(filename);
+        let index_file_path = match self
+            .store_link
+            .read_link(self.index_dir, &store_file_path)
+            .await
+        {
+            Err(err) => {
+                error!(%err, ?store_file_path, "read store link failed");
+
+                let _ = result_sender.send(Err(err));
+
+                return;
+            }
+
+            Ok(index_file_path) => index_file_path,
+        };
+        let hash = match index_file_hash(&index_file_path) {
+            None => {
+                error!(?index_file_path, "index file doesn't contain filename");
+
+                let _ = result_sender.send(Err(Error::new(
+                    ErrorKind::Other,
+                    format!("index file {index_file_path:?} doesn't contain filename"),
+                )));
+
+                return;
+            }
+
+            Some(hash) => hash,
+        };
+
+        info!(?store_file_path, %hash, "get file hash done");
+
+        let modified = fs::metadata(&index_file_path)
+            .await
+            .and_then(|metadata| metadata.modified())
+            .tap_err(|err| warn!(%err, ?index_file_path, "get index file modified time failed"))
+            .ok();
+
+        let result = file_getter.get_file(&store_file_path).await.map(|content| {
+            Some(GetFileDetail {
+                hash,
+                index_path: index_file_path,
+                modified,
+                content,
+            })
+        });
+        let _ = result_sender.send(result);
+    }
+
+    /// serve the index file by hash, no store link is created, so reading an index-only file
+    /// never changes what is shared
+    #[instrument(skip(self, file_getter, result_sender))]
+    async fn handle_get_index_file_command<FileGetter>(
+        &mut self,
+        hash: String,
+        file_getter: FileGetter,
+        result_sender: Sender<io::Result<Option<GetFileDetail<FileGetter::FileContent>>>>,
+    ) where
+        FileGetter: command::FileGetter + Send + 'static,
+    {
+        // the hash is joined to the index dir, reject the path traversal
+        if hash.is_empty() || !hash.bytes().all(|b| b.is_ascii_alphanumeric()) {
+            error!(%hash, "invalid hash");
+
+            let _ = result_sender.send(Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("invalid hash {hash}"),
+            )));
+
+            return;
+        }
+
+        let index_path = match self
+            .index_naming
+            .resolve_index_path(self.store_layout, self.index_dir, &hash, "")
+            .await
+        {
+            Err(err) => {
+                error!(%err, %hash, "resolve index file path failed");
+
+                let _ = result_sender.send(Err(err));
+
+                return;
+            }
+
+            Ok(index_path) => index_path,
+        };
+        let modified = match fs::metadata(&index_path).await {
+            Err(err) if err.kind() == ErrorKind::NotFound => {
+                info!(?index_path, "index file not found");
+
+                let _ = result_sender.send(Ok(None));
+
+                return;
+            }
+
+            Err(err) => {
+                error!(%err, ?index_path, "get index file metadata failed");
+
+                let _ = result_sender.send(Err(err));
+
+                return;
+            }
+
+            Ok(metadata) if !metadata.is_file() => {
+                error!(?index_path, "index path is not a file");
+
+                let _ = result_sender.send(Ok(None));
+
+                return;
+            }
+
+            Ok(metadata) => metadata.modified().ok(),
+        };
+
+        let result = file_getter.get_file(&index_path).await.map(|content| {
+            Some(GetFileDetail {
+                hash,
+                index_path,
+                modified,
+                content,
+            })
+        });
+        let _ = result_sender.send(result);
+    }
+}
+
+/// check the given hash of the added file, it names the index file, so it must be a full length
+/// hex hash of the algorithm, which also rejects the path traversal, and it is uppercased like the
+/// calculated one
+fn normalize_known_hash(hash: &str, hash_algorithm: HashAlgorithm) -> io::Result<String> {
+    if hash.len() != hash_algorithm.hex_len() || !hash.bytes().all(|b| b.is_ascii_hexdigit()) {
+        error!(%hash, ?hash_algorithm, "invalid hash");
+
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("invalid hash {hash}"),
+        ));
+    }
+
+    Ok(hash.to_uppercase())
+}
+
+/// copy the file into the temp dir and hash it in a single pass, move the copy into the index
+/// store when the index file doesn't exist, then link the store file to it
+#[allow(clippy::too_many_arguments)]
+#[instrument(err)]
+async fn add_file(
+    file_path: &Path,
+    store_file_path: &Path,
+    known_hash: Option<&str>,
+    verify_on_add: bool,
+    hash_algorithm: HashAlgorithm,
+    hash_buffer_size: usize,
+    index_dir: &Path,
+    temp_dir: &Path,
+    store_layout: StoreLayout,
+    index_naming: IndexNaming,
+    store_link: StoreLink,
+) -> io::Result<()> {
+    let mut file = File::open(&file_path)
+        .await
+        .tap_err(|err| error!(%err, ?file_path, "open file failed"))?;
+
+    info!(?file_path, "open file done");
+
+    // the adds of the same store file are coalesced, so the temp file isn't shared
+    let tmp_path = create_temp_dir(temp_dir).await?.join(format!(
+        ".add.{}",
+        hex::encode(Sha256::digest(store_file_path.to_string_lossy().as_bytes()))
+    ));
+    let mut tmp_file = File::create(&tmp_path)
+        .await
+        .tap_err(|err| error!(%err, ?tmp_path, "create add temp file failed"))?;
+
+    let mut buf = BytesMut::with_capacity(hash_buffer_size);
+    // the trusted known hash skips hashing, the file content is not checked at all
+    let mut hasher = (verify_on_add || known_hash.is_none()).then(|| hash_algorithm.hasher());
+
+    let result = async {
+        loop {
+            if file.read_buf(&mut buf).await? == 0 {
+                break;
+            }
+
+            if let Some(hasher) = &mut hasher {
+                hasher.update(&buf);
+            }
+            tmp_file.write_all(&buf).await?;
+            buf.clear();
+        }
 
-            info!(%peer_id, %peer, "remove peer done");
+        tmp_file.flush().await
+    }
+    .await;
+    drop(tmp_file);
+
+    if let Err(err) = result {
+        error!(%err, ?file_path, ?tmp_path, "copy and hash file failed");
+
+        let _ = fs::remove_file(&tmp_path).await;
+
+        return Err(err);
+    }
+
+    let hash = match (hasher, known_hash) {
+        (Some(hasher), known_hash) => {
+            let hash = hasher.finalize();
+
+            info!(%hash, "calculate file hash done");
+
+            if let Some(known_hash) = known_hash.filter(|known_hash| *known_hash != hash) {
+                error!(%hash, %known_hash, ?file_path, "add file hash mismatch");
+
+                let _ = fs::remove_file(&tmp_path).await;
+
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("file hash {hash} mismatches the given hash {known_hash}"),
+                ));
+            }
+
+            hash
         }
 
-        let result = self
-            .config_manager
-            .swap(Cow::Owned(config.into_owned()))
+        // the hashing is only skipped when the hash is known
+        (None, known_hash) => {
+            let hash = known_hash.unwrap().to_string();
+
+            info!(%hash, "trust the given hash, skip hashing");
+
+            hash
+        }
+    };
+
+    let filename = store_file_path
+        .file_name()
+        .map(|filename| filename.to_string_lossy())
+        .unwrap_or_default();
+    let result = async {
+        let index_path = index_naming
+            .resolve_index_path(store_layout, index_dir, &hash, &filename)
             .await
-            .tap_ok(|_| info!("swap config done"))
-            .tap_err(|err| error!(%err, "swap config failed"))
-            .map(|_| ());
+            .tap_err(|err| error!(%err, %hash, "resolve index file path failed"))?;
+        match fs::metadata(&index_path).await {
+            Err(err) if err.kind() != ErrorKind::NotFound => {
+                error!(%err, ?index_path, "check index file exists failed");
 
-        let _ = result_sender.send(result);
-    }
+                return Err(err);
+            }
 
-    #[instrument(skip(self, file_getter, result_sender))]
-    async fn handle_get_file_command<FileGetter>(
-        &mut self,
-        filename: String,
-        file_getter: FileGetter,
-        result_sender: Sender<io::Result<Option<FileGetter::FileContent>>>,
-    ) where
-        FileGetter: command::FileGetter + Send + 'static,
-    {
-        let store_dir = self.store_dir;
-        let store_filenames = match collect_filenames(store_dir).await {
-            Err(err) => {
-                let _ = result_sender.send(Err(err));
+            Err(_) => {
+                info!(?index_path, "index file not exists, create it");
 
-                return;
+                create_parent_dir(&index_path).await?;
+                set_readonly(&tmp_path).await?;
+                move_file(&tmp_path, &index_path).await.tap_err(|err| {
+                    error!(%err, ?tmp_path, ?index_path, "move add temp file to index store failed")
+                })?;
+
+                info!(?index_path, "move add temp file to index store done");
             }
 
-            Ok(filenames) => filenames,
-        };
+            Ok(metadata) if !metadata.is_file() => {
+                error!(
+                    ?index_path,
+                    "index file is not a file, index store may be broken"
+                );
 
-        let filename = OsString::from(filename);
-        if !store_filenames.contains(&filename) {
-            error!(?filename, "file not found");
+                return Err(Error::new(
+                    ErrorKind::Other,
+                    format!("index file {index_path:?} is not a file, index store may be broken"),
+                ));
+            }
 
-            let _ = result_sender.send(Ok(None));
+            Ok(_) => {}
+        }
 
-            return;
+        Ok(index_path)
+    }
+    .await;
+
+    // the temp file is left when the same hash index file exists or storing it failed
+    if let Err(err) = fs::remove_file(&tmp_path).await {
+        if err.kind() != ErrorKind::NotFound {
+            warn!(%err, ?tmp_path, "remove add temp file failed");
         }
+    }
 
-        info!(?filename, "check file done and file exists");
+    let index_path = result?;
+
+    if let Err(err) = store_link.remove(index_dir, store_file_path).await {
+        if err.kind() != ErrorKind::NotFound {
+            error!(%err, ?store_file_path, "try remove store file failed");
+
+            return Err(err);
+        }
+
+        info!(?store_file_path, "remove store file done");
+    }
 
-        let result = file_getter.get_file(&store_dir.join(filename)).await;
-        let _ = result_sender.send(result.map(Some));
+    match store_link
+        .link(index_dir, &index_path, store_file_path)
+        .await
+    {
+        // the same file is linked by the other task, such as the upload
+        Err(err)
+            if err.kind() == ErrorKind::AlreadyExists
+                && store_link
+                    .read_link(index_dir, store_file_path)
+                    .await
+                    .ok()
+                    .as_ref()
+                    == Some(&index_path) =>
+        {
+            info!(
+                ?store_file_path,
+                ?index_path,
+                "store file is already linked"
+            );
+        }
+
+        Err(err) => {
+            error!(%err, ?store_file_path, "create store link failed");
+
+            return Err(err);
+        }
+
+        Ok(_) => {
+            info!(?store_file_path, "create store link done");
+        }
+    }
+
+    Ok(())
+}
+
+/// read the next upload data, the canceled upload returns [`ErrorKind::Interrupted`] error even
+/// if the stream is ended, the dropped upload request ends the stream early
+async fn next_upload_data<FileStream: Stream<Item = io::Result<Bytes>> + Unpin>(
+    file_stream: &mut FileStream,
+    cancel: &CancellationToken,
+) -> io::Result<Option<Bytes>> {
+    let data = select! {
+        biased;
+
+        _ = cancel.cancelled() => None,
+        data = file_stream.next() => data,
+    };
+    if cancel.is_cancelled() {
+        return Err(Error::new(ErrorKind::Interrupted, "upload is canceled"));
     }
+
+    data.transpose()
 }
 
-#[instrument(skip(file_stream))]
+#[allow(clippy::too_many_arguments)]
+#[instrument(skip(file_stream, cancel))]
 async fn upload_file<FileStream: Stream<Item = io::Result<Bytes>> + Unpin + Send + 'static>(
     filename: &str,
     hash: Option<&str>,
+    hash_algorithm: HashAlgorithm,
     index_dir: PathBuf,
+    store_layout: StoreLayout,
+    index_naming: IndexNaming,
+    temp_dir: PathBuf,
     store_dir: PathBuf,
+    store_link: StoreLink,
     mut file_stream: FileStream,
+    cancel: &CancellationToken,
     result_sender: Sender<io::Result<()>>,
 ) {
-    let mut tmp_path = match create_temp_dir(&index_dir).await {
+    let mut tmp_path = match create_temp_dir(&temp_dir).await {
         Err(err) => {
             let _ = result_sender.send(Err(err));
 
@@ -710,7 +2388,7 @@ async fn upload_file<FileStream: Stream<Item = io::Result<Bytes>> + Unpin + Send
 
     info!(?tmp_path, "create temp index dir done");
 
-    let mut hasher = Sha256::new();
+    let mut hasher = hash_algorithm.hasher();
     let tmp_filename = Alphanumeric.sample_string(&mut rand::thread_rng(), 16);
     tmp_path.push(format!(".upload.{tmp_filename}"));
 
@@ -733,48 +2411,382 @@ async fn upload_file<FileStream: Stream<Item = io::Result<Bytes>> + Unpin + Send
         Ok(file) => file,
     };
 
-    while let Some(result) = file_stream.next().await {
-        let mut data = match result {
+    let result = async {
+        while let Some(mut data) = next_upload_data(&mut file_stream, cancel).await? {
+            hasher.update(&data);
+
+            upload_file
+                .write_all_buf(&mut data)
+                .await
+                .tap_err(|err| error!(%err, "write data to upload temp file failed"))?;
+        }
+
+        Ok::<_, Error>(())
+    }
+    .await;
+
+    // the incomplete upload temp file is useless
+    if let Err(err) = result {
+        error!(%err, ?tmp_path, "read upload file content failed");
+
+        let _ = fs::remove_file(&tmp_path)
+            .await
+            .tap_err(|err| error!(%err, ?tmp_path, "remove upload temp file failed"));
+        let _ = result_sender.send(Err(err));
+
+        return;
+    }
+
+    let hash_result = hasher.finalize();
+    if let Some(hash) = hash {
+        if hash_result != hash {
+            warn!(%hash_result, "hash result is not equal request hash");
+        }
+    }
+
+    info!(%hash_result, "write data to upload temp file done");
+
+    let result = match index_naming
+        .resolve_index_path(store_layout, &index_dir, &hash_result, filename)
+        .await
+    {
+        Err(err) => {
+            error!(%err, %hash_result, "resolve index file path failed");
+
+            Err(err)
+        }
+
+        Ok(index_path) => {
+            store_upload_file(
+                &tmp_path,
+                &index_dir,
+                &index_path,
+                &store_dir.join(filename),
+                store_link,
+            )
+            .await
+        }
+    };
+    let _ = result_sender.send(result);
+}
+
+/// append the chunk to the partial upload file which is keyed by the filename and hash, the
+/// partial file is verified and stored when the last chunk is appended
+#[allow(clippy::too_many_arguments)]
+#[instrument(err, skip(file_stream, cancel))]
+async fn upload_file_range<FileStream: Stream<Item = io::Result<Bytes>> + Unpin>(
+    filename: &str,
+    hash: &str,
+    range: UploadRange,
+    hash_algorithm: HashAlgorithm,
+    index_dir: &Path,
+    store_layout: StoreLayout,
+    index_naming: IndexNaming,
+    temp_dir: &Path,
+    store_dir: &Path,
+    store_link: StoreLink,
+    mut file_stream: FileStream,
+    cancel: &CancellationToken,
+) -> io::Result<()> {
+    if range.start > range.end || range.end > range.total {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("invalid upload range {range:?}"),
+        ));
+    }
+
+    let tmp_path = create_temp_dir(temp_dir).await?.join(format!(
+        ".upload.{hash}.{}",
+        hex::encode(Sha256::digest(filename))
+    ));
+    let mut partial_file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&tmp_path)
+        .await
+        .tap_err(|err| error!(%err, ?tmp_path, "open partial upload file failed"))?;
+
+    let offset = partial_file
+        .metadata()
+        .await
+        .tap_err(|err| error!(%err, ?tmp_path, "get partial upload file metadata failed"))?
+        .len();
+    if offset != range.start {
+        error!(
+            offset,
+            ?range,
+            "upload range doesn't start at the partial file end"
+        );
+
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!(
+                "upload range starts at {}, but the partial file length is {offset}",
+                range.start
+            ),
+        ));
+    }
+
+    let mut written = 0;
+    let result = async {
+        while let Some(mut data) = next_upload_data(&mut file_stream, cancel).await? {
+            written += data.len() as u64;
+            if range.start + written > range.end {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("upload data exceeds the range {range:?}"),
+                ));
+            }
+
+            partial_file.write_all_buf(&mut data).await?;
+        }
+
+        partial_file.flush().await
+    }
+    .await;
+
+    // drop the incomplete chunk, so the client can resume from the range start
+    if let Err(err) = result {
+        error!(%err, ?tmp_path, "write upload range failed");
+
+        partial_file.set_len(range.start).await?;
+
+        return Err(err);
+    }
+
+    info!(?tmp_path, ?range, written, "write upload range done");
+
+    if range.start + written < range.total {
+        return Ok(());
+    }
+
+    let hash_result = hash_algorithm.hash_file(&tmp_path).await?;
+    if hash_result != hash {
+        error!(%hash_result, %hash, "resumable upload hash mismatch, drop it");
+
+        fs::remove_file(&tmp_path)
+            .await
+            .tap_err(|err| error!(%err, ?tmp_path, "remove partial upload file failed"))?;
+
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("upload file hash {hash_result} is not equal to {hash}"),
+        ));
+    }
+
+    let index_path = index_naming
+        .resolve_index_path(store_layout, index_dir, &hash_result, filename)
+        .await
+        .tap_err(|err| error!(%err, %hash_result, "resolve index file path failed"))?;
+    store_upload_file(
+        &tmp_path,
+        index_dir,
+        &index_path,
+        &store_dir.join(filename),
+        store_link,
+    )
+    .await
+}
+
+/// remove the listed store files and the store files matching the pattern, the index files which
+/// are no longer referred by any store file are removed too
+#[instrument(err)]
+async fn remove_store_files(
+    index_dir: &Path,
+    store_dir: &Path,
+    store_link: StoreLink,
+    syncing_files: &HashMap<String, Vec<String>>,
+    filenames: Vec<String>,
+    pattern: Option<&str>,
+) -> io::Result<RemoveFilesDetail> {
+    let store_filenames = collect_filenames(store_dir)
+        .await?
+        .into_iter()
+        .map(|filename| filename.to_string_lossy().to_string())
+        .collect::<HashSet<_>>();
+
+    let matched_filenames = store_filenames
+        .iter()
+        .filter(|filename| pattern.map_or(false, |pattern| match_pattern(pattern, filename)))
+        .sorted()
+        .cloned();
+    let filenames = filenames
+        .iter()
+        .map(|filename| normalize_filename(filename))
+        .chain(matched_filenames)
+        .unique()
+        .collect::<Vec<_>>();
+
+    let mut files = Vec::with_capacity(filenames.len());
+    // the removed index files by hash, the index filename may have the extension
+    let mut removed_index_paths = HashMap::new();
+    for filename in filenames {
+        if is_syncing(syncing_files, &filename) {
+            error!(%filename, "store file is being synced");
+
+            let err = FileSyncingError {
+                filename: filename.clone(),
+            };
+            files.push((filename, Err(err.into())));
+
+            continue;
+        }
+
+        // only the store files can be removed, it also rejects the path traversal
+        if !store_filenames.contains(&filename) {
+            error!(%filename, "store file not exists");
+
+            let err = Error::new(ErrorKind::NotFound, format!("file {filename} not exists"));
+            files.push((filename, Err(err)));
+
+            continue;
+        }
+
+        let store_path = store_dir.join(&filename);
+        let result = async {
+            // the tampered store file can be removed, but its link target is never touched
+            let index_path = match store_link.read_link(index_dir, &store_path).await {
+                Err(err) if err.kind() == ErrorKind::InvalidData => None,
+                Err(err) => return Err(err),
+                Ok(index_path) => Some(index_path),
+            };
+            store_link.remove(index_dir, &store_path).await?;
+
+            Ok::<_, Error>(index_path)
+        }
+        .await;
+
+        match result {
             Err(err) => {
-                error!("read file content failed");
+                error!(%err, ?store_path, "remove store file failed");
 
-                let _ = result_sender.send(Err(err));
+                files.push((filename, Err(err)));
+            }
 
-                return;
+            Ok(index_path) => {
+                info!(?store_path, ?index_path, "remove store file done");
+
+                if let Some(index_path) = index_path {
+                    if let Some(hash) = index_file_hash(&index_path) {
+                        removed_index_paths.insert(hash, index_path);
+                    }
+                }
+
+                files.push((filename, Ok(())));
+            }
+        }
+    }
+
+    let mut referred_hashes = HashSet::new();
+    for filename in collect_filenames(store_dir).await? {
+        let store_path = store_dir.join(filename);
+        let index_path = match store_link.read_link(index_dir, &store_path).await {
+            Err(err) if err.kind() == ErrorKind::InvalidData => continue,
+
+            Err(err) => {
+                error!(%err, ?store_path, "read store link failed");
+
+                return Err(err);
             }
 
-            Ok(data) => data,
+            Ok(index_path) => index_path,
         };
+        if let Some(hash) = index_file_hash(&index_path) {
+            referred_hashes.insert(hash);
+        }
+    }
 
-        hasher.update(&data);
+    let mut removed_index_files = vec![];
+    for (hash, index_path) in removed_index_paths
+        .iter()
+        .filter(|(hash, _)| !referred_hashes.contains(*hash))
+        .sorted()
+    {
+        match fs::remove_file(index_path).await {
+            // the store file is removed, the index file is cleaned when it is removed next time
+            Err(err) if err.kind() != ErrorKind::NotFound => {
+                error!(%err, ?index_path, "remove unreferred index file failed");
+            }
 
-        if let Err(err) = upload_file.write_all_buf(&mut data).await {
-            error!(%err, "write data to upload temp file failed");
+            Err(_) => {}
 
-            let _ = result_sender.send(Err(err));
+            Ok(_) => {
+                info!(?index_path, "remove unreferred index file done");
 
-            return;
+                removed_index_files.push(hash.clone());
+            }
         }
     }
 
-    let hash_result = hex::encode_upper(hasher.finalize_fixed());
-    if let Some(hash) = hash {
-        if hash_result != hash {
-            warn!(%hash_result, "hash result is not equal request hash");
+    Ok(RemoveFilesDetail {
+        files,
+        removed_index_files,
+    })
+}
+
+/// query the space of the filesystem backing the dir, the failure is logged and doesn't fail the
+/// stats
+async fn disk_space(dir: &Path) -> Option<DiskSpace> {
+    let path = dir.to_path_buf();
+    let result = task::spawn_blocking(move || statvfs(&path))
+        .await
+        .unwrap()
+        .map_err(io::Error::from);
+
+    match result {
+        Err(err) => {
+            warn!(%err, ?dir, "query disk space failed");
+
+            None
+        }
+
+        // the statvfs field types differ by platform
+        #[allow(clippy::unnecessary_cast)]
+        Ok(stat) => {
+            let fragment_size = stat.fragment_size() as u64;
+
+            Some(DiskSpace {
+                free: stat.blocks_available() as u64 * fragment_size,
+                total: stat.blocks() as u64 * fragment_size,
+            })
         }
     }
+}
 
-    info!(%hash_result, "write data to upload temp file done");
+fn gossip_topic_stats(gossip: &Gossipsub, topic: &TopicHash) -> GossipTopicStats {
+    GossipTopicStats {
+        mesh_peers: gossip.mesh_peers(topic).count(),
+        subscribed_peers: gossip
+            .all_peers()
+            .filter(|(_, topics)| topics.contains(&topic))
+            .count(),
+    }
+}
 
-    let index_path = index_dir.join(hash_result);
+/// check whether the store file is the target of a syncing file
+fn is_syncing(syncing_files: &HashMap<String, Vec<String>>, filename: &str) -> bool {
+    syncing_files
+        .values()
+        .flatten()
+        .any(|syncing_filename| syncing_filename == filename)
+}
 
-    match fs::rename(&tmp_path, &index_path).await {
+/// move the uploaded temp file into the index dir and link the store file to it
+async fn store_upload_file(
+    tmp_path: &Path,
+    index_dir: &Path,
+    index_path: &Path,
+    store_path: &Path,
+    store_link: StoreLink,
+) -> io::Result<()> {
+    create_parent_dir(index_path).await?;
+    set_readonly(tmp_path).await?;
+
+    match move_file(tmp_path, index_path).await {
         Err(err) if err.kind() != ErrorKind::AlreadyExists => {
             error!(%err, ?index_path, ?tmp_path, "move upload temp file to index dir failed");
 
-            let _ = result_sender.send(Err(err));
-
-            return;
+            return Err(err);
         }
 
         Err(_) => info!(?index_path, ?tmp_path, "same hash index file exists"),
@@ -785,19 +2797,236 @@ async fn upload_file<FileStream: Stream<Item = io::Result<Bytes>> + Unpin + Send
         ),
     }
 
-    let store_path = store_dir.join(filename);
-    match fs::symlink(&index_path, &store_path).await {
+    match store_link.link(index_dir, index_path, store_path).await {
         Err(err) if err.kind() != ErrorKind::AlreadyExists => {
-            error!(%err, ?index_path, ?store_path, "create symlink failed");
+            error!(%err, ?index_path, ?store_path, "create store link failed");
 
-            let _ = result_sender.send(Err(err));
+            Err(err)
+        }
 
-            return;
+        Err(_) => {
+            info!(?index_path, ?store_path, "same filename store file exists");
+
+            Ok(())
+        }
+
+        Ok(_) => {
+            info!(?index_path, ?store_path, "create store link done");
+
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::TempDir;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_remove_store_files() {
+        let index_dir = TempDir::new().unwrap();
+        let store_dir = TempDir::new().unwrap();
+        // the index file A is named with the extension
+        for index_filename in ["A.mp4", "B"] {
+            fs::write(index_dir.path().join(index_filename), index_filename)
+                .await
+                .unwrap();
+        }
+        for (filename, index_filename) in [("a.mp4", "A.mp4"), ("b.mp4", "B"), ("b.txt", "B")] {
+            fs::symlink(
+                index_dir.path().join(index_filename),
+                store_dir.path().join(filename),
+            )
+            .await
+            .unwrap();
         }
 
-        Err(_) => info!(?index_path, ?store_path, "same filename store file exists"),
-        Ok(_) => info!(?index_path, ?store_path, "create symlink done"),
+        let detail = remove_store_files(
+            index_dir.path(),
+            store_dir.path(),
+            StoreLink::Symlink,
+            &HashMap::new(),
+            vec!["missing.txt".to_string()],
+            Some("*.MP4"),
+        )
+        .await
+        .unwrap();
+
+        let files = detail
+            .files
+            .iter()
+            .map(|(filename, result)| (filename.as_str(), result.is_ok()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            files,
+            [("missing.txt", false), ("a.mp4", true), ("b.mp4", true)]
+        );
+        // the index file B is still referred by b.txt
+        assert_eq!(detail.removed_index_files, ["A"]);
+        assert!(!index_dir.path().join("A.mp4").exists());
+        assert!(index_dir.path().join("B").exists());
+        assert!(store_dir.path().join("b.txt").exists());
+    }
+
+    #[tokio::test]
+    async fn test_remove_syncing_store_file() {
+        let index_dir = TempDir::new().unwrap();
+        let store_dir = TempDir::new().unwrap();
+        fs::write(index_dir.path().join("A"), "A").await.unwrap();
+        fs::symlink(index_dir.path().join("A"), store_dir.path().join("a.mp4"))
+            .await
+            .unwrap();
+        // the newer version of a.mp4 is being synced
+        let syncing_files = HashMap::from([("B".to_string(), vec!["a.mp4".to_string()])]);
+
+        let detail = remove_store_files(
+            index_dir.path(),
+            store_dir.path(),
+            StoreLink::Symlink,
+            &syncing_files,
+            vec!["a.mp4".to_string()],
+            None,
+        )
+        .await
+        .unwrap();
+
+        let (filename, result) = &detail.files[0];
+        assert_eq!(filename, "a.mp4");
+        assert!(FileSyncingError::is_cause_of(result.as_ref().unwrap_err()));
+        assert!(detail.removed_index_files.is_empty());
+        assert!(store_dir.path().join("a.mp4").exists());
+    }
+
+    #[tokio::test]
+    async fn test_add_file_with_hashed_layout() {
+        let dir = TempDir::new().unwrap();
+        let index_dir = dir.path().join("index");
+        let store_dir = dir.path().join("store");
+        fs::create_dir(&index_dir).await.unwrap();
+        fs::create_dir(&store_dir).await.unwrap();
+        let file_path = dir.path().join("test.txt");
+        fs::write(&file_path, b"test").await.unwrap();
+        let store_file_path = store_dir.join("test.txt");
+
+        let temp_dir = dir.path().join("tmp");
+
+        add_file(
+            &file_path,
+            &store_file_path,
+            None,
+            true,
+            HashAlgorithm::Sha256,
+            2,
+            &index_dir,
+            &temp_dir,
+            StoreLayout::Hashed,
+            IndexNaming::Hash,
+            StoreLink::Symlink,
+        )
+        .await
+        .unwrap();
+
+        let hash = HashAlgorithm::Sha256.hash_file(&file_path).await.unwrap();
+        let index_path = index_dir.join(&hash[..2]).join(&hash[2..4]).join(&hash);
+        assert_eq!(fs::read(&index_path).await.unwrap(), b"test");
+        assert!(fs::metadata(&index_path)
+            .await
+            .unwrap()
+            .permissions()
+            .readonly());
+        assert_eq!(fs::read_link(&store_file_path).await.unwrap(), index_path);
+        assert!(fs::read_dir(&temp_dir)
+            .await
+            .unwrap()
+            .next_entry()
+            .await
+            .unwrap()
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn test_add_file_with_known_hash() {
+        let dir = TempDir::new().unwrap();
+        let index_dir = dir.path().join("index");
+        let store_dir = dir.path().join("store");
+        let temp_dir = dir.path().join("tmp");
+        fs::create_dir(&index_dir).await.unwrap();
+        fs::create_dir(&store_dir).await.unwrap();
+        let file_path = dir.path().join("test.txt");
+        fs::write(&file_path, b"test").await.unwrap();
+        let store_file_path = store_dir.join("test.txt");
+        let add = |known_hash, verify_on_add| {
+            add_file(
+                &file_path,
+                &store_file_path,
+                known_hash,
+                verify_on_add,
+                HashAlgorithm::Sha256,
+                2,
+                &index_dir,
+                &temp_dir,
+                StoreLayout::Hashed,
+                IndexNaming::Hash,
+                StoreLink::Symlink,
+            )
+        };
+
+        // the short or non hex hash is rejected, the valid one is uppercased
+        for hash in ["ABCD", &"G".repeat(64), ""] {
+            let err = normalize_known_hash(hash, HashAlgorithm::Sha256).unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::InvalidInput);
+        }
+        let known_hash = normalize_known_hash(&"ab".repeat(32), HashAlgorithm::Sha256).unwrap();
+        assert_eq!(known_hash, "AB".repeat(32));
+
+        // the wrong hash is rejected when it is verified
+        let err = add(Some(&known_hash), true).await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+        assert!(collect_filenames(&index_dir).await.unwrap().is_empty());
+        assert!(collect_filenames(&temp_dir).await.unwrap().is_empty());
+
+        // the trusted hash names the index file without hashing
+        add(Some(&known_hash), false).await.unwrap();
+        let index_path = index_dir.join("AB").join("AB").join(&known_hash);
+        assert_eq!(fs::read(&index_path).await.unwrap(), b"test");
+        assert_eq!(fs::read_link(&store_file_path).await.unwrap(), index_path);
     }
 
-    let _ = result_sender.send(Ok(()));
+    #[tokio::test]
+    async fn test_cancel_upload_file() {
+        let dir = TempDir::new().unwrap();
+        let index_dir = dir.path().join("index");
+        let store_dir = dir.path().join("store");
+        let temp_dir = dir.path().join("tmp");
+        fs::create_dir(&index_dir).await.unwrap();
+        fs::create_dir(&store_dir).await.unwrap();
+
+        // the dropped upload request cancels the upload before the stream is ended
+        let cancel = CancellationToken::new();
+        cancel.cancel();
+        let (result_sender, result_receiver) = oneshot::channel();
+        upload_file(
+            "test.txt",
+            None,
+            HashAlgorithm::Sha256,
+            index_dir.clone(),
+            StoreLayout::default(),
+            IndexNaming::default(),
+            temp_dir.clone(),
+            store_dir.clone(),
+            StoreLink::Symlink,
+            stream::iter([Ok(Bytes::from_static(b"test"))]),
+            &cancel,
+            result_sender,
+        )
+        .await;
+
+        let err = result_receiver.await.unwrap().unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Interrupted);
+        for dir in [&index_dir, &store_dir, &temp_dir] {
+            assert!(collect_filenames(dir).await.unwrap().is_empty(), "{dir:?}");
+        }
+    }
 }
@@ -1,18 +1,555 @@
-use std::path::PathBuf;
+use std::collections::HashSet;
+use std::io;
+use std::io::ErrorKind;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
 use std::time::Duration;
 
+use anyhow::anyhow;
+use http::Uri;
+use libp2p::gossipsub::ValidationMode;
 use libp2p::identity::Keypair;
-use libp2p::pnet::PreSharedKey;
-use libp2p::Multiaddr;
+use libp2p::multiaddr::Protocol;
+use libp2p::{tcp, Multiaddr, PeerId};
+use tokio::fs;
+
+use crate::hash::HashAlgorithm;
+use crate::node::behaviour::FileProtocol;
+use crate::node::pnet::PnetKeys;
+use crate::node::store_link::StoreLink;
+use crate::util::{ExcludePatterns, SharePatterns};
+
+/// default min interval of publishing the peers learned from identify
+pub const DEFAULT_DISCOVER_INTERVAL: Duration = Duration::from_secs(5);
+
+/// default max number of the files which are synced concurrently
+pub const DEFAULT_MAX_SYNC_FILES: usize = 4;
+
+/// default number of the chunk requests kept in flight for a syncing file
+pub const DEFAULT_SYNC_READ_AHEAD: usize = 4;
+
+/// default listen backlog of the tcp listen sockets, it is the libp2p default
+pub const DEFAULT_TCP_LISTEN_BACKLOG: u32 = 1024;
+
+/// default max read rate of the index files scrub, 16MiB per second
+pub const DEFAULT_SCRUB_RATE: u64 = 16 * 1024 * 1024;
+
+/// default age of the stale upload temp files which are removed on startup
+pub const DEFAULT_STALE_TEMP_FILE_AGE: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// default max number of the accepted discover addrs of a peer
+pub const DEFAULT_MAX_DISCOVER_ADDRS: usize = 8;
+
+/// default max number of the concurrent inbound file requests of a peer
+pub const DEFAULT_MAX_PEER_INBOUND_REQUESTS: usize = 32;
+
+/// default max number of the configured peer addrs
+pub const DEFAULT_MAX_PEER_ADDRS: usize = 256;
+
+/// default read buffer size of hashing and copying the added file
+pub const DEFAULT_HASH_BUFFER_SIZE: usize = 1024 * 1024;
+
+/// default max number of the pooled read buffers
+pub const DEFAULT_BUFFER_POOL_SIZE: usize = 16;
+
+/// default max number of the retries of a file which failed to sync
+pub const DEFAULT_SYNC_RETRY_MAX_ATTEMPTS: u32 = 3;
+
+/// default delay of the first retry of a file which failed to sync
+pub const DEFAULT_SYNC_RETRY_BACKOFF: Duration = Duration::from_secs(10);
+
+/// default max time of reading nothing from a file request or response
+pub const DEFAULT_FILE_READ_IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// max length of the store filename extension which is appended to the index filename
+const MAX_INDEX_EXTENSION_LEN: usize = 16;
+
+/// default temp dir name under the index dir
+pub const DEFAULT_TEMP_DIR_NAME: &str = ".tmp";
 
 #[derive(Debug)]
 pub struct Config {
     pub key: Keypair,
     pub index_dir: PathBuf,
+    /// the layout of the index files in the `index_dir`
+    pub store_layout: StoreLayout,
+    /// how the index files are named
+    pub index_naming: IndexNaming,
+    /// the dir of the in-progress downloads and uploads, the done files are moved into the
+    /// `index_dir`
+    pub temp_dir: PathBuf,
+    /// the upload temp files which aren't modified for it are removed on startup
+    pub stale_temp_file_age: Duration,
+    /// rehash the index files every interval, the scrub is disabled when it is `None`
+    pub scrub_interval: Option<Duration>,
+    /// the max bytes per second read by the scrub
+    pub scrub_rate: u64,
     pub store_dir: PathBuf,
-    pub handshake_key: PreSharedKey,
+    /// how the store files link to the index files
+    pub store_link: StoreLink,
+    /// the accepted pre-shared keys, the first one is used by the outbound connections
+    pub handshake_keys: PnetKeys,
+    /// gossip message validation mode, `Strict` requires message signing which depends on the
+    /// keypair, `Anonymous` publishes messages without author and signature
+    pub gossip_validation_mode: ValidationMode,
+    /// mixed into the gossip topic names, the nodes of different networks ignore each other
+    pub network_id: Option<String>,
     pub refresh_store_interval: Duration,
     pub sync_file_interval: Duration,
+    /// the random jitter percentage of the refresh store and the sync file intervals
+    pub interval_jitter: u8,
+    /// the min interval of publishing the peers learned from identify
+    pub discover_interval: Duration,
+    /// max number of the accepted discover addrs of a peer, the global addrs are preferred
+    pub max_discover_addrs: usize,
+    /// accept the loopback and the link local discover addrs
+    pub allow_local_discover_addrs: bool,
+    /// max number of the concurrent inbound file requests of a peer
+    pub max_peer_inbound_requests: usize,
+    /// max number of the configured peer addrs
+    pub max_peer_addrs: usize,
+    /// hash algorithm of the index files, all nodes in a swarm must use the same one
+    pub hash_algorithm: HashAlgorithm,
+    /// read buffer size of hashing and copying the added file
+    pub hash_buffer_size: usize,
+    /// max number of the pooled read buffers for serving file chunks
+    pub buffer_pool_size: usize,
+    /// memory map the large read only index files when serving them, fallback to `read_at` when
+    /// the file is writable or mmap fails
+    pub enable_mmap: bool,
+    /// max number of the files which are synced concurrently
+    pub max_sync_files: usize,
+    /// number of the chunk requests kept in flight for a syncing file
+    pub sync_read_ahead: usize,
+    /// the peer files larger than the size are not synced automatically, unlimited when it is
+    /// `None`
+    pub max_auto_sync_file_size: Option<u64>,
+    /// only the files of the peers are synced, all peers are synced when it is empty
+    pub sync_from_peers: HashSet<PeerId>,
+    /// copy the chunks of the previous local version which the peer file still has before
+    /// fetching the rest of a changed file, and digest the chunks of the added and synced files
+    /// ahead for the peers
+    pub content_defined_chunking: bool,
+    /// max number of the established peer connections, the established connections are kept
+    /// and the new ones are rejected when it is reached, unlimited when it is `None`
+    pub max_connections: Option<u32>,
+    /// max number of the concurrent upload tasks, unlimited when it is `None`
+    pub max_uploads: Option<usize>,
+    /// the order of syncing the files
+    pub sync_order: SyncOrder,
+    /// the retry policy of the files which failed to sync
+    pub sync_retry: SyncRetry,
+    /// the store files matching the patterns are never announced or synced
+    pub exclude_patterns: ExcludePatterns,
+    /// only the store files matching the patterns are announced and served
+    pub share_only: SharePatterns,
+    /// reject adding the file whose name differs from a store file only by case, otherwise only
+    /// warn
+    pub reject_case_conflict: bool,
+    /// hash the added file even when its hash is given, otherwise the given hash is trusted
+    pub verify_on_add: bool,
+    /// reject the commands which change the store files or the peers
+    pub read_only: bool,
+    /// post the synced file to the http url when a file finishes syncing
+    pub sync_complete_webhook: Option<Uri>,
     pub enable_relay_behaviour: bool,
     pub relay_server_addr: Option<Multiaddr>,
+    /// don't listen the direct addrs, only accept the connections through the relay server, the
+    /// observed addrs are not advertised, it is for the node behind the strict NAT
+    pub relay_only: bool,
+    /// push the identify info to the connected peers when our addrs change
+    pub identify_push: bool,
+    /// custom dns servers, use system resolver when it is `None`
+    pub dns_servers: Option<Vec<SocketAddr>>,
+    /// dial and listen the plain tcp addrs
+    pub enable_tcp: bool,
+    /// the socket options of both the plain tcp and the websocket transports
+    pub tcp_options: TcpOptions,
+    /// dial and listen the websocket addrs
+    pub enable_websocket: bool,
+    /// the noise handshake pattern, all nodes in a swarm must use the same one
+    pub noise_handshake: NoiseHandshake,
+    /// the highest file protocol version, the lower versions are supported too
+    pub max_file_protocol: FileProtocol,
+    /// the stalled file transfer is aborted when nothing is read for it
+    pub file_read_idle_timeout: Duration,
+    /// the ip family of the dialed and listened swarm addrs
+    pub ip_family: IpFamily,
+}
+
+/// the order of syncing the files when there are more files than the max sync files
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+pub enum SyncOrder {
+    /// the small files are available fast
+    #[default]
+    SmallestFirst,
+    LargestFirst,
+    /// order by the filename
+    Alphabetical,
+}
+
+impl FromStr for SyncOrder {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "smallest_first" => Ok(SyncOrder::SmallestFirst),
+            "largest_first" => Ok(SyncOrder::LargestFirst),
+            "alphabetical" => Ok(SyncOrder::Alphabetical),
+            _ => Err(anyhow!("unknown sync order {s}")),
+        }
+    }
+}
+
+/// the retry policy of the files which failed to sync, the retries don't wait for the sync
+/// interval
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct SyncRetry {
+    /// max number of the retries, the file is synced at the next sync interval after it
+    pub max_attempts: u32,
+    /// the delay of the first retry, it doubles for each failure
+    pub backoff: Duration,
+}
+
+impl Default for SyncRetry {
+    fn default() -> Self {
+        Self {
+            max_attempts: DEFAULT_SYNC_RETRY_MAX_ATTEMPTS,
+            backoff: DEFAULT_SYNC_RETRY_BACKOFF,
+        }
+    }
+}
+
+impl SyncRetry {
+    /// the delay before the retry after the `attempts`th failure, it is capped at `max_backoff`
+    pub fn backoff(self, attempts: u32, max_backoff: Duration) -> Duration {
+        self.backoff
+            .saturating_mul(2u32.saturating_pow(attempts.saturating_sub(1)))
+            .min(max_backoff)
+    }
+}
+
+/// the layout of the index files in the index dir
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+pub enum StoreLayout {
+    /// all index files are in the index dir
+    #[default]
+    Flat,
+    /// the index files are sharded by the hash prefix, like `{index_dir}/ab/cd/{hash}`, it
+    /// performs better with lots of files on some filesystems
+    Hashed,
+}
+
+impl FromStr for StoreLayout {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "flat" => Ok(StoreLayout::Flat),
+            "hashed" => Ok(StoreLayout::Hashed),
+            _ => Err(anyhow!("unknown store layout {s}")),
+        }
+    }
+}
+
+impl StoreLayout {
+    /// the depth of the shard dirs under the index dir
+    pub fn shard_depth(self) -> usize {
+        match self {
+            StoreLayout::Flat => 0,
+            StoreLayout::Hashed => 2,
+        }
+    }
+
+    /// the index file path of the hash, the too short hash is never sharded
+    pub fn index_path(self, index_dir: &Path, hash: &str) -> PathBuf {
+        match (self, hash.get(..2), hash.get(2..4)) {
+            (StoreLayout::Hashed, Some(first), Some(second)) => {
+                index_dir.join(first).join(second).join(hash)
+            }
+
+            _ => index_dir.join(hash),
+        }
+    }
+}
+
+/// how the index files are named, the hash is always the identity of the index file
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+pub enum IndexNaming {
+    /// the index filename is the hash
+    #[default]
+    Hash,
+    /// the extension of the store filename is appended to the hash, like `{hash}.mp4`, so the
+    /// content type can be identified from the index dir, the index files named by the hash
+    /// only are still found
+    HashExtension,
+}
+
+impl FromStr for IndexNaming {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "hash" => Ok(IndexNaming::Hash),
+            "hash_extension" => Ok(IndexNaming::HashExtension),
+            _ => Err(anyhow!("unknown index naming {s}")),
+        }
+    }
+}
+
+impl IndexNaming {
+    /// the path of the new index file of the hash, `filename` is the store filename
+    pub fn index_path(
+        self,
+        store_layout: StoreLayout,
+        index_dir: &Path,
+        hash: &str,
+        filename: &str,
+    ) -> PathBuf {
+        let index_path = store_layout.index_path(index_dir, hash);
+        match (self, index_extension(filename)) {
+            (IndexNaming::HashExtension, Some(extension)) => index_path.with_extension(extension),
+            _ => index_path,
+        }
+    }
+
+    /// the path of the existing index file of the hash, or the path of the new index file when
+    /// it doesn't exist
+    pub async fn resolve_index_path(
+        self,
+        store_layout: StoreLayout,
+        index_dir: &Path,
+        hash: &str,
+        filename: &str,
+    ) -> io::Result<PathBuf> {
+        let index_path = self.index_path(store_layout, index_dir, hash, filename);
+        if self == IndexNaming::Hash || path_exists(&index_path).await? {
+            return Ok(index_path);
+        }
+
+        let hash_path = store_layout.index_path(index_dir, hash);
+        if path_exists(&hash_path).await? {
+            return Ok(hash_path);
+        }
+
+        // the index file may be named by the extension of another store file
+        let dir = hash_path.parent().unwrap_or(index_dir);
+        let mut read_dir = match fs::read_dir(dir).await {
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(index_path),
+            Err(err) => return Err(err),
+            Ok(read_dir) => read_dir,
+        };
+        while let Some(entry) = read_dir.next_entry().await? {
+            let path = entry.path();
+            if index_file_hash(&path).map_or(false, |index_hash| index_hash == hash) {
+                return Ok(path);
+            }
+        }
+
+        Ok(index_path)
+    }
+}
+
+async fn path_exists(path: &Path) -> io::Result<bool> {
+    match fs::metadata(path).await {
+        Err(err) if err.kind() == ErrorKind::NotFound => Ok(false),
+        Err(err) => Err(err),
+        Ok(_) => Ok(true),
+    }
+}
+
+/// the hash of the index file, the extension of the index filename is stripped
+pub fn index_file_hash(index_path: &Path) -> Option<String> {
+    let name = index_path.file_name()?.to_string_lossy();
+
+    Some(match name.split_once('.') {
+        None => name.to_string(),
+        Some((hash, _)) => hash.to_string(),
+    })
+}
+
+/// the lowercase extension of the store filename, the unusual extensions are ignored
+fn index_extension(filename: &str) -> Option<String> {
+    let (_, extension) = filename.rsplit_once('.')?;
+    if extension.is_empty()
+        || extension.len() > MAX_INDEX_EXTENSION_LEN
+        || !extension.bytes().all(|b| b.is_ascii_alphanumeric())
+    {
+        return None;
+    }
+
+    Some(extension.to_ascii_lowercase())
+}
+
+/// the socket options of the tcp transport, `SO_REUSEADDR` is always set and `TCP_NODELAY` is
+/// always enabled
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct TcpOptions {
+    /// the listen backlog of the new listen sockets
+    pub listen_backlog: u32,
+    /// set `SO_REUSEPORT` on the listen sockets and dial from the listen ports, it helps the NAT
+    /// traversal
+    pub port_reuse: bool,
+    /// the `IP_TTL` of the new sockets, the OS default is used when it is `None`
+    pub ttl: Option<u32>,
+}
+
+impl Default for TcpOptions {
+    fn default() -> Self {
+        Self {
+            listen_backlog: DEFAULT_TCP_LISTEN_BACKLOG,
+            port_reuse: false,
+            ttl: None,
+        }
+    }
+}
+
+impl TcpOptions {
+    pub fn to_config(self) -> tcp::Config {
+        let config = tcp::Config::new()
+            .nodelay(true)
+            .listen_backlog(self.listen_backlog)
+            .port_reuse(self.port_reuse);
+
+        match self.ttl {
+            None => config,
+            Some(ttl) => config.ttl(ttl),
+        }
+    }
+}
+
+/// the noise handshake pattern of authenticating the connections, the connections are already
+/// encrypted by the pre-share key, so a passive observer without the key sees neither pattern
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+pub enum NoiseHandshake {
+    /// 1.5 round trips, both static keys are encrypted and the initiator reveals its identity
+    /// only after it authenticates the responder, it is the libp2p default and interoperable
+    #[default]
+    XX,
+    /// 1 round trip, faster to connect, but the initiator sends its static key unencrypted in
+    /// the first message, and the responder reveals its identity to anyone who dials it
+    IX,
+}
+
+impl FromStr for NoiseHandshake {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "xx" => Ok(NoiseHandshake::XX),
+            "ix" => Ok(NoiseHandshake::IX),
+            _ => Err(anyhow!("unknown noise handshake {s}")),
+        }
+    }
+}
+
+/// the ip family of the swarm addrs, the addrs of the other family are neither dialed nor listened
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+pub enum IpFamily {
+    #[default]
+    Any,
+    V4,
+    V6,
+}
+
+impl IpFamily {
+    /// whether the addr can be dialed or listened, the `/dns` addr may resolve to both families,
+    /// it is always allowed and restricted when dialing
+    pub fn allows(self, addr: &Multiaddr) -> bool {
+        match addr.iter().next() {
+            Some(Protocol::Ip4(_) | Protocol::Dns4(_)) => self != IpFamily::V6,
+            Some(Protocol::Ip6(_) | Protocol::Dns6(_)) => self != IpFamily::V4,
+            _ => true,
+        }
+    }
+}
+
+impl FromStr for IpFamily {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "any" => Ok(IpFamily::Any),
+            "ipv4" => Ok(IpFamily::V4),
+            "ipv6" => Ok(IpFamily::V6),
+            _ => Err(anyhow!("unknown ip family {s}")),
+        }
+    }
+}
+
+/// parse the gossip validation mode, the mode is one of `strict`, `permissive`, `anonymous` and
+/// `none`
+pub fn parse_validation_mode(mode: &str) -> anyhow::Result<ValidationMode> {
+    match mode.to_lowercase().as_str() {
+        "strict" => Ok(ValidationMode::Strict),
+        "permissive" => Ok(ValidationMode::Permissive),
+        "anonymous" => Ok(ValidationMode::Anonymous),
+        "none" => Ok(ValidationMode::None),
+        _ => Err(anyhow!("unknown gossip validation mode {mode}")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::TempDir;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_resolve_index_path() {
+        let dir = TempDir::new().unwrap();
+        let index_dir = dir.path();
+        let naming = IndexNaming::HashExtension;
+
+        assert_eq!(
+            naming
+                .resolve_index_path(StoreLayout::Flat, index_dir, "A", "a.MP4")
+                .await
+                .unwrap(),
+            index_dir.join("A.mp4")
+        );
+        assert_eq!(
+            naming.index_path(StoreLayout::Flat, index_dir, "A", "a.tar.gz!"),
+            index_dir.join("A")
+        );
+
+        // the existing index file is named by the extension of another store file
+        fs::write(index_dir.join("B.mkv"), b"b").await.unwrap();
+        let index_path = naming
+            .resolve_index_path(StoreLayout::Flat, index_dir, "B", "b.mp4")
+            .await
+            .unwrap();
+        assert_eq!(index_path, index_dir.join("B.mkv"));
+        assert_eq!(index_file_hash(&index_path).unwrap(), "B");
+
+        assert_eq!(
+            IndexNaming::Hash
+                .resolve_index_path(StoreLayout::Flat, index_dir, "C", "c.mp4")
+                .await
+                .unwrap(),
+            index_dir.join("C")
+        );
+    }
+
+    #[test]
+    fn test_ip_family_allows() {
+        let ipv4_addr = "/ip4/127.0.0.1/tcp/1234".parse::<Multiaddr>().unwrap();
+        let ipv6_addr = "/dns6/example.com/tcp/1234".parse::<Multiaddr>().unwrap();
+        let dns_addr = "/dns/example.com/tcp/1234".parse::<Multiaddr>().unwrap();
+
+        assert!(IpFamily::Any.allows(&ipv4_addr));
+        assert!(IpFamily::Any.allows(&ipv6_addr));
+
+        assert!(IpFamily::V4.allows(&ipv4_addr));
+        assert!(!IpFamily::V4.allows(&ipv6_addr));
+        assert!(IpFamily::V4.allows(&dns_addr));
+
+        assert!(!IpFamily::V6.allows(&ipv4_addr));
+        assert!(IpFamily::V6.allows(&ipv6_addr));
+        assert!(IpFamily::V6.allows(&dns_addr));
+
+        assert_eq!("IPv4".parse::<IpFamily>().unwrap(), IpFamily::V4);
+        assert!("ipv5".parse::<IpFamily>().is_err());
+    }
 }
@@ -0,0 +1,195 @@
+use std::error::Error;
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use libp2p::core::upgrade::{NegotiationError, ProtocolError};
+use libp2p::noise::NoiseError;
+use libp2p::swarm::{DialError, PendingConnectionError, PendingInboundConnectionError};
+use libp2p::TransportError;
+
+use crate::command::ConnectionErrorStats;
+
+/// the category of the failed connection
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum ConnectionErrorKind {
+    /// the peer is unreachable, like the connection is refused
+    Dial,
+    Timeout,
+    /// the protocol negotiation or the noise handshake failed, or the peer id mismatches
+    Handshake,
+    /// the first protocol negotiation after the pnet handshake reads garbage, the peers can't
+    /// decrypt each other, it is almost always a different pre-shared key
+    KeyMismatch,
+    Other,
+}
+
+/// the counters of the failed connections by the category, the transport shares them to count the
+/// pnet handshake failures, which are not visible in the swarm errors
+#[derive(Debug, Clone, Default)]
+pub struct ConnectionErrors {
+    counters: Arc<Counters>,
+}
+
+#[derive(Debug, Default)]
+struct Counters {
+    dial: AtomicU64,
+    timeout: AtomicU64,
+    handshake: AtomicU64,
+    key_mismatch: AtomicU64,
+    pnet: AtomicU64,
+    other: AtomicU64,
+}
+
+impl ConnectionErrors {
+    pub fn record(&self, kind: ConnectionErrorKind) {
+        let counter = match kind {
+            ConnectionErrorKind::Dial => &self.counters.dial,
+            ConnectionErrorKind::Timeout => &self.counters.timeout,
+            ConnectionErrorKind::Handshake => &self.counters.handshake,
+            ConnectionErrorKind::KeyMismatch => &self.counters.key_mismatch,
+            ConnectionErrorKind::Other => &self.counters.other,
+        };
+
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_pnet(&self) {
+        self.counters.pnet.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn stats(&self) -> ConnectionErrorStats {
+        ConnectionErrorStats {
+            dial: self.counters.dial.load(Ordering::Relaxed),
+            timeout: self.counters.timeout.load(Ordering::Relaxed),
+            handshake: self.counters.handshake.load(Ordering::Relaxed),
+            key_mismatch: self.counters.key_mismatch.load(Ordering::Relaxed),
+            pnet: self.counters.pnet.load(Ordering::Relaxed),
+            other: self.counters.other.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// classify the outgoing connection error, the transport errors of all addrs are checked, the
+/// first handshake or timeout error wins, otherwise it is a dial failure
+pub fn classify_dial_error(error: &DialError) -> ConnectionErrorKind {
+    match error {
+        DialError::WrongPeerId { .. } => ConnectionErrorKind::Handshake,
+        DialError::ConnectionIo(err) => classify_io_error(err).unwrap_or(ConnectionErrorKind::Dial),
+        DialError::Transport(errors) => errors
+            .iter()
+            .find_map(|(_, err)| classify_transport_error(err))
+            .unwrap_or(ConnectionErrorKind::Dial),
+        _ => ConnectionErrorKind::Other,
+    }
+}
+
+/// classify the incoming connection error, the accepted connection is never a dial failure
+pub fn classify_incoming_error(
+    error: &PendingInboundConnectionError<io::Error>,
+) -> ConnectionErrorKind {
+    match error {
+        PendingConnectionError::WrongPeerId { .. } => ConnectionErrorKind::Handshake,
+        PendingConnectionError::IO(err) => {
+            classify_io_error(err).unwrap_or(ConnectionErrorKind::Other)
+        }
+        PendingConnectionError::Transport(err) => {
+            classify_transport_error(err).unwrap_or(ConnectionErrorKind::Other)
+        }
+        _ => ConnectionErrorKind::Other,
+    }
+}
+
+fn classify_transport_error(error: &TransportError<io::Error>) -> Option<ConnectionErrorKind> {
+    match error {
+        TransportError::MultiaddrNotSupported(_) => None,
+        TransportError::Other(err) => classify_io_error(err),
+    }
+}
+
+/// walk the error chain, the boxed transport wraps the upgrade errors in the [`io::Error`]
+fn classify_io_error(error: &io::Error) -> Option<ConnectionErrorKind> {
+    let mut error: Option<&(dyn Error + 'static)> = Some(error);
+    while let Some(err) = error {
+        if let Some(err) = err.downcast_ref::<NegotiationError>() {
+            return Some(if is_garbled_negotiation(err) {
+                ConnectionErrorKind::KeyMismatch
+            } else {
+                ConnectionErrorKind::Handshake
+            });
+        }
+        if err.is::<NoiseError>() {
+            return Some(ConnectionErrorKind::Handshake);
+        }
+
+        error = match err.downcast_ref::<io::Error>() {
+            Some(err) if err.kind() == io::ErrorKind::TimedOut => {
+                return Some(ConnectionErrorKind::Timeout)
+            }
+            // the source of the custom io error skips the wrapped error itself
+            Some(err) => err.get_ref().map(|err| err as &(dyn Error + 'static)),
+            None => err.source(),
+        };
+    }
+
+    None
+}
+
+/// the pnet handshake only exchanges the nonces, so it succeeds with the different keys, the
+/// mismatch appears as the invalid multistream messages decrypted by the wrong key
+fn is_garbled_negotiation(error: &NegotiationError) -> bool {
+    match error {
+        NegotiationError::ProtocolError(
+            ProtocolError::InvalidMessage | ProtocolError::InvalidProtocol,
+        ) => true,
+        // the garbage length prefix exceeds the max frame length
+        NegotiationError::ProtocolError(ProtocolError::IoError(err)) => {
+            err.kind() == io::ErrorKind::InvalidData
+        }
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use libp2p::Multiaddr;
+
+    use super::*;
+
+    #[test]
+    fn test_classify_dial_error() {
+        let transport_error = |err: io::Error| {
+            DialError::Transport(vec![(Multiaddr::empty(), TransportError::Other(err))])
+        };
+
+        assert_eq!(
+            classify_dial_error(&transport_error(io::ErrorKind::ConnectionRefused.into())),
+            ConnectionErrorKind::Dial
+        );
+        assert_eq!(
+            classify_dial_error(&transport_error(io::Error::new(
+                io::ErrorKind::Other,
+                io::Error::from(io::ErrorKind::TimedOut)
+            ))),
+            ConnectionErrorKind::Timeout
+        );
+        assert_eq!(
+            classify_dial_error(&transport_error(io::Error::new(
+                io::ErrorKind::Other,
+                NegotiationError::Failed
+            ))),
+            ConnectionErrorKind::Handshake
+        );
+        assert_eq!(
+            classify_dial_error(&transport_error(io::Error::new(
+                io::ErrorKind::Other,
+                NegotiationError::ProtocolError(ProtocolError::InvalidMessage)
+            ))),
+            ConnectionErrorKind::KeyMismatch
+        );
+        assert_eq!(
+            classify_dial_error(&DialError::NoAddresses),
+            ConnectionErrorKind::Other
+        );
+    }
+}
@@ -0,0 +1,88 @@
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use libp2p::core::transport::{ListenerId, TransportError, TransportEvent};
+use libp2p::{Multiaddr, Transport};
+use tracing::info;
+
+/// wrap the direct transport of the relay only node, the listen requests are accepted but
+/// nothing is listened, so the node is reachable through the relay server only, the dials are
+/// passed to the inner transport
+#[derive(Debug)]
+pub struct DialOnlyTransport<T> {
+    inner: T,
+    dial_only: bool,
+}
+
+impl<T> DialOnlyTransport<T> {
+    pub fn new(inner: T, dial_only: bool) -> Self {
+        Self { inner, dial_only }
+    }
+}
+
+impl<T> Transport for DialOnlyTransport<T>
+where
+    T: Transport + Unpin,
+{
+    type Output = T::Output;
+    type Error = T::Error;
+    type ListenerUpgrade = T::ListenerUpgrade;
+    type Dial = T::Dial;
+
+    fn listen_on(&mut self, addr: Multiaddr) -> Result<ListenerId, TransportError<Self::Error>> {
+        if self.dial_only {
+            info!(%addr, "dial only transport skips listening");
+
+            return Ok(ListenerId::new());
+        }
+
+        self.inner.listen_on(addr)
+    }
+
+    fn remove_listener(&mut self, id: ListenerId) -> bool {
+        self.inner.remove_listener(id)
+    }
+
+    fn dial(&mut self, addr: Multiaddr) -> Result<Self::Dial, TransportError<Self::Error>> {
+        self.inner.dial(addr)
+    }
+
+    fn dial_as_listener(
+        &mut self,
+        addr: Multiaddr,
+    ) -> Result<Self::Dial, TransportError<Self::Error>> {
+        self.inner.dial_as_listener(addr)
+    }
+
+    fn poll(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<TransportEvent<Self::ListenerUpgrade, Self::Error>> {
+        Pin::new(&mut self.inner).poll(cx)
+    }
+
+    fn address_translation(&self, listen: &Multiaddr, observed: &Multiaddr) -> Option<Multiaddr> {
+        self.inner.address_translation(listen, observed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use libp2p::core::transport::MemoryTransport;
+
+    use super::*;
+
+    #[test]
+    fn test_dial_only_skips_listen() {
+        let addr: Multiaddr = "/memory/19231".parse().unwrap();
+
+        let mut transport = DialOnlyTransport::new(MemoryTransport::new(), true);
+        transport.listen_on(addr.clone()).unwrap();
+        // the addr is still free
+        let mut other_transport = MemoryTransport::new();
+        other_transport.listen_on(addr.clone()).unwrap();
+
+        let mut transport = DialOnlyTransport::new(MemoryTransport::new(), false);
+        assert!(transport.listen_on(addr).is_err());
+    }
+}
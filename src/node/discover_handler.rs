@@ -0,0 +1,178 @@
+use std::collections::HashMap;
+use std::time::SystemTime;
+
+use itertools::Itertools;
+use libp2p::gossipsub::Sha256Topic;
+use libp2p::identity::Keypair;
+use libp2p::{Multiaddr, PeerId, Swarm};
+use prost::Message as _;
+use tap::TapFallible;
+use tracing::{error, info, instrument};
+
+use crate::node::behaviour::Behaviour;
+use crate::node::message::{DiscoverMessage, Peer};
+use crate::node::publish_retry::PublishRetries;
+
+/// publish our signed addresses and the signed addresses of the discovered peers when new peers
+/// are identified, the publish is batched, so a churny swarm won't flood the discover topic
+pub struct DiscoverHandler<'a> {
+    swarm: &'a mut Swarm<Behaviour>,
+    key: &'a Keypair,
+    discover_peers: &'a HashMap<PeerId, Vec<Peer>>,
+    pending_discover: &'a mut bool,
+    publish_retries: &'a mut PublishRetries,
+    /// the discover topic of the share network
+    topic: &'a Sha256Topic,
+}
+
+impl<'a> DiscoverHandler<'a> {
+    pub fn new(
+        swarm: &'a mut Swarm<Behaviour>,
+        key: &'a Keypair,
+        discover_peers: &'a HashMap<PeerId, Vec<Peer>>,
+        pending_discover: &'a mut bool,
+        publish_retries: &'a mut PublishRetries,
+        topic: &'a Sha256Topic,
+    ) -> Self {
+        Self {
+            swarm,
+            key,
+            discover_peers,
+            pending_discover,
+            publish_retries,
+            topic,
+        }
+    }
+
+    #[instrument(err, skip(self))]
+    pub fn handle_tick(self) -> anyhow::Result<()> {
+        if !*self.pending_discover {
+            return Ok(());
+        }
+        *self.pending_discover = false;
+
+        let local_addrs = self
+            .swarm
+            .listeners()
+            .cloned()
+            .chain(
+                self.swarm
+                    .external_addresses()
+                    .map(|record| record.addr.clone()),
+            )
+            .unique()
+            .collect::<Vec<_>>();
+
+        let discover_message = create_discover_message(self.key, &local_addrs, self.discover_peers)
+            .tap_err(|err| error!(%err, "create discover message failed"))?;
+
+        info!(?discover_message, "create discover message done");
+
+        let discover_message = discover_message.encode_to_vec();
+
+        self.publish_retries
+            .publish(
+                &mut self.swarm.behaviour_mut().gossip,
+                self.topic,
+                discover_message,
+            )
+            .tap_err(|err| error!(%err, topic = ?self.topic, "publish discover message failed"))?;
+
+        Ok(())
+    }
+}
+
+/// sign our addresses, the addresses of the discovered peers are already signed by themselves
+fn create_discover_message(
+    key: &Keypair,
+    local_addrs: &[Multiaddr],
+    discover_peers: &HashMap<PeerId, Vec<Peer>>,
+) -> anyhow::Result<DiscoverMessage> {
+    let mut peers = local_addrs
+        .iter()
+        .map(|addr| Peer::new_signed(key, addr))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    peers.extend(discover_peers.values().flatten().cloned());
+
+    Ok(DiscoverMessage {
+        peers,
+        discover_time: SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_micros() as _,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use libp2p::gossipsub::ValidationMode;
+
+    use super::*;
+    use crate::node::behaviour::{FileProtocol, ShareTopics};
+    use crate::node::buffer_pool::BufferPool;
+    use crate::node::config::DEFAULT_FILE_READ_IDLE_TIMEOUT;
+    use crate::node::create_memory_transport;
+    use crate::node::pnet::{PnetKeys, KEY_SIZE};
+
+    #[tokio::test]
+    async fn test_publish_without_peers() {
+        let topics = ShareTopics::new(None);
+        let key = Keypair::generate_ed25519();
+        let (transport, _) = create_memory_transport(&key, PnetKeys::new(vec![[0; KEY_SIZE]]));
+        let behaviour = Behaviour::new(
+            key.clone(),
+            ValidationMode::Strict,
+            false,
+            false,
+            None,
+            FileProtocol::default(),
+            DEFAULT_FILE_READ_IDLE_TIMEOUT,
+            BufferPool::new(0),
+            &topics,
+        )
+        .unwrap();
+        let mut swarm = Swarm::with_tokio_executor(transport, behaviour, key.public().to_peer_id());
+        let mut pending_discover = true;
+        let mut publish_retries = PublishRetries::default();
+
+        DiscoverHandler::new(
+            &mut swarm,
+            &key,
+            &HashMap::new(),
+            &mut pending_discover,
+            &mut publish_retries,
+            &topics.discover,
+        )
+        .handle_tick()
+        .unwrap();
+        assert!(!pending_discover);
+    }
+
+    #[test]
+    fn test_create_discover_message() {
+        let key = Keypair::generate_ed25519();
+        let other_key = Keypair::generate_ed25519();
+        let addr1: Multiaddr = "/ip4/127.0.0.1/tcp/1".parse().unwrap();
+        let addr2: Multiaddr = "/ip4/127.0.0.1/tcp/2".parse().unwrap();
+
+        let other_peer = Peer::new_signed(&other_key, &addr2).unwrap();
+        let discover_peers =
+            HashMap::from([(other_key.public().to_peer_id(), vec![other_peer.clone()])]);
+
+        let discover_message =
+            create_discover_message(&key, &[addr1.clone(), addr2], &discover_peers).unwrap();
+
+        assert_eq!(discover_message.peers.len(), 3);
+        assert!(discover_message.peers.contains(&other_peer));
+
+        let verified = discover_message
+            .peers
+            .iter()
+            .map(|peer| peer.verify().unwrap())
+            .filter(|(peer_id, _)| *peer_id == key.public().to_peer_id())
+            .map(|(_, addr)| addr)
+            .collect::<Vec<_>>();
+        assert_eq!(verified.len(), 2);
+        assert!(verified.contains(&addr1));
+    }
+}
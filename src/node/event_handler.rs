@@ -1,50 +1,95 @@
-use std::collections::hash_map::Entry;
 use std::collections::{HashMap, HashSet};
 use std::io;
 use std::io::{Error, ErrorKind};
-use std::path::Path;
-use std::time::{Duration, SystemTime};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
-use bytes::{Bytes, BytesMut};
+use bytes::Bytes;
 use derive_builder::Builder;
 use either::Either;
-use futures_channel::oneshot::Sender;
-use itertools::Itertools;
+use futures_util::stream::FuturesUnordered;
 use libp2p::core::ConnectedPoint;
 use libp2p::gossipsub::GossipsubEvent;
 use libp2p::request_response::{
-    OutboundFailure, RequestId, RequestResponseEvent, RequestResponseMessage,
+    OutboundFailure, RequestId, RequestResponseEvent, RequestResponseMessage, ResponseChannel,
 };
 use libp2p::swarm::{AddressScore, SwarmEvent};
-use libp2p::{identify, Multiaddr, PeerId, Swarm};
+use libp2p::{identify, ping, Multiaddr, PeerId, Swarm};
 use libp2p_auto_relay::{endpoint, relay};
 use prost::Message as _;
 use tap::TapFallible;
-use tokio::fs;
 use tokio::fs::File;
+use tokio::sync::broadcast;
+use tokio::task;
+use tokio::task::JoinHandle;
 use tokio_util::time::DelayQueue;
 use tracing::{debug, error, info, instrument, warn};
 
-use crate::ext::{AsyncFileExt, RequestResponseEventExt};
-use crate::node::behaviour::{
-    Behaviour, BehaviourEvent, FileRequest, FileResponse, DISCOVER_SHARE_TOPIC, FILE_SHARE_TOPIC,
+use crate::command::{FileEvent, PeerIdentity};
+use crate::ext::RequestResponseEventExt;
+use crate::node::behaviour::{Behaviour, BehaviourEvent, FileRequest, FileResponse, ShareTopics};
+use crate::node::buffer_pool::BufferPool;
+use crate::node::chunking::{ChunkDigest, ChunkDigestCache};
+use crate::node::config::{index_file_hash, IpFamily, StoreLayout};
+use crate::node::connection_errors::{
+    classify_dial_error, classify_incoming_error, ConnectionErrorKind, ConnectionErrors,
 };
 use crate::node::file_cache::FileCache;
-use crate::node::message::{DiscoverMessage, FileMessage, Peer};
-use crate::node::PeerNodeStore;
+use crate::node::file_get_requests::FileGetRequests;
+use crate::node::message::{self, DiscoverMessage, FileMessage, Peer};
+use crate::node::peer_failures::PeerFailures;
+use crate::node::peer_pings::PeerPings;
+use crate::node::relay_stats::RelayStats;
+use crate::node::store_link::StoreLink;
+use crate::node::sync_counters::SyncCounters;
+use crate::node::{ConnectedPeer, FileMessageStatus, PeerNodeStore};
+use crate::util::{check_peer_filename, is_global_addr, is_local_addr, SharePatterns};
 
 #[derive(Builder)]
 #[builder(pattern = "owned")]
 pub struct EventHandler<'a> {
     index_dir: &'a Path,
+    store_layout: StoreLayout,
     store_dir: &'a Path,
+    store_link: StoreLink,
+    /// the store files not matching it are never served
+    share_only: &'a SharePatterns,
     swarm: &'a mut Swarm<Behaviour>,
+    /// the gossip topics of the share network
+    topics: &'a ShareTopics,
     peer_stores: &'a mut HashMap<PeerId, PeerNodeStore>,
-    file_get_requests: &'a mut HashMap<RequestId, Sender<io::Result<FileResponse>>>,
+    file_get_requests: &'a mut FileGetRequests,
+    /// the inbound file requests of the peers which are not responded yet
+    inbound_requests: &'a mut HashMap<PeerId, HashSet<RequestId>>,
+    /// max number of the inbound file requests of a peer, the excess requests are responded busy
+    max_peer_inbound_requests: usize,
     peer_addr_receiver: &'a mut DelayQueue<Multiaddr>,
     peer_addr_connecting: &'a mut HashMap<PeerId, Multiaddr>,
+    /// the ping peer commands which are waiting for the ping
+    peer_pings: &'a mut PeerPings,
     cache_files: &'a mut FileCache,
-    connected_peer: &'a mut HashMap<PeerId, HashSet<Multiaddr>>,
+    connected_peer: &'a mut HashMap<PeerId, ConnectedPeer>,
+    peer_failures: &'a mut PeerFailures,
+    discover_peers: &'a mut HashMap<PeerId, Vec<Peer>>,
+    pending_discover: &'a mut bool,
+    buffer_pool: &'a BufferPool,
+    relay_only: bool,
+    /// push the identify info to the connected peers when a new external addr is added
+    identify_push: bool,
+    /// max number of the accepted discover addrs of a peer
+    max_discover_addrs: usize,
+    /// accept the loopback and the link local discover addrs
+    allow_local_discover_addrs: bool,
+    /// the discover addrs of the other ip family are ignored
+    ip_family: IpFamily,
+    connection_errors: &'a ConnectionErrors,
+    relay_stats: &'a mut RelayStats,
+    /// count the bytes served to the peers
+    sync_counters: &'a SyncCounters,
+    file_events: &'a broadcast::Sender<FileEvent>,
+    chunk_digest_cache: &'a ChunkDigestCache,
+    /// the chunk digests tasks of the inbound requests
+    chunk_digest_tasks: &'a mut FuturesUnordered<JoinHandle<ChunkDigestsResponse>>,
 }
 
 impl<'a> EventHandler<'a> {
@@ -78,7 +123,11 @@ impl<'a> EventHandler<'a> {
                     info!(%request_id, "handle request respond event done");
                 }
 
-                BehaviourEvent::Keepalive(_) | BehaviourEvent::Ping(_) => {}
+                BehaviourEvent::Keepalive(_) => {}
+
+                BehaviourEvent::Ping(event) => {
+                    self.handle_ping_event(event);
+                }
 
                 BehaviourEvent::Identify(event) => {
                     self.handle_identify_event(event).await?;
@@ -118,9 +167,12 @@ impl<'a> EventHandler<'a> {
             }
 
             SwarmEvent::ConnectionClosed {
-                peer_id, endpoint, ..
+                peer_id,
+                endpoint,
+                num_established,
+                ..
             } => {
-                self.handle_connection_closed_event(peer_id, endpoint);
+                self.handle_connection_closed_event(peer_id, endpoint, num_established);
 
                 info!(%peer_id, "disconnect with peer");
             }
@@ -131,12 +183,43 @@ impl<'a> EventHandler<'a> {
                 send_back_addr,
                 error,
             } => {
-                error!(%local_addr, %send_back_addr, %error, "incoming connection error");
+                let kind = classify_incoming_error(&error);
+                self.connection_errors.record(kind);
+
+                if kind == ConnectionErrorKind::KeyMismatch {
+                    error!(
+                        %local_addr,
+                        %send_back_addr,
+                        "pre-shared key mismatch with peer {send_back_addr}, check the \
+                         pre_share_key of both nodes"
+                    );
+                } else {
+                    error!(%local_addr, %send_back_addr, %error, ?kind, "incoming connection error");
+                }
             }
             SwarmEvent::OutgoingConnectionError { peer_id, error } => {
-                error!(?peer_id, %error, "outgoing connection error");
+                let kind = classify_dial_error(&error);
+                self.connection_errors.record(kind);
+
+                if kind == ConnectionErrorKind::KeyMismatch {
+                    let peer =
+                        peer_id.map_or_else(|| "unknown".to_string(), |peer| peer.to_string());
+                    let addr = peer_id.and_then(|peer_id| self.peer_addr_connecting.get(&peer_id));
+
+                    error!(
+                        ?addr,
+                        "pre-shared key mismatch with peer {peer}, check the pre_share_key of \
+                         both nodes"
+                    );
+                } else {
+                    error!(?peer_id, %error, ?kind, "outgoing connection error");
+                }
 
                 if let Some(peer_id) = peer_id {
+                    if !self.connected_peer.contains_key(&peer_id) {
+                        self.peer_pings.finish(&peer_id, Err(error.to_string()));
+                    }
+
                     if let Some(addr) = self.peer_addr_connecting.remove(&peer_id) {
                         info!(%peer_id, %addr, "re-dialing peer");
 
@@ -160,15 +243,28 @@ impl<'a> EventHandler<'a> {
     async fn handle_gossip_event(&mut self, event: GossipsubEvent) -> anyhow::Result<()> {
         match event {
             GossipsubEvent::Message { message, .. } => {
-                if message.topic == FILE_SHARE_TOPIC.hash() {
-                    let msg = FileMessage::decode(message.data.as_slice())
+                if message.topic == self.topics.file.hash() {
+                    let mut msg = FileMessage::decode(message.data.as_slice())
                         .tap_err(|err| error!(%err, "decode file message failed"))?;
-                    let peer_id = bs58::decode(&msg.peer_id).into_vec().tap_err(
-                        |err| error!(%err, peer_id = %msg.peer_id, "decode peer id failed"),
-                    )?;
-                    let peer_id = PeerId::from_bytes(&peer_id).tap_err(
-                        |err| error!(%err, peer_id = %msg.peer_id, "parse peer id failed"),
-                    )?;
+                    let peer_id = match msg.verify() {
+                        Err(err) => {
+                            warn!(%err, peer_id = %msg.peer_id, "verify file message failed, reject it");
+
+                            return Ok(());
+                        }
+
+                        Ok(peer_id) => peer_id,
+                    };
+
+                    if let Some(source) = message.source {
+                        if source != peer_id {
+                            warn!(%source, %peer_id, "file message source doesn't match signer, reject it");
+
+                            return Ok(());
+                        }
+                    }
+
+                    drop_unsafe_files(peer_id, &mut msg.file_list);
 
                     info!(%peer_id, ?msg, "receive file message from peer");
 
@@ -177,45 +273,79 @@ impl<'a> EventHandler<'a> {
                         .entry(peer_id)
                         .or_insert(PeerNodeStore::default());
 
-                    peer_node_store.files.clear();
-                    peer_node_store.index.clear();
-
-                    msg.file_list.into_iter().for_each(|file| {
-                        peer_node_store
-                            .index
-                            .insert(file.hash.clone(), file.file_size);
-                        peer_node_store
-                            .files
-                            .entry(file.filename)
-                            .or_insert_with(|| file.hash);
-                    });
+                    let refresh_time = msg.refresh_time;
+                    let (part, total_parts) = (msg.part, msg.total_parts);
+                    match peer_node_store.accept_file_message(msg) {
+                        FileMessageStatus::Old => {
+                            warn!(
+                                %peer_id,
+                                refresh_time,
+                                last_refresh_time = peer_node_store.refresh_time,
+                                "file message is older than the last one, reject it"
+                            );
+                        }
+
+                        FileMessageStatus::InvalidPart => {
+                            warn!(%peer_id, part, total_parts, "file message part is invalid, reject it");
+                        }
 
-                    info!(%peer_id, "update peer store done");
-                } else if message.topic == DISCOVER_SHARE_TOPIC.hash() {
+                        FileMessageStatus::Pending => {
+                            info!(%peer_id, part, total_parts, "wait for the other file message parts");
+                        }
+
+                        FileMessageStatus::Applied(removed) => {
+                            info!(%peer_id, removed = removed.len(), "update peer store done");
+
+                            send_peer_files_removed(self.file_events, peer_id, removed);
+                        }
+                    }
+                } else if message.topic == self.topics.discover.hash() {
                     let msg = DiscoverMessage::decode(message.data.as_slice())
                         .tap_err(|err| error!(%err, "decode discover message failed"))?;
-                    let peers = msg
-                        .peers
-                        .into_iter()
-                        .map(|peer| {
-                            let peer_id = bs58::decode(&peer.peer_id).into_vec().tap_err(
-                                |err| error!(%err, peer_id = %peer.peer_id, "decode peer id failed"),
-                            )?;
-                            let peer_id = PeerId::from_bytes(&peer_id).tap_err(
-                                |err| error!(%err, peer_id = %peer.peer_id, "parse peer id failed"),
-                            )?;
-
-                            let addr = Multiaddr::try_from(peer.addr)
-                                .tap_err(|err| error!(%err, "parse peer addr failed"))?;
-
-                            Ok::<_, anyhow::Error>((peer_id, addr))
-                        })
-                        .try_collect::<_, Vec<_>, _>()?;
-
-                    info!(?peers, "collect peers done");
-
+                    let local_peer_id = *self.swarm.local_peer_id();
                     let behaviour = self.swarm.behaviour_mut();
-                    for (peer_id, addr) in peers {
+                    for peer in msg.peers {
+                        // the addr must be signed by the introduced peer, otherwise anyone can
+                        // make us dial an arbitrary addr
+                        let (peer_id, addr) = match peer.verify() {
+                            Err(err) => {
+                                warn!(%err, peer_id = %peer.peer_id, "verify discover peer failed, reject it");
+
+                                continue;
+                            }
+
+                            Ok(peer) => peer,
+                        };
+                        if peer_id == local_peer_id {
+                            continue;
+                        }
+                        if !self.allow_local_discover_addrs && is_local_addr(&addr) {
+                            debug!(%peer_id, ?addr, "ignore local discover addr");
+
+                            continue;
+                        }
+                        if !self.ip_family.allows(&addr) {
+                            debug!(%peer_id, ?addr, ip_family = ?self.ip_family, "ignore discover addr of the other ip family");
+
+                            continue;
+                        }
+
+                        let signed_peers = self.discover_peers.entry(peer_id).or_default();
+                        if !signed_peers
+                            .iter()
+                            .any(|signed_peer| signed_peer.addr == peer.addr)
+                            && !accept_discover_addr(
+                                signed_peers,
+                                peer,
+                                &addr,
+                                self.max_discover_addrs,
+                            )
+                        {
+                            warn!(%peer_id, ?addr, "peer has too many discover addrs, ignore it");
+
+                            continue;
+                        }
+
                         if behaviour.request_respond.is_connected(&peer_id) {
                             continue;
                         }
@@ -260,6 +390,8 @@ impl<'a> EventHandler<'a> {
             } => {
                 error!(%err, %request_id, %peer, "send file request failed");
 
+                self.peer_failures.record_failure(peer);
+
                 let err = match err {
                     OutboundFailure::DialFailure | OutboundFailure::UnsupportedProtocols => {
                         Error::new(ErrorKind::Other, err)
@@ -270,9 +402,7 @@ impl<'a> EventHandler<'a> {
                     }
                 };
 
-                if let Some(sender) = self.file_get_requests.remove(&request_id) {
-                    let _ = sender.send(Err(err));
-                }
+                self.file_get_requests.finish(&request_id, Err(err));
             }
             RequestResponseEvent::InboundFailure {
                 peer,
@@ -280,9 +410,13 @@ impl<'a> EventHandler<'a> {
                 error: err,
             } => {
                 error!(%err,  %request_id, %peer, "receive file request failed");
+
+                self.finish_inbound_request(peer, request_id);
             }
             RequestResponseEvent::ResponseSent { peer, request_id } => {
                 info!(%peer, %request_id, "send file response success");
+
+                self.finish_inbound_request(peer, request_id);
             }
         }
 
@@ -303,23 +437,76 @@ impl<'a> EventHandler<'a> {
             } => {
                 info!(%request_id, %peer, ?request, "receive file request from peer");
 
-                let content = self
+                let inbound_requests = self.inbound_requests.entry(peer).or_default();
+                if inbound_requests.len() >= self.max_peer_inbound_requests {
+                    warn!(
+                        %request_id,
+                        %peer,
+                        inbound_requests = inbound_requests.len(),
+                        "peer has too many inbound file requests, respond busy"
+                    );
+
+                    let busy_response = FileResponse {
+                        content: None,
+                        busy: true,
+                        chunk_digests: vec![],
+                    };
+                    if self
+                        .swarm
+                        .behaviour_mut()
+                        .request_respond
+                        .send_response(channel, busy_response)
+                        .is_err()
+                    {
+                        error!(?request, "send file busy response failed");
+                    }
+
+                    return Ok(());
+                }
+
+                inbound_requests.insert(request_id);
+
+                if request.chunk_digests {
+                    return self
+                        .respond_chunk_digests(peer, request_id, request, channel)
+                        .await;
+                }
+
+                let content = match self
                     .read_file(
                         &request.filename,
                         &request.hash,
                         request.offset,
                         request.length,
                     )
-                    .await?;
+                    .await
+                {
+                    Err(err) => {
+                        self.finish_inbound_request(peer, request_id);
+
+                        return Err(err.into());
+                    }
+
+                    Ok(content) => content,
+                };
 
                 if self
                     .swarm
                     .behaviour_mut()
                     .request_respond
-                    .send_response(channel, FileResponse { content })
+                    .send_response(
+                        channel,
+                        FileResponse {
+                            content,
+                            busy: false,
+                            chunk_digests: vec![],
+                        },
+                    )
                     .is_err()
                 {
                     error!(?request, "send file content failed");
+
+                    self.finish_inbound_request(peer, request_id);
                 } else {
                     info!(?request, "send file content done");
                 }
@@ -331,70 +518,187 @@ impl<'a> EventHandler<'a> {
             } => {
                 info!(%request_id, "receive file response from peer");
 
-                if let Some(sender) = self.file_get_requests.remove(&request_id) {
-                    let _ = sender.send(Ok(response));
-                }
+                self.file_get_requests.finish(&request_id, Ok(response));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// respond the cached chunk digests of the file, the uncached file is digested in a blocking
+    /// task, so the swarm isn't blocked by the large file, and the response is sent by the node
+    /// when the task is done, the file which isn't shared or fails to digest is responded without
+    /// the digests
+    async fn respond_chunk_digests(
+        &mut self,
+        peer: PeerId,
+        request_id: RequestId,
+        request: FileRequest,
+        channel: ResponseChannel<FileResponse>,
+    ) -> anyhow::Result<()> {
+        let index_path = match self
+            .shared_index_path(&request.filename, &request.hash)
+            .await
+        {
+            Err(err) => {
+                self.finish_inbound_request(peer, request_id);
+
+                return Err(err.into());
+            }
+
+            Ok(index_path) => index_path,
+        };
+
+        let index_path = match index_path {
+            None => {
+                self.send_chunk_digests(peer, request_id, &request, channel, vec![]);
+
+                return Ok(());
             }
+
+            Some(index_path) => index_path,
+        };
+
+        if let Some(chunk_digests) = self.chunk_digest_cache.get(&request.hash) {
+            self.send_chunk_digests(peer, request_id, &request, channel, chunk_digests.to_vec());
+
+            return Ok(());
         }
 
+        let chunk_digest_cache = self.chunk_digest_cache.clone();
+        self.chunk_digest_tasks.push(tokio::spawn(async move {
+            let hash = request.hash.clone();
+            let chunk_digests = match task::spawn_blocking(move || {
+                chunk_digest_cache.digest_file(&hash, &index_path)
+            })
+            .await
+            {
+                Err(err) => {
+                    error!(%err, ?request, "digest file chunks task failed");
+
+                    vec![]
+                }
+
+                Ok(result) => result
+                    .map(|chunk_digests| chunk_digests.to_vec())
+                    .unwrap_or_default(),
+            };
+
+            info!(
+                %peer,
+                ?request,
+                chunks = chunk_digests.len(),
+                "digest file chunks done"
+            );
+
+            ChunkDigestsResponse {
+                peer,
+                request_id,
+                channel,
+                response: FileResponse {
+                    content: None,
+                    busy: false,
+                    chunk_digests,
+                },
+            }
+        }));
+
         Ok(())
     }
 
+    fn send_chunk_digests(
+        &mut self,
+        peer: PeerId,
+        request_id: RequestId,
+        request: &FileRequest,
+        channel: ResponseChannel<FileResponse>,
+        chunk_digests: Vec<ChunkDigest>,
+    ) {
+        let response = FileResponse {
+            content: None,
+            busy: false,
+            chunk_digests,
+        };
+        if self
+            .swarm
+            .behaviour_mut()
+            .request_respond
+            .send_response(channel, response)
+            .is_err()
+        {
+            error!(?request, "send chunk digests failed");
+
+            self.finish_inbound_request(peer, request_id);
+        } else {
+            info!(?request, "send chunk digests done");
+        }
+    }
+
+    /// the inbound request is responded or failed, it doesn't occupy the limit of the peer anymore
+    fn finish_inbound_request(&mut self, peer: PeerId, request_id: RequestId) {
+        finish_inbound_request(self.inbound_requests, peer, request_id);
+    }
+
     #[instrument(err, skip(self, event))]
     async fn handle_identify_event(&mut self, event: identify::Event) -> anyhow::Result<()> {
         match event {
             identify::Event::Received { peer_id, info } => {
-                if !self
-                    .swarm
-                    .external_addresses()
-                    .any(|record| record.addr == info.observed_addr)
+                // the observed addr of the relay only node is behind the NAT, peers can't dial it
+                if !self.relay_only
+                    && !self
+                        .swarm
+                        .external_addresses()
+                        .any(|record| record.addr == info.observed_addr)
                 {
+                    info!(%peer_id, observed_addr = %info.observed_addr, "add external address");
+
                     self.swarm
                         .add_external_address(info.observed_addr, AddressScore::Infinite);
-                }
 
-                let peers = info
-                    .listen_addrs
-                    .into_iter()
-                    .map(|addr| Peer {
-                        peer_id: peer_id.to_base58(),
-                        addr: addr.to_vec(),
-                    })
-                    .collect::<Vec<_>>();
-
-                let discover_message = DiscoverMessage {
-                    peers,
-                    discover_time: SystemTime::now()
-                        .duration_since(SystemTime::UNIX_EPOCH)
-                        .unwrap()
-                        .as_micros() as _,
-                };
+                    // the identify push only follows the listen addrs, push the external addr
+                    // change by ourselves
+                    if self.identify_push {
+                        let peers = self.connected_peer.keys().copied().collect::<Vec<_>>();
+                        self.swarm.behaviour_mut().identify.push(peers);
+                    }
+                }
 
-                info!(?discover_message, "create discover message done");
+                self.swarm
+                    .behaviour_mut()
+                    .gossip
+                    .add_explicit_peer(&peer_id);
+
+                if let Some(connected_peer) = self.connected_peer.get_mut(&peer_id) {
+                    info!(
+                        %peer_id,
+                        protocol_version = %info.protocol_version,
+                        agent_version = %info.agent_version,
+                        "update peer identity"
+                    );
 
-                let discover_message = discover_message.encode_to_vec();
-                let behaviour = self.swarm.behaviour_mut();
+                    connected_peer.identity = Some(PeerIdentity {
+                        protocol_version: info.protocol_version,
+                        agent_version: info.agent_version,
+                        protocols: info.protocols,
+                    });
+                }
 
-                behaviour.gossip.add_explicit_peer(&peer_id);
+                // the identified listen addrs are not signed by the peer, so we only announce the
+                // signed addrs, the discover message is published by the discover ticker in batch
+                *self.pending_discover = true;
 
-                behaviour
-                    .gossip
-                    .publish(DISCOVER_SHARE_TOPIC.clone(), discover_message)
-                    .tap_err(|err| {
-                        error!(
-                            %err, topic = ?&*DISCOVER_SHARE_TOPIC,
-                            "publish discover message failed"
-                        )
-                    })?;
-
-                info!(topic = ?&*DISCOVER_SHARE_TOPIC, "publish discover message done");
+                info!(%peer_id, "mark pending discover");
             }
 
             identify::Event::Sent { peer_id } => {
                 info!(%peer_id, "send identify response to peer done");
             }
 
-            identify::Event::Pushed { .. } | identify::Event::Error { .. } => {}
+            identify::Event::Pushed { peer_id } => {
+                info!(%peer_id, "push identify info to peer done");
+            }
+
+            identify::Event::Error { .. } => {}
         }
 
         Ok(())
@@ -410,30 +714,75 @@ impl<'a> EventHandler<'a> {
         self.peer_addr_connecting.remove(&peer_id);
         self.connected_peer
             .entry(peer_id)
-            .or_insert_with(HashSet::new)
-            .insert(endpoint.get_remote_address().clone());
+            .or_insert_with(ConnectedPeer::new)
+            .add_connection(endpoint.get_remote_address());
     }
 
     #[instrument(skip(self))]
-    fn handle_connection_closed_event(&mut self, peer_id: PeerId, endpoint: ConnectedPoint) {
+    fn handle_connection_closed_event(
+        &mut self,
+        peer_id: PeerId,
+        endpoint: ConnectedPoint,
+        num_established: u32,
+    ) {
+        let addr = endpoint.get_remote_address();
+        let last_addr_connection = self
+            .connected_peer
+            .get_mut(&peer_id)
+            .map(|connected_peer| connected_peer.remove_connection(addr))
+            .unwrap_or(true);
         let behaviour = self.swarm.behaviour_mut();
+        if last_addr_connection {
+            behaviour.request_respond.remove_address(&peer_id, addr);
+        }
+
+        // the other connections of the peer are still alive
+        if num_established > 0 {
+            info!(%peer_id, num_established, "peer still has connections");
+
+            return;
+        }
 
+        // the peer is fully disconnected, the uptime is reset when it reconnects
         behaviour.gossip.remove_explicit_peer(&peer_id);
-        behaviour
-            .request_respond
-            .remove_address(&peer_id, endpoint.get_remote_address());
+        self.connected_peer.remove(&peer_id);
+        self.inbound_requests.remove(&peer_id);
+        self.relay_stats.remove_peer(&peer_id);
+        self.peer_pings.finish(
+            &peer_id,
+            Err("connection closed before the ping".to_string()),
+        );
+    }
 
-        let entry = self.connected_peer.entry(peer_id).and_modify(|addrs| {
-            addrs.remove(endpoint.get_remote_address());
-        });
-        if matches!(entry, Entry::Occupied(_)) {
-            self.connected_peer.remove(&peer_id);
+    /// the outbound ping result is sent to the waiting ping peer commands, the connection dialed
+    /// for the ping only is closed
+    fn handle_ping_event(&mut self, event: ping::Event) {
+        let result = match event.result {
+            Ok(ping::Success::Ping { rtt }) => Ok(rtt),
+            // the inbound ping doesn't measure the rtt
+            Ok(ping::Success::Pong) => return,
+            Err(err) => Err(err.to_string()),
+        };
+
+        if self.peer_pings.finish(&event.peer, result) {
+            info!(peer = %event.peer, "close the connection dialed for the ping");
+
+            let _ = self.swarm.disconnect_peer_id(event.peer);
         }
     }
 
     #[instrument(skip(self))]
     fn handle_relay_event(&mut self, event: relay::Event) {
         info!(?event, "receive relay event");
+
+        match event {
+            relay::Event::Listen { listen_peer_id, .. } => {
+                self.relay_stats.record_reservation(listen_peer_id)
+            }
+            relay::Event::StartCopy { .. } => self.relay_stats.record_circuit(),
+            relay::Event::ListenOrDialFailed { .. }
+            | relay::Event::ConnectToNotListenPeer { .. } => {}
+        }
     }
 
     #[instrument(skip(self))]
@@ -441,112 +790,196 @@ impl<'a> EventHandler<'a> {
         info!(?event, "receive endpoint event");
     }
 
+    /// the index file of the shared store file, `None` means the file is not shared
     #[instrument(err, skip(self))]
-    async fn read_file(
-        &mut self,
-        filename: &str,
-        hash: &str,
-        offset: u64,
-        length: u64,
-    ) -> io::Result<Option<Bytes>> {
+    async fn shared_index_path(&self, filename: &str, hash: &str) -> io::Result<Option<PathBuf>> {
+        if !self.share_only.is_shared(filename) {
+            info!(filename, hash, "file is not in the share only patterns");
+
+            return Ok(None);
+        }
+
         let file_path = self.store_dir.join(filename);
-        let index_path = self.index_dir.join(hash);
+        let hash_index_path = self.store_layout.index_path(self.index_dir, hash);
 
-        let file = match fs::read_link(&file_path).await {
+        match self.store_link.read_link(self.index_dir, &file_path).await {
             Err(err) if err.kind() != ErrorKind::NotFound => {
-                error!(%err, ?file_path, "read file symlink failed");
+                error!(%err, ?file_path, "read file store link failed");
 
-                return Err(err);
+                Err(err)
             }
 
+            // the un-shared index file may still exist, never serve it
             Err(_) => {
-                info!(filename, hash, "symlink file not exists, create it");
-
-                let index_file = match self
-                    .cache_files
-                    .get_or_open_file(hash, || async { File::open(&index_path).await })
-                    .await
-                {
-                    Err(err) if err.kind() == ErrorKind::NotFound => {
-                        info!(filename, hash, "file not found");
-
-                        return Ok(None);
-                    }
-
-                    Err(err) => {
-                        error!(%err, ?index_path, "open file failed");
-
-                        return Err(err);
-                    }
-
-                    Ok(index_file) => index_file,
-                };
-
-                info!(filename, ?index_path, "open index file done");
+                info!(filename, hash, "file is not shared");
 
-                fs::symlink(&index_path, &file_path).await.tap_err(
-                    |err| error!(%err, ?index_path, ?file_path, "create symlink failed"),
-                )?;
-
-                info!(filename, hash, "create symlink file done");
-
-                index_file
+                Ok(None)
             }
 
-            Ok(file_index_path) => {
-                if file_index_path != index_path {
+            Ok(index_path) => {
+                // the index filename may have the extension, compare the hash and the dir only
+                if index_file_hash(&index_path).as_deref() != Some(hash)
+                    || index_path.parent() != hash_index_path.parent()
+                {
                     error!(
                         filename,
                         hash,
-                        ?file_index_path,
+                        ?index_path,
                         "file found, but hash incorrect"
                     );
 
-                    return Err(Error::new(ErrorKind::InvalidData, format!("file {filename} found, but hash {hash} incorrect, symlink {file_index_path:?}")));
+                    return Err(Error::new(ErrorKind::InvalidData, format!("file {filename} found, but hash {hash} incorrect, index file {index_path:?}")));
                 }
 
-                match self
-                    .cache_files
-                    .get_or_open_file(hash, || async { File::open(&index_path).await })
-                    .await
-                {
-                    Err(err) if err.kind() == ErrorKind::NotFound => {
-                        info!(filename, hash, "file not found");
+                Ok(Some(index_path))
+            }
+        }
+    }
 
-                        return Ok(None);
-                    }
+    #[instrument(err, skip(self))]
+    async fn read_file(
+        &mut self,
+        filename: &str,
+        hash: &str,
+        offset: u64,
+        length: u64,
+    ) -> io::Result<Option<Bytes>> {
+        let index_path = match self.shared_index_path(filename, hash).await? {
+            None => return Ok(None),
+            Some(index_path) => index_path,
+        };
 
-                    Err(err) => {
-                        error!(%err, ?index_path, "open file failed");
+        let file = match self
+            .cache_files
+            .get_or_open_file(hash, || async { File::open(&index_path).await })
+            .await
+        {
+            Err(err) if err.kind() == ErrorKind::NotFound => {
+                info!(filename, hash, "file not found");
 
-                        return Err(err);
-                    }
+                return Ok(None);
+            }
 
-                    Ok(index_file) => {
-                        info!(filename, ?index_path, "open index file done");
+            Err(err) => {
+                error!(%err, ?index_path, "open file failed");
 
-                        index_file
-                    }
-                }
+                return Err(err);
+            }
+
+            Ok(index_file) => {
+                info!(filename, ?index_path, "open index file done");
+
+                index_file
             }
         };
 
-        let mut buf = BytesMut::zeroed(length as _);
-        let read_length = file
-            .read_at(&mut buf, offset)
+        // the codec returns the content to the pool after the response is encoded
+        let content = file
+            .read_buf_at(self.buffer_pool.get(), length as _, offset)
             .await
-            .tap_err(|err| error!(%err, offset, length, "read data failed"))?;
+            .tap_err(|err| error!(%err, offset, length, "read data failed"))?
+            .freeze();
+        let read_length = content.len();
+
+        self.sync_counters.record_served(read_length as _);
 
         info!(
             filename,
             hash, offset, length, read_length, "read file done"
         );
 
-        // Safety: read_length <= length
-        unsafe {
-            buf.set_len(read_length as _);
+        Ok(Some(content))
+    }
+}
+
+/// the chunk digests response of the inbound request, it is sent by the node
+pub struct ChunkDigestsResponse {
+    pub peer: PeerId,
+    pub request_id: RequestId,
+    pub channel: ResponseChannel<FileResponse>,
+    pub response: FileResponse,
+}
+
+/// drop the files of the peer whose filenames or hashes are unsafe to log or join to the local
+/// dirs, the other files of the message are still accepted, the filenames are logged escaped
+pub fn drop_unsafe_files(peer_id: PeerId, file_list: &mut Vec<message::File>) {
+    file_list.retain(|file| {
+        let reason = match check_peer_filename(&file.filename) {
+            Err(reason) => reason,
+            // the hash is joined to the temp dir and the index dir
+            Ok(_) if file.hash.is_empty() || !file.hash.bytes().all(|b| b.is_ascii_alphanumeric()) => {
+                "hash is not alphanumeric"
+            }
+            Ok(_) => return true,
+        };
+
+        warn!(%peer_id, filename = ?file.filename, hash = ?file.hash, reason, "reject unsafe peer file");
+
+        false
+    });
+}
+
+/// notify the subscribers of the files which the peer doesn't advertise anymore
+pub fn send_peer_files_removed(
+    file_events: &broadcast::Sender<FileEvent>,
+    peer_id: PeerId,
+    removed: Vec<(String, String)>,
+) {
+    for (filename, hash) in removed {
+        debug!(%peer_id, %filename, %hash, "peer file is removed");
+
+        // no subscriber is fine
+        let _ = file_events.send(FileEvent::PeerFileRemoved {
+            peer: peer_id,
+            filename,
+            hash,
+        });
+    }
+}
+
+/// remove the responded or failed inbound request of the peer
+pub fn finish_inbound_request(
+    inbound_requests: &mut HashMap<PeerId, HashSet<RequestId>>,
+    peer: PeerId,
+    request_id: RequestId,
+) {
+    if let Some(requests) = inbound_requests.get_mut(&peer) {
+        requests.remove(&request_id);
+        if requests.is_empty() {
+            inbound_requests.remove(&peer);
         }
+    }
+}
+
+/// accept the new discover addr when the peer has less than `max_addrs` addrs, otherwise the
+/// global addr replaces a non global one, return false when the addr is dropped
+fn accept_discover_addr(
+    signed_peers: &mut Vec<Peer>,
+    peer: Peer,
+    addr: &Multiaddr,
+    max_addrs: usize,
+) -> bool {
+    if signed_peers.len() < max_addrs {
+        signed_peers.push(peer);
+
+        return true;
+    }
 
-        Ok(Some(buf.freeze()))
+    if !is_global_addr(addr) {
+        return false;
+    }
+
+    let non_global = signed_peers.iter().position(|signed_peer| {
+        Multiaddr::try_from(signed_peer.addr.clone())
+            .map(|addr| !is_global_addr(&addr))
+            .unwrap_or(true)
+    });
+    match non_global {
+        None => false,
+        Some(index) => {
+            signed_peers[index] = peer;
+
+            true
+        }
     }
 }
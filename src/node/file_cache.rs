@@ -4,22 +4,61 @@ use std::num::NonZeroUsize;
 use std::sync::Arc;
 use std::time::{Duration, Instant};
 
+use bytes::BytesMut;
 use lru::LruCache;
+use memmap2::Mmap;
 use tokio::fs::File;
-use tracing::instrument;
+use tracing::{instrument, warn};
+
+use crate::ext::AsyncFileExt;
 
 // Safety: 64 > 0
 const MAX_FILE_COUNT: NonZeroUsize = unsafe { NonZeroUsize::new_unchecked(64) };
 
+/// small files are not worth mapping
+const MMAP_MIN_FILE_SIZE: u64 = 1024 * 1024; // 1MiB
+
+/// the opened index file, it is memory mapped when mmap is enabled, so reading chunks doesn't
+/// need syscalls
+#[derive(Debug)]
+pub struct CachedFile {
+    file: File,
+    mmap: Option<Mmap>,
+}
+
+impl CachedFile {
+    /// read at most `length` bytes into `buf`, like [`AsyncFileExt::read_buf_at`]
+    pub async fn read_buf_at(
+        &self,
+        mut buf: BytesMut,
+        length: usize,
+        offset: u64,
+    ) -> io::Result<BytesMut> {
+        match &self.mmap {
+            None => self.file.read_buf_at(buf, length, offset).await,
+
+            Some(mmap) => {
+                let start = (offset as usize).min(mmap.len());
+                let end = start.saturating_add(length).min(mmap.len());
+                buf.extend_from_slice(&mmap[start..end]);
+
+                Ok(buf)
+            }
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct FileCache {
-    files: LruCache<String, (Arc<File>, Instant)>,
+    files: LruCache<String, (Arc<CachedFile>, Instant)>,
+    enable_mmap: bool,
 }
 
 impl FileCache {
-    pub fn new() -> Self {
+    pub fn new(enable_mmap: bool) -> Self {
         Self {
             files: LruCache::new(MAX_FILE_COUNT),
+            enable_mmap,
         }
     }
 
@@ -28,7 +67,7 @@ impl FileCache {
         &mut self,
         hash: &str,
         open_fn: F,
-    ) -> io::Result<Arc<File>> {
+    ) -> io::Result<Arc<CachedFile>> {
         if let Some((file, instant)) = self.files.get_mut(hash) {
             *instant = Instant::now();
 
@@ -36,7 +75,12 @@ impl FileCache {
         }
 
         let file = open_fn().await?;
-        let file = Arc::new(file);
+        let mmap = if self.enable_mmap {
+            map_file(&file).await
+        } else {
+            None
+        };
+        let file = Arc::new(CachedFile { file, mmap });
         let instant = Instant::now();
 
         self.files.put(hash.to_string(), (file.clone(), instant));
@@ -44,6 +88,24 @@ impl FileCache {
         Ok(file)
     }
 
+    /// the number of the opened files
+    pub fn len(&self) -> usize {
+        self.files.len()
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.files.cap().get()
+    }
+
+    /// the total size of the memory mapped files
+    pub fn mapped_bytes(&self) -> u64 {
+        self.files
+            .iter()
+            .filter_map(|(_, (file, _))| file.mmap.as_ref())
+            .map(|mmap| mmap.len() as u64)
+            .sum()
+    }
+
     pub fn clean_timeout(&mut self, timeout: Duration) {
         let timeout_hash_list = self
             .files
@@ -58,3 +120,85 @@ impl FileCache {
         }
     }
 }
+
+/// map the index file, return `None` when the file is too small, writable or mmap fails, then the
+/// file is read by `read_at`
+async fn map_file(file: &File) -> Option<Mmap> {
+    let metadata = file
+        .metadata()
+        .await
+        .map_err(|err| warn!(%err, "get file metadata failed, don't mmap it"))
+        .ok()?;
+    if metadata.len() < MMAP_MIN_FILE_SIZE {
+        return None;
+    }
+    // the store file may be a hard link of the index file, writing or truncating it through the
+    // link changes the mapped pages or raises SIGBUS
+    if !metadata.permissions().readonly() {
+        return None;
+    }
+
+    // Safety: only the file without any write permission is mapped and the node never modifies
+    // it, the mapping is still broken if another process makes the file writable and truncates
+    // it, which the store doesn't support
+    unsafe { Mmap::map(file) }
+        .map_err(|err| warn!(%err, "mmap file failed, fallback to read_at"))
+        .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::TempDir;
+    use tokio::fs;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_read_cached_file() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("test");
+        let data = (0..MMAP_MIN_FILE_SIZE as usize + 10)
+            .map(|i| i as u8)
+            .collect::<Vec<_>>();
+        fs::write(&path, &data).await.unwrap();
+
+        // the writable file is never mapped
+        let mut file_cache = FileCache::new(true);
+        let file = file_cache
+            .get_or_open_file("test", || File::open(&path))
+            .await
+            .unwrap();
+        assert!(file.mmap.is_none());
+
+        let mut permissions = fs::metadata(&path).await.unwrap().permissions();
+        permissions.set_readonly(true);
+        fs::set_permissions(&path, permissions).await.unwrap();
+
+        for enable_mmap in [true, false] {
+            let mut file_cache = FileCache::new(enable_mmap);
+            let file = file_cache
+                .get_or_open_file("test", || File::open(&path))
+                .await
+                .unwrap();
+
+            assert_eq!(file.mmap.is_some(), enable_mmap);
+
+            let offset = data.len() - 20;
+            let buf = file
+                .read_buf_at(BytesMut::new(), 100, offset as _)
+                .await
+                .unwrap();
+
+            assert_eq!(buf.as_ref(), &data[offset..]);
+
+            assert_eq!(file_cache.len(), 1);
+            assert_eq!(
+                file_cache.mapped_bytes(),
+                if enable_mmap { data.len() as u64 } else { 0 }
+            );
+
+            file_cache.clean_timeout(Duration::ZERO);
+            assert_eq!(file_cache.len(), 0);
+        }
+    }
+}
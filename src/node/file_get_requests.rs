@@ -0,0 +1,232 @@
+use std::collections::HashMap;
+use std::io;
+
+use futures_channel::oneshot;
+use futures_channel::oneshot::{Receiver, Sender};
+use libp2p::request_response::RequestId;
+use libp2p::{PeerId, Swarm};
+use tracing::info;
+
+use crate::node::behaviour::{Behaviour, FileRequest, FileResponse};
+
+/// the identical chunk requests share one response
+#[derive(Debug, Clone, Hash, Eq, PartialEq)]
+struct ChunkKey {
+    hash: String,
+    offset: u64,
+    length: u64,
+}
+
+impl ChunkKey {
+    /// the chunk digests request is never shared
+    fn new(file_request: &FileRequest) -> Option<Self> {
+        (!file_request.chunk_digests).then(|| Self {
+            hash: file_request.hash.clone(),
+            offset: file_request.offset,
+            length: file_request.length,
+        })
+    }
+}
+
+struct PendingRequest {
+    /// the peer which the request is sent to
+    peer_id: PeerId,
+    key: Option<ChunkKey>,
+    senders: Vec<Sender<io::Result<FileResponse>>>,
+}
+
+/// the sent file request, the response is received by the `receiver`
+pub struct SentRequest {
+    pub request_id: RequestId,
+    /// the peer serving the request, it is not the chosen peer when the request is shared
+    pub peer_id: PeerId,
+    /// the response comes from an identical chunk request in flight
+    pub shared: bool,
+    pub receiver: Receiver<io::Result<FileResponse>>,
+}
+
+/// the file requests which are waiting for the peer responses, the chunk request identical to an
+/// in-flight one by `(hash, offset, length)` waits for its response instead of being sent again,
+/// like a file synced under two filenames or a range fetched on demand while syncing
+#[derive(Default)]
+pub struct FileGetRequests {
+    pending: HashMap<RequestId, PendingRequest>,
+    /// the in-flight chunk requests
+    chunks: HashMap<ChunkKey, RequestId>,
+}
+
+impl FileGetRequests {
+    /// send the file request to the peer unless an identical chunk request is in flight
+    pub fn send(
+        &mut self,
+        swarm: &mut Swarm<Behaviour>,
+        peer_id: PeerId,
+        file_request: FileRequest,
+    ) -> SentRequest {
+        let key = ChunkKey::new(&file_request);
+        if let Some(sent_request) = key.as_ref().and_then(|key| self.share(key)) {
+            info!(
+                request_id = %sent_request.request_id,
+                ?key,
+                "share the in-flight chunk request"
+            );
+
+            return sent_request;
+        }
+
+        let request_id = swarm
+            .behaviour_mut()
+            .request_respond
+            .send_request(&peer_id, file_request);
+
+        self.insert(request_id, peer_id, key)
+    }
+
+    /// send the response to all requests sharing it
+    pub fn finish(&mut self, request_id: &RequestId, result: io::Result<FileResponse>) {
+        let pending = match self.pending.remove(request_id) {
+            None => return,
+            Some(pending) => pending,
+        };
+        if let Some(key) = &pending.key {
+            self.chunks.remove(key);
+        }
+
+        for sender in pending.senders {
+            // io::Error isn't cloneable
+            let result = match &result {
+                Ok(response) => Ok(response.clone()),
+                Err(err) => Err(io::Error::new(err.kind(), err.to_string())),
+            };
+
+            let _ = sender.send(result);
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.pending.len()
+    }
+
+    fn share(&mut self, key: &ChunkKey) -> Option<SentRequest> {
+        let request_id = *self.chunks.get(key)?;
+        let pending = self.pending.get_mut(&request_id)?;
+        let (sender, receiver) = oneshot::channel();
+        pending.senders.push(sender);
+
+        Some(SentRequest {
+            request_id,
+            peer_id: pending.peer_id,
+            shared: true,
+            receiver,
+        })
+    }
+
+    fn insert(
+        &mut self,
+        request_id: RequestId,
+        peer_id: PeerId,
+        key: Option<ChunkKey>,
+    ) -> SentRequest {
+        let (sender, receiver) = oneshot::channel();
+        if let Some(key) = &key {
+            self.chunks.insert(key.clone(), request_id);
+        }
+        self.pending.insert(
+            request_id,
+            PendingRequest {
+                peer_id,
+                key,
+                senders: vec![sender],
+            },
+        );
+
+        SentRequest {
+            request_id,
+            peer_id,
+            shared: false,
+            receiver,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use bytes::Bytes;
+    use libp2p::core::muxing::StreamMuxerBox;
+    use libp2p::core::transport::dummy::DummyTransport;
+    use libp2p::gossipsub::ValidationMode;
+    use libp2p::identity::Keypair;
+    use libp2p::swarm::SwarmBuilder;
+    use libp2p::Transport;
+
+    use super::*;
+    use crate::node::behaviour::{FileProtocol, ShareTopics};
+    use crate::node::buffer_pool::BufferPool;
+
+    fn file_request(offset: u64, chunk_digests: bool) -> FileRequest {
+        FileRequest {
+            filename: "test.bin".to_string(),
+            hash: "HASH".to_string(),
+            offset,
+            length: 10,
+            chunk_digests,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_share_chunk_request() {
+        let key = Keypair::generate_ed25519();
+        let local_peer_id = key.public().to_peer_id();
+        let behaviour = Behaviour::new(
+            key,
+            ValidationMode::Strict,
+            false,
+            false,
+            None,
+            FileProtocol::V2,
+            Duration::from_secs(10),
+            BufferPool::new(0),
+            &ShareTopics::new(None),
+        )
+        .unwrap();
+        let transport = DummyTransport::<(PeerId, StreamMuxerBox)>::new().boxed();
+        let mut swarm =
+            SwarmBuilder::with_tokio_executor(transport, behaviour, local_peer_id).build();
+
+        let (peer1, peer2) = (PeerId::random(), PeerId::random());
+        let mut file_get_requests = FileGetRequests::default();
+        let sent1 = file_get_requests.send(&mut swarm, peer1, file_request(0, false));
+        let sent2 = file_get_requests.send(&mut swarm, peer2, file_request(0, false));
+        let sent3 = file_get_requests.send(&mut swarm, peer2, file_request(10, false));
+        let sent4 = file_get_requests.send(&mut swarm, peer2, file_request(0, true));
+
+        // the identical chunk request is shared, the other offset and the chunk digests are not
+        assert!(!sent1.shared);
+        assert!(sent2.shared);
+        assert_eq!(sent2.request_id, sent1.request_id);
+        assert_eq!(sent2.peer_id, peer1);
+        assert!(!sent3.shared);
+        assert!(!sent4.shared);
+        assert_eq!(file_get_requests.len(), 3);
+
+        file_get_requests.finish(
+            &sent1.request_id,
+            Ok(FileResponse {
+                content: Some(Bytes::from_static(b"0123456789")),
+                busy: false,
+                chunk_digests: vec![],
+            }),
+        );
+        for receiver in [sent1.receiver, sent2.receiver] {
+            let response = receiver.await.unwrap().unwrap();
+            assert_eq!(response.content.unwrap(), b"0123456789"[..]);
+        }
+
+        // the finished chunk request is sent again
+        let sent5 = file_get_requests.send(&mut swarm, peer2, file_request(0, false));
+        assert!(!sent5.shared);
+        assert_eq!(file_get_requests.len(), 3);
+    }
+}
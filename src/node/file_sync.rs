@@ -1,355 +1,1966 @@
-use std::collections::{HashMap, HashSet};
-use std::io;
+use std::cmp::Reverse;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt::{Display, Formatter};
 use std::io::{Error, ErrorKind};
+use std::ops::Range;
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::time::Duration;
+use std::{fmt, io};
 
-use futures_channel::oneshot;
-use futures_channel::oneshot::Sender;
-use futures_util::{StreamExt, TryStreamExt};
-use libp2p::request_response::RequestId;
+use derive_builder::Builder;
+use futures_util::stream::FuturesUnordered;
+use futures_util::{future, StreamExt, TryStreamExt};
 use libp2p::{PeerId, Swarm};
-use rand::prelude::SliceRandom;
 use tap::TapFallible;
-use tokio::fs;
 use tokio::fs::{File, OpenOptions};
+use tokio::task;
 use tokio::task::JoinHandle;
-use tracing::{error, info, instrument};
-
+use tokio::time::Instant;
+use tokio::{fs, select};
+use tokio_util::sync::CancellationToken;
+use tokio_util::time::delay_queue::Key;
+use tokio_util::time::DelayQueue;
+use tracing::{debug, error, info, instrument, warn};
+
+use crate::command::{SyncEntry, SyncFileDetail, SyncPlanFile, SyncState};
 use crate::ext::{AsyncFileExt, IterExt};
+use crate::hash::HashAlgorithm;
+use crate::node::availability::{Availability, RangeSet};
 use crate::node::behaviour::Behaviour;
+use crate::node::chunking::{reuse_local_chunks, ChunkDigestCache};
+use crate::node::config::{index_file_hash, IndexNaming, StoreLayout, SyncOrder, SyncRetry};
+use crate::node::file_get_requests::{FileGetRequests, SentRequest};
+use crate::node::peer_failures::PeerFailures;
+use crate::node::store_link::StoreLink;
+use crate::node::sync_counters::SyncCounters;
+use crate::node::webhook::SyncWebhook;
 use crate::node::{FileRequest, FileResponse, PeerNodeStore};
-use crate::util::collect_filenames;
+use crate::util::{
+    collect_filenames, create_parent_dir, create_temp_dir, move_file, set_readonly, ExcludePatterns,
+};
 
 /// 8MiB
-const MAX_FILE_CHUNK_SIZE: u64 = 8 * 1024 * 1024;
+pub const MAX_FILE_CHUNK_SIZE: u64 = 8 * 1024 * 1024;
+
+/// the max delay of retrying a failed file
+const MAX_SYNC_RETRY_BACKOFF: Duration = Duration::from_secs(10 * 60);
+
+pub type SyncFileTask = JoinHandle<(String, anyhow::Result<Option<HashFile>>)>;
+
+/// the sync failures which are not caused by the transport
+#[derive(Debug)]
+pub enum SyncError {
+    /// the peer responded the chunk request without the content, it doesn't serve the file
+    ChunkNotServed { peer_id: PeerId, hash: String },
+    /// none of the peers could serve the file
+    NoPeerServes { hash: String },
+    /// the peer has too many inbound requests from us, the chunk should be fetched later
+    PeerBusy { peer_id: PeerId, hash: String },
+    /// the peer responded less content than the chunk, the chunk should be fetched again
+    ShortChunk {
+        peer_id: PeerId,
+        hash: String,
+        offset: u64,
+        expect: u64,
+        actual: u64,
+    },
+    /// the node is shutting down, the sync is canceled before the file is moved into the index dir
+    Canceled { hash: String },
+}
 
-/// limit the number of max concurrent sync tasks
-const MAX_CONCURRENT_SYNC_TASKS: usize = 16;
+impl Display for SyncError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            SyncError::ChunkNotServed { peer_id, hash } => {
+                write!(f, "peer {peer_id} doesn't serve file {hash}")
+            }
+            SyncError::NoPeerServes { hash } => write!(f, "no peer could serve file {hash}"),
+            SyncError::PeerBusy { peer_id, hash } => {
+                write!(f, "peer {peer_id} is busy to serve file {hash}")
+            }
+            SyncError::ShortChunk {
+                peer_id,
+                hash,
+                offset,
+                expect,
+                actual,
+            } => write!(
+                f,
+                "peer {peer_id} responded {actual} bytes of file {hash} at offset {offset}, \
+                 expect {expect} bytes"
+            ),
+            SyncError::Canceled { hash } => write!(f, "sync file {hash} is canceled"),
+        }
+    }
+}
 
-pub type SyncFilesResult = anyhow::Result<Option<SyncFileTask>>;
-pub type SyncFileTask = JoinHandle<anyhow::Result<Option<HashMap<String, HashFile>>>>;
+impl std::error::Error for SyncError {}
 
+#[derive(Builder)]
+#[builder(pattern = "owned")]
 pub struct FileSync<'a> {
     index_dir: &'a Path,
+    store_layout: StoreLayout,
+    index_naming: IndexNaming,
+    /// the dir of the in-progress downloads
+    temp_dir: &'a Path,
     store_dir: &'a Path,
+    store_link: StoreLink,
     swarm: &'a mut Swarm<Behaviour>,
     peer_stores: &'a HashMap<PeerId, PeerNodeStore>,
-    file_get_requests: &'a mut HashMap<RequestId, Sender<io::Result<FileResponse>>>,
-    syncing_files: Option<HashMap<String, HashFile>>,
+    file_get_requests: &'a mut FileGetRequests,
+    hash_algorithm: HashAlgorithm,
+    exclude_patterns: &'a ExcludePatterns,
+    peer_failures: &'a PeerFailures,
+    availability: &'a Availability,
+    sync_complete_webhook: Option<&'a SyncWebhook>,
+    /// the larger files are left to be fetched on demand
+    max_auto_sync_file_size: Option<u64>,
+    /// only the files of the peers are synced, all peers are synced when it is empty
+    sync_from_peers: &'a HashSet<PeerId>,
+    /// reuse the unchanged chunks of the previous local version of the file
+    content_defined_chunking: bool,
+    /// the number of the chunk requests kept in flight for a syncing file
+    sync_read_ahead: usize,
+    sync_counters: &'a SyncCounters,
+    /// digest the chunks of the synced files for the peers
+    chunk_digest_cache: &'a ChunkDigestCache,
 }
 
 impl<'a> FileSync<'a> {
-    pub fn new(
-        index_dir: &'a Path,
-        store_dir: &'a Path,
-        swarm: &'a mut Swarm<Behaviour>,
-        peer_stores: &'a HashMap<PeerId, PeerNodeStore>,
-        file_get_requests: &'a mut HashMap<RequestId, Sender<io::Result<FileResponse>>>,
-        syncing_files: Option<HashMap<String, HashFile>>,
-    ) -> Self {
-        Self {
-            index_dir,
-            store_dir,
-            swarm,
-            peer_stores,
-            file_get_requests,
-            syncing_files,
+    /// send the chunk requests of the missing chunks until the read ahead chunks are in flight,
+    /// the rarest chunks are requested first, and spawn a task to wait for the next fetched chunk,
+    /// the task returns the file with the in-flight chunks when it is not finished yet
+    #[instrument(err, skip(self, cancel))]
+    async fn sync_file(
+        &mut self,
+        mut hash_file: HashFile,
+        cancel: &CancellationToken,
+    ) -> anyhow::Result<SyncFileTask> {
+        let hash = hash_file.hash.clone();
+
+        // a fresh sync, drop the data left by the previous process, the chunks fetched on demand
+        // or synced by the previous failed sync are kept, the in-flight chunks still write into
+        // the temp index file
+        let available = self.availability.get(&hash);
+        if available.is_none() && hash_file.in_flight.is_empty() {
+            let tmp_path = self.temp_dir.join(&hash);
+            match fs::remove_file(&tmp_path).await {
+                Err(err) if err.kind() != ErrorKind::NotFound => {
+                    error!(%err, ?tmp_path, "remove old temp index file failed");
+
+                    return Err(err.into());
+                }
+
+                Err(_) | Ok(_) => {}
+            }
+        }
+
+        let tmp_index_file = Arc::new(open_temp_index_file(self.temp_dir, &hash).await?);
+
+        info!(%hash, "create temp index file done");
+
+        // copy the unchanged chunks of the previous version first, the rest are fetched at the
+        // next round
+        if self.content_defined_chunking && !hash_file.reuse_checked && available.is_none() {
+            hash_file.reuse_checked = true;
+
+            if let Some(task) = self.reuse_previous_version(&hash_file).await {
+                // no chunk is fetched until the reused chunks are copied
+                hash_file.in_flight.push(InFlightChunk {
+                    range: 0..hash_file.size,
+                    task,
+                });
+
+                return Ok(self.handle_sync_file_result(hash_file, cancel));
+            }
+        }
+
+        let available = available.unwrap_or_default();
+        let missing_chunks = missing_ranges(&available, hash_file.size)
+            .into_iter()
+            .filter(|chunk| !hash_file.is_in_flight(chunk))
+            .collect::<Vec<_>>();
+        let chunks = schedule_chunks(missing_chunks, |chunk| {
+            hash_file
+                .peers
+                .iter()
+                .copied()
+                .filter(|peer_id| {
+                    self.peer_stores
+                        .get(peer_id)
+                        .map(|peer_store| peer_store.has_chunk(&hash, chunk))
+                        .unwrap_or(false)
+                })
+                .collect()
+        });
+
+        let read_ahead = self
+            .sync_read_ahead
+            .saturating_sub(hash_file.in_flight.len());
+        for (chunk, holders) in chunks.into_iter().take(read_ahead) {
+            let file_request = FileRequest {
+                filename: hash_file.filenames[0].clone(),
+                hash: hash.clone(),
+                offset: chunk.start,
+                length: chunk.end - chunk.start,
+                chunk_digests: false,
+            };
+
+            // avoid the recently failed peers
+            let peer_id = self.peer_failures.choose_peer(&holders).unwrap();
+            let task = fetch_chunk(
+                self.swarm,
+                self.file_get_requests,
+                self.availability.clone(),
+                self.sync_counters.clone(),
+                *peer_id,
+                file_request,
+                hash_file.size,
+                tmp_index_file.clone(),
+            );
+
+            hash_file
+                .in_flight
+                .push(InFlightChunk { range: chunk, task });
         }
+
+        if hash_file.in_flight.is_empty() && !available.contains(&(0..hash_file.size)) {
+            return Err(SyncError::NoPeerServes { hash }.into());
+        }
+
+        Ok(self.handle_sync_file_result(hash_file, cancel))
     }
 
-    #[instrument(err, skip(self))]
-    pub async fn sync_files(mut self) -> SyncFilesResult {
-        let mut need_sync_files = match self.syncing_files.take() {
-            None => {
-                let need_sync_filenames = match self.need_sync().await? {
-                    None => {
-                        info!("no need sync");
+    fn handle_sync_file_result(
+        &self,
+        hash_file: HashFile,
+        cancel: &CancellationToken,
+    ) -> SyncFileTask {
+        handle_sync_file_result(
+            self.index_dir.to_path_buf(),
+            self.store_layout,
+            self.index_naming,
+            self.temp_dir.to_path_buf(),
+            self.store_dir.to_path_buf(),
+            self.store_link,
+            self.hash_algorithm,
+            self.availability.clone(),
+            self.sync_complete_webhook.cloned(),
+            self.chunk_digest_cache.clone(),
+            hash_file,
+            cancel.clone(),
+        )
+    }
 
-                        return Ok(None);
-                    }
+    /// request the chunk digests of the file from a peer which has the whole file when a previous
+    /// version of the file is stored, and spawn a task to copy the chunks which the previous
+    /// version also has into the temp index file, the failed request only skips the reuse
+    async fn reuse_previous_version(
+        &mut self,
+        hash_file: &HashFile,
+    ) -> Option<JoinHandle<anyhow::Result<()>>> {
+        let local_index_path = self.previous_version(hash_file).await?;
+        let holders = hash_file
+            .peers
+            .iter()
+            .copied()
+            .filter(|peer_id| {
+                self.peer_stores
+                    .get(peer_id)
+                    .map(|peer_store| peer_store.has_chunk(&hash_file.hash, &(0..hash_file.size)))
+                    .unwrap_or(false)
+            })
+            .collect::<Vec<_>>();
+        let peer_id = *self.peer_failures.choose_peer(&holders)?;
+
+        let hash = hash_file.hash.clone();
+        let file_request = FileRequest {
+            filename: hash_file.filenames[0].clone(),
+            hash: hash.clone(),
+            offset: 0,
+            length: 0,
+            chunk_digests: true,
+        };
+        let SentRequest {
+            request_id,
+            receiver,
+            ..
+        } = self
+            .file_get_requests
+            .send(self.swarm, peer_id, file_request);
 
-                    Some(need_sync_filenames) => need_sync_filenames,
-                };
+        info!(%peer_id, %hash, %request_id, ?local_index_path, "sending chunk digests request to peer");
 
-                info!(?need_sync_filenames, "need sync files");
+        let availability = self.availability.clone();
+        let tmp_path = self.temp_dir.join(&hash);
 
-                match fs::remove_dir_all(self.index_dir.join(".tmp")).await {
-                    Err(err) if err.kind() != ErrorKind::NotFound => {
-                        error!(%err, "remove tmp dir at first done");
+        Some(tokio::spawn(async move {
+            let chunk_digests = match receiver.await {
+                Err(err) => {
+                    warn!(%err, %hash, "receive chunk digests response result failed");
 
-                        return Err(err.into());
-                    }
+                    return Ok(());
+                }
+
+                Ok(Err(err)) => {
+                    warn!(%err, %hash, "receive chunk digests response failed");
+
+                    return Ok(());
+                }
+
+                Ok(Ok(file_resp)) => file_resp.chunk_digests,
+            };
+            if chunk_digests.is_empty() {
+                info!(%peer_id, %hash, "peer responded no chunk digests, skip reusing chunks");
+
+                return Ok(());
+            }
 
-                    Err(_) => {}
-                    Ok(_) => {
-                        info!("remove tmp dir at first done");
+            match task::spawn_blocking(move || {
+                reuse_local_chunks(&local_index_path, &tmp_path, &chunk_digests)
+            })
+            .await
+            .unwrap()
+            {
+                Err(err) => warn!(%err, %hash, "reuse local chunks failed"),
+
+                Ok(reused) => {
+                    for range in reused {
+                        availability.insert(&hash, range);
                     }
                 }
+            }
+
+            Ok(())
+        }))
+    }
 
-                need_sync_filenames
+    /// the index file of the stored file which has the same filename but a different hash
+    async fn previous_version(&self, hash_file: &HashFile) -> Option<PathBuf> {
+        for filename in &hash_file.filenames {
+            let file_path = self.store_dir.join(filename);
+            let index_path = match self.store_link.read_link(self.index_dir, &file_path).await {
+                Err(_) => continue,
+                Ok(index_path) => index_path,
+            };
+
+            if index_file_hash(&index_path).map_or(false, |hash| hash != hash_file.hash) {
+                return Some(index_path);
             }
+        }
 
-            Some(need_sync_filenames) => need_sync_filenames,
-        };
+        None
+    }
+
+    /// collect the files which need sync, the syncing files are skipped
+    async fn need_sync(
+        &self,
+        syncing_files: &HashMap<String, Vec<String>>,
+    ) -> anyhow::Result<Vec<HashFile>> {
+        need_sync(
+            self.index_dir,
+            self.store_dir,
+            self.store_link,
+            self.peer_stores,
+            self.exclude_patterns,
+            self.max_auto_sync_file_size,
+            self.sync_from_peers,
+            syncing_files,
+        )
+        .await
+        .map_err(Into::into)
+    }
+}
+
+/// collect the peer files which are not in the store dir, the syncing files are skipped, the
+/// files advertised only by the peers not in the non-empty `sync_from_peers` are skipped too
+#[allow(clippy::too_many_arguments)]
+#[instrument(
+    err,
+    skip(peer_stores, exclude_patterns, sync_from_peers, syncing_files)
+)]
+async fn need_sync(
+    index_dir: &Path,
+    store_dir: &Path,
+    store_link: StoreLink,
+    peer_stores: &HashMap<PeerId, PeerNodeStore>,
+    exclude_patterns: &ExcludePatterns,
+    max_auto_sync_file_size: Option<u64>,
+    sync_from_peers: &HashSet<PeerId>,
+    syncing_files: &HashMap<String, Vec<String>>,
+) -> io::Result<Vec<HashFile>> {
+    let store_filenames: HashSet<(String, String)> = collect_filenames(store_dir)
+        .await?
+        .into_stream()
+        .then(|filename| async move {
+            let file_path = store_dir.join(&filename);
+            let index_file_path = match store_link.read_link(index_dir, &file_path).await {
+                // the tampered store file is skipped, it is warned by the read link
+                Err(err) if err.kind() == ErrorKind::InvalidData => return Ok(None),
+
+                Err(err) => {
+                    error!(%err, ?file_path, "read store link failed");
+
+                    return Err(err);
+                }
+
+                Ok(index_file_path) => index_file_path,
+            };
+            let hash = index_file_hash(&index_file_path).ok_or_else(|| {
+                error!(?index_file_path, "index file path doesn't have filename");
+
+                Error::new(
+                    ErrorKind::InvalidData,
+                    format!("index file path {index_file_path:?} doesn't have filename"),
+                )
+            })?;
+
+            Ok::<_, Error>(Some((filename.to_string_lossy().to_string(), hash)))
+        })
+        .try_filter_map(future::ok)
+        .try_collect::<HashSet<_>>()
+        .await?;
+
+    info!(?store_filenames, "collect store filenames done");
+
+    let mut hash_files = HashMap::<_, HashFile>::with_capacity(peer_stores.len());
+    for (peer, peer_store) in peer_stores {
+        if !sync_from_peers.is_empty() && !sync_from_peers.contains(peer) {
+            debug!(%peer, "peer is not in the sync from peers, skip it");
+
+            continue;
+        }
+
+        for (filename, hash_ref) in &peer_store.files {
+            let filename_hash = (filename.clone(), hash_ref.clone());
+            if store_filenames.contains(&filename_hash) {
+                continue;
+            }
+            if syncing_files.contains_key(hash_ref) {
+                continue;
+            }
+            // the peer may use the different exclude patterns
+            if exclude_patterns.is_excluded(filename) {
+                continue;
+            }
+            let size = peer_store.index.get(hash_ref).copied().unwrap();
+            if max_auto_sync_file_size.map_or(false, |max_size| size > max_size) {
+                debug!(%filename, size, "file is too large to sync automatically, skip it");
+
+                continue;
+            }
+            let (filename, hash) = filename_hash;
+
+            hash_files
+                .entry(hash)
+                .and_modify(|hash_file| {
+                    hash_file.peers.push(*peer);
+                    hash_file.filenames.push(filename.clone());
+                })
+                .or_insert_with(move || HashFile {
+                    hash: hash_ref.clone(),
+                    filenames: vec![filename],
+                    peers: vec![*peer],
+                    size,
+                    reuse_checked: false,
+                    in_flight: vec![],
+                });
+        }
+    }
+
+    Ok(hash_files.into_values().collect())
+}
+
+/// the files which the next sync would download in the sync order, nothing is downloaded, the
+/// syncing files are included and marked
+#[allow(clippy::too_many_arguments)]
+pub async fn plan_sync(
+    index_dir: &Path,
+    store_dir: &Path,
+    store_link: StoreLink,
+    peer_stores: &HashMap<PeerId, PeerNodeStore>,
+    exclude_patterns: &ExcludePatterns,
+    max_auto_sync_file_size: Option<u64>,
+    sync_from_peers: &HashSet<PeerId>,
+    syncing_files: &HashMap<String, Vec<String>>,
+    sync_failures: &HashMap<String, SyncFailure>,
+    sync_order: SyncOrder,
+) -> io::Result<Vec<SyncPlanFile>> {
+    let mut hash_files = need_sync(
+        index_dir,
+        store_dir,
+        store_link,
+        peer_stores,
+        exclude_patterns,
+        max_auto_sync_file_size,
+        sync_from_peers,
+        &HashMap::new(),
+    )
+    .await?;
+
+    sort_files(&mut hash_files, sync_order);
+
+    Ok(hash_files
+        .into_iter()
+        .map(|hash_file| {
+            let failure = sync_failures.get(&hash_file.hash);
+
+            SyncPlanFile {
+                syncing: syncing_files.contains_key(&hash_file.hash),
+                failed_attempts: failure.map(|failure| failure.attempts).unwrap_or_default(),
+                last_error: failure.map(|failure| failure.last_error.clone()),
+                hash: hash_file.hash,
+                filenames: hash_file.filenames,
+                size: hash_file.size,
+                peers: hash_file.peers,
+            }
+        })
+        .collect())
+}
+
+/// the syncing files and the files waiting for a sync attempt with their progress, the active
+/// files come first, then the retrying, the queued and the failed files, each state keeps the
+/// sync order, the too large files are only listed when they are synced on demand
+#[allow(clippy::too_many_arguments)]
+pub async fn list_syncs(
+    index_dir: &Path,
+    store_dir: &Path,
+    store_link: StoreLink,
+    peer_stores: &HashMap<PeerId, PeerNodeStore>,
+    exclude_patterns: &ExcludePatterns,
+    max_auto_sync_file_size: Option<u64>,
+    sync_from_peers: &HashSet<PeerId>,
+    availability: &Availability,
+    syncing_files: &HashMap<String, Vec<String>>,
+    sync_failures: &HashMap<String, SyncFailure>,
+    sync_order: SyncOrder,
+) -> io::Result<Vec<SyncEntry>> {
+    let mut hash_files = need_sync(
+        index_dir,
+        store_dir,
+        store_link,
+        peer_stores,
+        exclude_patterns,
+        None,
+        sync_from_peers,
+        &HashMap::new(),
+    )
+    .await?;
+
+    sort_files(&mut hash_files, sync_order);
+
+    let mut entries = hash_files
+        .into_iter()
+        .filter_map(|hash_file| {
+            let failure = sync_failures.get(&hash_file.hash);
+            let state = match failure {
+                _ if syncing_files.contains_key(&hash_file.hash) => SyncState::Active,
+                Some(failure) if failure.retry_in().is_some() => SyncState::Retrying,
+                Some(_) => SyncState::Failed,
+                None if max_auto_sync_file_size
+                    .map_or(false, |max_size| hash_file.size > max_size) =>
+                {
+                    return None;
+                }
+                None => SyncState::Queued,
+            };
+            let synced = availability
+                .get(&hash_file.hash)
+                .map(|range_set| {
+                    range_set
+                        .ranges()
+                        .iter()
+                        .map(|range| range.end - range.start)
+                        .sum()
+                })
+                .unwrap_or(0);
+
+            Some(SyncEntry {
+                synced,
+                bytes_per_sec: (state == SyncState::Active)
+                    .then(|| availability.download_rate(&hash_file.hash))
+                    .flatten(),
+                state,
+                failed_attempts: failure.map(|failure| failure.attempts).unwrap_or_default(),
+                last_error: failure.map(|failure| failure.last_error.clone()),
+                retry_in: failure.and_then(SyncFailure::retry_in),
+                sources: availability.download_sources(&hash_file.hash),
+                hash: hash_file.hash,
+                filenames: hash_file.filenames,
+                size: hash_file.size,
+                peers: hash_file.peers,
+            })
+        })
+        .collect::<Vec<_>>();
+
+    // the sort is stable, the files with the same state keep the sync order
+    entries.sort_by_key(|entry| entry.state);
+
+    Ok(entries)
+}
+
+#[derive(Debug)]
+pub struct HashFile {
+    hash: String,
+    filenames: Vec<String>,
+    peers: Vec<PeerId>,
+    size: u64,
+    /// the chunks of the previous local version are already reused or not found
+    reuse_checked: bool,
+    /// the chunk requests which are not finished yet, they are kept across the sync rounds
+    in_flight: Vec<InFlightChunk>,
+}
+
+impl HashFile {
+    fn is_in_flight(&self, range: &Range<u64>) -> bool {
+        self.in_flight
+            .iter()
+            .any(|chunk| chunk.range.start < range.end && range.start < chunk.range.end)
+    }
+}
+
+#[derive(Debug)]
+struct InFlightChunk {
+    range: Range<u64>,
+    task: JoinHandle<anyhow::Result<()>>,
+}
+
+#[instrument(err)]
+pub async fn open_temp_index_file(temp_dir: &Path, hash: &str) -> io::Result<File> {
+    let tmp_path = create_temp_dir(temp_dir).await?.join(hash);
+
+    OpenOptions::new()
+        .create(true)
+        .read(true)
+        .write(true)
+        .open(&tmp_path)
+        .await
+        .tap_err(|err| error!(%err, ?tmp_path, "create temp file failed"))
+}
+
+/// remove the stale temp files left by the previous process, the sync temp files are always
+/// removed because the availability isn't persisted and a new sync starts from scratch, the
+/// hidden upload temp files are removed only when they aren't modified for `max_age`, so the
+/// resumable uploads are kept
+#[instrument(err)]
+pub async fn clean_temp_dir(temp_dir: &Path, max_age: Duration) -> io::Result<()> {
+    let mut entries = match fs::read_dir(temp_dir).await {
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(()),
+        Err(err) => {
+            error!(%err, ?temp_dir, "read temp dir failed");
+
+            return Err(err);
+        }
+
+        Ok(entries) => entries,
+    };
+
+    let mut removed_count = 0;
+    let mut removed_size = 0;
+    while let Some(entry) = entries
+        .next_entry()
+        .await
+        .tap_err(|err| error!(%err, ?temp_dir, "read temp dir entry failed"))?
+    {
+        let path = entry.path();
+        let metadata = entry
+            .metadata()
+            .await
+            .tap_err(|err| error!(%err, ?path, "get temp file metadata failed"))?;
+        if !metadata.is_file() {
+            continue;
+        }
 
-        let mut remaining_task_number = MAX_CONCURRENT_SYNC_TASKS;
-        let mut futs = Vec::with_capacity(MAX_CONCURRENT_SYNC_TASKS.min(need_sync_files.len()));
-        for (hash, hash_file) in need_sync_files.iter_mut() {
-            if remaining_task_number == 0 {
-                break;
+        if entry.file_name().to_string_lossy().starts_with('.') {
+            let age = metadata
+                .modified()
+                .ok()
+                .and_then(|modified| modified.elapsed().ok())
+                .unwrap_or_default();
+            if age < max_age {
+                continue;
             }
+        }
+
+        fs::remove_file(&path)
+            .await
+            .tap_err(|err| error!(%err, ?path, "remove stale temp file failed"))?;
+
+        info!(?path, size = metadata.len(), "remove stale temp file done");
 
-            let tmp_index_file = Arc::new(self.create_or_open_temp_index_file(hash).await?);
+        removed_count += 1;
+        removed_size += metadata.len();
+    }
 
-            info!(%hash, "create temp index file done");
+    info!(
+        ?temp_dir,
+        removed_count, removed_size, "clean temp dir done"
+    );
 
-            let mut offset = hash_file.syncing_offset;
-            while offset < hash_file.size {
-                let length = MAX_FILE_CHUNK_SIZE;
+    Ok(())
+}
+
+/// send the chunk request to the peer and spawn a task to write the chunk into the temp index
+/// file, the written range is recorded in the `availability`
+#[allow(clippy::too_many_arguments)]
+pub fn fetch_chunk(
+    swarm: &mut Swarm<Behaviour>,
+    file_get_requests: &mut FileGetRequests,
+    availability: Availability,
+    sync_counters: SyncCounters,
+    peer_id: PeerId,
+    file_request: FileRequest,
+    file_size: u64,
+    tmp_index_file: Arc<File>,
+) -> JoinHandle<anyhow::Result<()>> {
+    let hash = file_request.hash.clone();
+    let offset = file_request.offset;
+    let length = file_request.length;
+    let expect = length.min(file_size.saturating_sub(offset));
+
+    // the shared request is served by the peer of the in-flight one
+    let SentRequest {
+        request_id,
+        peer_id,
+        shared,
+        receiver,
+    } = file_get_requests.send(swarm, peer_id, file_request);
+
+    info!(%peer_id, %hash, %request_id, offset, length, shared, "sending file request to peer");
+
+    tokio::spawn(async move {
+        match receiver
+            .await
+            .tap_err(|err| error!(%err, %hash, "receive file response result failed"))?
+        {
+            Err(err) => {
+                error!(%err, %hash, "receive file response failed");
 
-                let (sender, receiver) = oneshot::channel();
+                Err::<_, anyhow::Error>(err.into())
+            }
 
-                let file_request = FileRequest {
-                    filename: hash_file.filenames[0].clone(),
-                    hash: hash.clone(),
+            Ok(file_resp) => {
+                write_chunk(
+                    &availability,
+                    &sync_counters,
+                    &tmp_index_file,
+                    peer_id,
+                    &hash,
                     offset,
-                    length,
-                };
+                    expect,
+                    file_resp,
+                )
+                .await
+            }
+        }
+    })
+}
 
-                let peer_id = hash_file.peers.choose(&mut rand::thread_rng()).unwrap();
-                let request_id = self
-                    .swarm
-                    .behaviour_mut()
-                    .request_respond
-                    .send_request(peer_id, file_request);
-
-                info!(%peer_id, %hash, %request_id, offset, length, "sending file request to peer");
-
-                self.file_get_requests.insert(request_id, sender);
-
-                let tmp_index_file = tmp_index_file.clone();
-                let hash = hash.clone();
-                futs.push(tokio::spawn(async move {
-                    match receiver
-                        .await
-                        .tap_err(|err| error!(%err, %hash, "receive file response result failed"))?
-                    {
-                        Err(err) => {
-                            error!(%err, %hash, "receive file response failed");
-
-                            Err::<_, anyhow::Error>(err.into())
-                        }
-
-                        Ok(file_resp) => {
-                            match file_resp.content {
-                                None => return Ok(()),
-                                Some(data) => {
-                                    tmp_index_file.write_at_all(&data, offset).await.tap_err(
-                                        |err| error!(%err, %hash, offset, "write index file data failed"),
-                                    )?;
-
-                                    info!(%hash, offset, "write index file data done");
-                                }
-                            }
-
-                            Ok(())
-                        }
-                    }
-                }));
+/// write the chunk content into the temp index file, the peer reads at most the chunk length,
+/// less content than `expect` means a truncated read, the chunk is not marked as available so it
+/// is fetched again
+#[allow(clippy::too_many_arguments)]
+async fn write_chunk(
+    availability: &Availability,
+    sync_counters: &SyncCounters,
+    tmp_index_file: &File,
+    peer_id: PeerId,
+    hash: &str,
+    offset: u64,
+    expect: u64,
+    file_resp: FileResponse,
+) -> anyhow::Result<()> {
+    match file_resp.content {
+        None if file_resp.busy => {
+            warn!(%peer_id, %hash, offset, "peer is busy, fetch the chunk later");
+
+            Err(SyncError::PeerBusy {
+                peer_id,
+                hash: hash.to_string(),
+            }
+            .into())
+        }
 
-                offset += length;
+        None => {
+            warn!(%peer_id, %hash, offset, "peer doesn't serve the file");
 
-                remaining_task_number -= 1;
-                // task number is full, need store sync status
-                if remaining_task_number == 0 {
-                    break;
-                }
+            Err(SyncError::ChunkNotServed {
+                peer_id,
+                hash: hash.to_string(),
             }
+            .into())
+        }
 
-            hash_file.syncing_offset = offset;
+        Some(data) if (data.len() as u64) < expect => {
+            warn!(%peer_id, %hash, offset, expect, actual = data.len(), "short chunk");
+
+            Err(SyncError::ShortChunk {
+                peer_id,
+                hash: hash.to_string(),
+                offset,
+                expect,
+                actual: data.len() as _,
+            }
+            .into())
         }
 
-        let index_dir = self.index_dir.to_path_buf();
-        let store_dir = self.store_dir.to_path_buf();
-        let handle = handle_sync_files_result(index_dir, store_dir, futs, need_sync_files);
+        Some(data) => {
+            tmp_index_file
+                .write_at_all(&data, offset)
+                .await
+                .tap_err(|err| error!(%err, %hash, offset, "write index file data failed"))?;
+
+            availability.insert(hash, offset..offset + data.len() as u64);
+            availability.record_download(hash, peer_id, data.len() as _);
+            sync_counters.record_downloaded(data.len() as _);
 
-        Ok(Some(handle))
+            info!(%hash, offset, "write index file data done");
+
+            Ok(())
+        }
     }
+}
 
-    #[instrument(err, skip(self))]
-    async fn create_or_open_temp_index_file(&self, hash: &str) -> io::Result<File> {
-        let mut tmp_path = self.index_dir.join(".tmp");
-        match fs::create_dir(&tmp_path).await {
-            Err(err) if err.kind() != ErrorKind::AlreadyExists => {
-                error!(%err, ?tmp_path, "create temp dir failed");
+#[allow(clippy::too_many_arguments)]
+fn handle_sync_file_result(
+    index_dir: PathBuf,
+    store_layout: StoreLayout,
+    index_naming: IndexNaming,
+    temp_dir: PathBuf,
+    store_dir: PathBuf,
+    store_link: StoreLink,
+    hash_algorithm: HashAlgorithm,
+    availability: Availability,
+    sync_complete_webhook: Option<SyncWebhook>,
+    chunk_digest_cache: ChunkDigestCache,
+    hash_file: HashFile,
+    cancel: CancellationToken,
+) -> SyncFileTask {
+    tokio::spawn(async move {
+        let hash = hash_file.hash.clone();
+        let result = finish_sync_file(
+            &index_dir,
+            store_layout,
+            index_naming,
+            &temp_dir,
+            &store_dir,
+            store_link,
+            hash_algorithm,
+            &availability,
+            sync_complete_webhook.as_ref(),
+            &chunk_digest_cache,
+            hash_file,
+            &cancel,
+        )
+        .await;
+
+        (hash, result)
+    })
+}
 
-                return Err(err);
+#[allow(clippy::too_many_arguments)]
+#[instrument(
+    err,
+    skip(availability, sync_complete_webhook, chunk_digest_cache, cancel)
+)]
+async fn finish_sync_file(
+    index_dir: &Path,
+    store_layout: StoreLayout,
+    index_naming: IndexNaming,
+    temp_dir: &Path,
+    store_dir: &Path,
+    store_link: StoreLink,
+    hash_algorithm: HashAlgorithm,
+    availability: &Availability,
+    sync_complete_webhook: Option<&SyncWebhook>,
+    chunk_digest_cache: &ChunkDigestCache,
+    mut hash_file: HashFile,
+    cancel: &CancellationToken,
+) -> anyhow::Result<Option<HashFile>> {
+    let results = select! {
+        results = wait_fetched_chunk(&mut hash_file.in_flight) => results,
+
+        _ = cancel.cancelled() => {
+            // the chunks only write into the temp index file, which is removed at the next startup
+            for chunk in &hash_file.in_flight {
+                chunk.task.abort();
             }
 
-            Err(_) | Ok(_) => {}
+            return Err(SyncError::Canceled { hash: hash_file.hash }.into());
+        }
+    };
+    if let Err(err) = merge_chunk_results(&hash_file.hash, results) {
+        // the failed file is synced again later, don't let the left chunks write into it
+        for chunk in hash_file.in_flight {
+            let _ = chunk.task.await;
+        }
+
+        return Err(err);
+    }
+
+    let synced = availability
+        .get(&hash_file.hash)
+        .map(|range_set| {
+            range_set
+                .ranges()
+                .iter()
+                .map(|range| range.end - range.start)
+                .sum::<u64>()
+        })
+        .unwrap_or(0);
+
+    info!(hash = %hash_file.hash, synced, size = hash_file.size, "sync file progress");
+
+    // not yet finish sync, the next round keeps the read ahead chunks in flight
+    if !availability.contains(&hash_file.hash, &(0..hash_file.size))
+        || !hash_file.in_flight.is_empty()
+    {
+        return Ok(Some(hash_file));
+    }
+
+    info!(
+        ?hash_file,
+        "hash file is sync done, start move to index store and create store link"
+    );
+
+    // the temp file doesn't exist when the index file is already stored
+    let tmp_file_path = temp_dir.join(&hash_file.hash);
+    let hash_result = select! {
+        result = hash_algorithm.hash_file(&tmp_file_path) => result,
+
+        _ = cancel.cancelled() => return Err(SyncError::Canceled { hash: hash_file.hash }.into()),
+    };
+    match hash_result {
+        Err(err) if err.kind() == ErrorKind::NotFound => {}
+
+        Err(err) => {
+            error!(%err, ?tmp_file_path, "hash synced temp file failed");
+
+            return Err(err.into());
+        }
+
+        // drop the broken file, it will be synced again at the next tick
+        Ok(hash) if hash != hash_file.hash => {
+            warn!(%hash, expect_hash = %hash_file.hash, "synced file hash mismatch, drop it");
+
+            fs::remove_file(&tmp_file_path)
+                .await
+                .tap_err(|err| error!(%err, ?tmp_file_path, "remove temp file failed"))?;
+
+            return Ok(None);
         }
 
-        tmp_path.push(hash);
+        Ok(_) => {
+            info!(?tmp_file_path, "verify synced file hash done");
+
+            set_readonly(&tmp_file_path).await?;
+        }
+    }
+
+    // the same hash index file may exist, it is named by the extension of another store file
+    let filename = hash_file.filenames.first().map_or("", String::as_str);
+    let index_file_path = index_naming
+        .resolve_index_path(store_layout, index_dir, &hash_file.hash, filename)
+        .await
+        .tap_err(|err| error!(%err, hash = %hash_file.hash, "resolve index file path failed"))?;
+    create_parent_dir(&index_file_path).await?;
+
+    for filename in &hash_file.filenames {
+        let store_file_path = store_dir.join(filename);
+
+        match move_file(&tmp_file_path, &index_file_path).await {
+            Err(err) if err.kind() != ErrorKind::NotFound => {
+                error!(%err, ?tmp_file_path, ?index_file_path, "move temp file to index dir failed");
 
-        OpenOptions::new()
-            .create(true)
-            .read(true)
-            .write(true)
-            .open(&tmp_path)
+                return Err(err.into());
+            }
+
+            Err(_) => {}
+
+            Ok(_) => {
+                info!(
+                    ?tmp_file_path,
+                    ?index_file_path,
+                    "move temp file to index dir done"
+                );
+            }
+        }
+
+        store_link
+            .link(index_dir, &index_file_path, &store_file_path)
             .await
-            .tap_err(|err| error!(%err, ?tmp_path, "create temp file failed"))
+            .tap_err(|err| {
+                error!(
+                    %err, ?index_file_path, ?store_file_path,
+                    "create store link failed"
+                );
+            })?;
+
+        info!(?index_file_path, ?store_file_path, "create store link done");
     }
 
-    #[instrument(err, skip(self))]
-    async fn need_sync(&mut self) -> anyhow::Result<Option<HashMap<String, HashFile>>> {
-        let store_dir = self.store_dir;
+    info!(
+        ?hash_file,
+        "hash file move to index store and create store link"
+    );
 
-        let store_filenames: HashSet<(String, String)> = collect_filenames(store_dir)
-            .await?
-            .into_stream()
-            .then(|filename| async move {
-                let file_path = store_dir.join(&filename);
-                let index_file_path = fs::read_link(&file_path)
-                    .await
-                    .tap_err(|err| error!(%err, ?file_path, "read symlink failed"))?;
-                let hash = index_file_path.file_name().ok_or_else(|| {
-                    error!(?index_file_path, "index file path doesn't have filename");
-
-                    Error::new(
-                        ErrorKind::InvalidData,
-                        format!("index file path {index_file_path:?} doesn't have filename"),
-                    )
-                })?;
-
-                Ok::<_, Error>((
-                    filename.to_string_lossy().to_string(),
-                    hash.to_string_lossy().to_string(),
-                ))
-            })
-            .try_collect::<HashSet<_>>()
-            .await?;
+    chunk_digest_cache.precompute(hash_file.hash.clone(), index_file_path);
 
-        info!(?store_filenames, "collect store filenames done");
+    let sources = availability.download_sources(&hash_file.hash);
 
-        let mut hash_files = HashMap::<_, HashFile>::with_capacity(self.peer_stores.len());
-        for (peer, peer_store) in self.peer_stores {
-            for (filename, hash_ref) in &peer_store.files {
-                let filename_hash = (filename.clone(), hash_ref.clone());
-                if store_filenames.contains(&filename_hash) {
-                    continue;
-                }
-                let (filename, hash) = filename_hash;
+    info!(hash = %hash_file.hash, ?sources, "file download sources");
 
-                hash_files
-                    .entry(hash)
-                    .and_modify(|hash_file| {
-                        hash_file.peers.push(*peer);
-                        hash_file.filenames.push(filename.clone());
-                    })
-                    .or_insert_with(move || HashFile {
-                        hash: hash_ref.clone(),
-                        filenames: vec![filename],
-                        peers: vec![*peer],
-                        size: peer_store.index.get(hash_ref).copied().unwrap(),
-                        syncing_offset: 0,
-                    });
+    if let Some(webhook) = sync_complete_webhook {
+        for filename in &hash_file.filenames {
+            webhook.notify(filename, &hash_file.hash, hash_file.size, &sources);
+        }
+    }
+
+    Ok(None)
+}
+
+/// wait until a chunk is fetched or all chunks are finished, the finished chunks are removed from
+/// `in_flight`, the chunks which are not served don't end the wait, because the other peers may
+/// still serve the file
+async fn wait_fetched_chunk(in_flight: &mut Vec<InFlightChunk>) -> Vec<anyhow::Result<()>> {
+    let mut results = vec![];
+    while !in_flight.is_empty() {
+        let (result, index, _) =
+            future::select_all(in_flight.iter_mut().map(|chunk| &mut chunk.task)).await;
+        in_flight.swap_remove(index);
+
+        let result = result.unwrap();
+        let not_served = matches!(
+            result.as_ref().err().and_then(|err| err.downcast_ref()),
+            Some(SyncError::ChunkNotServed { .. })
+        );
+        results.push(result);
+
+        if !not_served {
+            break;
+        }
+    }
+
+    results
+}
+
+/// merge the chunk fetching results of a sync round, the transport failure is returned first,
+/// it is reported as `NoPeerServes` when none of the peers serves its chunk, the busy chunk is
+/// left missing and fetched again at the next round
+fn merge_chunk_results(hash: &str, results: Vec<anyhow::Result<()>>) -> anyhow::Result<()> {
+    let mut served = false;
+    let mut not_served = None;
+    for result in results {
+        match result {
+            Ok(_) => served = true,
+            Err(err) if matches!(err.downcast_ref(), Some(SyncError::PeerBusy { .. })) => {
+                served = true
+            }
+            Err(err) if matches!(err.downcast_ref(), Some(SyncError::ChunkNotServed { .. })) => {
+                not_served = Some(err)
             }
+            Err(err) => return Err(err.context(format!("fetch chunks of file {hash} failed"))),
         }
+    }
 
-        if hash_files.is_empty() {
-            Ok(None)
-        } else {
-            Ok(Some(hash_files))
+    match not_served {
+        Some(_) if !served => Err(SyncError::NoPeerServes {
+            hash: hash.to_string(),
         }
+        .into()),
+        _ => Ok(()),
+    }
+}
+
+/// the failures of a file which isn't synced yet
+#[derive(Debug, Default)]
+pub struct SyncFailure {
+    /// the number of the failed attempts
+    pub attempts: u32,
+    pub last_error: String,
+    /// the key in the retry queue when the file is waiting for the retry
+    retry_key: Option<Key>,
+    /// when the retry is scheduled, it is meaningful only when the `retry_key` is set
+    retry_at: Option<Instant>,
+}
+
+impl SyncFailure {
+    /// the time until the scheduled retry, `None` when the file isn't waiting for the retry
+    pub fn retry_in(&self) -> Option<Duration> {
+        self.retry_key
+            .and(self.retry_at)
+            .map(|retry_at| retry_at.saturating_duration_since(Instant::now()))
     }
 }
 
+/// the event of the [`SyncScheduler`] which the node handles with a [`FileSync`]
+pub enum SyncEvent {
+    /// a syncing file task is done
+    Result(String, anyhow::Result<Option<HashFile>>),
+    /// a failed file is ready to retry
+    Retry(String),
+}
+
+/// schedule the files syncing, each file is synced by its own task, at most `max_sync_files`
+/// files are synced concurrently, so a slow large file doesn't block the small files
 #[derive(Debug)]
-pub struct HashFile {
-    hash: String,
-    filenames: Vec<String>,
-    peers: Vec<PeerId>,
-    size: u64,
-    syncing_offset: u64,
+pub struct SyncScheduler {
+    max_sync_files: usize,
+    sync_order: SyncOrder,
+    sync_retry: SyncRetry,
+    pending_files: VecDeque<HashFile>,
+    /// the filenames of the syncing files, keyed by the hash
+    syncing_files: HashMap<String, Vec<String>>,
+    tasks: FuturesUnordered<SyncFileTask>,
+    /// the failures of the files which aren't synced yet, keyed by the hash
+    failures: HashMap<String, SyncFailure>,
+    /// the hashes of the failed files which are waiting for the retry
+    retry_queue: DelayQueue<String>,
+    /// cancel the syncing files when the node is shutting down
+    cancel: CancellationToken,
 }
 
-fn handle_sync_files_result(
-    index_dir: PathBuf,
-    store_dir: PathBuf,
-    futs: Vec<JoinHandle<anyhow::Result<()>>>,
-    mut need_sync_files: HashMap<String, HashFile>,
-) -> SyncFileTask {
-    tokio::spawn(async move {
-        for fut in futs {
-            fut.await.unwrap()?;
+impl SyncScheduler {
+    pub fn new(max_sync_files: usize, sync_order: SyncOrder, sync_retry: SyncRetry) -> Self {
+        Self {
+            max_sync_files,
+            sync_order,
+            sync_retry,
+            pending_files: Default::default(),
+            syncing_files: Default::default(),
+            tasks: Default::default(),
+            failures: Default::default(),
+            retry_queue: Default::default(),
+            cancel: Default::default(),
         }
+    }
 
-        let tmp_dir = index_dir.join(".tmp");
+    /// collect the files which need sync and start syncing them if there are free slots, the
+    /// files waiting for the retry are skipped
+    #[instrument(err, skip(self, file_sync))]
+    pub async fn handle_tick(&mut self, mut file_sync: FileSync<'_>) -> anyhow::Result<()> {
+        let mut need_sync_files = file_sync.need_sync(&self.syncing_files).await?;
+
+        // forget the failures of the files which don't need sync anymore
+        let need_sync_hashes = need_sync_files
+            .iter()
+            .map(|hash_file| hash_file.hash.as_str())
+            .collect::<HashSet<_>>();
+        let retry_queue = &mut self.retry_queue;
+        let syncing_files = &self.syncing_files;
+        self.failures.retain(|hash, failure| {
+            let keep = need_sync_hashes.contains(hash.as_str()) || syncing_files.contains_key(hash);
+            if !keep {
+                if let Some(retry_key) = failure.retry_key.take() {
+                    retry_queue.remove(&retry_key);
+                }
+            }
 
-        let mut finish_hash_list = vec![];
-        for hash_file in need_sync_files.values() {
-            // not yet finish sync
-            if hash_file.syncing_offset < hash_file.size {
-                continue;
+            keep
+        });
+        need_sync_files.retain(|hash_file| {
+            self.failures
+                .get(&hash_file.hash)
+                .map_or(true, |failure| failure.retry_key.is_none())
+        });
+
+        if need_sync_files.is_empty() && self.syncing_files.is_empty() {
+            info!("no need sync");
+
+            return Ok(());
+        }
+
+        sort_files(&mut need_sync_files, self.sync_order);
+
+        info!(?need_sync_files, sync_order = ?self.sync_order, "need sync files");
+
+        self.pending_files = need_sync_files.into();
+
+        self.start_pending_files(&mut file_sync).await
+    }
+
+    /// wait for the next syncing file task result or the next failed file whose retry delay is
+    /// expired, it is pending when no file is syncing or waiting for the retry
+    pub async fn next_event(&mut self) -> SyncEvent {
+        select! {
+            Some(result) = self.tasks.next() => {
+                let (hash, result) = result.unwrap();
+
+                SyncEvent::Result(hash, result)
             }
 
-            info!(
-                ?hash_file,
-                "hash file is sync done, start move to index store and create symlink"
-            );
+            Some(expired) = self.retry_queue.next() => SyncEvent::Retry(expired.into_inner()),
+
+            else => future::pending().await,
+        }
+    }
 
-            finish_hash_list.push(hash_file.hash.clone());
+    /// cancel the syncing files and wait for their tasks, the file which is being moved into the
+    /// index dir is finished first, so no partial index file is left
+    pub async fn shutdown(&mut self) {
+        self.cancel.cancel();
 
-            for filename in &hash_file.filenames {
-                let store_file_path = store_dir.join(filename);
-                let index_file_path = index_dir.join(&hash_file.hash);
-                let tmp_file_path = tmp_dir.join(&hash_file.hash);
+        while let Some(result) = self.tasks.next().await {
+            match result {
+                Err(err) => error!(%err, "join sync file task failed"),
+                Ok((hash, Err(err))) => info!(%err, %hash, "sync file is stopped"),
+                Ok((hash, Ok(_))) => info!(%hash, "sync file is stopped"),
+            }
+        }
 
-                match fs::rename(&tmp_file_path, &index_file_path).await {
-                    Err(err) if err.kind() != ErrorKind::NotFound => {
-                        error!(%err, ?tmp_file_path, ?index_file_path, "move temp file to index dir failed");
+        self.syncing_files.clear();
+    }
 
-                        return Err(err.into());
-                    }
+    pub fn syncing_files(&self) -> &HashMap<String, Vec<String>> {
+        &self.syncing_files
+    }
+
+    pub fn sync_order(&self) -> SyncOrder {
+        self.sync_order
+    }
+
+    pub fn failures(&self) -> &HashMap<String, SyncFailure> {
+        &self.failures
+    }
+
+    /// sync the failed file again before the other pending files, the file may not need sync
+    /// anymore, like the peers stop sharing it
+    #[instrument(err, skip(self, file_sync))]
+    pub async fn handle_retry(
+        &mut self,
+        mut file_sync: FileSync<'_>,
+        hash: String,
+    ) -> anyhow::Result<()> {
+        if let Some(failure) = self.failures.get_mut(&hash) {
+            failure.retry_key = None;
+        }
+
+        if self.syncing_files.contains_key(&hash) {
+            return Ok(());
+        }
+
+        let hash_file = file_sync
+            .need_sync(&self.syncing_files)
+            .await?
+            .into_iter()
+            .find(|hash_file| hash_file.hash == hash);
+        match hash_file {
+            None => {
+                info!(%hash, "file doesn't need sync anymore, skip retry");
+
+                self.failures.remove(&hash);
+            }
+
+            Some(hash_file) => {
+                info!(%hash, "retry sync file");
+
+                self.pending_files.push_front(hash_file);
+            }
+        }
+
+        self.start_pending_files(&mut file_sync).await
+    }
+
+    /// start syncing the file now, the max sync files is ignored, the file waiting for the retry
+    /// is synced without waiting for the backoff
+    #[instrument(skip(self, file_sync))]
+    pub async fn sync_file_now(
+        &mut self,
+        mut file_sync: FileSync<'_>,
+        filename: &str,
+    ) -> io::Result<SyncFileDetail> {
+        if let Some((hash, _)) = self
+            .syncing_files
+            .iter()
+            .find(|(_, filenames)| filenames.iter().any(|name| name == filename))
+        {
+            info!(%hash, "file is already syncing");
+
+            return Ok(SyncFileDetail {
+                hash: hash.clone(),
+                already_syncing: true,
+            });
+        }
+
+        let hash_file = file_sync
+            .need_sync(&self.syncing_files)
+            .await
+            .map_err(|err| Error::new(ErrorKind::Other, err.to_string()))?
+            .into_iter()
+            .find(|hash_file| hash_file.filenames.iter().any(|name| name == filename))
+            .ok_or_else(|| {
+                Error::new(
+                    ErrorKind::NotFound,
+                    format!("file {filename} is not advertised by any peer or already stored"),
+                )
+            })?;
+
+        let hash = hash_file.hash.clone();
+        if let Some(retry_key) = self
+            .failures
+            .get_mut(&hash)
+            .and_then(|failure| failure.retry_key.take())
+        {
+            self.retry_queue.remove(&retry_key);
+        }
+
+        self.syncing_files
+            .insert(hash.clone(), hash_file.filenames.clone());
+
+        info!(%hash, "start sync file now");
+
+        match file_sync.sync_file(hash_file, &self.cancel).await {
+            Err(err) => {
+                self.syncing_files.remove(&hash);
 
-                    Err(_) => {}
+                let kind = match err.downcast_ref() {
+                    Some(SyncError::NoPeerServes { .. }) => ErrorKind::NotFound,
+                    _ => ErrorKind::Other,
+                };
+
+                Err(Error::new(kind, err.to_string()))
+            }
 
-                    Ok(_) => {
-                        info!(
-                            ?tmp_file_path,
-                            ?index_file_path,
-                            "move temp file to index dir done"
-                        );
+            Ok(task) => {
+                self.tasks.push(task);
+
+                Ok(SyncFileDetail {
+                    hash,
+                    already_syncing: false,
+                })
+            }
+        }
+    }
+
+    /// record the failure, the file is retried after the backoff until the max attempts are
+    /// reached, then it waits for the sync ticks
+    fn record_failure(&mut self, hash: String, err: &anyhow::Error) {
+        let failure = self.failures.entry(hash.clone()).or_default();
+        failure.attempts += 1;
+        failure.last_error = err.to_string();
+
+        if failure.attempts > self.sync_retry.max_attempts {
+            warn!(%hash, attempts = failure.attempts, "file keeps failing to sync, wait for the next sync");
+
+            return;
+        }
+
+        let delay = self
+            .sync_retry
+            .backoff(failure.attempts, MAX_SYNC_RETRY_BACKOFF);
+
+        info!(%hash, attempts = failure.attempts, ?delay, "retry sync file later");
+
+        failure.retry_key = Some(self.retry_queue.insert(hash, delay));
+        failure.retry_at = Some(Instant::now() + delay);
+    }
+
+    /// continue syncing the unfinished file, or start the pending files when it is done
+    #[instrument(skip(self, file_sync, result))]
+    pub async fn handle_result(
+        &mut self,
+        mut file_sync: FileSync<'_>,
+        hash: String,
+        result: anyhow::Result<Option<HashFile>>,
+    ) -> anyhow::Result<()> {
+        match result {
+            Err(err) => self.handle_sync_error(&file_sync, hash, err),
+
+            Ok(None) => {
+                info!(%hash, "sync file done");
+
+                self.syncing_files.remove(&hash);
+                self.failures.remove(&hash);
+                file_sync.availability.remove(&hash);
+                file_sync.sync_counters.record_synced();
+            }
+
+            Ok(Some(hash_file)) => {
+                info!(%hash, "need continue sync file");
+
+                match file_sync.sync_file(hash_file, &self.cancel).await {
+                    Err(err) => self.handle_sync_error(&file_sync, hash, err),
+
+                    Ok(task) => {
+                        self.tasks.push(task);
+
+                        return Ok(());
                     }
                 }
+            }
+        }
 
-                fs::symlink(&index_file_path, &store_file_path)
-                    .await
-                    .tap_err(|err| {
-                        error!(
-                            %err, ?index_file_path, ?store_file_path,
-                            "create symlink failed"
-                        );
-                    })?;
+        self.start_pending_files(&mut file_sync).await
+    }
 
-                info!(?index_file_path, ?store_file_path, "create symlink done");
+    /// the file sync is failed, like no peer could serve it, the file will be synced again by the
+    /// retry or the next tick, the synced chunks are kept
+    fn handle_sync_error(&mut self, file_sync: &FileSync<'_>, hash: String, err: anyhow::Error) {
+        match err.downcast_ref() {
+            Some(SyncError::NoPeerServes { .. }) => {
+                warn!(%err, %hash, "sync file failed, no peer could serve it")
             }
+            _ => error!(%err, %hash, "sync file failed"),
+        }
 
-            info!(
-                ?hash_file,
-                "hash file move to index store and create symlink"
-            );
+        self.syncing_files.remove(&hash);
+        file_sync.sync_counters.record_failed();
+        self.record_failure(hash, &err);
+    }
+
+    async fn start_pending_files(&mut self, file_sync: &mut FileSync<'_>) -> anyhow::Result<()> {
+        while self.syncing_files.len() < self.max_sync_files {
+            let hash_file = match self.pending_files.pop_front() {
+                None => break,
+                Some(hash_file) => hash_file,
+            };
+
+            // the file may be synced by the other task after the pending files are collected
+            if self.syncing_files.contains_key(&hash_file.hash) {
+                continue;
+            }
+            self.syncing_files
+                .insert(hash_file.hash.clone(), hash_file.filenames.clone());
+
+            info!(hash = %hash_file.hash, "start sync file");
+
+            let hash = hash_file.hash.clone();
+            match file_sync.sync_file(hash_file, &self.cancel).await {
+                Err(err) => self.handle_sync_error(file_sync, hash, err),
+                Ok(task) => self.tasks.push(task),
+            }
         }
 
-        for hash in finish_hash_list {
-            need_sync_files.remove(&hash);
+        Ok(())
+    }
+}
+
+/// the unavailable ranges of the file, they are split at the sync chunk boundaries, so a range
+/// never crosses a chunk which the partial peers may not have
+fn missing_ranges(available: &RangeSet, size: u64) -> Vec<Range<u64>> {
+    let mut missing = vec![];
+    for offset in (0..size).step_by(MAX_FILE_CHUNK_SIZE as _) {
+        let chunk_end = (offset + MAX_FILE_CHUNK_SIZE).min(size);
+        let ranges = available.ranges();
+        let index = ranges.partition_point(|range| range.end <= offset);
+
+        let mut start = offset;
+        for range in ranges[index..]
+            .iter()
+            .take_while(|range| range.start < chunk_end)
+        {
+            if range.start > start {
+                missing.push(start..range.start);
+            }
+            start = start.max(range.end);
+        }
+        if start < chunk_end {
+            missing.push(start..chunk_end);
+        }
+    }
+
+    missing
+}
+
+/// order the chunks by the number of the peers holding them, the rarest chunks come first, so
+/// they are fetched before their only holders go away, the chunks nobody holds are skipped
+fn schedule_chunks<I, F>(chunks: I, mut holders: F) -> Vec<(Range<u64>, Vec<PeerId>)>
+where
+    I: IntoIterator<Item = Range<u64>>,
+    F: FnMut(&Range<u64>) -> Vec<PeerId>,
+{
+    let mut chunks = chunks
+        .into_iter()
+        .map(|chunk| {
+            let peers = holders(&chunk);
+
+            (chunk, peers)
+        })
+        .filter(|(_, peers)| !peers.is_empty())
+        .collect::<Vec<_>>();
+
+    // the sort is stable, the chunks with the same rarity keep the file order
+    chunks.sort_by_key(|(_, peers)| peers.len());
+
+    chunks
+}
+
+fn sort_files(files: &mut [HashFile], sync_order: SyncOrder) {
+    match sync_order {
+        SyncOrder::SmallestFirst => files.sort_by_key(|file| file.size),
+        SyncOrder::LargestFirst => files.sort_by_key(|file| Reverse(file.size)),
+        SyncOrder::Alphabetical => {
+            files.sort_by_cached_key(|file| file.filenames.iter().min().cloned())
         }
+    }
+}
 
-        if need_sync_files.is_empty() {
-            Ok(None)
-        } else {
-            Ok::<_, anyhow::Error>(Some(need_sync_files))
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use futures_channel::oneshot;
+    use tempfile::TempDir;
+
+    use super::*;
+
+    fn hash_file(filename: &str, size: u64) -> HashFile {
+        HashFile {
+            hash: filename.to_uppercase(),
+            filenames: vec![filename.to_string()],
+            peers: vec![],
+            size,
+            reuse_checked: false,
+            in_flight: vec![],
         }
-    })
+    }
+
+    #[test]
+    fn test_sort_files() {
+        let mut files = vec![hash_file("b", 1), hash_file("c", 3), hash_file("a", 2)];
+        let order = |files: &[HashFile]| {
+            files
+                .iter()
+                .map(|file| file.filenames[0].as_str())
+                .collect::<String>()
+        };
+
+        sort_files(&mut files, SyncOrder::SmallestFirst);
+        assert_eq!(order(&files), "bac");
+
+        sort_files(&mut files, SyncOrder::LargestFirst);
+        assert_eq!(order(&files), "cab");
+
+        sort_files(&mut files, SyncOrder::Alphabetical);
+        assert_eq!(order(&files), "abc");
+    }
+
+    #[test]
+    fn test_sync_retry_backoff() {
+        let sync_retry = SyncRetry {
+            max_attempts: 3,
+            backoff: Duration::from_secs(10),
+        };
+
+        assert_eq!(
+            sync_retry.backoff(1, MAX_SYNC_RETRY_BACKOFF),
+            Duration::from_secs(10)
+        );
+        assert_eq!(
+            sync_retry.backoff(3, MAX_SYNC_RETRY_BACKOFF),
+            Duration::from_secs(40)
+        );
+        assert_eq!(
+            sync_retry.backoff(40, MAX_SYNC_RETRY_BACKOFF),
+            MAX_SYNC_RETRY_BACKOFF
+        );
+    }
+
+    #[test]
+    fn test_schedule_chunks() {
+        let (a, b) = (PeerId::random(), PeerId::random());
+        let chunks = [0..10, 10..20, 20..30, 30..40];
+
+        let scheduled = schedule_chunks(chunks, |chunk| match chunk.start {
+            0 => vec![a, b],
+            10 => vec![b],
+            20 => vec![],
+            _ => vec![a],
+        });
+
+        assert_eq!(
+            scheduled,
+            [(10..20, vec![b]), (30..40, vec![a]), (0..10, vec![a, b])]
+        );
+    }
+
+    #[test]
+    fn test_missing_ranges() {
+        let size = MAX_FILE_CHUNK_SIZE * 2 + 100;
+        assert_eq!(
+            missing_ranges(&RangeSet::default(), size),
+            [
+                0..MAX_FILE_CHUNK_SIZE,
+                MAX_FILE_CHUNK_SIZE..MAX_FILE_CHUNK_SIZE * 2,
+                MAX_FILE_CHUNK_SIZE * 2..size
+            ]
+        );
+
+        let mut available = RangeSet::default();
+        available.insert(10..20);
+        available.insert(MAX_FILE_CHUNK_SIZE - 5..MAX_FILE_CHUNK_SIZE * 2);
+        assert_eq!(
+            missing_ranges(&available, size),
+            [
+                0..10,
+                20..MAX_FILE_CHUNK_SIZE - 5,
+                MAX_FILE_CHUNK_SIZE * 2..size
+            ]
+        );
+    }
+
+    #[test]
+    fn test_merge_chunk_results() {
+        let not_served = || {
+            Err(SyncError::ChunkNotServed {
+                peer_id: PeerId::random(),
+                hash: "HASH".to_string(),
+            }
+            .into())
+        };
+
+        assert!(merge_chunk_results("HASH", vec![Ok(()), not_served()]).is_ok());
+
+        let busy = || {
+            Err(SyncError::PeerBusy {
+                peer_id: PeerId::random(),
+                hash: "HASH".to_string(),
+            }
+            .into())
+        };
+        assert!(merge_chunk_results("HASH", vec![busy(), not_served()]).is_ok());
+
+        let err = merge_chunk_results("HASH", vec![not_served(), not_served()]).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref(),
+            Some(SyncError::NoPeerServes { hash }) if hash == "HASH"
+        ));
+
+        let err = merge_chunk_results(
+            "HASH",
+            vec![not_served(), Err(anyhow::anyhow!("connection closed"))],
+        )
+        .unwrap_err();
+        assert!(err.downcast_ref::<SyncError>().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_wait_fetched_chunk() {
+        let (sender, receiver) = oneshot::channel::<()>();
+        let mut in_flight = vec![
+            InFlightChunk {
+                range: 0..1,
+                task: tokio::spawn(async {
+                    Err(SyncError::ChunkNotServed {
+                        peer_id: PeerId::random(),
+                        hash: "HASH".to_string(),
+                    }
+                    .into())
+                }),
+            },
+            InFlightChunk {
+                range: 1..2,
+                task: tokio::spawn(async {
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+
+                    Ok(())
+                }),
+            },
+            InFlightChunk {
+                range: 2..3,
+                task: tokio::spawn(async move {
+                    let _ = receiver.await;
+
+                    Ok(())
+                }),
+            },
+        ];
+
+        // the chunk which is not served doesn't end the wait
+        let results = wait_fetched_chunk(&mut in_flight).await;
+        assert_eq!(results.len(), 2);
+        assert!(merge_chunk_results("HASH", results).is_ok());
+        assert_eq!(in_flight.len(), 1);
+        assert_eq!(in_flight[0].range, 2..3);
+
+        let mut file = hash_file("a", 3);
+        file.in_flight = in_flight;
+        assert!(file.is_in_flight(&(2..3)));
+        assert!(!file.is_in_flight(&(0..2)));
+
+        drop(sender);
+        let results = wait_fetched_chunk(&mut file.in_flight).await;
+        assert!(results[0].is_ok());
+        assert!(file.in_flight.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_write_short_chunk() {
+        let dir = TempDir::new().unwrap();
+        let availability = Availability::default();
+        let sync_counters = SyncCounters::default();
+        let tmp_index_file = open_temp_index_file(dir.path(), "HASH").await.unwrap();
+        let size = MAX_FILE_CHUNK_SIZE + 10;
+        let write = |offset, content: &'static [u8]| {
+            write_chunk(
+                &availability,
+                &sync_counters,
+                &tmp_index_file,
+                PeerId::random(),
+                "HASH",
+                offset,
+                MAX_FILE_CHUNK_SIZE.min(size - offset),
+                FileResponse {
+                    content: Some(Bytes::from_static(content)),
+                    busy: false,
+                    chunk_digests: vec![],
+                },
+            )
+        };
+
+        // the empty content of the non-final chunk is retriable
+        let err = write(0, b"").await.unwrap_err();
+        assert!(matches!(
+            err.downcast_ref(),
+            Some(SyncError::ShortChunk { expect, actual: 0, .. }) if *expect == MAX_FILE_CHUNK_SIZE
+        ));
+        assert!(availability.get("HASH").is_none());
+
+        write(MAX_FILE_CHUNK_SIZE, b"0123456789").await.unwrap();
+        assert!(availability.contains("HASH", &(MAX_FILE_CHUNK_SIZE..size)));
+        // only the written chunk is counted
+        assert_eq!(sync_counters.stats().downloaded_bytes, 10);
+    }
+
+    #[tokio::test]
+    async fn test_clean_temp_dir() {
+        let dir = TempDir::new().unwrap();
+        let sync_path = dir.path().join("HASH");
+        let upload_path = dir.path().join(".upload.abc");
+        fs::write(&sync_path, b"sync").await.unwrap();
+        fs::write(&upload_path, b"upload").await.unwrap();
+
+        clean_temp_dir(dir.path(), Duration::from_secs(3600))
+            .await
+            .unwrap();
+        assert!(!sync_path.exists());
+        assert!(upload_path.exists());
+
+        clean_temp_dir(dir.path(), Duration::ZERO).await.unwrap();
+        assert!(!upload_path.exists());
+
+        clean_temp_dir(&dir.path().join("not_exists"), Duration::ZERO)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_plan_sync() {
+        let dir = TempDir::new().unwrap();
+        let store_dir = dir.path().join("store");
+        fs::create_dir(&store_dir).await.unwrap();
+        fs::symlink(dir.path().join("HASHA"), store_dir.join("a.txt"))
+            .await
+            .unwrap();
+
+        let peer = PeerId::random();
+        let mut peer_store = PeerNodeStore::default();
+        for (filename, hash, size) in [
+            ("a.txt", "HASHA", 1),
+            ("b.txt", "HASHB", 10),
+            ("c.txt", "HASHC", 5),
+            ("d.tmp", "HASHD", 1),
+        ] {
+            peer_store
+                .files
+                .insert(filename.to_string(), hash.to_string());
+            peer_store.index.insert(hash.to_string(), size);
+        }
+        let peer_stores = HashMap::from([(peer, peer_store)]);
+        let syncing_files = HashMap::from([("HASHB".to_string(), vec!["b.txt".to_string()])]);
+        let sync_failures = HashMap::from([(
+            "HASHC".to_string(),
+            SyncFailure {
+                attempts: 2,
+                last_error: "no peer serves".to_string(),
+                retry_key: None,
+                retry_at: None,
+            },
+        )]);
+
+        let files = plan_sync(
+            dir.path(),
+            &store_dir,
+            StoreLink::Symlink,
+            &peer_stores,
+            &ExcludePatterns::new(["*.tmp".to_string()]),
+            None,
+            &HashSet::new(),
+            &syncing_files,
+            &sync_failures,
+            SyncOrder::SmallestFirst,
+        )
+        .await
+        .unwrap();
+
+        let files = files
+            .iter()
+            .map(|file| {
+                (
+                    file.hash.as_str(),
+                    file.size,
+                    file.syncing,
+                    &file.peers,
+                    file.failed_attempts,
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            files,
+            [
+                ("HASHC", 5, false, &vec![peer], 2),
+                ("HASHB", 10, true, &vec![peer], 0)
+            ]
+        );
+
+        // the files of the peer not in the sync from peers are not synced
+        let files = plan_sync(
+            dir.path(),
+            &store_dir,
+            StoreLink::Symlink,
+            &peer_stores,
+            &ExcludePatterns::new(["*.tmp".to_string()]),
+            None,
+            &HashSet::from([PeerId::random()]),
+            &syncing_files,
+            &sync_failures,
+            SyncOrder::SmallestFirst,
+        )
+        .await
+        .unwrap();
+        assert!(files.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_list_syncs() {
+        let dir = TempDir::new().unwrap();
+        let store_dir = dir.path().join("store");
+        fs::create_dir(&store_dir).await.unwrap();
+        fs::symlink(dir.path().join("HASHA"), store_dir.join("a.txt"))
+            .await
+            .unwrap();
+
+        let peer = PeerId::random();
+        let mut peer_store = PeerNodeStore::default();
+        for (filename, hash, size) in [
+            ("a.txt", "HASHA", 1),
+            ("b.txt", "HASHB", 10),
+            ("c.txt", "HASHC", 5),
+            ("d.txt", "HASHD", 1),
+            ("e.txt", "HASHE", 2),
+            ("f.txt", "HASHF", 100),
+        ] {
+            peer_store
+                .files
+                .insert(filename.to_string(), hash.to_string());
+            peer_store.index.insert(hash.to_string(), size);
+        }
+        let peer_stores = HashMap::from([(peer, peer_store)]);
+        let availability = Availability::default();
+        availability.insert("HASHB", 0..4);
+        let syncing_files = HashMap::from([("HASHB".to_string(), vec!["b.txt".to_string()])]);
+        let mut retry_queue = DelayQueue::new();
+        let sync_failures = HashMap::from([
+            (
+                "HASHC".to_string(),
+                SyncFailure {
+                    attempts: 1,
+                    last_error: "no peer serves".to_string(),
+                    retry_key: Some(
+                        retry_queue.insert("HASHC".to_string(), Duration::from_secs(60)),
+                    ),
+                    retry_at: Some(Instant::now() + Duration::from_secs(60)),
+                },
+            ),
+            (
+                "HASHE".to_string(),
+                SyncFailure {
+                    attempts: 5,
+                    last_error: "connection closed".to_string(),
+                    retry_key: None,
+                    retry_at: None,
+                },
+            ),
+        ]);
+
+        let entries = list_syncs(
+            dir.path(),
+            &store_dir,
+            StoreLink::Symlink,
+            &peer_stores,
+            &ExcludePatterns::default(),
+            Some(50),
+            &HashSet::new(),
+            &availability,
+            &syncing_files,
+            &sync_failures,
+            SyncOrder::SmallestFirst,
+        )
+        .await
+        .unwrap();
+
+        let entries = entries
+            .iter()
+            .map(|entry| {
+                (
+                    entry.hash.as_str(),
+                    entry.state,
+                    entry.synced,
+                    entry.failed_attempts,
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            entries,
+            [
+                ("HASHB", SyncState::Active, 4, 0),
+                ("HASHC", SyncState::Retrying, 0, 1),
+                ("HASHD", SyncState::Queued, 0, 0),
+                ("HASHE", SyncState::Failed, 0, 5),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_sync_scheduler() {
+        let dir = TempDir::new().unwrap();
+        let (mut sender, receiver) = oneshot::channel::<()>();
+        let mut file = hash_file("a", 3);
+        file.in_flight.push(InFlightChunk {
+            range: 0..3,
+            task: tokio::spawn(async move {
+                let _ = receiver.await;
+
+                Ok(())
+            }),
+        });
+
+        let mut sync_scheduler = SyncScheduler::new(1, SyncOrder::default(), SyncRetry::default());
+        sync_scheduler
+            .syncing_files
+            .insert(file.hash.clone(), file.filenames.clone());
+        let task = handle_sync_file_result(
+            dir.path().to_path_buf(),
+            StoreLayout::default(),
+            IndexNaming::default(),
+            dir.path().join(".tmp"),
+            dir.path().join("store"),
+            StoreLink::default(),
+            HashAlgorithm::default(),
+            Availability::default(),
+            None,
+            ChunkDigestCache::new(false),
+            file,
+            sync_scheduler.cancel.clone(),
+        );
+        sync_scheduler.tasks.push(task);
+
+        sync_scheduler.shutdown().await;
+        assert!(sync_scheduler.tasks.is_empty());
+        assert!(sync_scheduler.syncing_files.is_empty());
+
+        // the in-flight chunk is aborted instead of writing into the temp index file
+        sender.cancellation().await;
+    }
 }
@@ -0,0 +1,97 @@
+use std::pin::Pin;
+use std::time::Duration;
+
+use rand::Rng;
+use tokio::time;
+use tokio::time::{Instant, Sleep};
+
+/// a ticker whose every interval is randomized by the jitter percentage, so the nodes started
+/// together don't tick together, the first tick completes immediately like the [`time::Interval`]
+#[derive(Debug)]
+pub struct JitterTicker {
+    interval: Duration,
+    /// the interval is randomized in `[-jitter, +jitter]` percent
+    jitter: u8,
+    sleep: Pin<Box<Sleep>>,
+}
+
+impl JitterTicker {
+    pub fn new(interval: Duration, jitter: u8) -> Self {
+        Self {
+            interval,
+            jitter,
+            sleep: Box::pin(time::sleep_until(Instant::now())),
+        }
+    }
+
+    /// wait for the next tick, it is cancel safe
+    pub async fn tick(&mut self) {
+        self.sleep.as_mut().await;
+
+        self.reset();
+    }
+
+    /// the next tick completes after a randomized interval from now
+    pub fn reset(&mut self) {
+        let interval = jitter_interval(self.interval, self.jitter);
+
+        self.sleep.as_mut().reset(Instant::now() + interval);
+    }
+
+    /// the next tick completes immediately
+    pub fn tick_now(&mut self) {
+        self.sleep.as_mut().reset(Instant::now());
+    }
+
+    /// the time until the next tick, it is zero when the tick is due but not handled yet
+    pub fn remaining(&self) -> Duration {
+        self.sleep
+            .deadline()
+            .saturating_duration_since(Instant::now())
+    }
+}
+
+/// randomize the interval in `[-jitter, +jitter]` percent
+pub fn jitter_interval(interval: Duration, jitter: u8) -> Duration {
+    if jitter == 0 {
+        return interval;
+    }
+
+    let jitter = f64::from(jitter) / 100.0;
+    let factor = 1.0 + rand::thread_rng().gen_range(-jitter..=jitter);
+
+    interval.mul_f64(factor)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_jitter_interval() {
+        let interval = Duration::from_secs(10);
+
+        assert_eq!(jitter_interval(interval, 0), interval);
+
+        for _ in 0..100 {
+            let jittered = jitter_interval(interval, 20);
+            // tolerate the float rounding
+            assert!(jittered >= Duration::from_millis(7_999), "{jittered:?}");
+            assert!(jittered <= Duration::from_millis(12_001), "{jittered:?}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_remaining() {
+        let interval = Duration::from_secs(10);
+        let mut ticker = JitterTicker::new(interval, 0);
+
+        // the first tick is due immediately
+        assert_eq!(ticker.remaining(), Duration::ZERO);
+
+        ticker.tick().await;
+        let remaining = ticker.remaining();
+        assert!(remaining <= interval, "{remaining:?}");
+        assert!(remaining > Duration::from_secs(9), "{remaining:?}");
+    }
+}
@@ -1,4 +1,11 @@
-#[derive(prost::Message)]
+use std::mem;
+
+use anyhow::anyhow;
+use libp2p::identity::{Keypair, PublicKey};
+use libp2p::{Multiaddr, PeerId};
+use prost::Message as _;
+
+#[derive(Clone, prost::Message)]
 pub struct FileMessage {
     #[prost(string, tag = "1")]
     pub peer_id: String,
@@ -9,9 +16,126 @@ pub struct FileMessage {
     /// use for avoid duplicate gossip message
     #[prost(uint64, tag = "3")]
     pub refresh_time: u64,
+
+    /// signature of the message which signature field is empty
+    #[prost(bytes, tag = "4")]
+    pub signature: Vec<u8>,
+
+    /// protobuf encoded public key of the signer
+    #[prost(bytes, tag = "5")]
+    pub public_key: Vec<u8>,
+
+    /// the index of the part when the file list is split across messages
+    #[prost(uint32, tag = "6")]
+    pub part: u32,
+
+    /// the number of the parts sharing the `refresh_time`, `0` means the message isn't split
+    #[prost(uint32, tag = "7")]
+    pub total_parts: u32,
 }
 
-#[derive(prost::Message)]
+impl FileMessage {
+    /// sign the message, the file list is split across the messages sharing the refresh time
+    /// when the encoded message exceeds `max_size`, a file which alone exceeds it is sent in its
+    /// own part
+    pub fn sign_split(self, key: &Keypair, max_size: usize) -> anyhow::Result<Vec<FileMessage>> {
+        let mut message = self;
+        message.sign(key)?;
+        if message.encoded_len() <= max_size {
+            return Ok(vec![message]);
+        }
+
+        let FileMessage {
+            peer_id,
+            file_list,
+            refresh_time,
+            ..
+        } = message;
+
+        // the part fields are encoded in the max size
+        let mut empty_part = FileMessage {
+            peer_id: peer_id.clone(),
+            file_list: vec![],
+            refresh_time,
+            signature: vec![],
+            public_key: vec![],
+            part: u32::MAX,
+            total_parts: u32::MAX,
+        };
+        empty_part.sign(key)?;
+        let empty_part_len = empty_part.encoded_len();
+
+        let mut file_lists = vec![];
+        let mut current_files = vec![];
+        let mut current_len = empty_part_len;
+        for file in file_list {
+            let file_len = prost::encoding::message::encoded_len(2, &file);
+            if !current_files.is_empty() && current_len + file_len > max_size {
+                file_lists.push(mem::take(&mut current_files));
+                current_len = empty_part_len;
+            }
+
+            current_len += file_len;
+            current_files.push(file);
+        }
+        file_lists.push(current_files);
+
+        let total_parts = file_lists.len() as u32;
+        file_lists
+            .into_iter()
+            .enumerate()
+            .map(|(part, file_list)| {
+                let mut message = FileMessage {
+                    peer_id: peer_id.clone(),
+                    file_list,
+                    refresh_time,
+                    signature: vec![],
+                    public_key: vec![],
+                    part: part as _,
+                    total_parts,
+                };
+                message.sign(key)?;
+
+                Ok(message)
+            })
+            .collect()
+    }
+
+    /// sign the message with the node keypair, the public key is signed too
+    pub fn sign(&mut self, key: &Keypair) -> anyhow::Result<()> {
+        self.public_key = key.public().to_protobuf_encoding();
+        self.signature = key.sign(&self.signing_payload())?;
+
+        Ok(())
+    }
+
+    /// verify the signature, and the public key must match the peer id in the message, return
+    /// the signer peer id
+    pub fn verify(&self) -> anyhow::Result<PeerId> {
+        verify_signature(
+            &self.peer_id,
+            &self.public_key,
+            &self.signing_payload(),
+            &self.signature,
+        )
+    }
+
+    fn signing_payload(&self) -> Vec<u8> {
+        let message = FileMessage {
+            peer_id: self.peer_id.clone(),
+            file_list: self.file_list.clone(),
+            refresh_time: self.refresh_time,
+            signature: vec![],
+            public_key: self.public_key.clone(),
+            part: self.part,
+            total_parts: self.total_parts,
+        };
+
+        message.encode_to_vec()
+    }
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
 pub struct File {
     #[prost(string, tag = "1")]
     pub filename: String,
@@ -21,6 +145,11 @@ pub struct File {
 
     #[prost(uint64, tag = "3")]
     pub file_size: u64,
+
+    /// the available chunks bitfield of the file which is still syncing, the file is complete
+    /// when it is not set
+    #[prost(bytes, optional, tag = "4")]
+    pub chunks: Option<Vec<u8>>,
 }
 
 #[derive(prost::Message)]
@@ -33,11 +162,186 @@ pub struct DiscoverMessage {
     pub discover_time: u64,
 }
 
-#[derive(prost::Message)]
+/// the address of a peer, it is signed by the peer itself, so the other peers can't make us
+/// dial a forged address
+#[derive(Clone, PartialEq, Eq, prost::Message)]
 pub struct Peer {
     #[prost(string, tag = "1")]
     pub peer_id: String,
 
     #[prost(bytes, tag = "2")]
     pub addr: Vec<u8>,
+
+    /// signature of the peer which signature field is empty
+    #[prost(bytes, tag = "3")]
+    pub signature: Vec<u8>,
+
+    /// protobuf encoded public key of the peer
+    #[prost(bytes, tag = "4")]
+    pub public_key: Vec<u8>,
+}
+
+impl Peer {
+    /// create the signed address of the local peer
+    pub fn new_signed(key: &Keypair, addr: &Multiaddr) -> anyhow::Result<Self> {
+        let public_key = key.public();
+        let mut peer = Peer {
+            peer_id: public_key.to_peer_id().to_base58(),
+            addr: addr.to_vec(),
+            signature: vec![],
+            public_key: public_key.to_protobuf_encoding(),
+        };
+        peer.signature = key.sign(&peer.signing_payload())?;
+
+        Ok(peer)
+    }
+
+    /// verify the address is signed by the peer, return the peer id and the address
+    pub fn verify(&self) -> anyhow::Result<(PeerId, Multiaddr)> {
+        let peer_id = verify_signature(
+            &self.peer_id,
+            &self.public_key,
+            &self.signing_payload(),
+            &self.signature,
+        )?;
+        let addr = Multiaddr::try_from(self.addr.clone())?;
+
+        Ok((peer_id, addr))
+    }
+
+    fn signing_payload(&self) -> Vec<u8> {
+        let peer = Peer {
+            signature: vec![],
+            ..self.clone()
+        };
+
+        peer.encode_to_vec()
+    }
+}
+
+/// the public key must match the claimed peer id, return the signer peer id
+fn verify_signature(
+    claimed_peer_id: &str,
+    public_key: &[u8],
+    payload: &[u8],
+    signature: &[u8],
+) -> anyhow::Result<PeerId> {
+    let public_key = PublicKey::from_protobuf_encoding(public_key)?;
+    let peer_id = public_key.to_peer_id();
+
+    if peer_id.to_base58() != claimed_peer_id {
+        return Err(anyhow!(
+            "public key peer id {peer_id} doesn't match claimed peer id {claimed_peer_id}"
+        ));
+    }
+
+    if !public_key.verify(payload, signature) {
+        return Err(anyhow!("invalid signature from {peer_id}"));
+    }
+
+    Ok(peer_id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn create_message(key: &Keypair) -> FileMessage {
+        FileMessage {
+            peer_id: key.public().to_peer_id().to_base58(),
+            file_list: vec![File {
+                filename: "test.txt".to_string(),
+                hash: "ABC".to_string(),
+                file_size: 1,
+                chunks: None,
+            }],
+            refresh_time: 1,
+            signature: vec![],
+            public_key: vec![],
+            part: 0,
+            total_parts: 0,
+        }
+    }
+
+    #[test]
+    fn test_sign_and_verify_file_message() {
+        let key = Keypair::generate_ed25519();
+        let mut message = create_message(&key);
+        message.sign(&key).unwrap();
+
+        let message = FileMessage::decode(message.encode_to_vec().as_slice()).unwrap();
+        assert_eq!(message.verify().unwrap(), key.public().to_peer_id());
+
+        // tampered file list
+        let mut tampered = message;
+        tampered.file_list[0].hash = "DEF".to_string();
+        assert!(tampered.verify().is_err());
+
+        // unsigned message
+        assert!(create_message(&key).verify().is_err());
+
+        // signed by the other key
+        let mut forged = create_message(&key);
+        forged.sign(&Keypair::generate_ed25519()).unwrap();
+        assert!(forged.verify().is_err());
+    }
+
+    #[test]
+    fn test_sign_split_file_message() {
+        let key = Keypair::generate_ed25519();
+        let mut message = create_message(&key);
+        message.file_list = (0..100)
+            .map(|i| File {
+                filename: format!("{i}.txt"),
+                hash: "A".repeat(64),
+                file_size: i,
+                chunks: None,
+            })
+            .collect();
+
+        let messages = message.clone().sign_split(&key, 65536).unwrap();
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].total_parts, 0);
+
+        let messages = message.clone().sign_split(&key, 2048).unwrap();
+        assert!(messages.len() > 1);
+        for (part, split_message) in messages.iter().enumerate() {
+            assert!(split_message.encoded_len() <= 2048);
+            assert_eq!(split_message.part, part as u32);
+            assert_eq!(split_message.total_parts, messages.len() as u32);
+            assert_eq!(split_message.refresh_time, message.refresh_time);
+            split_message.verify().unwrap();
+        }
+
+        let file_list = messages
+            .into_iter()
+            .flat_map(|split_message| split_message.file_list)
+            .collect::<Vec<_>>();
+        assert_eq!(file_list, message.file_list);
+
+        // the part fields are signed
+        let mut messages = message.sign_split(&key, 2048).unwrap();
+        messages[0].total_parts = 1;
+        assert!(messages[0].verify().is_err());
+    }
+
+    #[test]
+    fn test_sign_and_verify_peer() {
+        let key = Keypair::generate_ed25519();
+        let addr: Multiaddr = "/ip4/127.0.0.1/tcp/1".parse().unwrap();
+        let peer = Peer::new_signed(&key, &addr).unwrap();
+
+        let peer = Peer::decode(peer.encode_to_vec().as_slice()).unwrap();
+        assert_eq!(peer.verify().unwrap(), (key.public().to_peer_id(), addr));
+
+        // redirect to the other address
+        let mut tampered = peer.clone();
+        tampered.addr = "/ip4/10.0.0.1/tcp/1".parse::<Multiaddr>().unwrap().to_vec();
+        assert!(tampered.verify().is_err());
+
+        // introduce the other peer with our own key
+        let mut forged = peer;
+        forged.peer_id = PeerId::random().to_base58();
+        assert!(forged.verify().is_err());
+    }
 }
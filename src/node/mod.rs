@@ -1,57 +1,114 @@
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::future::Future;
 use std::io::ErrorKind;
+use std::net::SocketAddr;
+use std::ops::Range;
 use std::path::PathBuf;
 use std::sync::Arc;
-use std::time::Duration;
-use std::{future, io};
+use std::time::{Duration, SystemTime};
+use std::{io, mem};
 
 use bytes::Bytes;
 use futures_channel::mpsc::Receiver;
-use futures_channel::oneshot::Sender;
-use futures_util::{Stream, StreamExt};
+use futures_util::stream::FuturesUnordered;
+use futures_util::{pin_mut, AsyncRead, AsyncWrite, Stream, StreamExt};
 use libp2p::bandwidth::{BandwidthLogging, BandwidthSinks};
 use libp2p::core::either::EitherTransport;
 use libp2p::core::muxing::StreamMuxerBox;
 use libp2p::core::transport::Boxed;
+#[cfg(test)]
+use libp2p::core::transport::MemoryTransport;
+use libp2p::core::transport::OrTransport;
 use libp2p::core::upgrade::Version;
-use libp2p::dns::TokioDnsConfig;
+use libp2p::core::ConnectedPoint;
+use libp2p::dns::{ResolverConfig, ResolverOpts, TokioDnsConfig};
 use libp2p::identity::Keypair;
-use libp2p::pnet::{PnetConfig, PnetError, PreSharedKey};
 use libp2p::request_response::RequestId;
+use libp2p::swarm::{ConnectionLimits, SwarmBuilder};
 use libp2p::yamux::YamuxConfig;
 use libp2p::{noise, tcp, websocket, Multiaddr, PeerId, Swarm, Transport};
 use libp2p_auto_relay::combine::CombineTransport;
 use libp2p_auto_relay::endpoint;
 use tap::TapFallible;
+use tokio::sync::{broadcast, Semaphore};
+use tokio::task::JoinHandle;
 use tokio::time;
 use tokio::time::Interval;
 use tokio_util::time::DelayQueue;
 use tracing::{error, info};
+use trust_dns_resolver::config::{NameServerConfig, Protocol};
 
 use crate::command;
-use crate::command::Command;
+use crate::command::{Command, FileEvent, PeerIdentity};
 use crate::config::ConfigManager;
-use crate::node::behaviour::{Behaviour, FILE_SHARE_TOPIC, MAX_CHUNK_SIZE};
+use crate::hash::HashAlgorithm;
+use crate::node::availability::{Availability, RangeSet};
+use crate::node::behaviour::{Behaviour, ShareTopics, MAX_CHUNK_SIZE};
 pub use crate::node::behaviour::{FileRequest, FileResponse};
-use crate::node::command_handler::CommandHandlerBuilder;
-use crate::node::config::Config;
-use crate::node::event_handler::EventHandlerBuilder;
+use crate::node::buffer_pool::BufferPool;
+pub use crate::node::builder::NodeBuilder;
+use crate::node::chunking::ChunkDigestCache;
+use crate::node::command_handler::{AddingFiles, CommandHandlerBuilder};
+use crate::node::config::{Config, IndexNaming, IpFamily, NoiseHandshake, StoreLayout, TcpOptions};
+use crate::node::connection_errors::ConnectionErrors;
+use crate::node::dial_only::DialOnlyTransport;
+use crate::node::discover_handler::DiscoverHandler;
+use crate::node::event_handler::{
+    finish_inbound_request, ChunkDigestsResponse, EventHandlerBuilder,
+};
 use crate::node::file_cache::FileCache;
-use crate::node::file_sync::FileSync;
+use crate::node::file_get_requests::FileGetRequests;
+use crate::node::file_sync::{clean_temp_dir, FileSyncBuilder, SyncEvent, SyncScheduler};
+use crate::node::jitter_ticker::JitterTicker;
+use crate::node::message::{File, FileMessage, Peer};
 use crate::node::peer_connector::PeerConnector;
+use crate::node::peer_failures::PeerFailures;
+use crate::node::peer_pings::PeerPings;
+use crate::node::pnet::{PnetKeys, PnetOutput};
+use crate::node::publish_retry::PublishRetries;
 use crate::node::refresh_store_handler::RefreshStoreHandler;
+use crate::node::relay_stats::RelayStats;
+use crate::node::scrub::{run_scrub, SharedScrubStatus};
+use crate::node::store_link::StoreLink;
+use crate::node::sync_counters::SyncCounters;
+use crate::node::webhook::SyncWebhook;
+use crate::util::{normalize_filename, ExcludePatterns, SharePatterns};
 
+mod availability;
 mod behaviour;
+mod buffer_pool;
+mod builder;
+mod chunking;
 mod command_handler;
 pub mod config;
+mod connection_errors;
+mod dial_only;
+mod discover_handler;
 mod event_handler;
 mod file_cache;
+mod file_get_requests;
 mod file_sync;
+mod jitter_ticker;
 mod message;
 mod peer_connector;
+mod peer_failures;
+mod peer_pings;
+pub mod pnet;
+mod publish_retry;
 mod refresh_store_handler;
+mod relay_stats;
+mod scrub;
+pub mod store_link;
+mod sync_counters;
+mod webhook;
 
 const FILE_CACHE_TIMEOUT: Duration = Duration::from_secs(30);
+/// max parts of a split file message, the message with more parts is rejected to bound the
+/// memory of the pending parts
+const MAX_FILE_MESSAGE_PARTS: u32 = 1024;
+
+/// the file events buffered for a slow subscriber, it misses the older events when it lags behind
+const FILE_EVENTS_CAPACITY: usize = 256;
 
 type BoxedTransport = Boxed<(PeerId, StreamMuxerBox)>;
 
@@ -61,19 +118,83 @@ where
     FileGetter: command::FileGetter + Send + 'static,
 {
     index_dir: PathBuf,
+    store_layout: StoreLayout,
+    index_naming: IndexNaming,
+    temp_dir: PathBuf,
+    stale_temp_file_age: Duration,
+    scrub_interval: Option<Duration>,
+    scrub_rate: u64,
+    max_auto_sync_file_size: Option<u64>,
+    sync_from_peers: HashSet<PeerId>,
+    content_defined_chunking: bool,
+    /// number of the chunk requests kept in flight for a syncing file
+    sync_read_ahead: usize,
+    /// the observed addrs are not advertised by the relay only node
+    relay_only: bool,
+    identify_push: bool,
+    max_discover_addrs: usize,
+    allow_local_discover_addrs: bool,
+    max_peer_inbound_requests: usize,
+    max_peer_addrs: usize,
+    /// the addrs of the other ip family are not dialed
+    ip_family: IpFamily,
+    /// the result of the last index files scrub
+    scrub_status: SharedScrubStatus,
     store_dir: PathBuf,
+    store_link: StoreLink,
     swarm: Swarm<Behaviour>,
+    /// the gossip topics of the share network
+    topics: ShareTopics,
     peer_stores: HashMap<PeerId, PeerNodeStore>,
-    file_get_requests: HashMap<RequestId, Sender<io::Result<FileResponse>>>,
+    file_get_requests: FileGetRequests,
+    inbound_requests: HashMap<PeerId, HashSet<RequestId>>,
+    chunk_digest_cache: ChunkDigestCache,
+    /// the chunk digests of the inbound requests which are being computed
+    chunk_digest_tasks: FuturesUnordered<JoinHandle<ChunkDigestsResponse>>,
     peer_addr_receiver: DelayQueue<Multiaddr>,
     peer_addr_connecting: HashMap<PeerId, Multiaddr>,
+    /// the ping peer commands which are waiting for the ping
+    peer_pings: PeerPings,
     command_receiver: Receiver<Command<FileStream, FileGetter>>,
-    refresh_store_ticker: Interval,
-    sync_file_ticker: Interval,
+    refresh_store_ticker: JitterTicker,
+    sync_file_ticker: JitterTicker,
+    discover_ticker: Interval,
+    sync_scheduler: SyncScheduler,
+    max_connections: Option<u32>,
+    /// the signed addrs of the discovered peers, they are introduced to the new peers
+    discover_peers: HashMap<PeerId, Vec<Peer>>,
+    pending_discover: bool,
+    /// the gossip messages which failed to publish by a transient error
+    publish_retries: PublishRetries,
+    buffer_pool: BufferPool,
     cache_files: FileCache,
-    connected_peer: HashMap<PeerId, HashSet<Multiaddr>>,
+    connected_peer: HashMap<PeerId, ConnectedPeer>,
+    /// the request failures of the peers, it is used to avoid the flaky peers when syncing
+    peer_failures: PeerFailures,
+    /// the available byte ranges of the syncing files
+    availability: Availability,
+    adding_files: AddingFiles,
+    /// the permits of the upload tasks, `None` means unlimited
+    upload_permits: Option<Arc<Semaphore>>,
     bandwidth_sinks: Arc<BandwidthSinks>,
+    /// the failed connections by the category
+    connection_errors: ConnectionErrors,
+    /// the load of the relay service
+    relay_stats: RelayStats,
+    /// the sync and serving counters since the node started
+    sync_counters: SyncCounters,
+    file_events: broadcast::Sender<FileEvent>,
     config_manager: ConfigManager,
+    /// sign the file messages
+    key: Keypair,
+    hash_algorithm: HashAlgorithm,
+    hash_buffer_size: usize,
+    exclude_patterns: ExcludePatterns,
+    share_only: SharePatterns,
+    reject_case_conflict: bool,
+    verify_on_add: bool,
+    read_only: bool,
+    sync_complete_webhook: Option<SyncWebhook>,
 }
 
 impl<FileStream, FileGetter> Node<FileStream, FileGetter>
@@ -81,54 +202,138 @@ where
     FileStream: Stream<Item = io::Result<Bytes>> + Unpin + Send + 'static,
     FileGetter: command::FileGetter + Send + 'static,
 {
-    pub fn new(
+    /// create node with a prepared transport, the transport related fields in [`Config`] are
+    /// ignored
+    #[allow(clippy::too_many_arguments)]
+    fn with_transport(
         config: Config,
+        transport: BoxedTransport,
+        bandwidth_sinks: Arc<BandwidthSinks>,
+        connection_errors: ConnectionErrors,
+        endpoint_behaviour: Option<endpoint::Behaviour>,
         peer_addr_receiver: DelayQueue<Multiaddr>,
         command_receiver: Receiver<Command<FileStream, FileGetter>>,
         config_manager: ConfigManager,
     ) -> anyhow::Result<Self> {
         let peer_id = config.key.public().to_peer_id();
-
-        info!("local node peer id {}", peer_id);
-
-        let (transport, bandwidth_sinks, endpoint_behaviour) = create_transport(
-            config.key.clone(),
-            config.handshake_key,
-            config.relay_server_addr,
-        )?;
+        let key = config.key.clone();
+        let topics = ShareTopics::new(config.network_id.as_deref());
+        let buffer_pool = BufferPool::new(config.buffer_pool_size);
         let behaviour = Behaviour::new(
             config.key,
+            config.gossip_validation_mode,
             config.enable_relay_behaviour,
+            config.identify_push,
             endpoint_behaviour,
+            config.max_file_protocol,
+            config.file_read_idle_timeout,
+            buffer_pool.clone(),
+            &topics,
         )?;
 
-        let swarm = Swarm::with_tokio_executor(transport, behaviour, peer_id);
+        // the limit rejects the new incoming connections and dials, the established ones are kept
+        let swarm = SwarmBuilder::with_tokio_executor(transport, behaviour, peer_id)
+            .connection_limits(
+                ConnectionLimits::default().with_max_established(config.max_connections),
+            )
+            .build();
 
         Ok(Self {
             index_dir: config.index_dir,
+            store_layout: config.store_layout,
+            index_naming: config.index_naming,
+            temp_dir: config.temp_dir,
+            stale_temp_file_age: config.stale_temp_file_age,
+            scrub_interval: config.scrub_interval,
+            scrub_rate: config.scrub_rate,
+            max_auto_sync_file_size: config.max_auto_sync_file_size,
+            sync_from_peers: config.sync_from_peers,
+            content_defined_chunking: config.content_defined_chunking,
+            sync_read_ahead: config.sync_read_ahead,
+            relay_only: config.relay_only,
+            identify_push: config.identify_push,
+            max_discover_addrs: config.max_discover_addrs,
+            allow_local_discover_addrs: config.allow_local_discover_addrs,
+            max_peer_inbound_requests: config.max_peer_inbound_requests,
+            max_peer_addrs: config.max_peer_addrs,
+            ip_family: config.ip_family,
+            scrub_status: Default::default(),
             store_dir: config.store_dir,
+            store_link: config.store_link,
             swarm,
+            topics,
             peer_stores: Default::default(),
             file_get_requests: Default::default(),
+            inbound_requests: Default::default(),
+            chunk_digest_cache: ChunkDigestCache::new(config.content_defined_chunking),
+            chunk_digest_tasks: Default::default(),
             peer_addr_receiver,
             peer_addr_connecting: Default::default(),
+            peer_pings: Default::default(),
             command_receiver,
-            refresh_store_ticker: time::interval(config.refresh_store_interval),
-            sync_file_ticker: time::interval(config.sync_file_interval),
-            cache_files: FileCache::new(),
+            refresh_store_ticker: JitterTicker::new(
+                config.refresh_store_interval,
+                config.interval_jitter,
+            ),
+            sync_file_ticker: JitterTicker::new(config.sync_file_interval, config.interval_jitter),
+            discover_ticker: time::interval(config.discover_interval),
+            sync_scheduler: SyncScheduler::new(
+                config.max_sync_files,
+                config.sync_order,
+                config.sync_retry,
+            ),
+            max_connections: config.max_connections,
+            discover_peers: Default::default(),
+            pending_discover: false,
+            publish_retries: Default::default(),
+            buffer_pool,
+            cache_files: FileCache::new(config.enable_mmap),
             connected_peer: Default::default(),
+            peer_failures: Default::default(),
+            availability: Default::default(),
+            adding_files: Default::default(),
+            upload_permits: config
+                .max_uploads
+                .map(|max_uploads| Arc::new(Semaphore::new(max_uploads))),
             bandwidth_sinks,
+            connection_errors,
+            relay_stats: RelayStats::new(config.enable_relay_behaviour),
+            sync_counters: Default::default(),
+            file_events: broadcast::channel(FILE_EVENTS_CAPACITY).0,
             config_manager,
+            key,
+            hash_algorithm: config.hash_algorithm,
+            hash_buffer_size: config.hash_buffer_size,
+            exclude_patterns: config.exclude_patterns,
+            share_only: config.share_only,
+            reject_case_conflict: config.reject_case_conflict,
+            verify_on_add: config.verify_on_add,
+            read_only: config.read_only,
+            sync_complete_webhook: config.sync_complete_webhook.map(SyncWebhook::new),
         })
     }
 
-    pub async fn run(&mut self, addr: Multiaddr) -> anyhow::Result<()> {
-        self.swarm
-            .listen_on(addr)
-            .tap_err(|err| error!(%err, "swarm listen failed"))?;
+    /// run the node until the `shutdown` is done, the syncing files are canceled and the handling
+    /// command, like storing the config, is finished before returning
+    pub async fn run<F>(&mut self, shutdown: F) -> anyhow::Result<()>
+    where
+        F: Future<Output = anyhow::Result<()>>,
+    {
+        pin_mut!(shutdown);
 
-        let mut sync_file_task = None;
-        let mut syncing_files = None;
+        clean_temp_dir(&self.temp_dir, self.stale_temp_file_age).await?;
+
+        if let Some(scrub_interval) = self.scrub_interval {
+            tokio::spawn(run_scrub(
+                self.index_dir.clone(),
+                self.store_layout,
+                self.hash_algorithm,
+                scrub_interval,
+                self.scrub_rate,
+                self.scrub_status.clone(),
+                self.chunk_digest_cache.clone(),
+            ));
+        }
 
         loop {
             let swarm = &mut self.swarm;
@@ -136,171 +341,292 @@ where
             let command_receiver = &mut self.command_receiver;
             let refresh_store_ticker = &mut self.refresh_store_ticker;
             let sync_file_ticker = &mut self.sync_file_ticker;
+            let discover_ticker = &mut self.discover_ticker;
+            let sync_scheduler = &mut self.sync_scheduler;
+            let publish_retries = &mut self.publish_retries;
+            let peer_pings = &mut self.peer_pings;
 
             self.cache_files.clean_timeout(FILE_CACHE_TIMEOUT);
 
-            match (sync_file_task.take(), &syncing_files) {
-                (None, None) => {
-                    tokio::select! {
-                        Some(event) = swarm.next() => {
-                            EventHandlerBuilder::default()
-                                .index_dir(&self.index_dir)
-                                .store_dir( &self.store_dir)
-                                .swarm(swarm)
-                                .peer_stores( &mut self.peer_stores)
-                                .file_get_requests( &mut self.file_get_requests)
-                                .peer_addr_receiver(peer_addr_receiver)
-                                .peer_addr_connecting(&mut self.peer_addr_connecting)
-                                .cache_files(&mut self.cache_files)
-                                .connected_peer(&mut self.connected_peer)
-                                .build()
-                                .unwrap()
-                                .handle_event(event)
-                                .await?;
-                        }
+            tokio::select! {
+                result = &mut shutdown => {
+                    info!("node is shutting down, cancel the syncing files");
 
-                        Some(addr) = peer_addr_receiver.next() => {
-                            PeerConnector::new(swarm, &mut self.peer_addr_connecting)
-                                .connect_peer(addr.into_inner()).await;
-                        }
+                    sync_scheduler.shutdown().await;
 
-                        Some(cmd) = command_receiver.next() => {
-                            CommandHandlerBuilder::default()
-                                .index_dir(&self.index_dir)
-                                .store_dir(&self.store_dir)
-                                .peer_stores(&mut self.peer_stores)
-                                .connected_peer(&self.connected_peer)
-                                .bandwidth_sinks(&self.bandwidth_sinks)
-                                .config_manager(&mut self.config_manager)
-                                .peer_addr_receiver(peer_addr_receiver)
-                                .swarm(swarm)
-                                .build()
-                                .unwrap()
-                                .handle_command(cmd)
-                                .await
-                        }
+                    return result;
+                }
 
-                        _ = refresh_store_ticker.tick() => {
-                            RefreshStoreHandler::new(&self.store_dir, swarm)
-                                .handle_tick(FILE_SHARE_TOPIC.clone()).await?;
+                Some(event) = swarm.next() => {
+                    EventHandlerBuilder::default()
+                        .index_dir(&self.index_dir)
+                        .store_layout(self.store_layout)
+                        .store_dir( &self.store_dir)
+                        .store_link(self.store_link)
+                        .share_only(&self.share_only)
+                        .swarm(swarm)
+                        .topics(&self.topics)
+                        .peer_stores( &mut self.peer_stores)
+                        .file_get_requests( &mut self.file_get_requests)
+                        .inbound_requests(&mut self.inbound_requests)
+                        .peer_addr_receiver(peer_addr_receiver)
+                        .peer_addr_connecting(&mut self.peer_addr_connecting)
+                        .peer_pings(peer_pings)
+                        .cache_files(&mut self.cache_files)
+                        .connected_peer(&mut self.connected_peer)
+                        .peer_failures(&mut self.peer_failures)
+                        .discover_peers(&mut self.discover_peers)
+                        .pending_discover(&mut self.pending_discover)
+                        .relay_only(self.relay_only)
+                        .identify_push(self.identify_push)
+                        .max_discover_addrs(self.max_discover_addrs)
+                        .allow_local_discover_addrs(self.allow_local_discover_addrs)
+                        .max_peer_inbound_requests(self.max_peer_inbound_requests)
+                        .ip_family(self.ip_family)
+                        .buffer_pool(&self.buffer_pool)
+                        .connection_errors(&self.connection_errors)
+                        .relay_stats(&mut self.relay_stats)
+                        .sync_counters(&self.sync_counters)
+                        .chunk_digest_cache(&self.chunk_digest_cache)
+                        .file_events(&self.file_events)
+                        .chunk_digest_tasks(&mut self.chunk_digest_tasks)
+                        .build()
+                        .unwrap()
+                        .handle_event(event)
+                        .await?;
+                }
 
-                            refresh_store_ticker.reset();
-                        }
+                Some(result) = self.chunk_digest_tasks.next() => {
+                    let ChunkDigestsResponse {
+                        peer,
+                        request_id,
+                        channel,
+                        response,
+                    } = match result {
+                        Err(err) => {
+                            error!(%err, "chunk digests task failed, drop the request");
 
-                        _ = sync_file_ticker.tick() => {
-                            let task = FileSync::new(
-                                &self.index_dir,
-                                &self.store_dir,
-                                swarm,
-                                &self.peer_stores,
-                                &mut self.file_get_requests,
-                                None
-                            ).sync_files().await?;
-
-                            match task {
-                                None => {
-                                    sync_file_ticker.reset();
-                                }
-
-                                Some(task) => {
-                                    info!("start sync files");
-
-                                    sync_file_task.replace(task);
-                                }
-                            }
+                            continue;
                         }
+
+                        Ok(response) => response,
+                    };
+
+                    if swarm
+                        .behaviour_mut()
+                        .request_respond
+                        .send_response(channel, response)
+                        .is_err()
+                    {
+                        error!(%peer, %request_id, "send chunk digests failed");
+
+                        finish_inbound_request(&mut self.inbound_requests, peer, request_id);
                     }
                 }
 
-                (Some(mut task), _) => {
-                    tokio::select! {
-                        Some(event) = swarm.next() => {
-                            EventHandlerBuilder::default()
+                Some(addr) = peer_addr_receiver.next() => {
+                    PeerConnector::new(
+                        swarm,
+                        &mut self.peer_addr_connecting,
+                        peer_addr_receiver,
+                        self.max_connections,
+                        self.ip_family,
+                    )
+                    .connect_peer(addr.into_inner())
+                    .await;
+                }
+
+                Some(cmd) = command_receiver.next() => {
+                    match cmd {
+                        // the on demand sync needs the sync scheduler, it is handled by the node
+                        Command::SyncFile { filename, result_sender } => {
+                            // the file is requested explicitly, the max auto sync size doesn't apply
+                            let file_sync = FileSyncBuilder::default()
                                 .index_dir(&self.index_dir)
-                                .store_dir( &self.store_dir)
+                                .store_layout(self.store_layout)
+                                .index_naming(self.index_naming)
+                                .temp_dir(&self.temp_dir)
+                                .store_dir(&self.store_dir)
+                                .store_link(self.store_link)
                                 .swarm(swarm)
-                                .peer_stores( &mut self.peer_stores)
-                                .file_get_requests( &mut self.file_get_requests)
-                                .peer_addr_receiver(peer_addr_receiver)
-                                .peer_addr_connecting(&mut self.peer_addr_connecting)
-                                .cache_files(&mut self.cache_files)
-                                .connected_peer(&mut self.connected_peer)
+                                .peer_stores(&self.peer_stores)
+                                .file_get_requests(&mut self.file_get_requests)
+                                .hash_algorithm(self.hash_algorithm)
+                                .exclude_patterns(&self.exclude_patterns)
+                                .peer_failures(&self.peer_failures)
+                                .availability(&self.availability)
+                                .sync_complete_webhook(self.sync_complete_webhook.as_ref())
+                                .max_auto_sync_file_size(None)
+                                .sync_from_peers(&self.sync_from_peers)
+                                .content_defined_chunking(self.content_defined_chunking)
+                                .sync_read_ahead(self.sync_read_ahead)
+                                .sync_counters(&self.sync_counters)
+                                .chunk_digest_cache(&self.chunk_digest_cache)
                                 .build()
-                                .unwrap()
-                                .handle_event(event)
-                                .await?;
-                        }
+                                .unwrap();
 
-                        Some(addr) = peer_addr_receiver.next() => {
-                            PeerConnector::new(swarm, &mut self.peer_addr_connecting)
-                                .connect_peer(addr.into_inner()).await;
+                            let result = sync_scheduler.sync_file_now(file_sync, &filename).await;
+                            let _ = result_sender.send(result);
                         }
 
-                        Some(cmd) = command_receiver.next() => {
+                        cmd => {
                             CommandHandlerBuilder::default()
                                 .index_dir(&self.index_dir)
+                                .store_layout(self.store_layout)
+                                .index_naming(self.index_naming)
+                                .temp_dir(&self.temp_dir)
                                 .store_dir(&self.store_dir)
+                                .store_link(self.store_link)
                                 .peer_stores(&mut self.peer_stores)
                                 .connected_peer(&self.connected_peer)
+                                .discover_peers(&self.discover_peers)
+                                .peer_addr_connecting(&self.peer_addr_connecting)
+                                .peer_pings(peer_pings)
                                 .bandwidth_sinks(&self.bandwidth_sinks)
                                 .config_manager(&mut self.config_manager)
                                 .peer_addr_receiver(peer_addr_receiver)
                                 .swarm(swarm)
+                                .topics(&self.topics)
+                                .hash_algorithm(self.hash_algorithm)
+                                .hash_buffer_size(self.hash_buffer_size)
+                                .reject_case_conflict(self.reject_case_conflict)
+                                .verify_on_add(self.verify_on_add)
+                                .read_only(self.read_only)
+                                .availability(&self.availability)
+                                .file_get_requests(&mut self.file_get_requests)
+                                .peer_failures(&self.peer_failures)
+                                .adding_files(&self.adding_files)
+                                .upload_permits(self.upload_permits.as_ref())
+                                .syncing_files(sync_scheduler.syncing_files())
+                                .sync_failures(sync_scheduler.failures())
+                                .exclude_patterns(&self.exclude_patterns)
+                                .share_only(&self.share_only)
+                                .max_auto_sync_file_size(self.max_auto_sync_file_size)
+                                .sync_from_peers(&self.sync_from_peers)
+                                .sync_order(sync_scheduler.sync_order())
+                                .scrub_status(&self.scrub_status)
+                                .connection_errors(&self.connection_errors)
+                                .relay_stats(&self.relay_stats)
+                                .sync_counters(&self.sync_counters)
+                                .chunk_digest_cache(&self.chunk_digest_cache)
+                                .key(&self.key)
+                                .cache_files(&self.cache_files)
+                                .refresh_store_ticker(refresh_store_ticker)
+                                .sync_file_ticker(sync_file_ticker)
+                                .file_events(&self.file_events)
+                                .max_peer_addrs(self.max_peer_addrs)
                                 .build()
                                 .unwrap()
                                 .handle_command(cmd)
-                                .await
-                        }
-
-                        // syncing files task is done
-                        result = future::poll_fn(|cx| {
-                            Pin::new(&mut task).poll(cx)
-                        }) => {
-                            let result_syncing_files = result.unwrap()?;
-
-                            info!(?result_syncing_files, "sync files task done");
-
-                            // files are still syncing, need continue
-                            if let Some(result_syncing_files) = result_syncing_files {
-                                info!(?result_syncing_files, "need continue sync files");
-
-                                syncing_files.replace(result_syncing_files);
-                            }
-
-                            sync_file_ticker.reset();
-
-                            continue;
+                                .await;
                         }
                     }
-
-                    sync_file_task.replace(task);
                 }
 
-                // no running syncing files task, but still have files to sync
-                (None, Some(_)) => {
-                    let task = FileSync::new(
+                _ = refresh_store_ticker.tick() => {
+                    RefreshStoreHandler::new(
                         &self.index_dir,
                         &self.store_dir,
+                        self.store_link,
+                        &self.key,
                         swarm,
+                        &self.exclude_patterns,
+                        &self.share_only,
+                        &self.availability,
                         &self.peer_stores,
-                        &mut self.file_get_requests,
-                        syncing_files.take(),
+                        publish_retries,
+                        &self.chunk_digest_cache,
                     )
-                    .sync_files()
+                    .handle_tick(self.topics.file.clone())
                     .await?;
 
-                    match task {
-                        None => {
-                            unreachable!(
-                                "still have files to sync but no syncing files task return"
-                            );
-                        }
+                    refresh_store_ticker.reset();
+                }
 
-                        Some(task) => {
-                            info!("continue sync files");
+                _ = discover_ticker.tick() => {
+                    DiscoverHandler::new(
+                        swarm,
+                        &self.key,
+                        &self.discover_peers,
+                        &mut self.pending_discover,
+                        publish_retries,
+                        &self.topics.discover,
+                    )
+                    .handle_tick()?;
+                }
 
-                            sync_file_task.replace(task);
+                Some(pending) = publish_retries.next() => {
+                    publish_retries.retry(&mut swarm.behaviour_mut().gossip, pending);
+                }
+
+                Some((peer, transient)) = peer_pings.next_timeout() => {
+                    if transient {
+                        info!(%peer, "close the connection dialed for the timed out ping");
+
+                        let _ = swarm.disconnect_peer_id(peer);
+                    }
+                }
+
+                _ = sync_file_ticker.tick() => {
+                    let file_sync = FileSyncBuilder::default()
+                        .index_dir(&self.index_dir)
+                        .store_layout(self.store_layout)
+                        .index_naming(self.index_naming)
+                        .temp_dir(&self.temp_dir)
+                        .store_dir(&self.store_dir)
+                        .store_link(self.store_link)
+                        .swarm(swarm)
+                        .peer_stores(&self.peer_stores)
+                        .file_get_requests(&mut self.file_get_requests)
+                        .hash_algorithm(self.hash_algorithm)
+                        .exclude_patterns(&self.exclude_patterns)
+                        .peer_failures(&self.peer_failures)
+                        .availability(&self.availability)
+                        .sync_complete_webhook(self.sync_complete_webhook.as_ref())
+                        .max_auto_sync_file_size(self.max_auto_sync_file_size)
+                        .sync_from_peers(&self.sync_from_peers)
+                        .content_defined_chunking(self.content_defined_chunking)
+                        .sync_read_ahead(self.sync_read_ahead)
+                        .sync_counters(&self.sync_counters)
+                        .chunk_digest_cache(&self.chunk_digest_cache)
+                        .build()
+                        .unwrap();
+
+                    sync_scheduler.handle_tick(file_sync).await?;
+                }
+
+                // a syncing file task is done or a failed file is ready to retry
+                event = sync_scheduler.next_event() => {
+                    let file_sync = FileSyncBuilder::default()
+                        .index_dir(&self.index_dir)
+                        .store_layout(self.store_layout)
+                        .index_naming(self.index_naming)
+                        .temp_dir(&self.temp_dir)
+                        .store_dir(&self.store_dir)
+                        .store_link(self.store_link)
+                        .swarm(swarm)
+                        .peer_stores(&self.peer_stores)
+                        .file_get_requests(&mut self.file_get_requests)
+                        .hash_algorithm(self.hash_algorithm)
+                        .exclude_patterns(&self.exclude_patterns)
+                        .peer_failures(&self.peer_failures)
+                        .availability(&self.availability)
+                        .sync_complete_webhook(self.sync_complete_webhook.as_ref())
+                        .max_auto_sync_file_size(self.max_auto_sync_file_size)
+                        .sync_from_peers(&self.sync_from_peers)
+                        .content_defined_chunking(self.content_defined_chunking)
+                        .sync_read_ahead(self.sync_read_ahead)
+                        .sync_counters(&self.sync_counters)
+                        .chunk_digest_cache(&self.chunk_digest_cache)
+                        .build()
+                        .unwrap();
+
+                    match event {
+                        SyncEvent::Result(hash, result) => {
+                            sync_scheduler.handle_result(file_sync, hash, result).await?
                         }
+
+                        SyncEvent::Retry(hash) => sync_scheduler.handle_retry(file_sync, hash).await?,
                     }
                 }
             }
@@ -308,10 +634,18 @@ where
     }
 }
 
+#[allow(clippy::too_many_arguments)]
 pub fn create_transport(
     keypair: Keypair,
-    handshake_key: PreSharedKey,
+    handshake_keys: PnetKeys,
     relay_server_addr: Option<Multiaddr>,
+    dns_servers: Option<Vec<SocketAddr>>,
+    enable_tcp: bool,
+    enable_websocket: bool,
+    tcp_options: TcpOptions,
+    noise_handshake: NoiseHandshake,
+    relay_only: bool,
+    connection_errors: ConnectionErrors,
 ) -> io::Result<(
     BoxedTransport,
     Arc<BandwidthSinks>,
@@ -331,19 +665,50 @@ pub fn create_transport(
         }
     };
 
-    let tcp_transport =
-        TokioDnsConfig::system(tcp::tokio::Transport::new(tcp::Config::new().nodelay(true)))?
-            .and_then(move |conn, connected_point| async move {
-                let conn = PnetConfig::new(handshake_key)
-                    .handshake(conn)
-                    .await
-                    .tap_err(|err| error!(%err, ?connected_point, "handshake failed"))?;
+    let new_tcp_transport = || {
+        let tcp_transport = tcp::tokio::Transport::new(tcp_options.to_config());
+        let dns_transport = match &dns_servers {
+            None => TokioDnsConfig::system(tcp_transport)?,
+            Some(dns_servers) => {
+                let mut resolver_config = ResolverConfig::new();
+                for dns_server in dns_servers {
+                    resolver_config
+                        .add_name_server(NameServerConfig::new(*dns_server, Protocol::Udp));
+                    resolver_config
+                        .add_name_server(NameServerConfig::new(*dns_server, Protocol::Tcp));
+                }
 
-                info!(?connected_point, "handshake done");
+                info!(?resolver_config, "use custom dns servers");
 
-                Ok::<_, PnetError>(conn)
-            });
-    let transport = websocket::WsConfig::new(tcp_transport);
+                TokioDnsConfig::custom(tcp_transport, resolver_config, ResolverOpts::default())?
+            }
+        };
+
+        let handshake_keys = handshake_keys.clone();
+        let connection_errors = connection_errors.clone();
+
+        Ok::<_, io::Error>(dns_transport.and_then(move |conn, connected_point| {
+            pnet_handshake(handshake_keys, connection_errors, conn, connected_point)
+        }))
+    };
+
+    // the websocket addr contains the tcp addr, so try the websocket transport first
+    let transport = match (enable_tcp, enable_websocket) {
+        (true, true) => EitherTransport::Left(OrTransport::new(
+            websocket::WsConfig::new(new_tcp_transport()?),
+            new_tcp_transport()?,
+        )),
+        (false, true) => EitherTransport::Right(EitherTransport::Left(websocket::WsConfig::new(
+            new_tcp_transport()?,
+        ))),
+        (true, false) => EitherTransport::Right(EitherTransport::Right(new_tcp_transport()?)),
+        (false, false) => {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "tcp and websocket transports are both disabled",
+            ));
+        }
+    };
 
     let (transport, endpoint_behaviour) = match relay_server {
         None => (EitherTransport::Left(transport), None),
@@ -355,31 +720,994 @@ pub fn create_transport(
             );
 
             (
-                EitherTransport::Right(CombineTransport::new(transport, endpoint_transport)),
+                EitherTransport::Right(CombineTransport::new(
+                    DialOnlyTransport::new(transport, relay_only),
+                    endpoint_transport,
+                )),
                 Some(endpoint_behaviour),
             )
         }
     };
 
+    let (transport, bandwidth_sinks) = upgrade_transport(transport, &keypair, noise_handshake);
+
+    Ok((transport, bandwidth_sinks, endpoint_behaviour))
+}
+
+/// create an in-process transport, it is useful to run multi nodes in tests without real sockets
+#[cfg(test)]
+fn create_memory_transport(
+    keypair: &Keypair,
+    handshake_keys: PnetKeys,
+) -> (BoxedTransport, Arc<BandwidthSinks>) {
+    // the failures of the test transport are not counted
+    let transport = MemoryTransport::new().and_then(move |conn, connected_point| {
+        pnet_handshake(
+            handshake_keys,
+            ConnectionErrors::default(),
+            conn,
+            connected_point,
+        )
+    });
+
+    upgrade_transport(transport, keypair, NoiseHandshake::default())
+}
+
+async fn pnet_handshake<C>(
+    handshake_keys: PnetKeys,
+    connection_errors: ConnectionErrors,
+    conn: C,
+    connected_point: ConnectedPoint,
+) -> io::Result<PnetOutput<C>>
+where
+    C: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+{
+    let conn = handshake_keys
+        .handshake(conn, connected_point.is_dialer())
+        .await
+        .tap_err(|err| {
+            error!(%err, ?connected_point, "handshake failed");
+
+            connection_errors.record_pnet();
+        })?;
+
+    info!(?connected_point, "handshake done");
+
+    Ok(conn)
+}
+
+fn upgrade_transport<T>(
+    transport: T,
+    keypair: &Keypair,
+    noise_handshake: NoiseHandshake,
+) -> (BoxedTransport, Arc<BandwidthSinks>)
+where
+    T: Transport + Send + Unpin + 'static,
+    T::Output: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+    T::Error: Send + Sync + 'static,
+    T::Dial: Send + 'static,
+    T::ListenerUpgrade: Send + 'static,
+{
     let (transport, bandwidth_sinks) = BandwidthLogging::new(transport);
 
     let mut yamux_config = YamuxConfig::default();
     yamux_config.set_max_buffer_size(MAX_CHUNK_SIZE * 2);
     yamux_config.set_receive_window_size((MAX_CHUNK_SIZE * 2) as _);
 
-    Ok((
-        transport
-            .upgrade(Version::V1)
-            .authenticate(noise::NoiseAuthenticated::xx(&keypair).unwrap())
+    let transport = transport.upgrade(Version::V1);
+    let transport = match noise_handshake {
+        NoiseHandshake::XX => transport
+            .authenticate(noise::NoiseAuthenticated::xx(keypair).unwrap())
             .multiplex(yamux_config)
             .boxed(),
-        bandwidth_sinks,
-        endpoint_behaviour,
-    ))
+        NoiseHandshake::IX => {
+            let noise_keys = noise::Keypair::<noise::X25519Spec>::new()
+                .into_authentic(keypair)
+                .unwrap();
+
+            transport
+                .authenticate(noise::NoiseConfig::ix(noise_keys).into_authenticated())
+                .multiplex(yamux_config)
+                .boxed()
+        }
+    };
+
+    (transport, bandwidth_sinks)
 }
 
 #[derive(Debug, Default)]
 pub struct PeerNodeStore {
     files: HashMap<String, String>,
     index: HashMap<String, u64>,
+    /// refresh time of the last accepted file message
+    refresh_time: u64,
+    /// the available ranges of the files which the peer is still syncing, the peer has the
+    /// whole file when the hash is not in it
+    partial_files: HashMap<String, RangeSet>,
+    /// the received parts of the newest split file message
+    pending_parts: Option<PendingFileParts>,
+}
+
+/// the received parts of a split file message, the parts share the refresh time
+#[derive(Debug)]
+struct PendingFileParts {
+    refresh_time: u64,
+    total_parts: u32,
+    parts: BTreeMap<u32, Vec<File>>,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+enum FileMessageStatus {
+    /// the refresh time isn't newer than the accepted one, it is a replay
+    Old,
+    /// the part index or the number of the parts is invalid
+    InvalidPart,
+    /// the part is kept until all parts are received
+    Pending,
+    /// the files are replaced, the filenames and hashes which the peer doesn't advertise anymore
+    /// are returned
+    Applied(Vec<(String, String)>),
+}
+
+impl PeerNodeStore {
+    /// accept the verified file message, the files of a split message are replaced when all
+    /// parts are received, an incomplete split message is dropped by a newer one
+    fn accept_file_message(&mut self, msg: FileMessage) -> FileMessageStatus {
+        // the signed refresh time only increases, an old message is a replay
+        if msg.refresh_time <= self.refresh_time {
+            return FileMessageStatus::Old;
+        }
+
+        if msg.total_parts == 0 {
+            self.pending_parts = None;
+            let removed = self.replace_files(msg.refresh_time, msg.file_list);
+
+            return FileMessageStatus::Applied(removed);
+        }
+
+        if msg.total_parts > MAX_FILE_MESSAGE_PARTS || msg.part >= msg.total_parts {
+            return FileMessageStatus::InvalidPart;
+        }
+
+        let mut pending = match self.pending_parts.take() {
+            Some(pending) if pending.refresh_time > msg.refresh_time => {
+                self.pending_parts = Some(pending);
+
+                return FileMessageStatus::Old;
+            }
+
+            Some(pending) if pending.refresh_time == msg.refresh_time => {
+                if pending.total_parts != msg.total_parts {
+                    self.pending_parts = Some(pending);
+
+                    return FileMessageStatus::InvalidPart;
+                }
+
+                pending
+            }
+
+            _ => PendingFileParts {
+                refresh_time: msg.refresh_time,
+                total_parts: msg.total_parts,
+                parts: BTreeMap::new(),
+            },
+        };
+
+        pending.parts.insert(msg.part, msg.file_list);
+        if pending.parts.len() < pending.total_parts as usize {
+            self.pending_parts = Some(pending);
+
+            return FileMessageStatus::Pending;
+        }
+
+        let files = pending.parts.into_values().flatten().collect();
+        let removed = self.replace_files(pending.refresh_time, files);
+
+        FileMessageStatus::Applied(removed)
+    }
+
+    /// replace the files by the new list, return the filenames and hashes which are not in it,
+    /// the file whose content is changed is not removed
+    fn replace_files(&mut self, refresh_time: u64, files: Vec<File>) -> Vec<(String, String)> {
+        let old_files = mem::take(&mut self.files);
+        self.refresh_time = refresh_time;
+        self.index.clear();
+        self.partial_files.clear();
+
+        for file in files {
+            if let Some(chunks) = &file.chunks {
+                self.partial_files.insert(
+                    file.hash.clone(),
+                    RangeSet::from_bitfield(chunks, file.file_size),
+                );
+            }
+            self.index.insert(file.hash.clone(), file.file_size);
+            self.files
+                .entry(normalize_filename(&file.filename))
+                .or_insert_with(|| file.hash);
+        }
+
+        old_files
+            .into_iter()
+            .filter(|(filename, _)| !self.files.contains_key(filename))
+            .collect()
+    }
+
+    /// check whether the peer can serve the chunk of the file
+    pub fn has_chunk(&self, hash: &str, chunk: &Range<u64>) -> bool {
+        if !self.index.contains_key(hash) {
+            return false;
+        }
+
+        self.partial_files
+            .get(hash)
+            .map(|range_set| range_set.contains(chunk))
+            .unwrap_or(true)
+    }
+}
+
+#[derive(Debug)]
+pub struct ConnectedPeer {
+    /// the remote addrs of the connections, there may be multi connections with the same addr
+    addrs: HashMap<Multiaddr, usize>,
+    /// when the first connection of the peer is established
+    connected_since: SystemTime,
+    /// the latest identify info of the peer
+    identity: Option<PeerIdentity>,
+}
+
+impl ConnectedPeer {
+    fn new() -> Self {
+        Self {
+            addrs: HashMap::new(),
+            connected_since: SystemTime::now(),
+            identity: None,
+        }
+    }
+
+    fn add_connection(&mut self, addr: &Multiaddr) {
+        *self.addrs.entry(addr.clone()).or_default() += 1;
+    }
+
+    /// return true when it is the last connection with the addr
+    fn remove_connection(&mut self, addr: &Multiaddr) -> bool {
+        match self.addrs.get_mut(addr) {
+            None => true,
+            Some(count) if *count > 1 => {
+                *count -= 1;
+
+                false
+            }
+            Some(_) => {
+                self.addrs.remove(addr);
+
+                true
+            }
+        }
+    }
+
+    fn connections(&self) -> usize {
+        self.addrs.values().sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::path::Path;
+    use std::time::Instant;
+
+    use futures_channel::{mpsc, oneshot};
+    use futures_util::stream::Empty;
+    use futures_util::{future, SinkExt};
+    use libp2p::gossipsub::ValidationMode;
+    use libp2p::multiaddr::Protocol as MultiaddrProtocol;
+    use prost::Message as _;
+    use sha2::{Digest, Sha256};
+    use tempfile::TempDir;
+    use tokio::fs;
+    use tokio::task::{self, LocalSet};
+
+    use super::*;
+    use crate::command::PingTarget;
+    use crate::config::{Config as FileConfig, PreShareKey};
+    use crate::manipulate::http::MemoryFileGetter;
+    use crate::node::config::{
+        SyncOrder, DEFAULT_FILE_READ_IDLE_TIMEOUT, DEFAULT_HASH_BUFFER_SIZE,
+        DEFAULT_SYNC_READ_AHEAD, DEFAULT_TEMP_DIR_NAME,
+    };
+    use crate::node::pnet::KEY_SIZE;
+
+    type TestCommandSender = mpsc::Sender<Command<Empty<io::Result<Bytes>>, MemoryFileGetter>>;
+
+    struct TestNode {
+        dir: TempDir,
+        peer_id: PeerId,
+        addr: Multiaddr,
+        command_sender: TestCommandSender,
+    }
+
+    impl TestNode {
+        fn index_dir(&self) -> PathBuf {
+            self.dir.path().join("index")
+        }
+
+        fn store_dir(&self) -> PathBuf {
+            self.dir.path().join("store")
+        }
+
+        fn dial_addr(&self) -> Multiaddr {
+            self.addr
+                .clone()
+                .with(MultiaddrProtocol::P2p(self.peer_id.into()))
+        }
+    }
+
+    /// spawn a node which uses the memory transport, the node will dial the `peer_addrs`, it
+    /// must be called in a [`LocalSet`]
+    async fn spawn_test_node(handshake_keys: PnetKeys, peer_addrs: Vec<Multiaddr>) -> TestNode {
+        let dir = TempDir::new_in(env::temp_dir()).unwrap();
+        let index_dir = dir.path().join("index");
+        let store_dir = dir.path().join("store");
+        fs::create_dir_all(&index_dir).await.unwrap();
+        fs::create_dir_all(&store_dir).await.unwrap();
+
+        let file_config = FileConfig {
+            index_dir: index_dir.to_string_lossy().to_string(),
+            store_layout: None,
+            index_naming: None,
+            temp_dir: None,
+            thumbnail_dir: None,
+            stale_temp_file_age: None,
+            scrub_interval: None,
+            scrub_rate: None,
+            store_dir: store_dir.to_string_lossy().to_string(),
+            store_link: None,
+            secret_key_path: "secret.pem".to_string(),
+            public_key_path: "public.pem".to_string(),
+            pre_share_key: PreShareKey::Single("test".to_string()),
+            refresh_interval: "100ms".to_string(),
+            sync_file_interval: "100ms".to_string(),
+            interval_jitter: None,
+            discover_interval: None,
+            max_discover_addrs: None,
+            allow_local_discover_addrs: None,
+            max_peer_inbound_requests: None,
+            max_peer_addrs: None,
+            hash_algorithm: None,
+            hash_buffer_size: None,
+            buffer_pool_size: None,
+            enable_mmap: None,
+            max_sync_files: None,
+            sync_read_ahead: None,
+            max_auto_sync_file_size: None,
+            sync_from_peers: None,
+            content_defined_chunking: None,
+            max_connections: None,
+            max_uploads: None,
+            sync_order: None,
+            sync_retry_max_attempts: None,
+            sync_retry_backoff: None,
+            exclude_patterns: None,
+            share_only: None,
+            reject_case_conflict: None,
+            verify_on_add: None,
+            read_only: None,
+            peer_addrs: peer_addrs.iter().map(ToString::to_string).collect(),
+            http_listen: vec!["127.0.0.1:0".parse().unwrap()],
+            https_listen: None,
+            tls_cert_path: None,
+            tls_key_path: None,
+            https_redirect_listen: None,
+            swarm_listen: "/memory/0".to_string(),
+            relay_server_addr: None,
+            relay_only: None,
+            identify_push: None,
+            dns_servers: None,
+            enable_tcp: None,
+            tcp_listen_backlog: None,
+            tcp_port_reuse: None,
+            tcp_ttl: None,
+            enable_websocket: None,
+            noise_handshake: None,
+            max_file_protocol: None,
+            file_read_idle_timeout: None,
+            ip_family: None,
+            api_base_path: None,
+            ui_base_path: None,
+            enable_ui: None,
+            canvaskit_url: None,
+            ui_dir: None,
+            webdav_writable: None,
+            access_log_size: None,
+            api_token: None,
+            http_protocol: None,
+            command_buffer_size: None,
+            command_send_timeout: None,
+            gossip_validation_mode: None,
+            network_id: None,
+            sync_complete_webhook: None,
+            dlna_advertise_host: None,
+            public_url: None,
+        };
+        fs::write(
+            dir.path().join("config.yaml"),
+            serde_yaml::to_string(&file_config).unwrap(),
+        )
+        .await
+        .unwrap();
+        let config_manager = ConfigManager::new(dir.path().to_path_buf()).await.unwrap();
+
+        let key = Keypair::generate_ed25519();
+        let peer_id = key.public().to_peer_id();
+        let (transport, bandwidth_sinks) = create_memory_transport(&key, handshake_keys.clone());
+        let config = Config {
+            key,
+            temp_dir: index_dir.join(DEFAULT_TEMP_DIR_NAME),
+            stale_temp_file_age: Duration::from_secs(60),
+            scrub_interval: None,
+            scrub_rate: config::DEFAULT_SCRUB_RATE,
+            index_dir,
+            store_layout: StoreLayout::Flat,
+            index_naming: IndexNaming::Hash,
+            store_dir,
+            store_link: StoreLink::Symlink,
+            handshake_keys,
+            gossip_validation_mode: ValidationMode::Strict,
+            network_id: None,
+            refresh_store_interval: Duration::from_millis(100),
+            sync_file_interval: Duration::from_millis(100),
+            interval_jitter: 0,
+            discover_interval: Duration::from_millis(100),
+            max_discover_addrs: config::DEFAULT_MAX_DISCOVER_ADDRS,
+            allow_local_discover_addrs: false,
+            max_peer_inbound_requests: config::DEFAULT_MAX_PEER_INBOUND_REQUESTS,
+            max_peer_addrs: config::DEFAULT_MAX_PEER_ADDRS,
+            hash_algorithm: HashAlgorithm::Sha256,
+            hash_buffer_size: DEFAULT_HASH_BUFFER_SIZE,
+            buffer_pool_size: 4,
+            enable_mmap: true,
+            max_sync_files: 2,
+            sync_read_ahead: DEFAULT_SYNC_READ_AHEAD,
+            max_auto_sync_file_size: None,
+            sync_from_peers: Default::default(),
+            content_defined_chunking: false,
+            max_connections: None,
+            max_uploads: None,
+            sync_order: SyncOrder::SmallestFirst,
+            sync_retry: Default::default(),
+            exclude_patterns: Default::default(),
+            share_only: Default::default(),
+            reject_case_conflict: false,
+            verify_on_add: true,
+            read_only: false,
+            sync_complete_webhook: None,
+            enable_relay_behaviour: false,
+            relay_server_addr: None,
+            relay_only: false,
+            identify_push: false,
+            dns_servers: None,
+            enable_tcp: true,
+            tcp_options: Default::default(),
+            enable_websocket: true,
+            noise_handshake: Default::default(),
+            max_file_protocol: Default::default(),
+            file_read_idle_timeout: DEFAULT_FILE_READ_IDLE_TIMEOUT,
+            ip_family: Default::default(),
+        };
+
+        let addr = Multiaddr::empty().with(MultiaddrProtocol::Memory(rand::random()));
+        let (builder, command_sender) = NodeBuilder::new(config, config_manager)
+            .transport(transport, bandwidth_sinks)
+            .listen_addr(addr.clone())
+            .peer_addrs(peer_addrs)
+            .command_channel(1);
+        let mut node = builder.build().unwrap();
+
+        // node run future is not Send, it must be spawned in a LocalSet
+        task::spawn_local(async move { node.run(future::pending()).await });
+
+        TestNode {
+            dir,
+            peer_id,
+            addr,
+            command_sender,
+        }
+    }
+
+    /// add file into the node index store and share it
+    async fn add_store_file(node: &TestNode, filename: &str, content: &[u8]) {
+        let hash = hex::encode_upper(Sha256::digest(content));
+        let index_path = node.index_dir().join(hash);
+
+        fs::write(&index_path, content).await.unwrap();
+        fs::symlink(&index_path, node.store_dir().join(filename))
+            .await
+            .unwrap();
+    }
+
+    async fn wait_store_file(node: &TestNode, filename: &str, timeout: Duration) -> Vec<u8> {
+        let path = node.store_dir().join(filename);
+        let start = Instant::now();
+
+        loop {
+            if let Ok(content) = fs::read(&path).await {
+                return content;
+            }
+
+            if start.elapsed() > timeout {
+                panic!("wait store file {path:?} timeout");
+            }
+
+            time::sleep(Duration::from_millis(100)).await;
+        }
+    }
+
+    fn exists(path: &Path) -> bool {
+        path.symlink_metadata().is_ok()
+    }
+
+    #[test]
+    fn test_connected_peer_with_two_connections() {
+        let addr: Multiaddr = "/ip4/127.0.0.1/tcp/1".parse().unwrap();
+        let mut connected_peer = ConnectedPeer::new();
+
+        connected_peer.add_connection(&addr);
+        connected_peer.add_connection(&addr);
+        assert_eq!(connected_peer.connections(), 2);
+
+        // the other connection still uses the addr
+        assert!(!connected_peer.remove_connection(&addr));
+        assert_eq!(connected_peer.connections(), 1);
+        assert!(connected_peer.addrs.contains_key(&addr));
+
+        assert!(connected_peer.remove_connection(&addr));
+        assert_eq!(connected_peer.connections(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_sync_file_between_nodes() {
+        let handshake_keys = PnetKeys::new(vec![[1; KEY_SIZE]]);
+        let content = (0..MAX_CHUNK_SIZE / 2).map(|i| i as u8).collect::<Vec<_>>();
+
+        LocalSet::new()
+            .run_until(async {
+                let node1 = spawn_test_node(handshake_keys.clone(), vec![]).await;
+                add_store_file(&node1, "test.bin", &content).await;
+
+                let node2 = spawn_test_node(handshake_keys.clone(), vec![node1.dial_addr()]).await;
+
+                let synced_content =
+                    wait_store_file(&node2, "test.bin", Duration::from_secs(30)).await;
+
+                assert_eq!(synced_content, content);
+                assert!(exists(
+                    &node2
+                        .index_dir()
+                        .join(hex::encode_upper(Sha256::digest(&content)))
+                ));
+            })
+            .await;
+    }
+
+    #[tokio::test]
+    async fn test_get_index_file_keeps_store() {
+        let handshake_keys = PnetKeys::new(vec![[2; KEY_SIZE]]);
+        let content = b"index only".to_vec();
+        let hash = hex::encode_upper(Sha256::digest(&content));
+
+        LocalSet::new()
+            .run_until(async {
+                let mut node = spawn_test_node(handshake_keys.clone(), vec![]).await;
+                let index_path = node.index_dir().join(&hash);
+                fs::write(&index_path, &content).await.unwrap();
+
+                let (result_sender, result_receiver) = oneshot::channel();
+                node.command_sender
+                    .send(Command::GetIndexFile {
+                        hash: hash.clone(),
+                        file_getter: MemoryFileGetter::new([(
+                            index_path.clone(),
+                            Bytes::from(content.clone()),
+                        )]),
+                        result_sender,
+                    })
+                    .await
+                    .unwrap();
+
+                let file_detail = result_receiver.await.unwrap().unwrap().unwrap();
+                assert_eq!(file_detail.hash, hash);
+                assert_eq!(file_detail.index_path, index_path);
+
+                let mut store_dir = fs::read_dir(node.store_dir()).await.unwrap();
+                assert!(store_dir.next_entry().await.unwrap().is_none());
+
+                let (result_sender, result_receiver) = oneshot::channel();
+                node.command_sender
+                    .send(Command::GetIndexFile {
+                        hash: "../config.yaml".to_string(),
+                        file_getter: MemoryFileGetter::default(),
+                        result_sender,
+                    })
+                    .await
+                    .unwrap();
+
+                let err = result_receiver.await.unwrap().unwrap_err();
+                assert_eq!(err.kind(), ErrorKind::InvalidInput);
+            })
+            .await;
+    }
+
+    #[tokio::test]
+    async fn test_verify_file() {
+        let handshake_keys = PnetKeys::new(vec![[3; KEY_SIZE]]);
+        let content = b"verify me".to_vec();
+        let hash = hex::encode_upper(Sha256::digest(&content));
+
+        LocalSet::new()
+            .run_until(async {
+                let node = spawn_test_node(handshake_keys.clone(), vec![]).await;
+                add_store_file(&node, "test.txt", &content).await;
+
+                let verify = |filename: &str| {
+                    let (result_sender, result_receiver) = oneshot::channel();
+                    let command = Command::VerifyFile {
+                        filename: filename.to_string(),
+                        result_sender,
+                    };
+                    let mut command_sender = node.command_sender.clone();
+
+                    async move {
+                        command_sender.send(command).await.unwrap();
+
+                        result_receiver.await.unwrap().unwrap()
+                    }
+                };
+
+                let detail = verify("test.txt").await.unwrap();
+                assert_eq!(detail.expected_hash, hash);
+                assert_eq!(detail.computed_hash, hash);
+
+                assert!(verify("not_exists.txt").await.is_none());
+
+                // the bit rot index file
+                fs::write(node.index_dir().join(&hash), b"verify mE")
+                    .await
+                    .unwrap();
+                let detail = verify("test.txt").await.unwrap();
+                assert_eq!(detail.expected_hash, hash);
+                assert_ne!(detail.computed_hash, hash);
+            })
+            .await;
+    }
+
+    #[tokio::test]
+    async fn test_sync_file_not_advertised() {
+        let handshake_keys = PnetKeys::new(vec![[5; KEY_SIZE]]);
+
+        LocalSet::new()
+            .run_until(async {
+                let mut node = spawn_test_node(handshake_keys.clone(), vec![]).await;
+                add_store_file(&node, "test.txt", b"stored").await;
+
+                for filename in ["test.txt", "not_exists.txt"] {
+                    let (result_sender, result_receiver) = oneshot::channel();
+                    node.command_sender
+                        .send(Command::SyncFile {
+                            filename: filename.to_string(),
+                            result_sender,
+                        })
+                        .await
+                        .unwrap();
+
+                    let err = result_receiver.await.unwrap().unwrap_err();
+                    assert_eq!(err.kind(), ErrorKind::NotFound);
+                }
+            })
+            .await;
+    }
+
+    #[test]
+    fn test_accept_split_file_message() {
+        let file_message = |refresh_time, part, total_parts, filename: &str| FileMessage {
+            peer_id: String::new(),
+            file_list: vec![File {
+                filename: filename.to_string(),
+                hash: filename.to_uppercase(),
+                file_size: 1,
+                chunks: None,
+            }],
+            refresh_time,
+            signature: vec![],
+            public_key: vec![],
+            part,
+            total_parts,
+        };
+
+        let mut peer_store = PeerNodeStore::default();
+        assert_eq!(
+            peer_store.accept_file_message(file_message(1, 0, 0, "a")),
+            FileMessageStatus::Applied(vec![])
+        );
+
+        assert_eq!(
+            peer_store.accept_file_message(file_message(2, 1, 2, "c")),
+            FileMessageStatus::Pending
+        );
+        // the pending parts don't replace the files
+        assert_eq!(peer_store.files.len(), 1);
+        assert_eq!(
+            peer_store.accept_file_message(file_message(2, 0, 3, "b")),
+            FileMessageStatus::InvalidPart
+        );
+        assert_eq!(
+            peer_store.accept_file_message(file_message(2, 2, 2, "b")),
+            FileMessageStatus::InvalidPart
+        );
+        assert_eq!(
+            peer_store.accept_file_message(file_message(2, 0, 2, "b")),
+            FileMessageStatus::Applied(vec![("a".to_string(), "A".to_string())])
+        );
+        assert_eq!(peer_store.refresh_time, 2);
+        assert_eq!(peer_store.files.len(), 2);
+        assert_eq!(peer_store.files["b"], "B");
+        assert_eq!(peer_store.files["c"], "C");
+
+        // the incomplete message is dropped by the newer one
+        assert_eq!(
+            peer_store.accept_file_message(file_message(3, 0, 2, "d")),
+            FileMessageStatus::Pending
+        );
+        let mut removed = match peer_store.accept_file_message(file_message(4, 0, 0, "e")) {
+            FileMessageStatus::Applied(removed) => removed,
+            status => panic!("unexpected status {status:?}"),
+        };
+        removed.sort();
+        assert_eq!(
+            removed,
+            [
+                ("b".to_string(), "B".to_string()),
+                ("c".to_string(), "C".to_string())
+            ]
+        );
+        assert_eq!(
+            peer_store.accept_file_message(file_message(3, 1, 2, "f")),
+            FileMessageStatus::Old
+        );
+        assert_eq!(peer_store.files.keys().collect::<Vec<_>>(), ["e"]);
+    }
+
+    #[tokio::test]
+    async fn test_import_manifest() {
+        let handshake_keys = PnetKeys::new(vec![[7; KEY_SIZE]]);
+
+        LocalSet::new()
+            .run_until(async {
+                let mut node1 = spawn_test_node(handshake_keys.clone(), vec![]).await;
+                add_store_file(&node1, "test.txt", b"manifest").await;
+                let node2 = spawn_test_node(handshake_keys.clone(), vec![]).await;
+
+                let (result_sender, result_receiver) = oneshot::channel();
+                node1
+                    .command_sender
+                    .send(Command::ExportManifest { result_sender })
+                    .await
+                    .unwrap();
+                let manifest = result_receiver.await.unwrap().unwrap();
+
+                let import = |node: &TestNode, manifest: Bytes| {
+                    let mut command_sender = node.command_sender.clone();
+
+                    async move {
+                        let (result_sender, result_receiver) = oneshot::channel();
+                        command_sender
+                            .send(Command::ImportManifest {
+                                manifest,
+                                result_sender,
+                            })
+                            .await
+                            .unwrap();
+
+                        result_receiver.await.unwrap()
+                    }
+                };
+
+                // the node doesn't import its own manifest
+                let err = import(&node1, manifest.clone()).await.unwrap_err();
+                assert_eq!(err.kind(), ErrorKind::InvalidInput);
+
+                // the tampered manifest fails to verify
+                let mut tampered = FileMessage::decode(manifest.clone()).unwrap();
+                tampered.file_list[0].filename = "other.txt".to_string();
+                let err = import(&node2, tampered.encode_to_vec().into())
+                    .await
+                    .unwrap_err();
+                assert_eq!(err.kind(), ErrorKind::InvalidData);
+
+                let detail = import(&node2, manifest.clone()).await.unwrap();
+                assert_eq!(detail.peer, node1.peer_id);
+                assert_eq!(detail.files, 1);
+                assert!(!detail.outdated);
+
+                let detail = import(&node2, manifest).await.unwrap();
+                assert!(detail.outdated);
+
+                // the unsafe files of a signed manifest are dropped, the others are kept
+                let key = Keypair::generate_ed25519();
+                let mut message = FileMessage {
+                    peer_id: key.public().to_peer_id().to_base58(),
+                    file_list: [
+                        "safe.txt",
+                        "../escape.txt",
+                        "fake\nlog.txt",
+                        "trailing.txt ",
+                    ]
+                    .map(|filename| File {
+                        filename: filename.to_string(),
+                        hash: "ABC".to_string(),
+                        file_size: 1,
+                        chunks: None,
+                    })
+                    .to_vec(),
+                    refresh_time: 1,
+                    signature: vec![],
+                    public_key: vec![],
+                    part: 0,
+                    total_parts: 0,
+                };
+                message.sign(&key).unwrap();
+                let detail = import(&node2, message.encode_to_vec().into())
+                    .await
+                    .unwrap();
+                assert_eq!(detail.files, 1);
+            })
+            .await;
+    }
+
+    #[tokio::test]
+    async fn test_internal_diagnostics() {
+        let handshake_keys = PnetKeys::new(vec![[6; KEY_SIZE]]);
+
+        LocalSet::new()
+            .run_until(async {
+                let node1 = spawn_test_node(handshake_keys.clone(), vec![]).await;
+                let mut node2 =
+                    spawn_test_node(handshake_keys.clone(), vec![node1.dial_addr()]).await;
+
+                let start = Instant::now();
+                let diagnostics = loop {
+                    assert!(
+                        start.elapsed() < Duration::from_secs(30),
+                        "peer is not connected"
+                    );
+
+                    let (result_sender, result_receiver) = oneshot::channel();
+                    node2
+                        .command_sender
+                        .send(Command::GetInternalDiagnostics { result_sender })
+                        .await
+                        .unwrap();
+                    let diagnostics = result_receiver.await.unwrap();
+                    if diagnostics.connected_peers == 1 {
+                        break diagnostics;
+                    }
+
+                    time::sleep(Duration::from_millis(100)).await;
+                };
+
+                assert_eq!(diagnostics.peer_addr_connecting, 0);
+                assert_eq!(diagnostics.file_get_requests, 0);
+                assert_eq!(diagnostics.disconnected_peer_stores, 0);
+                assert_eq!(diagnostics.max_cached_files, 64);
+            })
+            .await;
+    }
+
+    #[tokio::test]
+    async fn test_list_peers_identity() {
+        let handshake_keys = PnetKeys::new(vec![[4; KEY_SIZE]]);
+
+        LocalSet::new()
+            .run_until(async {
+                let node1 = spawn_test_node(handshake_keys.clone(), vec![]).await;
+                let mut node2 =
+                    spawn_test_node(handshake_keys.clone(), vec![node1.dial_addr()]).await;
+
+                let start = Instant::now();
+                let identity = loop {
+                    assert!(
+                        start.elapsed() < Duration::from_secs(30),
+                        "peer is not identified"
+                    );
+
+                    let (result_sender, result_receiver) = oneshot::channel();
+                    node2
+                        .command_sender
+                        .send(Command::ListPeers { result_sender })
+                        .await
+                        .unwrap();
+                    let detail = result_receiver.await.unwrap();
+                    if let Some(identity) = detail
+                        .connected
+                        .into_iter()
+                        .find(|peer| peer.peer == node1.peer_id)
+                        .and_then(|peer| peer.identity)
+                    {
+                        break identity;
+                    }
+
+                    time::sleep(Duration::from_millis(100)).await;
+                };
+
+                assert_eq!(identity.protocol_version, "private-share-identify/0.1.0");
+                assert!(identity
+                    .agent_version
+                    .starts_with(concat!(env!("CARGO_PKG_NAME"), "/")));
+                assert!(!identity.protocols.is_empty());
+            })
+            .await;
+    }
+
+    #[tokio::test]
+    async fn test_ping_peer() {
+        let handshake_keys = PnetKeys::new(vec![[8; KEY_SIZE]]);
+
+        LocalSet::new()
+            .run_until(async {
+                let node1 = spawn_test_node(handshake_keys.clone(), vec![]).await;
+                let mut node2 = spawn_test_node(handshake_keys.clone(), vec![]).await;
+
+                // the peer id can't be dialed without the discovered addrs
+                let (result_sender, result_receiver) = oneshot::channel();
+                node2
+                    .command_sender
+                    .send(Command::PingPeer {
+                        target: PingTarget::Peer(node1.peer_id),
+                        result_sender,
+                    })
+                    .await
+                    .unwrap();
+                let err = result_receiver.await.unwrap().unwrap_err();
+                assert_eq!(err.kind(), ErrorKind::NotFound);
+
+                let (result_sender, result_receiver) = oneshot::channel();
+                node2
+                    .command_sender
+                    .send(Command::PingPeer {
+                        target: PingTarget::Addr(node1.dial_addr()),
+                        result_sender,
+                    })
+                    .await
+                    .unwrap();
+                let detail = result_receiver.await.unwrap().unwrap();
+                assert_eq!(detail.peer, node1.peer_id);
+                assert!(!detail.connected);
+                assert!(detail.result.is_ok(), "{detail:?}");
+
+                // the connection dialed for the ping is closed
+                let start = Instant::now();
+                loop {
+                    assert!(
+                        start.elapsed() < Duration::from_secs(30),
+                        "ping connection is not closed"
+                    );
+
+                    let (result_sender, result_receiver) = oneshot::channel();
+                    node2
+                        .command_sender
+                        .send(Command::ListPeers { result_sender })
+                        .await
+                        .unwrap();
+                    let detail = result_receiver.await.unwrap();
+                    if detail.connected.is_empty() {
+                        assert!(detail.configured.is_empty());
+
+                        break;
+                    }
+
+                    time::sleep(Duration::from_millis(100)).await;
+                }
+            })
+            .await;
+    }
 }
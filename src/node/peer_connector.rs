@@ -1,23 +1,39 @@
 use std::collections::HashMap;
+use std::time::Duration;
 
+use libp2p::multiaddr::Protocol;
 use libp2p::{Multiaddr, PeerId, Swarm};
-use tracing::{error, info, instrument};
+use tokio_util::time::DelayQueue;
+use tracing::{error, info, instrument, warn};
 
 use crate::node::behaviour::Behaviour;
+use crate::node::config::IpFamily;
+
+/// the head start of the ipv6 dialing, see RFC 8305
+const HAPPY_EYEBALLS_DELAY: Duration = Duration::from_millis(250);
 
 pub struct PeerConnector<'a> {
     swarm: &'a mut Swarm<Behaviour>,
     peer_addr_connecting: &'a mut HashMap<PeerId, Multiaddr>,
+    peer_addr_receiver: &'a mut DelayQueue<Multiaddr>,
+    max_connections: Option<u32>,
+    ip_family: IpFamily,
 }
 
 impl<'a> PeerConnector<'a> {
     pub fn new(
         swarm: &'a mut Swarm<Behaviour>,
         peer_addr_connecting: &'a mut HashMap<PeerId, Multiaddr>,
+        peer_addr_receiver: &'a mut DelayQueue<Multiaddr>,
+        max_connections: Option<u32>,
+        ip_family: IpFamily,
     ) -> Self {
         Self {
             swarm,
             peer_addr_connecting,
+            peer_addr_receiver,
+            max_connections,
+            ip_family,
         }
     }
 
@@ -33,6 +49,12 @@ impl<'a> PeerConnector<'a> {
             Some(peer_id) => peer_id,
         };
 
+        if !self.ip_family.allows(&addr) {
+            warn!(%addr, ip_family = ?self.ip_family, "addr is not of the ip family, skip dialing");
+
+            return;
+        }
+
         if self
             .swarm
             .connected_peers()
@@ -43,14 +65,94 @@ impl<'a> PeerConnector<'a> {
             return;
         }
 
-        if let Err(err) = self.swarm.dial(addr.clone()) {
-            error!(%err, %addr, "dial peer failed");
+        let established = self
+            .swarm
+            .network_info()
+            .connection_counters()
+            .num_established();
+        if let Some(max_connections) = self.max_connections {
+            if established >= max_connections {
+                warn!(
+                    %peer_id, established, max_connections,
+                    "max connections reached, skip dialing"
+                );
+
+                return;
+            }
+        }
+
+        // dial the ipv6 addr first, if it is not connected after the head start, the ipv4 addr is
+        // dialed concurrently, whichever connects first is used, only the addr of the forced ip
+        // family is dialed
+        let dial_addr = match (split_dual_stack(&addr), self.ip_family) {
+            (None, _) => addr.clone(),
+            (Some((_, ipv4_addr)), IpFamily::V4) => ipv4_addr,
+            (Some((ipv6_addr, _)), IpFamily::V6) => ipv6_addr,
+            (Some((ipv6_addr, ipv4_addr)), IpFamily::Any) => {
+                info!(%ipv6_addr, %ipv4_addr, "dial dual stack peer addr");
+
+                self.peer_addr_receiver
+                    .insert(ipv4_addr, HAPPY_EYEBALLS_DELAY);
+
+                ipv6_addr
+            }
+        };
+
+        if let Err(err) = self.swarm.dial(dial_addr.clone()) {
+            error!(%err, %dial_addr, "dial peer failed");
 
             return;
         }
 
-        info!(%peer_id, "peer is dialing");
+        info!(%peer_id, %dial_addr, "peer is dialing");
+
+        // keep the original dual stack addr for re-dialing
+        self.peer_addr_connecting.entry(peer_id).or_insert(addr);
+    }
+}
+
+/// split the `/dns` addr which may resolve to both A and AAAA records into the `/dns6` and
+/// `/dns4` addrs
+fn split_dual_stack(addr: &Multiaddr) -> Option<(Multiaddr, Multiaddr)> {
+    let mut protocols = addr.iter();
+    let host = match protocols.next()? {
+        Protocol::Dns(host) => host,
+        _ => return None,
+    };
+
+    let rest = protocols.collect::<Vec<_>>();
+    let ipv6_addr = [Protocol::Dns6(host.clone())]
+        .into_iter()
+        .chain(rest.iter().cloned())
+        .collect();
+    let ipv4_addr = [Protocol::Dns4(host)].into_iter().chain(rest).collect();
+
+    Some((ipv6_addr, ipv4_addr))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split_dual_stack() {
+        let peer_id = PeerId::random();
+        let addr = format!("/dns/example.com/tcp/1234/p2p/{peer_id}")
+            .parse::<Multiaddr>()
+            .unwrap();
+
+        let (ipv6_addr, ipv4_addr) = split_dual_stack(&addr).unwrap();
+
+        assert_eq!(
+            ipv6_addr.to_string(),
+            format!("/dns6/example.com/tcp/1234/p2p/{peer_id}")
+        );
+        assert_eq!(
+            ipv4_addr.to_string(),
+            format!("/dns4/example.com/tcp/1234/p2p/{peer_id}")
+        );
 
-        self.peer_addr_connecting.insert(peer_id, addr);
+        let addr = "/ip4/127.0.0.1/tcp/1234".parse::<Multiaddr>().unwrap();
+        assert!(split_dual_stack(&addr).is_none());
     }
 }
@@ -0,0 +1,106 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use libp2p::PeerId;
+use rand::prelude::SliceRandom;
+
+/// the penalty is halved every half life
+const PENALTY_HALF_LIFE: Duration = Duration::from_secs(30);
+
+/// the decayed penalty below it is forgotten
+const MIN_PENALTY: f64 = 0.01;
+
+/// the failure penalty of the peers, each failed request adds 1 penalty and the penalty decays
+/// over time, the peers which failed recently are less likely to be chosen
+#[derive(Debug, Default)]
+pub struct PeerFailures {
+    penalties: HashMap<PeerId, (f64, Instant)>,
+}
+
+impl PeerFailures {
+    pub fn record_failure(&mut self, peer_id: PeerId) {
+        self.record_failure_at(peer_id, Instant::now());
+    }
+
+    /// choose a peer randomly, the weight of a peer is `1/4` to the power of its penalty, when
+    /// all weights underflow, choose uniformly
+    pub fn choose_peer<'a>(&self, peers: &'a [PeerId]) -> Option<&'a PeerId> {
+        let now = Instant::now();
+        let mut rng = rand::thread_rng();
+
+        peers
+            .choose_weighted(&mut rng, |peer_id| weight(self.penalty_at(peer_id, now)))
+            .ok()
+            .or_else(|| peers.choose(&mut rng))
+    }
+
+    fn record_failure_at(&mut self, peer_id: PeerId, now: Instant) {
+        let penalty = self.penalty_at(&peer_id, now) + 1.0;
+        self.penalties.insert(peer_id, (penalty, now));
+
+        self.penalties.retain(|_, (penalty, time)| {
+            decay(*penalty, now.saturating_duration_since(*time)) >= MIN_PENALTY
+        });
+    }
+
+    fn penalty_at(&self, peer_id: &PeerId, now: Instant) -> f64 {
+        self.penalties
+            .get(peer_id)
+            .map(|(penalty, time)| decay(*penalty, now.saturating_duration_since(*time)))
+            .unwrap_or_default()
+    }
+}
+
+fn decay(penalty: f64, elapsed: Duration) -> f64 {
+    penalty * 0.5f64.powf(elapsed.as_secs_f64() / PENALTY_HALF_LIFE.as_secs_f64())
+}
+
+fn weight(penalty: f64) -> f64 {
+    0.25f64.powf(penalty)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_peer_failures() {
+        let mut peer_failures = PeerFailures::default();
+        let peer_id = PeerId::random();
+        let now = Instant::now();
+
+        peer_failures.record_failure_at(peer_id, now);
+        peer_failures.record_failure_at(peer_id, now);
+        assert_eq!(peer_failures.penalty_at(&peer_id, now), 2.0);
+        assert_eq!(
+            peer_failures.penalty_at(&peer_id, now + PENALTY_HALF_LIFE),
+            1.0
+        );
+        assert_eq!(peer_failures.penalty_at(&PeerId::random(), now), 0.0);
+
+        // the decayed penalty is forgotten
+        peer_failures.record_failure_at(PeerId::random(), now + PENALTY_HALF_LIFE * 10);
+        assert!(!peer_failures.penalties.contains_key(&peer_id));
+
+        assert_eq!(weight(0.0), 1.0);
+        assert_eq!(weight(1.0), 0.25);
+    }
+
+    #[test]
+    fn test_choose_peer() {
+        let mut peer_failures = PeerFailures::default();
+        let failed_peer = PeerId::random();
+        let peers = [failed_peer, PeerId::random()];
+
+        for _ in 0..20 {
+            peer_failures.record_failure(failed_peer);
+        }
+
+        // the weight of the failed peer is about 1/4^20
+        for _ in 0..100 {
+            assert_eq!(peer_failures.choose_peer(&peers), Some(&peers[1]));
+        }
+
+        assert_eq!(peer_failures.choose_peer(&[]), None);
+    }
+}
@@ -0,0 +1,135 @@
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::io;
+use std::time::Duration;
+
+use futures_channel::oneshot::Sender;
+use futures_util::StreamExt;
+use libp2p::PeerId;
+use tokio_util::time::delay_queue::Key;
+use tokio_util::time::DelayQueue;
+use tracing::info;
+
+use crate::command::PingPeerDetail;
+
+/// the max time of pinging a peer, it is longer than the ping interval, so the connected peer is
+/// pinged at least once
+const PING_PEER_TIMEOUT: Duration = Duration::from_secs(20);
+
+struct PendingPing {
+    /// the peer was connected before the ping
+    connected: bool,
+    /// the connection is dialed for the ping only, it is closed after the ping
+    transient: bool,
+    timeout_key: Key,
+    result_senders: Vec<Sender<io::Result<PingPeerDetail>>>,
+}
+
+/// the ping peer commands which are waiting for the ping of the peer
+#[derive(Default)]
+pub struct PeerPings {
+    pending: HashMap<PeerId, PendingPing>,
+    timeouts: DelayQueue<PeerId>,
+}
+
+impl PeerPings {
+    /// wait for the next ping of the peer, the concurrent pings of the same peer share the result
+    pub fn start(
+        &mut self,
+        peer: PeerId,
+        connected: bool,
+        transient: bool,
+        result_sender: Sender<io::Result<PingPeerDetail>>,
+    ) {
+        match self.pending.entry(peer) {
+            Entry::Occupied(mut entry) => entry.get_mut().result_senders.push(result_sender),
+
+            Entry::Vacant(entry) => {
+                let timeout_key = self.timeouts.insert(peer, PING_PEER_TIMEOUT);
+
+                entry.insert(PendingPing {
+                    connected,
+                    transient,
+                    timeout_key,
+                    result_senders: vec![result_sender],
+                });
+            }
+        }
+    }
+
+    pub fn is_pending(&self, peer: &PeerId) -> bool {
+        self.pending.contains_key(peer)
+    }
+
+    /// send the ping result of the peer, returns whether the connection is dialed for the ping
+    /// only and should be closed
+    pub fn finish(&mut self, peer: &PeerId, result: Result<Duration, String>) -> bool {
+        match self.pending.remove(peer) {
+            None => false,
+
+            Some(pending) => {
+                self.timeouts.remove(&pending.timeout_key);
+
+                send_result(peer, pending, result)
+            }
+        }
+    }
+
+    /// wait for the next peer whose ping timed out, the timed out ping is finished, returns the
+    /// peer and whether the connection should be closed, it is cancel safe
+    pub async fn next_timeout(&mut self) -> Option<(PeerId, bool)> {
+        let peer = self.timeouts.next().await?.into_inner();
+        let pending = self.pending.remove(&peer)?;
+        let transient = send_result(
+            &peer,
+            pending,
+            Err(format!("ping timeout after {PING_PEER_TIMEOUT:?}")),
+        );
+
+        Some((peer, transient))
+    }
+}
+
+fn send_result(peer: &PeerId, pending: PendingPing, result: Result<Duration, String>) -> bool {
+    info!(%peer, ?result, transient = pending.transient, "ping peer done");
+
+    for result_sender in pending.result_senders {
+        let _ = result_sender.send(Ok(PingPeerDetail {
+            peer: *peer,
+            connected: pending.connected,
+            result: result.clone(),
+        }));
+    }
+
+    pending.transient
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_channel::oneshot;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_peer_pings() {
+        let peer = PeerId::random();
+        let mut peer_pings = PeerPings::default();
+        let (sender1, receiver1) = oneshot::channel();
+        let (sender2, receiver2) = oneshot::channel();
+        peer_pings.start(peer, false, true, sender1);
+        // the later ping shares the pending one
+        peer_pings.start(peer, true, false, sender2);
+        assert!(peer_pings.is_pending(&peer));
+
+        assert!(peer_pings.finish(&peer, Ok(Duration::from_millis(10))));
+        assert!(!peer_pings.is_pending(&peer));
+        assert!(!peer_pings.finish(&peer, Ok(Duration::from_millis(10))));
+
+        for receiver in [receiver1, receiver2] {
+            let detail = receiver.await.unwrap().unwrap();
+            assert_eq!(detail.peer, peer);
+            assert!(!detail.connected);
+            assert_eq!(detail.result, Ok(Duration::from_millis(10)));
+        }
+    }
+}
@@ -0,0 +1,259 @@
+use std::fmt::{Debug, Formatter};
+use std::io;
+use std::io::ErrorKind;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+
+use futures_util::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use rand::RngCore;
+use salsa20::cipher::{KeyIvInit, StreamCipher};
+use salsa20::XSalsa20;
+
+pub const KEY_SIZE: usize = 32;
+const NONCE_SIZE: usize = 24;
+
+/// the plaintexts which the dialer sends first, the multistream header of the plain tcp, and the
+/// http upgrade request of the websocket, because the websocket transport runs over the pnet
+/// encrypted tcp connection, its `GET` request is the first thing encrypted by the pnet
+const KNOWN_PREFIXES: [&[u8; PREFIX_SIZE]; 2] = [b"\x13/mu", b"GET "];
+const PREFIX_SIZE: usize = 4;
+
+/// the accepted pre-shared keys of the private network, the first key is used by the outbound
+/// connections, the inbound connections may use any of them, so the swarm can rotate the key
+/// without a hard cutover
+///
+/// the dialer never falls back to the other keys, so during the rotation a node which puts the
+/// new key first can't dial the nodes which only accept the old key, the new key should be
+/// appended on all nodes before it is moved to the first
+#[derive(Clone, Eq, PartialEq)]
+pub struct PnetKeys {
+    keys: Vec<[u8; KEY_SIZE]>,
+}
+
+impl Debug for PnetKeys {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PnetKeys")
+            .field("keys", &self.keys.len())
+            .finish()
+    }
+}
+
+impl PnetKeys {
+    pub fn new(keys: Vec<[u8; KEY_SIZE]>) -> Self {
+        Self { keys }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.keys.len()
+    }
+
+    /// the handshake of the libp2p private network, it is compatible with the libp2p pnet when
+    /// there is only one key
+    ///
+    /// the handshake only exchanges the nonces, with more than one key the listener finds the key
+    /// of the dialer by decrypting the first bytes it sends
+    pub async fn handshake<C>(&self, mut conn: C, dialer: bool) -> io::Result<PnetOutput<C>>
+    where
+        C: AsyncRead + AsyncWrite + Unpin,
+    {
+        let mut local_nonce = [0; NONCE_SIZE];
+        let mut remote_nonce = [0; NONCE_SIZE];
+        rand::thread_rng().fill_bytes(&mut local_nonce);
+
+        conn.write_all(&local_nonce).await?;
+        conn.flush().await?;
+        conn.read_exact(&mut remote_nonce).await?;
+
+        let (key, read_cipher, prefix) = match self.keys.as_slice() {
+            [] => return Err(io::Error::new(ErrorKind::Other, "no pre-shared key")),
+
+            [key] => (key, XSalsa20::new(key.into(), &remote_nonce.into()), vec![]),
+
+            // the dialer always uses the first key, see the doc of `PnetKeys`
+            [key, ..] if dialer => (key, XSalsa20::new(key.into(), &remote_nonce.into()), vec![]),
+
+            keys => {
+                let mut encrypted = [0; PREFIX_SIZE];
+                conn.read_exact(&mut encrypted).await?;
+
+                keys.iter()
+                    .find_map(|key| {
+                        let mut read_cipher = XSalsa20::new(key.into(), &remote_nonce.into());
+                        let mut prefix = encrypted;
+                        read_cipher.apply_keystream(&mut prefix);
+
+                        KNOWN_PREFIXES
+                            .contains(&&prefix)
+                            .then(|| (key, read_cipher, prefix.to_vec()))
+                    })
+                    .ok_or_else(|| {
+                        io::Error::new(
+                            ErrorKind::InvalidData,
+                            "the remote pre-shared key isn't accepted",
+                        )
+                    })?
+            }
+        };
+
+        Ok(PnetOutput {
+            inner: conn,
+            read_cipher,
+            write_cipher: XSalsa20::new(key.into(), &local_nonce.into()),
+            prefix,
+            prefix_offset: 0,
+            write_buf: vec![],
+        })
+    }
+}
+
+/// the encrypted connection of the private network
+pub struct PnetOutput<C> {
+    inner: C,
+    read_cipher: XSalsa20,
+    write_cipher: XSalsa20,
+    /// the decrypted bytes which are read when finding the key
+    prefix: Vec<u8>,
+    prefix_offset: usize,
+    /// the encrypted bytes which are not written to the inner connection yet
+    write_buf: Vec<u8>,
+}
+
+impl<C> Debug for PnetOutput<C> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PnetOutput")
+            .field("write_buf", &self.write_buf.len())
+            .finish_non_exhaustive()
+    }
+}
+
+impl<C: AsyncWrite + Unpin> PnetOutput<C> {
+    fn poll_write_buf(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while !self.write_buf.is_empty() {
+            match ready!(Pin::new(&mut self.inner).poll_write(cx, &self.write_buf)) {
+                Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+                Err(err) => return Poll::Ready(Err(err)),
+                Ok(0) => return Poll::Ready(Err(ErrorKind::WriteZero.into())),
+                Ok(n) => {
+                    self.write_buf.drain(..n);
+                }
+            }
+        }
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<C: AsyncRead + Unpin> AsyncRead for PnetOutput<C> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+
+        if this.prefix_offset < this.prefix.len() {
+            let prefix = &this.prefix[this.prefix_offset..];
+            let n = prefix.len().min(buf.len());
+            buf[..n].copy_from_slice(&prefix[..n]);
+            this.prefix_offset += n;
+
+            return Poll::Ready(Ok(n));
+        }
+
+        let n = ready!(Pin::new(&mut this.inner).poll_read(cx, buf))?;
+        this.read_cipher.apply_keystream(&mut buf[..n]);
+
+        Poll::Ready(Ok(n))
+    }
+}
+
+impl<C: AsyncWrite + Unpin> AsyncWrite for PnetOutput<C> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_buf(cx))?;
+
+        this.write_buf.extend_from_slice(buf);
+        this.write_cipher.apply_keystream(&mut this.write_buf);
+
+        // the encrypted bytes are accepted, the rest are written by the next write or flush
+        if let Poll::Ready(Err(err)) = this.poll_write_buf(cx) {
+            return Poll::Ready(Err(err));
+        }
+
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_buf(cx))?;
+
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_buf(cx))?;
+
+        Pin::new(&mut this.inner).poll_close(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_util::future;
+    use tokio::io::duplex;
+    use tokio_util::compat::TokioAsyncReadCompatExt;
+
+    use super::*;
+
+    const HEADER: &[u8] = b"\x13/multistream/1.0.0\n";
+
+    async fn connect(dialer_keys: &PnetKeys, listener_keys: &PnetKeys) -> io::Result<Vec<u8>> {
+        let (dialer_conn, listener_conn) = duplex(1024);
+
+        let dial = async {
+            let mut output = dialer_keys.handshake(dialer_conn.compat(), true).await?;
+            output.write_all(HEADER).await?;
+            output.flush().await
+        };
+        let listen = async {
+            let mut output = listener_keys
+                .handshake(listener_conn.compat(), false)
+                .await?;
+            let mut header = vec![0; HEADER.len()];
+            output.read_exact(&mut header).await?;
+
+            Ok::<_, io::Error>(header)
+        };
+
+        let (dial_result, listen_result) = future::join(dial, listen).await;
+        dial_result?;
+
+        listen_result
+    }
+
+    #[tokio::test]
+    async fn test_rotate_keys() {
+        let old_keys = PnetKeys::new(vec![[1; KEY_SIZE]]);
+        let rotating_keys = PnetKeys::new(vec![[2; KEY_SIZE], [1; KEY_SIZE]]);
+
+        assert_eq!(connect(&old_keys, &rotating_keys).await.unwrap(), HEADER);
+        assert_eq!(
+            connect(&rotating_keys, &rotating_keys).await.unwrap(),
+            HEADER
+        );
+
+        // the key of the dialer isn't accepted
+        let other_keys = PnetKeys::new(vec![[3; KEY_SIZE]]);
+        let err = connect(&other_keys, &rotating_keys).await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,136 @@
+use std::time::Duration;
+
+use futures_util::StreamExt;
+use libp2p::gossipsub::error::PublishError;
+use libp2p::gossipsub::{Gossipsub, Sha256Topic};
+use tokio_util::time::DelayQueue;
+use tracing::{error, info, instrument, warn};
+
+use crate::node::behaviour::publish_message;
+use crate::node::jitter_ticker::jitter_interval;
+
+/// the max retries of a message which failed to publish by a transient error
+const MAX_PUBLISH_RETRIES: u32 = 3;
+/// the delay of the first retry, it is doubled by each retry
+const PUBLISH_RETRY_BACKOFF: Duration = Duration::from_millis(500);
+/// the max delay of a retry
+const MAX_PUBLISH_RETRY_BACKOFF: Duration = Duration::from_secs(5);
+/// the retry delay is randomized in `[-jitter, +jitter]` percent
+const PUBLISH_RETRY_JITTER: u8 = 20;
+
+/// the message which is waiting for the retry
+#[derive(Debug)]
+pub struct PendingPublish {
+    topic: Sha256Topic,
+    data: Vec<u8>,
+    /// the number of the failed publishes
+    attempts: u32,
+}
+
+/// retry the messages which failed to publish by a transient error with a jittered and capped
+/// backoff, so a file list or discover message doesn't wait for the next tick to propagate, the
+/// receivers ignore the outdated messages, so a late retry never rolls back a newer one
+#[derive(Debug, Default)]
+pub struct PublishRetries {
+    queue: DelayQueue<PendingPublish>,
+}
+
+impl PublishRetries {
+    /// publish the message, the transient failure is retried later, the permanent failure is
+    /// returned
+    pub fn publish(
+        &mut self,
+        gossip: &mut Gossipsub,
+        topic: &Sha256Topic,
+        data: Vec<u8>,
+    ) -> Result<(), PublishError> {
+        match publish_message(gossip, topic, data.clone()) {
+            Err(err) if is_transient(&err) => {
+                warn!(%err, ?topic, "publish message failed, retry later");
+
+                self.schedule(PendingPublish {
+                    topic: topic.clone(),
+                    data,
+                    attempts: 1,
+                });
+
+                Ok(())
+            }
+
+            result => result,
+        }
+    }
+
+    /// wait for the next message whose retry is due, it is cancel safe
+    pub async fn next(&mut self) -> Option<PendingPublish> {
+        self.queue.next().await.map(|expired| expired.into_inner())
+    }
+
+    /// publish the message again, it is scheduled again until the max retries are reached
+    #[instrument(skip(self, gossip, pending), fields(topic = ?pending.topic, attempts = pending.attempts))]
+    pub fn retry(&mut self, gossip: &mut Gossipsub, mut pending: PendingPublish) {
+        match publish_message(gossip, &pending.topic, pending.data.clone()) {
+            Err(err) if is_transient(&err) && pending.attempts < MAX_PUBLISH_RETRIES => {
+                warn!(%err, "retry publish message failed, retry later");
+
+                pending.attempts += 1;
+                self.schedule(pending);
+            }
+
+            Err(err) => error!(%err, "retry publish message failed, give up"),
+
+            Ok(_) => info!("retry publish message done"),
+        }
+    }
+
+    fn schedule(&mut self, pending: PendingPublish) {
+        let delay = jitter_interval(retry_backoff(pending.attempts), PUBLISH_RETRY_JITTER);
+
+        info!(topic = ?pending.topic, attempts = pending.attempts, ?delay, "schedule publish retry");
+
+        self.queue.insert(pending, delay);
+    }
+}
+
+/// the message may be published later when the transform fails, the others fail again with the
+/// same message
+fn is_transient(err: &PublishError) -> bool {
+    match err {
+        PublishError::TransformFailed(_) => true,
+
+        PublishError::Duplicate
+        | PublishError::SigningError(_)
+        | PublishError::InsufficientPeers
+        | PublishError::MessageTooLarge => false,
+    }
+}
+
+fn retry_backoff(attempts: u32) -> Duration {
+    PUBLISH_RETRY_BACKOFF
+        .saturating_mul(1 << attempts.saturating_sub(1).min(16))
+        .min(MAX_PUBLISH_RETRY_BACKOFF)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use super::*;
+
+    #[test]
+    fn test_is_transient() {
+        assert!(is_transient(&PublishError::TransformFailed(
+            io::ErrorKind::Other.into()
+        )));
+        assert!(!is_transient(&PublishError::MessageTooLarge));
+        assert!(!is_transient(&PublishError::InsufficientPeers));
+    }
+
+    #[test]
+    fn test_retry_backoff() {
+        assert_eq!(retry_backoff(1), Duration::from_millis(500));
+        assert_eq!(retry_backoff(2), Duration::from_secs(1));
+        assert_eq!(retry_backoff(3), Duration::from_secs(2));
+        assert_eq!(retry_backoff(100), MAX_PUBLISH_RETRY_BACKOFF);
+    }
+}
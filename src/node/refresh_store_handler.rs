@@ -1,47 +1,117 @@
+use std::collections::HashMap;
 use std::ffi::OsString;
 use std::io::{Error, ErrorKind};
 use std::os::unix::fs::MetadataExt;
 use std::path::Path;
 use std::time::SystemTime;
 
-use futures_util::{stream, StreamExt, TryStreamExt};
-use libp2p::gossipsub::error::PublishError;
+use futures_util::{future, stream, StreamExt, TryStreamExt};
 use libp2p::gossipsub::Sha256Topic;
-use libp2p::Swarm;
+use libp2p::identity::Keypair;
+use libp2p::{PeerId, Swarm};
 use prost::Message as _;
 use tap::TapFallible;
 use tokio::fs;
 use tracing::{error, info, instrument};
 
-use crate::node::behaviour::Behaviour;
+use crate::node::availability::Availability;
+use crate::node::behaviour::{Behaviour, MAX_FILE_MESSAGE_SIZE};
+use crate::node::chunking::ChunkDigestCache;
+use crate::node::config::index_file_hash;
 use crate::node::message::{File, FileMessage};
+use crate::node::publish_retry::PublishRetries;
+use crate::node::store_link::StoreLink;
+use crate::node::PeerNodeStore;
 use crate::util;
+use crate::util::{ExcludePatterns, SharePatterns};
 
 pub struct RefreshStoreHandler<'a> {
+    index_dir: &'a Path,
     store_dir: &'a Path,
+    store_link: StoreLink,
+    key: &'a Keypair,
     swarm: &'a mut Swarm<Behaviour>,
+    exclude_patterns: &'a ExcludePatterns,
+    /// only the store files matching it are announced
+    share_only: &'a SharePatterns,
+    availability: &'a Availability,
+    peer_stores: &'a HashMap<PeerId, PeerNodeStore>,
+    publish_retries: &'a mut PublishRetries,
+    chunk_digest_cache: &'a ChunkDigestCache,
 }
 
 impl<'a> RefreshStoreHandler<'a> {
-    pub fn new(store_dir: &'a Path, swarm: &'a mut Swarm<Behaviour>) -> Self {
-        Self { store_dir, swarm }
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        index_dir: &'a Path,
+        store_dir: &'a Path,
+        store_link: StoreLink,
+        key: &'a Keypair,
+        swarm: &'a mut Swarm<Behaviour>,
+        exclude_patterns: &'a ExcludePatterns,
+        share_only: &'a SharePatterns,
+        availability: &'a Availability,
+        peer_stores: &'a HashMap<PeerId, PeerNodeStore>,
+        publish_retries: &'a mut PublishRetries,
+        chunk_digest_cache: &'a ChunkDigestCache,
+    ) -> Self {
+        Self {
+            index_dir,
+            store_dir,
+            store_link,
+            key,
+            swarm,
+            exclude_patterns,
+            share_only,
+            availability,
+            peer_stores,
+            publish_retries,
+            chunk_digest_cache,
+        }
     }
 
     #[instrument(err, skip(self))]
     pub async fn handle_tick(self, topic: Sha256Topic) -> anyhow::Result<()> {
+        let index_dir = self.index_dir;
         let store_dir = self.store_dir;
+        let store_link = self.store_link;
+
+        let mut store_filenames = util::collect_filenames(store_dir).await?;
+        store_filenames.retain(|filename| {
+            let filename_str = filename.to_string_lossy();
+            if self.exclude_patterns.is_excluded(&filename_str) {
+                info!(?filename, "skip excluded store file");
+
+                return false;
+            }
+            if !self.share_only.is_shared(&filename_str) {
+                info!(?filename, "skip store file not in the share only patterns");
+
+                return false;
+            }
 
-        let store_filenames = util::collect_filenames(store_dir).await?;
+            true
+        });
 
         info!(?store_filenames, ?store_dir, "collect store files done");
 
-        let files = stream::iter(store_filenames.iter())
+        let mut files = stream::iter(store_filenames.iter())
             .then(|filename| async move {
                 let store_file_path = store_dir.join(filename);
 
-                let index_file_path = fs::read_link(&store_file_path)
-                    .await
-                    .tap_err(|err| error!(%err, ?store_file_path, "read symlink failed"))?;
+                let index_file_path = match store_link.read_link(index_dir, &store_file_path).await
+                {
+                    // never announce the tampered store file, it is warned by the read link
+                    Err(err) if err.kind() == ErrorKind::InvalidData => return Ok(None),
+
+                    Err(err) => {
+                        error!(%err, ?store_file_path, "read store link failed");
+
+                        return Err(err);
+                    }
+
+                    Ok(index_file_path) => index_file_path,
+                };
 
                 info!(
                     ?store_file_path,
@@ -54,7 +124,7 @@ impl<'a> RefreshStoreHandler<'a> {
                     .tap_err(|err| error!(%err, ?index_file_path, "get metadata failed"))?;
                 let file_size = metadata.size();
 
-                let hash = index_file_path.file_name().ok_or_else(|| {
+                let hash = index_file_hash(&index_file_path).ok_or_else(|| {
                     error!(?index_file_path, "index file doesn't contain filename");
 
                     Error::new(
@@ -70,13 +140,15 @@ impl<'a> RefreshStoreHandler<'a> {
                     "get file hash done"
                 );
 
-                Ok::<_, Error>((filename, hash.to_owned(), file_size))
+                Ok::<_, Error>(Some((filename, hash, file_size)))
             })
+            .try_filter_map(future::ok)
             .map_ok(
-                |(filename, hash, file_size): (&OsString, OsString, u64)| File {
+                |(filename, hash, file_size): (&OsString, String, u64)| File {
                     filename: filename.to_string_lossy().to_string(),
-                    hash: hash.to_string_lossy().to_string(),
+                    hash,
                     file_size,
+                    chunks: None,
                 },
             )
             .try_collect::<Vec<_>>()
@@ -84,6 +156,12 @@ impl<'a> RefreshStoreHandler<'a> {
 
         info!(?files, "collect message files hash done");
 
+        // the chunk digests of the unshared index files are never requested again
+        self.chunk_digest_cache
+            .retain(&files.iter().map(|file| file.hash.as_str()).collect());
+
+        files.extend(self.collect_syncing_files(&files));
+
         let message = FileMessage {
             peer_id: self.swarm.local_peer_id().to_base58(),
             file_list: files,
@@ -91,30 +169,61 @@ impl<'a> RefreshStoreHandler<'a> {
                 .duration_since(SystemTime::UNIX_EPOCH)
                 .unwrap()
                 .as_micros() as _,
+            signature: vec![],
+            public_key: vec![],
+            part: 0,
+            total_parts: 0,
         };
-        let message = message.encode_to_vec();
-
-        match self
-            .swarm
-            .behaviour_mut()
-            .gossip
-            .publish(topic.clone(), message)
-        {
-            Err(PublishError::InsufficientPeers) => {
-                info!(?topic, "no peer connected");
-            }
-
-            Err(err) => {
-                error!(%err, ?topic, "publish message to topic failed");
+        // a large file list exceeds the gossip max transmit size, it is split so the list is
+        // still propagated
+        let messages = message
+            .sign_split(self.key, MAX_FILE_MESSAGE_SIZE)
+            .tap_err(|err| error!(%err, "sign file message failed"))?;
+        if messages.len() > 1 {
+            info!(parts = messages.len(), "file message is split");
+        }
 
-                return Err(err.into());
-            }
+        for message in messages {
+            let part = message.part;
 
-            Ok(_) => {}
+            self.publish_retries
+                .publish(
+                    &mut self.swarm.behaviour_mut().gossip,
+                    &topic,
+                    message.encode_to_vec(),
+                )
+                .tap_err(|err| error!(%err, ?topic, part, "publish message to topic failed"))?;
         }
 
-        info!(?topic, "publish message to topic done");
-
         Ok(())
     }
+
+    /// announce the syncing files with their available chunks, so the other peers can fetch the
+    /// synced chunks from us, the filename and size come from the peers we sync the file from
+    fn collect_syncing_files(&self, store_files: &[File]) -> Vec<File> {
+        self.availability
+            .snapshot()
+            .into_iter()
+            .filter(|(hash, _)| !store_files.iter().any(|file| &file.hash == hash))
+            .filter_map(|(hash, range_set)| {
+                self.peer_stores.values().find_map(|peer_store| {
+                    let file_size = *peer_store.index.get(&hash)?;
+                    let (filename, _) = peer_store
+                        .files
+                        .iter()
+                        .find(|(_, file_hash)| **file_hash == hash)?;
+                    if !self.share_only.is_shared(filename) {
+                        return None;
+                    }
+
+                    Some(File {
+                        filename: filename.clone(),
+                        hash: hash.clone(),
+                        file_size,
+                        chunks: Some(range_set.to_bitfield(file_size)),
+                    })
+                })
+            })
+            .collect()
+    }
 }
@@ -0,0 +1,72 @@
+use std::collections::HashSet;
+
+use libp2p::PeerId;
+
+use crate::command::RelayServiceStats;
+
+/// the load of the relay service, the relay behaviour doesn't report the closed circuits or the
+/// relayed bytes, so the circuits are counted since the node started
+#[derive(Debug, Default)]
+pub struct RelayStats {
+    enabled: bool,
+    /// the peers which listen through this node, they are removed when fully disconnected
+    reservations: HashSet<PeerId>,
+    circuits: u64,
+}
+
+impl RelayStats {
+    pub fn new(enabled: bool) -> Self {
+        Self {
+            enabled,
+            ..Default::default()
+        }
+    }
+
+    pub fn record_reservation(&mut self, peer: PeerId) {
+        self.reservations.insert(peer);
+    }
+
+    pub fn record_circuit(&mut self) {
+        self.circuits += 1;
+    }
+
+    /// the fully disconnected peer doesn't listen through this node anymore
+    pub fn remove_peer(&mut self, peer: &PeerId) {
+        self.reservations.remove(peer);
+    }
+
+    /// `None` when the relay service is disabled
+    pub fn stats(&self) -> Option<RelayServiceStats> {
+        self.enabled.then_some(RelayServiceStats {
+            reservations: self.reservations.len(),
+            circuits: self.circuits,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_relay_stats() {
+        assert_eq!(RelayStats::new(false).stats(), None);
+
+        let (a, b) = (PeerId::random(), PeerId::random());
+        let mut relay_stats = RelayStats::new(true);
+        relay_stats.record_reservation(a);
+        relay_stats.record_reservation(a);
+        relay_stats.record_reservation(b);
+        relay_stats.record_circuit();
+        relay_stats.record_circuit();
+        relay_stats.remove_peer(&b);
+
+        assert_eq!(
+            relay_stats.stats(),
+            Some(RelayServiceStats {
+                reservations: 1,
+                circuits: 2,
+            })
+        );
+    }
+}
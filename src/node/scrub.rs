@@ -0,0 +1,224 @@
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+use tokio::fs;
+use tokio::time;
+use tokio::time::{Instant, MissedTickBehavior};
+use tracing::{error, info, instrument, warn};
+
+use crate::hash::HashAlgorithm;
+use crate::node::chunking::ChunkDigestCache;
+use crate::node::config::{index_file_hash, StoreLayout};
+
+pub type SharedScrubStatus = Arc<Mutex<ScrubStatus>>;
+
+/// the result of the last integrity scrub of the index dir
+#[derive(Debug, Clone, Default)]
+pub struct ScrubStatus {
+    /// when the last scrub finished, `None` when no scrub is finished yet
+    pub last_scrub_time: Option<SystemTime>,
+    pub checked_files: usize,
+    /// the hashes of the index files whose content doesn't match their names
+    pub corrupted_files: Vec<String>,
+    /// the failures of reading the index files
+    pub errors: Vec<String>,
+}
+
+/// rehash the index files every `interval`, the first scrub starts after an interval, so it
+/// doesn't compete with the startup
+pub async fn run_scrub(
+    index_dir: PathBuf,
+    store_layout: StoreLayout,
+    hash_algorithm: HashAlgorithm,
+    interval: Duration,
+    bytes_per_sec: u64,
+    status: SharedScrubStatus,
+    chunk_digest_cache: ChunkDigestCache,
+) {
+    let mut ticker = time::interval_at(Instant::now() + interval, interval);
+    // a slow scrub may take longer than the interval, don't start the next one at once
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+    loop {
+        ticker.tick().await;
+
+        let scrub_status =
+            scrub_index_dir(&index_dir, store_layout, hash_algorithm, bytes_per_sec).await;
+        // the cached chunk digests may not match the corrupted content
+        for hash in &scrub_status.corrupted_files {
+            chunk_digest_cache.remove(hash);
+        }
+        *status.lock().unwrap() = scrub_status;
+    }
+}
+
+/// rehash the index files, the hidden entries and the dirs like the temp dir are skipped, the
+/// shard dirs are walked in the hashed layout
+#[instrument]
+pub async fn scrub_index_dir(
+    index_dir: &Path,
+    store_layout: StoreLayout,
+    hash_algorithm: HashAlgorithm,
+    bytes_per_sec: u64,
+) -> ScrubStatus {
+    let mut status = ScrubStatus::default();
+    let mut dirs = vec![(index_dir.to_path_buf(), 0)];
+
+    while let Some((dir, depth)) = dirs.pop() {
+        let mut read_dir = match fs::read_dir(&dir).await {
+            Err(err) => {
+                error!(%err, ?dir, "read index dir failed");
+
+                status.errors.push(format!("read index dir failed: {err}"));
+
+                continue;
+            }
+
+            Ok(read_dir) => read_dir,
+        };
+
+        loop {
+            let entry = match read_dir.next_entry().await {
+                Err(err) => {
+                    error!(%err, ?dir, "read index dir entry failed");
+
+                    status
+                        .errors
+                        .push(format!("read index dir entry failed: {err}"));
+
+                    break;
+                }
+
+                Ok(None) => break,
+                Ok(Some(entry)) => entry,
+            };
+
+            let name = entry.file_name().to_string_lossy().to_string();
+            if name.starts_with('.') {
+                continue;
+            }
+
+            let path = entry.path();
+            match entry.file_type().await {
+                Err(err) => {
+                    error!(%err, ?path, "get index file type failed");
+
+                    status.errors.push(format!("{name}: {err}"));
+
+                    continue;
+                }
+
+                Ok(file_type) if file_type.is_dir() && depth < store_layout.shard_depth() => {
+                    dirs.push((path, depth + 1));
+
+                    continue;
+                }
+
+                Ok(file_type) if !file_type.is_file() || depth != store_layout.shard_depth() => {
+                    continue
+                }
+
+                Ok(_) => {}
+            }
+
+            // the index filename may have the extension
+            let index_hash = index_file_hash(&path).unwrap_or_else(|| name.clone());
+            match hash_algorithm
+                .hash_file_throttled(&path, bytes_per_sec)
+                .await
+            {
+                Err(err) => {
+                    error!(%err, ?path, "hash index file failed");
+
+                    status.errors.push(format!("{name}: {err}"));
+                }
+
+                Ok(hash) if hash != index_hash => {
+                    warn!(?path, %hash, "index file is corrupted");
+
+                    status.checked_files += 1;
+                    status.corrupted_files.push(index_hash);
+                }
+
+                Ok(_) => status.checked_files += 1,
+            }
+        }
+    }
+
+    status.last_scrub_time = Some(SystemTime::now());
+
+    info!(
+        checked_files = status.checked_files,
+        corrupted_files = ?status.corrupted_files,
+        errors = status.errors.len(),
+        "scrub index dir done"
+    );
+
+    status
+}
+
+#[cfg(test)]
+mod tests {
+    use sha2::{Digest, Sha256};
+    use tempfile::TempDir;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_scrub_index_dir() {
+        let dir = TempDir::new().unwrap();
+        let good_hash = hex::encode_upper(Sha256::digest(b"good"));
+        let bad_hash = hex::encode_upper(Sha256::digest(b"bad"));
+        fs::write(dir.path().join(&good_hash), b"good")
+            .await
+            .unwrap();
+        let extension_hash = hex::encode_upper(Sha256::digest(b"video"));
+        fs::write(dir.path().join(format!("{extension_hash}.mp4")), b"video")
+            .await
+            .unwrap();
+        fs::write(dir.path().join(&bad_hash), b"rotten")
+            .await
+            .unwrap();
+        fs::create_dir(dir.path().join(".tmp")).await.unwrap();
+        fs::write(dir.path().join(".tmp").join("HASH"), b"syncing")
+            .await
+            .unwrap();
+
+        let status = scrub_index_dir(
+            dir.path(),
+            StoreLayout::Flat,
+            HashAlgorithm::Sha256,
+            1024 * 1024,
+        )
+        .await;
+
+        assert!(status.last_scrub_time.is_some());
+        assert_eq!(status.checked_files, 3);
+        assert_eq!(status.corrupted_files, [bad_hash]);
+        assert!(status.errors.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_scrub_hashed_index_dir() {
+        let dir = TempDir::new().unwrap();
+        let hash = hex::encode_upper(Sha256::digest(b"good"));
+        let index_path = StoreLayout::Hashed.index_path(dir.path(), &hash);
+        fs::create_dir_all(index_path.parent().unwrap())
+            .await
+            .unwrap();
+        fs::write(&index_path, b"good").await.unwrap();
+
+        let status = scrub_index_dir(
+            dir.path(),
+            StoreLayout::Hashed,
+            HashAlgorithm::Sha256,
+            1024 * 1024,
+        )
+        .await;
+
+        assert_eq!(status.checked_files, 1);
+        assert!(status.corrupted_files.is_empty());
+        assert!(status.errors.is_empty());
+    }
+}
@@ -0,0 +1,305 @@
+use std::ffi::OsString;
+use std::io;
+use std::io::ErrorKind;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::path::{Component, Path, PathBuf};
+use std::str::FromStr;
+
+use anyhow::anyhow;
+use tap::TapFallible;
+use tokio::fs;
+use tracing::{error, info, instrument, warn};
+
+use crate::util::normalize_filename;
+
+/// the dir under the index dir which records the index files of the hardlink store files
+const STORE_LINKS_DIR_NAME: &str = ".store_links";
+
+/// how the store files link to the index files
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+pub enum StoreLink {
+    /// the store file is a symlink to the index file
+    #[default]
+    Symlink,
+    /// the store file is a hardlink to the index file, it looks like a regular file to the
+    /// clients, like the SMB clients, the index file path is recorded in a small mapping file
+    /// under `{index_dir}/.store_links`, the store dir and the index dir must be in the same
+    /// filesystem
+    Hardlink,
+}
+
+impl FromStr for StoreLink {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "symlink" => Ok(StoreLink::Symlink),
+            "hardlink" => Ok(StoreLink::Hardlink),
+            _ => Err(anyhow!("unknown store link {s}")),
+        }
+    }
+}
+
+impl StoreLink {
+    /// link the store file to the index file, it fails with [`ErrorKind::AlreadyExists`] when
+    /// the store file exists
+    #[instrument(err)]
+    pub async fn link(
+        self,
+        index_dir: &Path,
+        index_path: &Path,
+        store_path: &Path,
+    ) -> io::Result<()> {
+        match self {
+            StoreLink::Symlink => fs::symlink(index_path, store_path).await,
+
+            StoreLink::Hardlink => {
+                let mapping_path = mapping_path(index_dir, store_path)?;
+                fs::create_dir_all(index_dir.join(STORE_LINKS_DIR_NAME))
+                    .await
+                    .tap_err(|err| error!(%err, ?index_dir, "create store links dir failed"))?;
+
+                fs::hard_link(index_path, store_path).await?;
+
+                // the store file without the mapping can't be resolved, so don't leave it
+                if let Err(err) = fs::write(&mapping_path, index_path.as_os_str().as_bytes()).await
+                {
+                    error!(%err, ?mapping_path, "write store link mapping failed");
+
+                    let _ = fs::remove_file(store_path).await;
+
+                    return Err(err);
+                }
+
+                info!(?index_path, ?store_path, "create hardlink done");
+
+                Ok(())
+            }
+        }
+    }
+
+    /// the index file path of the store file, it fails with [`ErrorKind::NotFound`] when the
+    /// store file doesn't exist, and fails with [`ErrorKind::InvalidData`] when the store file
+    /// links outside the index dir, like a tampered symlink, so an arbitrary file is never served
+    pub async fn read_link(self, index_dir: &Path, store_path: &Path) -> io::Result<PathBuf> {
+        let index_path = self.read_link_target(index_dir, store_path).await?;
+
+        // the relative symlink target is relative to the store dir
+        let index_path = match store_path.parent() {
+            Some(store_dir) if index_path.is_relative() => store_dir.join(index_path),
+            _ => index_path,
+        };
+
+        check_index_path(index_dir, store_path, &index_path).await?;
+
+        Ok(index_path)
+    }
+
+    async fn read_link_target(self, index_dir: &Path, store_path: &Path) -> io::Result<PathBuf> {
+        match self {
+            StoreLink::Symlink => fs::read_link(store_path).await,
+
+            StoreLink::Hardlink => {
+                // a stale mapping may be left when the store file is removed by the user
+                fs::symlink_metadata(store_path).await?;
+
+                let mapping_path = mapping_path(index_dir, store_path)?;
+                let index_path = fs::read(&mapping_path).await.map_err(|err| {
+                    io::Error::new(
+                        err.kind(),
+                        format!("read store link mapping {mapping_path:?} failed: {err}"),
+                    )
+                })?;
+
+                Ok(PathBuf::from(OsString::from_vec(index_path)))
+            }
+        }
+    }
+
+    /// remove the store file and its mapping
+    #[instrument(err)]
+    pub async fn remove(self, index_dir: &Path, store_path: &Path) -> io::Result<()> {
+        fs::remove_file(store_path).await?;
+
+        if self == StoreLink::Hardlink {
+            let mapping_path = mapping_path(index_dir, store_path)?;
+            match fs::remove_file(&mapping_path).await {
+                Err(err) if err.kind() != ErrorKind::NotFound => {
+                    error!(%err, ?mapping_path, "remove store link mapping failed");
+
+                    return Err(err);
+                }
+
+                _ => {}
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// check the index file path is in the index dir after the symlinks are resolved, the hidden
+/// dirs in the index dir, like the temp dir, are rejected too
+async fn check_index_path(
+    index_dir: &Path,
+    store_path: &Path,
+    index_path: &Path,
+) -> io::Result<()> {
+    let valid = match fs::canonicalize(index_path).await {
+        // the missing index file is reported by the callers, only its path can be checked
+        Err(err) if err.kind() == ErrorKind::NotFound => is_in_index_dir(index_dir, index_path),
+        Err(err) => return Err(err),
+        Ok(real_index_path) => {
+            is_in_index_dir(&fs::canonicalize(index_dir).await?, &real_index_path)
+        }
+    };
+    if valid {
+        return Ok(());
+    }
+
+    warn!(
+        ?store_path,
+        ?index_path,
+        "store file links outside the index dir"
+    );
+
+    Err(io::Error::new(
+        ErrorKind::InvalidData,
+        format!("store file {store_path:?} links to {index_path:?} outside the index dir"),
+    ))
+}
+
+fn is_in_index_dir(index_dir: &Path, path: &Path) -> bool {
+    let relative_path = match path.strip_prefix(index_dir) {
+        Err(_) => return false,
+        Ok(relative_path) => relative_path,
+    };
+
+    relative_path.components().next().is_some()
+        && relative_path.components().all(|component| match component {
+            Component::Normal(name) => !name.as_bytes().starts_with(b"."),
+            _ => false,
+        })
+}
+
+/// the mapping file is named by the NFC form of the store filename, so it still matches after the
+/// store file is renamed to the NFC form
+fn mapping_path(index_dir: &Path, store_path: &Path) -> io::Result<PathBuf> {
+    let filename = store_path.file_name().ok_or_else(|| {
+        io::Error::new(
+            ErrorKind::InvalidInput,
+            format!("store path {store_path:?} doesn't have filename"),
+        )
+    })?;
+    let filename = match filename.to_str() {
+        Some(filename) => OsString::from(normalize_filename(filename)),
+        None => filename.to_owned(),
+    };
+
+    Ok(index_dir.join(STORE_LINKS_DIR_NAME).join(filename))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::MetadataExt;
+
+    use tempfile::TempDir;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_hardlink() {
+        let dir = TempDir::new().unwrap();
+        let index_dir = dir.path().join("index");
+        let store_dir = dir.path().join("store");
+        fs::create_dir_all(&index_dir).await.unwrap();
+        fs::create_dir_all(&store_dir).await.unwrap();
+
+        let index_path = index_dir.join("HASH");
+        fs::write(&index_path, b"test").await.unwrap();
+        let store_path = store_dir.join("a.txt");
+
+        StoreLink::Hardlink
+            .link(&index_dir, &index_path, &store_path)
+            .await
+            .unwrap();
+
+        let metadata = fs::symlink_metadata(&store_path).await.unwrap();
+        assert!(metadata.is_file());
+        assert_eq!(metadata.nlink(), 2);
+        assert_eq!(
+            StoreLink::Hardlink
+                .read_link(&index_dir, &store_path)
+                .await
+                .unwrap(),
+            index_path
+        );
+
+        let err = StoreLink::Hardlink
+            .link(&index_dir, &index_path, &store_path)
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::AlreadyExists);
+
+        StoreLink::Hardlink
+            .remove(&index_dir, &store_path)
+            .await
+            .unwrap();
+
+        let err = StoreLink::Hardlink
+            .read_link(&index_dir, &store_path)
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+        assert!(!index_dir.join(STORE_LINKS_DIR_NAME).join("a.txt").exists());
+        assert!(index_path.exists());
+    }
+
+    #[tokio::test]
+    async fn test_read_link_outside_index_dir() {
+        let dir = TempDir::new().unwrap();
+        let index_dir = dir.path().join("index");
+        let store_dir = dir.path().join("store");
+        fs::create_dir_all(index_dir.join(".tmp")).await.unwrap();
+        fs::create_dir_all(&store_dir).await.unwrap();
+        fs::write(index_dir.join("HASH"), b"test").await.unwrap();
+        fs::write(index_dir.join(".tmp").join("HASH"), b"syncing")
+            .await
+            .unwrap();
+        fs::write(dir.path().join("secret"), b"secret")
+            .await
+            .unwrap();
+
+        for (filename, target) in [
+            ("a.txt", index_dir.join("HASH")),
+            ("relative.txt", PathBuf::from("../index/HASH")),
+            // the missing index file is reported by the callers
+            ("missing.txt", index_dir.join("MISSING")),
+        ] {
+            let store_path = store_dir.join(filename);
+            fs::symlink(&target, &store_path).await.unwrap();
+
+            StoreLink::Symlink
+                .read_link(&index_dir, &store_path)
+                .await
+                .unwrap();
+        }
+
+        for (filename, target) in [
+            ("secret.txt", dir.path().join("secret")),
+            ("escape.txt", index_dir.join("..").join("secret")),
+            ("relative_escape.txt", PathBuf::from("../secret")),
+            ("temp.txt", index_dir.join(".tmp").join("HASH")),
+            ("index_dir.txt", index_dir.clone()),
+        ] {
+            let store_path = store_dir.join(filename);
+            fs::symlink(&target, &store_path).await.unwrap();
+
+            let err = StoreLink::Symlink
+                .read_link(&index_dir, &store_path)
+                .await
+                .unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::InvalidData, "{filename}");
+        }
+    }
+}
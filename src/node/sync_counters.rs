@@ -0,0 +1,80 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use crate::command::SyncCounterStats;
+
+/// the cumulative counters of the sync and the served data since the node started, the chunk
+/// tasks share them to count the downloaded bytes
+#[derive(Debug, Clone, Default)]
+pub struct SyncCounters {
+    counters: Arc<Counters>,
+}
+
+#[derive(Debug, Default)]
+struct Counters {
+    synced_files: AtomicU64,
+    failed_files: AtomicU64,
+    downloaded_bytes: AtomicU64,
+    served_bytes: AtomicU64,
+}
+
+impl SyncCounters {
+    pub fn record_synced(&self) {
+        self.counters.synced_files.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// every failed sync round is counted, a file retried several times is counted several times
+    pub fn record_failed(&self) {
+        self.counters.failed_files.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// the chunk written into the temp index file
+    pub fn record_downloaded(&self, bytes: u64) {
+        self.counters
+            .downloaded_bytes
+            .fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// the file content read for the peer requests
+    pub fn record_served(&self, bytes: u64) {
+        self.counters
+            .served_bytes
+            .fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub fn stats(&self) -> SyncCounterStats {
+        SyncCounterStats {
+            synced_files: self.counters.synced_files.load(Ordering::Relaxed),
+            failed_files: self.counters.failed_files.load(Ordering::Relaxed),
+            downloaded_bytes: self.counters.downloaded_bytes.load(Ordering::Relaxed),
+            served_bytes: self.counters.served_bytes.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sync_counters() {
+        let sync_counters = SyncCounters::default();
+        let shared = sync_counters.clone();
+        sync_counters.record_synced();
+        shared.record_failed();
+        shared.record_failed();
+        shared.record_downloaded(10);
+        shared.record_downloaded(5);
+        sync_counters.record_served(7);
+
+        assert_eq!(
+            sync_counters.stats(),
+            SyncCounterStats {
+                synced_files: 1,
+                failed_files: 2,
+                downloaded_bytes: 15,
+                served_bytes: 7,
+            }
+        );
+    }
+}
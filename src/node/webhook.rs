@@ -0,0 +1,156 @@
+use std::time::Duration;
+
+use http::header::CONTENT_TYPE;
+use http::{Method, Request, Uri};
+use hyper::client::HttpConnector;
+use hyper::{Body, Client};
+use serde::Serialize;
+use tokio::time;
+use tracing::{error, info, instrument};
+
+use crate::command::DownloadSource;
+
+/// the max time of posting a sync complete event
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// the event posted to the webhook when a file finishes syncing
+#[derive(Debug, Serialize)]
+struct SyncCompleteEvent<'a> {
+    filename: &'a str,
+    hash: &'a str,
+    size: u64,
+    /// the bytes served by each peer, the peer serving the most comes first
+    sources: Vec<SourceEvent>,
+}
+
+#[derive(Debug, Serialize)]
+struct SourceEvent {
+    peer_id: String,
+    bytes: u64,
+}
+
+/// post the sync complete events to the webhook url, the failures are only logged, so a broken
+/// webhook never stalls the sync
+#[derive(Debug, Clone)]
+pub struct SyncWebhook {
+    client: Client<HttpConnector>,
+    url: Uri,
+}
+
+impl SyncWebhook {
+    pub fn new(url: Uri) -> Self {
+        Self {
+            client: Client::new(),
+            url,
+        }
+    }
+
+    /// spawn a task to post the event
+    pub fn notify(&self, filename: &str, hash: &str, size: u64, sources: &[DownloadSource]) {
+        let body = match serde_json::to_vec(&SyncCompleteEvent {
+            filename,
+            hash,
+            size,
+            sources: sources
+                .iter()
+                .map(|source| SourceEvent {
+                    peer_id: source.peer.to_string(),
+                    bytes: source.bytes,
+                })
+                .collect(),
+        }) {
+            Err(err) => {
+                error!(%err, %filename, "encode sync complete event failed");
+
+                return;
+            }
+
+            Ok(body) => body,
+        };
+
+        let webhook = self.clone();
+        let filename = filename.to_string();
+        tokio::spawn(async move { webhook.post(&filename, body).await });
+    }
+
+    #[instrument(skip(self, body), fields(url = %self.url))]
+    async fn post(&self, filename: &str, body: Vec<u8>) {
+        let request = match Request::builder()
+            .method(Method::POST)
+            .uri(self.url.clone())
+            .header(CONTENT_TYPE, "application/json")
+            .body(Body::from(body))
+        {
+            Err(err) => {
+                error!(%err, "build webhook request failed");
+
+                return;
+            }
+
+            Ok(request) => request,
+        };
+
+        match time::timeout(WEBHOOK_TIMEOUT, self.client.request(request)).await {
+            Err(_) => error!("post sync complete event timeout"),
+            Ok(Err(err)) => error!(%err, "post sync complete event failed"),
+            Ok(Ok(response)) if !response.status().is_success() => {
+                error!(status = %response.status(), "webhook response is not success");
+            }
+            Ok(Ok(_)) => info!("post sync complete event done"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::Infallible;
+    use std::net::SocketAddr;
+
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::{Response, Server};
+    use libp2p::PeerId;
+    use serde_json::{json, Value};
+    use tokio::sync::mpsc;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_notify() {
+        let (sender, mut receiver) = mpsc::unbounded_channel();
+        let make_service = make_service_fn(move |_| {
+            let sender = sender.clone();
+
+            async move {
+                Ok::<_, Infallible>(service_fn(move |request: Request<Body>| {
+                    let sender = sender.clone();
+
+                    async move {
+                        let body = hyper::body::to_bytes(request.into_body()).await.unwrap();
+                        sender.send(body).unwrap();
+
+                        Ok::<_, Infallible>(Response::new(Body::empty()))
+                    }
+                }))
+            }
+        });
+        let server = Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(make_service);
+        let url = format!("http://{}/synced", server.local_addr())
+            .parse()
+            .unwrap();
+        tokio::spawn(server);
+
+        let peer = PeerId::random();
+        SyncWebhook::new(url).notify("test.txt", "abc", 4, &[DownloadSource { peer, bytes: 4 }]);
+
+        let body = receiver.recv().await.unwrap();
+        assert_eq!(
+            serde_json::from_slice::<Value>(&body).unwrap(),
+            json!({
+                "filename": "test.txt",
+                "hash": "abc",
+                "size": 4,
+                "sources": [{ "peer_id": peer.to_string(), "bytes": 4 }],
+            })
+        );
+    }
+}
@@ -0,0 +1,135 @@
+/// the junk files created by the OS, editors and downloaders, and the hidden files
+const DEFAULT_EXCLUDE_PATTERNS: &[&str] = &[
+    ".*",
+    "Thumbs.db",
+    "desktop.ini",
+    "~$*",
+    "*~",
+    "*.part",
+    "*.crdownload",
+    "*.tmp",
+];
+
+/// the filename patterns which are never announced or synced, `*` matches any chars and `?`
+/// matches one char, the matching is ascii case insensitive
+#[derive(Debug, Clone)]
+pub struct ExcludePatterns {
+    patterns: Vec<String>,
+}
+
+impl Default for ExcludePatterns {
+    fn default() -> Self {
+        Self::new(DEFAULT_EXCLUDE_PATTERNS.iter().map(ToString::to_string))
+    }
+}
+
+impl ExcludePatterns {
+    pub fn new<I: IntoIterator<Item = String>>(patterns: I) -> Self {
+        Self {
+            patterns: patterns.into_iter().collect(),
+        }
+    }
+
+    pub fn is_excluded(&self, filename: &str) -> bool {
+        self.patterns
+            .iter()
+            .any(|pattern| wildcard_match(pattern.as_bytes(), filename.as_bytes()))
+    }
+}
+
+/// the filename patterns of the only store files which are announced and served, the same
+/// wildcards as [`ExcludePatterns`] are supported, all files are shared when it is empty
+#[derive(Debug, Clone, Default)]
+pub struct SharePatterns {
+    patterns: Vec<String>,
+}
+
+impl SharePatterns {
+    pub fn new<I: IntoIterator<Item = String>>(patterns: I) -> Self {
+        Self {
+            patterns: patterns.into_iter().collect(),
+        }
+    }
+
+    pub fn is_shared(&self, filename: &str) -> bool {
+        self.patterns.is_empty()
+            || self
+                .patterns
+                .iter()
+                .any(|pattern| wildcard_match(pattern.as_bytes(), filename.as_bytes()))
+    }
+}
+
+/// match the filename with the pattern in the same way as [`ExcludePatterns`]
+pub fn match_pattern(pattern: &str, filename: &str) -> bool {
+    wildcard_match(pattern.as_bytes(), filename.as_bytes())
+}
+
+fn wildcard_match(pattern: &[u8], name: &[u8]) -> bool {
+    let (mut p, mut n) = (0, 0);
+    // the position of the last `*` and the name position it matched to
+    let mut star = None;
+
+    while n < name.len() {
+        match pattern.get(p) {
+            Some(b'*') => {
+                star = Some((p, n));
+                p += 1;
+            }
+
+            Some(c) if *c == b'?' || c.eq_ignore_ascii_case(&name[n]) => {
+                p += 1;
+                n += 1;
+            }
+
+            // backtrack, let the last `*` match one more char
+            _ => match star {
+                None => return false,
+                Some((star_p, star_n)) => {
+                    star = Some((star_p, star_n + 1));
+                    p = star_p + 1;
+                    n = star_n + 1;
+                }
+            },
+        }
+    }
+
+    pattern[p..].iter().all(|c| *c == b'*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_exclude_patterns() {
+        let exclude_patterns = ExcludePatterns::default();
+
+        assert!(exclude_patterns.is_excluded(".DS_Store"));
+        assert!(exclude_patterns.is_excluded("thumbs.db"));
+        assert!(exclude_patterns.is_excluded("movie.mp4.part"));
+        assert!(exclude_patterns.is_excluded("~$report.docx"));
+        assert!(!exclude_patterns.is_excluded("movie.mp4"));
+        assert!(!exclude_patterns.is_excluded("a.partial"));
+
+        let exclude_patterns = ExcludePatterns::new(["a?c*.txt".to_string()]);
+
+        assert!(exclude_patterns.is_excluded("abc.txt"));
+        assert!(exclude_patterns.is_excluded("ABCdef.txt"));
+        assert!(!exclude_patterns.is_excluded("ac.txt"));
+        assert!(!exclude_patterns.is_excluded("abc.txt.bak"));
+
+        assert!(!ExcludePatterns::new([]).is_excluded(".DS_Store"));
+    }
+
+    #[test]
+    fn test_share_patterns() {
+        assert!(SharePatterns::default().is_shared("movie.mp4"));
+
+        let share_patterns = SharePatterns::new(["*.mp4".to_string(), "public-*".to_string()]);
+
+        assert!(share_patterns.is_shared("movie.MP4"));
+        assert!(share_patterns.is_shared("public-notes.txt"));
+        assert!(!share_patterns.is_shared("private.txt"));
+    }
+}
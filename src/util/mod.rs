@@ -1,6 +1,7 @@
 use std::ffi::OsString;
 use std::io;
 use std::io::ErrorKind;
+use std::net::IpAddr;
 use std::path::{Path, PathBuf};
 
 use ed25519::pkcs8::{DecodePrivateKey, DecodePublicKey, PublicKeyBytes};
@@ -8,11 +9,57 @@ use ed25519::KeypairBytes;
 use futures_util::TryStreamExt;
 use libp2p::identity;
 use libp2p::identity::Keypair;
+use libp2p::multiaddr::Protocol;
+use libp2p::Multiaddr;
+use nix::errno::Errno;
 use tap::TapFallible;
 use tokio::fs;
 use tokio_stream::wrappers::ReadDirStream;
-use tracing::{error, info, instrument};
+use tracing::{error, info, instrument, warn};
+use unicode_normalization::{is_nfc, UnicodeNormalization};
 
+pub use self::exclude::{match_pattern, ExcludePatterns, SharePatterns};
+
+mod exclude;
+
+/// normalize the filename to the unicode NFC form, so the visually identical filenames from the
+/// different peers are equal
+pub fn normalize_filename(filename: &str) -> String {
+    if is_nfc(filename) {
+        filename.to_string()
+    } else {
+        filename.nfc().collect()
+    }
+}
+
+/// the max filename length of the common filesystems in bytes
+const MAX_FILENAME_LEN: usize = 255;
+
+/// check the filename advertised by a peer, it is joined to the store dir and logged, so the
+/// path separators, the control chars and the leading or trailing whitespaces are rejected,
+/// return the reason of the rejection
+pub fn check_peer_filename(filename: &str) -> Result<(), &'static str> {
+    if filename.is_empty() || filename == "." || filename == ".." {
+        return Err("filename is empty or a relative dir");
+    }
+    if filename.len() > MAX_FILENAME_LEN {
+        return Err("filename is too long");
+    }
+    if filename.contains(['/', '\\']) {
+        return Err("filename contains path separator");
+    }
+    if filename.chars().any(char::is_control) {
+        return Err("filename contains control char");
+    }
+    if filename.starts_with(char::is_whitespace) || filename.ends_with(char::is_whitespace) {
+        return Err("filename starts or ends with whitespace");
+    }
+
+    Ok(())
+}
+
+/// collect the non dir filenames, the entries which are not in the NFC form are renamed to the
+/// NFC form, if the NFC form entry exists, the entry is skipped
 #[instrument(err)]
 pub async fn collect_filenames(dir: &Path) -> io::Result<Vec<OsString>> {
     let read_dir = ReadDirStream::new(
@@ -27,37 +74,174 @@ pub async fn collect_filenames(dir: &Path) -> io::Result<Vec<OsString>> {
                 .metadata()
                 .await
                 .tap_err(|err| error!(%err, ?dir, "get entry metadata failed"))?;
-            Ok((!metadata.is_dir()).then_some(entry.file_name()))
+            if metadata.is_dir() {
+                return Ok(None);
+            }
+
+            let filename = entry.file_name();
+            let normalized_filename = match filename.to_str() {
+                Some(name) if !is_nfc(name) => OsString::from(normalize_filename(name)),
+                _ => return Ok(Some(filename)),
+            };
+
+            let path = entry.path();
+            let normalized_path = dir.join(&normalized_filename);
+            match fs::symlink_metadata(&normalized_path).await {
+                Err(err) if err.kind() == ErrorKind::NotFound => {}
+
+                Err(err) => {
+                    error!(%err, ?normalized_path, "get normalized entry metadata failed");
+
+                    return Err(err);
+                }
+
+                Ok(_) => {
+                    warn!(?path, ?normalized_path, "normalized entry exists, skip entry");
+
+                    return Ok(None);
+                }
+            }
+
+            fs::rename(&path, &normalized_path).await.tap_err(
+                |err| error!(%err, ?path, ?normalized_path, "rename entry to normalized filename failed"),
+            )?;
+
+            info!(?path, ?normalized_path, "rename entry to normalized filename done");
+
+            Ok(Some(normalized_filename))
         })
         .try_collect()
         .await
 }
 
 #[instrument(err)]
-pub async fn create_temp_dir(path: &Path) -> io::Result<PathBuf> {
-    let tmp_path = path.join(".tmp");
-
-    match fs::create_dir_all(&tmp_path).await {
+pub async fn create_temp_dir(tmp_path: &Path) -> io::Result<PathBuf> {
+    match fs::create_dir_all(tmp_path).await {
         Err(err) if err.kind() == ErrorKind::AlreadyExists => {
-            info!(?path, ?tmp_path, "temp dir exists");
+            info!(?tmp_path, "temp dir exists");
 
-            Ok(tmp_path)
+            Ok(tmp_path.to_path_buf())
         }
 
         Err(err) => {
-            error!(%err, ?path, ?tmp_path, "create temp die failed");
+            error!(%err, ?tmp_path, "create temp die failed");
 
             Err(err)
         }
 
         Ok(_) => {
-            info!(?path, ?tmp_path, "create temp dir done");
+            info!(?tmp_path, "create temp dir done");
 
-            Ok(tmp_path)
+            Ok(tmp_path.to_path_buf())
         }
     }
 }
 
+/// create the parent dir of the path, such as the shard dir of the index file
+#[instrument(err)]
+pub async fn create_parent_dir(path: &Path) -> io::Result<()> {
+    match path.parent() {
+        None => Ok(()),
+        Some(dir) => fs::create_dir_all(dir)
+            .await
+            .tap_err(|err| error!(%err, ?dir, "create parent dir failed")),
+    }
+}
+
+/// make the file read only, the index file is stored read only because it is never changed in
+/// place, and only the read only index file is memory mapped when serving it
+#[instrument(err)]
+pub async fn set_readonly(path: &Path) -> io::Result<()> {
+    let mut permissions = fs::metadata(path)
+        .await
+        .tap_err(|err| error!(%err, ?path, "get file metadata failed"))?
+        .permissions();
+    permissions.set_readonly(true);
+
+    fs::set_permissions(path, permissions)
+        .await
+        .tap_err(|err| error!(%err, ?path, "set file read only failed"))
+}
+
+/// move the file, copy it when `from` and `to` are on the different filesystems, the copy is
+/// written to a hidden file beside `to` first, so `to` is never partial
+#[instrument(err)]
+pub async fn move_file(from: &Path, to: &Path) -> io::Result<()> {
+    match fs::rename(from, to).await {
+        Err(err) if err.raw_os_error() == Some(Errno::EXDEV as _) => {
+            info!(?from, ?to, "rename crosses devices, fallback to copy");
+        }
+
+        result => return result,
+    }
+
+    let filename = to
+        .file_name()
+        .ok_or_else(|| io::Error::new(ErrorKind::InvalidInput, "no filename"))?;
+    let mut copy_filename = OsString::from(".");
+    copy_filename.push(filename);
+    copy_filename.push(".copying");
+    let copy_path = to.with_file_name(copy_filename);
+
+    if let Err(err) = fs::copy(from, &copy_path).await {
+        error!(%err, ?from, ?copy_path, "copy file failed");
+
+        let _ = fs::remove_file(&copy_path).await;
+
+        return Err(err);
+    }
+
+    fs::rename(&copy_path, to)
+        .await
+        .tap_err(|err| error!(%err, ?copy_path, ?to, "rename copied file failed"))?;
+
+    fs::remove_file(from)
+        .await
+        .tap_err(|err| error!(%err, ?from, "remove moved file failed"))
+}
+
+/// find the store filename which differs from `filename` only by case, they collide on the case
+/// insensitive filesystems
+pub fn find_case_conflict<'a>(filenames: &'a [OsString], filename: &str) -> Option<&'a OsString> {
+    let lowercase_filename = filename.to_lowercase();
+
+    filenames.iter().find(|store_filename| {
+        let store_filename = store_filename.to_string_lossy();
+
+        store_filename != filename && store_filename.to_lowercase() == lowercase_filename
+    })
+}
+
+/// the ip of the addr, `None` when the addr is not an ip addr, like the dns addr
+fn addr_ip(addr: &Multiaddr) -> Option<IpAddr> {
+    match addr.iter().next()? {
+        Protocol::Ip4(ip) => Some(ip.into()),
+        Protocol::Ip6(ip) => Some(ip.into()),
+        _ => None,
+    }
+}
+
+/// the loopback or the link local addr, the peers on the other hosts can't dial it
+pub fn is_local_addr(addr: &Multiaddr) -> bool {
+    match addr_ip(addr) {
+        Some(IpAddr::V4(ip)) => ip.is_loopback() || ip.is_link_local() || ip.is_unspecified(),
+        Some(IpAddr::V6(ip)) => {
+            ip.is_loopback() || (ip.segments()[0] & 0xffc0) == 0xfe80 || ip.is_unspecified()
+        }
+        None => false,
+    }
+}
+
+/// the addr which is routable on the internet, the non ip addr like the dns addr is considered
+/// global
+pub fn is_global_addr(addr: &Multiaddr) -> bool {
+    match addr_ip(addr) {
+        Some(IpAddr::V4(ip)) => !is_local_addr(addr) && !ip.is_private(),
+        Some(IpAddr::V6(ip)) => !is_local_addr(addr) && (ip.segments()[0] & 0xfe00) != 0xfc00,
+        None => true,
+    }
+}
+
 pub async fn load_keypair(secret_path: &Path, public_path: &Path) -> anyhow::Result<Keypair> {
     let secret = fs::read_to_string(secret_path).await?;
     let mut keypair = KeypairBytes::from_pkcs8_pem(&secret)?;
@@ -73,9 +257,32 @@ pub async fn load_keypair(secret_path: &Path, public_path: &Path) -> anyhow::Res
 #[cfg(test)]
 mod tests {
     use libp2p::PeerId;
+    use tempfile::TempDir;
 
     use super::*;
 
+    #[test]
+    fn test_addr_scope() {
+        let addrs = [
+            ("/ip4/127.0.0.1/tcp/1", true, false),
+            ("/ip4/169.254.1.1/tcp/1", true, false),
+            ("/ip4/192.168.1.1/tcp/1", false, false),
+            ("/ip4/1.1.1.1/tcp/1", false, true),
+            ("/ip6/::1/tcp/1", true, false),
+            ("/ip6/fe80::1/tcp/1", true, false),
+            ("/ip6/fd00::1/tcp/1", false, false),
+            ("/ip6/2001:4860::1/tcp/1", false, true),
+            ("/dns4/example.com/tcp/1", false, true),
+        ];
+
+        for (addr, local, global) in addrs {
+            let addr = addr.parse::<Multiaddr>().unwrap();
+
+            assert_eq!(is_local_addr(&addr), local, "{addr}");
+            assert_eq!(is_global_addr(&addr), global, "{addr}");
+        }
+    }
+
     #[tokio::test]
     async fn test_load_keypair() {
         let keypair = load_keypair(Path::new("secret.pem"), Path::new("public.pem"))
@@ -88,4 +295,87 @@ mod tests {
 
         assert_eq!(peer_id, keypair.public().to_peer_id());
     }
+
+    #[tokio::test]
+    async fn test_normalize_filename() {
+        let composed = "caf\u{e9}.txt";
+        let decomposed = "cafe\u{301}.txt";
+
+        assert_ne!(composed, decomposed);
+        assert_eq!(normalize_filename(decomposed), composed);
+        assert_eq!(normalize_filename(composed), composed);
+
+        let dir = TempDir::new().unwrap();
+        fs::write(dir.path().join(decomposed), b"test")
+            .await
+            .unwrap();
+
+        let filenames = collect_filenames(dir.path()).await.unwrap();
+        assert_eq!(filenames, vec![OsString::from(composed)]);
+        assert!(dir.path().join(composed).exists());
+
+        // both forms exist, the decomposed entry is skipped
+        fs::write(dir.path().join(decomposed), b"test")
+            .await
+            .unwrap();
+
+        let filenames = collect_filenames(dir.path()).await.unwrap();
+        assert_eq!(filenames, vec![OsString::from(composed)]);
+    }
+
+    #[test]
+    fn test_check_peer_filename() {
+        assert!(check_peer_filename("movie 2023.mp4").is_ok());
+        assert!(check_peer_filename("caf\u{e9}.txt").is_ok());
+
+        for filename in [
+            "",
+            ".",
+            "..",
+            "../secret",
+            "dir/file.txt",
+            "dir\\file.txt",
+            "fake\nlog line",
+            "bell\u{7}.txt",
+            "nul\0.txt",
+            "csi\u{9b}.txt",
+            "trailing.txt ",
+            " leading.txt",
+            "tab.txt\t",
+            "no-break.txt\u{a0}",
+            &"a".repeat(MAX_FILENAME_LEN + 1),
+        ] {
+            assert!(check_peer_filename(filename).is_err(), "{filename:?}");
+        }
+    }
+
+    #[test]
+    fn test_find_case_conflict() {
+        let filenames = vec![OsString::from("File.mp4"), OsString::from("a.txt")];
+
+        assert_eq!(
+            find_case_conflict(&filenames, "file.MP4"),
+            Some(&OsString::from("File.mp4"))
+        );
+        assert_eq!(find_case_conflict(&filenames, "File.mp4"), None);
+        assert_eq!(find_case_conflict(&filenames, "b.txt"), None);
+    }
+
+    #[tokio::test]
+    async fn test_move_file() {
+        let dir = TempDir::new().unwrap();
+        let temp_dir = create_temp_dir(&dir.path().join("tmp")).await.unwrap();
+        let from = temp_dir.join("hash");
+        let to = dir.path().join("hash");
+        fs::write(&from, b"test").await.unwrap();
+
+        move_file(&from, &to).await.unwrap();
+
+        assert!(!from.exists());
+        assert_eq!(fs::read(&to).await.unwrap(), b"test");
+        assert_eq!(
+            move_file(&from, &to).await.unwrap_err().kind(),
+            ErrorKind::NotFound
+        );
+    }
 }
@@ -27,7 +27,8 @@ class _BandwidthState extends State<Bandwidth> {
     final url =
         Util.getWsUri("/api/get_bandwidth", query: {"interval": "1000"});
 
-    _webSocketChannel = WebSocketChannel.connect(Uri.parse(url));
+    _webSocketChannel = WebSocketChannel.connect(Uri.parse(url),
+        protocols: [Util.wsProtocol]);
   }
 
   @override
@@ -26,7 +26,8 @@ class _TvListState extends State<TvList> {
 
     final url = Util.getWsUri("/api/list_tv", query: {"timeout": "10000"});
 
-    _webSocketChannel = WebSocketChannel.connect(Uri.parse(url));
+    _webSocketChannel = WebSocketChannel.connect(Uri.parse(url),
+        protocols: [Util.wsProtocol]);
   }
 
   @override
@@ -1,4 +1,7 @@
 class Util {
+  /// the websocket subprotocol which the server requires
+  static const String wsProtocol = "private-share-v1";
+
   static Uri getUri(String path, {Map<String, String>? query}) {
     Uri url;
     if (Uri.base.scheme == "http") {